@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bitcoin::{Amount, Transaction};
+use serde::{Deserialize, Serialize};
+
+use crate::node::Node;
+
+#[derive(Debug, Deserialize)]
+struct Unspent {
+    txid: String,
+    vout: u32,
+    address: Option<String>,
+    amount: f64,
+}
+
+/// A wallet UTXO, in the snapshot delta.
+#[derive(Debug, Clone, Serialize)]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Satoshis.
+    pub amount: u64,
+    /// Position in the batch (from 1) of the transaction creating it, if it is one of the batch's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_tx: Option<usize>,
+}
+
+/// The wallet's UTXO set (`listunspent`, unconfirmed included), by outpoint.
+pub struct Snapshot(BTreeMap<String, Utxo>);
+
+/// How the wallet's UTXO set changed during a run.
+#[derive(Debug, Serialize)]
+pub struct Delta {
+    pub consumed: Vec<Utxo>,
+    pub created: Vec<Utxo>,
+    /// Satoshis.
+    pub consumed_value: u64,
+    pub created_value: u64,
+    /// Created minus consumed: what the wallet gained (negative when it paid out).
+    pub net_value: i64,
+}
+
+impl Snapshot {
+    pub fn take(node: &Node) -> Result<Self> {
+        let unspent: Vec<Unspent> = serde_json::from_str(&node.run_wallet(&["listunspent", "0"])?)
+            .context("Failed to parse listunspent output")?;
+        let mut utxos = BTreeMap::new();
+        for utxo in unspent {
+            let amount = Amount::from_btc(utxo.amount)
+                .with_context(|| format!("Invalid amount of {}:{}", utxo.txid, utxo.vout))?;
+            utxos.insert(
+                format!("{}:{}", utxo.txid, utxo.vout),
+                Utxo {
+                    txid: utxo.txid,
+                    vout: utxo.vout,
+                    address: utxo.address,
+                    amount: amount.to_sat(),
+                    batch_tx: None,
+                },
+            );
+        }
+        Ok(Snapshot(utxos))
+    }
+
+    /// What changed from `self` to `after`, UTXOs created by `batch` transactions marked as such.
+    pub fn delta(&self, after: &Snapshot, batch: &[Option<Transaction>]) -> Delta {
+        let consumed: Vec<Utxo> = self
+            .0
+            .iter()
+            .filter(|(outpoint, _)| !after.0.contains_key(*outpoint))
+            .map(|(_, utxo)| utxo.clone())
+            .collect();
+        let created: Vec<Utxo> = after
+            .0
+            .iter()
+            .filter(|(outpoint, _)| !self.0.contains_key(*outpoint))
+            .map(|(_, utxo)| Utxo {
+                batch_tx: batch
+                    .iter()
+                    .position(|tx| {
+                        tx.as_ref()
+                            .is_some_and(|tx| tx.compute_txid().to_string() == utxo.txid)
+                    })
+                    .map(|i| i + 1),
+                ..utxo.clone()
+            })
+            .collect();
+        let consumed_value = consumed.iter().map(|utxo| utxo.amount).sum();
+        let created_value = created.iter().map(|utxo| utxo.amount).sum();
+        Delta {
+            consumed,
+            created,
+            consumed_value,
+            created_value,
+            net_value: created_value as i64 - consumed_value as i64,
+        }
+    }
+}
+
+pub fn write(path: &Path, delta: &Delta) -> Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(delta)? + "\n")
+        .with_context(|| format!("Failed to write UTXO delta to {}", path.display()))
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::sighash::SighashCache;
+use bitcoin::{absolute, ecdsa, relative, PublicKey, Transaction, TxOut};
+use miniscript::descriptor::DescriptorType;
+use miniscript::iter::TreeLike;
+use miniscript::policy::semantic::Policy;
+use miniscript::policy::Liftable;
+use miniscript::{Descriptor, DescriptorPublicKey, ForEachKey};
+use serde::Deserialize;
+
+use crate::node::Node;
+
+#[derive(Debug, Deserialize)]
+struct AddressInfo {
+    address: String,
+    solvable: Option<bool>,
+    desc: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChainTip {
+    blocks: u32,
+    mediantime: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Confirmations {
+    confirmations: Option<u32>,
+}
+
+/// Explain why the wallet could not complete an input, from the policy of the script it spends.
+///
+/// The wallet's descriptor for the prevout script is lifted to its spending
+/// policy, which is then checked against the transaction (locktime and the
+/// input's sequence), the chain tip, and the signatures already present, so
+/// `complete: false` turns into which keys, timelocks, or hash preimages are
+/// still missing.
+pub fn explain_input(
+    node: &Node,
+    tx: &Transaction,
+    input_index: usize,
+    spent: &TxOut,
+) -> Result<Vec<String>> {
+    let Some(info) = node.address_info(&spent.script_pubkey)? else {
+        return Ok(vec![
+            "prevout script has no address, cannot look it up in the wallet".into(),
+        ]);
+    };
+    let info: AddressInfo = serde_json::from_str(&info).context("Failed to parse address info")?;
+    let desc = match info.desc {
+        Some(desc) if info.solvable == Some(true) => desc,
+        _ => {
+            return Ok(vec![format!(
+                "wallet cannot solve {}: import its descriptor to sign this input",
+                info.address
+            )])
+        }
+    };
+
+    let secp = Secp256k1::verification_only();
+    let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&desc)
+        .with_context(|| format!("Failed to parse wallet descriptor {}", desc))?
+        .derived_descriptor(&secp, 0)
+        .with_context(|| format!("Failed to derive wallet descriptor {}", desc))?;
+    let policy = descriptor
+        .lift()
+        .context("Failed to lift descriptor policy")?;
+
+    let mut notes = Vec::new();
+
+    // Spending paths left open by the transaction's own timelock fields
+    let lock_time = tx.lock_time;
+    let sequence = tx.input[input_index].sequence;
+    let age = match sequence.to_relative_lock_time() {
+        Some(age) if tx.version.0 >= 2 => age,
+        _ => relative::LockTime::ZERO,
+    };
+    let enabled = policy.clone().at_lock_time(lock_time).at_age(age);
+    if enabled == Policy::Unsatisfiable && policy != Policy::Unsatisfiable {
+        for leaf in policy.pre_order_iter() {
+            match leaf {
+                Policy::After(after) => notes.push(format!(
+                    "{} requires nLockTime >= {}, transaction has {}",
+                    leaf, after, lock_time
+                )),
+                Policy::Older(_) => notes.push(format!(
+                    "{} requires a matching relative timelock in nSequence (version 2+), input has {:#x}",
+                    leaf,
+                    sequence.to_consensus_u32()
+                )),
+                _ => {}
+            }
+        }
+        return Ok(notes);
+    }
+
+    // Timelocks on the remaining paths that the chain has not reached yet
+    let tip: ChainTip = serde_json::from_str(&node.run(&["getblockchaininfo"])?)
+        .context("Failed to parse blockchain info")?;
+    let confirmations = serde_json::from_str::<Confirmations>(&node.run(&[
+        "getrawtransaction",
+        &tx.input[input_index].previous_output.txid.to_string(),
+        "true",
+    ])?)
+    .map(|info| info.confirmations.unwrap_or(0))
+    .ok();
+    for leaf in enabled.pre_order_iter() {
+        match leaf {
+            Policy::After(after) => match absolute::LockTime::from(*after) {
+                absolute::LockTime::Blocks(height) if height.to_consensus_u32() > tip.blocks => {
+                    notes.push(format!(
+                        "{} not mature yet: chain is at height {}",
+                        leaf, tip.blocks
+                    ))
+                }
+                absolute::LockTime::Seconds(time) if time.to_consensus_u32() > tip.mediantime => {
+                    notes.push(format!(
+                        "{} not mature yet: median time past is {}",
+                        leaf, tip.mediantime
+                    ))
+                }
+                _ => {}
+            },
+            Policy::Older(older) => match (relative::LockTime::from(*older), confirmations) {
+                (relative::LockTime::Blocks(blocks), Some(confirmations))
+                    if u32::from(blocks.value()) > confirmations =>
+                {
+                    notes.push(format!(
+                        "{} not mature yet: prevout has {} confirmation(s)",
+                        leaf, confirmations
+                    ))
+                }
+                _ => {}
+            },
+            Policy::Sha256(_) | Policy::Hash256(_) | Policy::Ripemd160(_) | Policy::Hash160(_) => {
+                notes.push(format!(
+                    "{} requires a hash preimage the wallet does not have",
+                    leaf
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    // Keys, split by whether a signature from them is already in the witness
+    let signed = signed_keys(tx, input_index, spent, &descriptor);
+    let keys: BTreeSet<PublicKey> = enabled
+        .pre_order_iter()
+        .filter_map(|leaf| match leaf {
+            Policy::Key(key) => Some(*key),
+            _ => None,
+        })
+        .collect();
+    let (present, missing): (Vec<PublicKey>, Vec<PublicKey>) =
+        keys.into_iter().partition(|key| signed.contains(key));
+    if !missing.is_empty() {
+        let keys: Vec<String> = missing.iter().map(PublicKey::to_string).collect();
+        notes.push(format!("missing signatures from {}", keys.join(", ")));
+    }
+    if !present.is_empty() {
+        let keys: Vec<String> = present.iter().map(PublicKey::to_string).collect();
+        notes.push(format!("signed by {}", keys.join(", ")));
+    }
+    notes.push(format!("spending policy: {}", enabled));
+
+    Ok(notes)
+}
+
+/// Keys with a valid ECDSA signature in a P2WSH input's (partial) witness.
+fn signed_keys(
+    tx: &Transaction,
+    input_index: usize,
+    spent: &TxOut,
+    descriptor: &Descriptor<PublicKey>,
+) -> Vec<PublicKey> {
+    if !matches!(
+        descriptor.desc_type(),
+        DescriptorType::Wsh
+            | DescriptorType::ShWsh
+            | DescriptorType::WshSortedMulti
+            | DescriptorType::ShWshSortedMulti
+    ) {
+        return Vec::new();
+    }
+    let Ok(script) = descriptor.explicit_script() else {
+        return Vec::new();
+    };
+
+    let secp = Secp256k1::verification_only();
+    let mut cache = SighashCache::new(tx);
+    let mut signed = Vec::new();
+    for element in tx.input[input_index].witness.iter() {
+        let Ok(signature) = ecdsa::Signature::from_slice(element) else {
+            continue;
+        };
+        let Ok(sighash) =
+            cache.p2wsh_signature_hash(input_index, &script, spent.value, signature.sighash_type)
+        else {
+            continue;
+        };
+        let msg = Message::from_digest(sighash.to_byte_array());
+        descriptor.for_each_key(|key| {
+            if secp
+                .verify_ecdsa(&msg, &signature.signature, &key.inner)
+                .is_ok()
+            {
+                signed.push(*key);
+            }
+            true
+        });
+    }
+    signed
+}
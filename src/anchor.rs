@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use bitcoin::hashes::Hash;
+use bitcoin::transaction::Version;
+use bitcoin::{
+    absolute, Amount, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut, WPubkeyHash,
+};
+
+use crate::coinselect::{self, Utxo};
+use crate::fees::{self, RedeemScripts};
+
+/// scriptPubKey of pay-to-anchor outputs: `OP_1 <0x4e73>`.
+const P2A_SCRIPT: [u8; 4] = [0x51, 0x02, 0x4e, 0x73];
+
+/// Whether a script is a pay-to-anchor output, spendable by anyone without a signature.
+pub fn is_p2a(script: &Script) -> bool {
+    script.as_bytes() == P2A_SCRIPT
+}
+
+/// scriptPubKey of a pay-to-anchor output.
+pub fn p2a_script() -> ScriptBuf {
+    ScriptBuf::from_bytes(P2A_SCRIPT.to_vec())
+}
+
+/// Pay-to-anchor outputs of the batch transactions, by outpoint.
+pub struct Anchors(HashMap<OutPoint, TxOut>);
+
+impl Anchors {
+    pub fn new(txs: &[Option<Transaction>]) -> Self {
+        let mut anchors = HashMap::new();
+        for tx in txs.iter().flatten() {
+            let txid = tx.compute_txid();
+            for (vout, output) in tx.output.iter().enumerate() {
+                if is_p2a(&output.script_pubkey) {
+                    anchors.insert(OutPoint::new(txid, vout as u32), output.clone());
+                }
+            }
+        }
+        Anchors(anchors)
+    }
+
+    pub fn get(&self, txid: &str, vout: u32) -> Option<&TxOut> {
+        let outpoint = OutPoint::from_str(&format!("{}:{}", txid, vout)).ok()?;
+        self.0.get(&outpoint)
+    }
+}
+
+/// Batch transactions with anchors, each with the batch transactions spending them.
+///
+/// A parent and its anchor children have to be submitted together as a
+/// package (`submitpackage`), since the parent alone usually pays too little.
+pub fn packages(txs: &[Option<Transaction>]) -> Vec<(usize, Vec<usize>)> {
+    let mut packages = Vec::new();
+    for (i, parent) in txs.iter().enumerate() {
+        let Some(parent) = parent else {
+            continue;
+        };
+        let anchors: Vec<OutPoint> = anchor_outpoints(parent).collect();
+        if anchors.is_empty() {
+            continue;
+        }
+        let children = txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| {
+                tx.iter()
+                    .flat_map(|tx| &tx.input)
+                    .any(|input| anchors.contains(&input.previous_output))
+            })
+            .map(|(j, _)| j)
+            .collect();
+        packages.push((i, children));
+    }
+    packages
+}
+
+fn anchor_outpoints(tx: &Transaction) -> impl Iterator<Item = OutPoint> + '_ {
+    let txid = tx.compute_txid();
+    tx.output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| is_p2a(&output.script_pubkey))
+        .map(move |(vout, _)| OutPoint::new(txid, vout as u32))
+}
+
+/// A child spending an anchor of `parent`, to an empty `OP_RETURN` output.
+fn child_skeleton(parent: &Transaction, anchor_vout: u32) -> Transaction {
+    Transaction {
+        version: if parent.version == Version(3) {
+            Version(3)
+        } else {
+            Version::TWO
+        },
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(parent.compute_txid(), anchor_vout),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return([]),
+        }],
+    }
+}
+
+/// What the wallet's UTXOs must bring in, beyond the fee of spending them,
+/// for a child spending an anchor of `parent` to make the package pay
+/// `fee_rate`: what the parent lacks, and the child's own fee with a change
+/// output (taken to be P2WPKH).
+pub fn child_need(
+    parent: &Transaction,
+    parent_spent: &[TxOut],
+    redeem_scripts: &RedeemScripts,
+    anchor_vout: u32,
+    fee_rate: f64,
+) -> Result<Amount> {
+    let parent_vsize = fees::estimate_signed_vsize(parent, parent_spent, redeem_scripts)?;
+    let parent_fee = fees::fee(parent, parent_spent)?;
+    let anchor = &parent.output[anchor_vout as usize];
+    let parent_deficit = (fee_rate * parent_vsize as f64).ceil() as i64
+        - parent_fee.to_sat() as i64
+        - anchor.value.to_sat() as i64;
+    let mut child = child_skeleton(parent, anchor_vout);
+    child.output[0].script_pubkey = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+    // Segwit marker and flag included, as coin selection does
+    let child_fee = coinselect::fee_for(child.weight().to_wu() + 2, fee_rate);
+    Ok(Amount::from_sat(parent_deficit.max(0) as u64 + child_fee))
+}
+
+/// Build a child spending an anchor of `parent` so the package pays `fee_rate`.
+///
+/// `parent_spent` holds the outputs spent by the parent's inputs, and
+/// `redeem_scripts` the redeem scripts of those that are P2SH. Inputs are
+/// picked from `utxos` to cover what the parent lacks plus the child's own
+/// fee; the rest goes to `change_script`, or to an empty `OP_RETURN` output if
+/// it isn't worth a change output. The child keeps the parent's version if it
+/// is a TRUC (version 3) transaction, as TRUC rules require.
+pub fn build_child(
+    parent: &Transaction,
+    parent_spent: &[TxOut],
+    redeem_scripts: &RedeemScripts,
+    anchor_vout: u32,
+    fee_rate: f64,
+    utxos: &[Utxo],
+    change_script: ScriptBuf,
+) -> Result<(Transaction, Vec<usize>)> {
+    let parent_vsize = fees::estimate_signed_vsize(parent, parent_spent, redeem_scripts)?;
+    let parent_fee = fees::fee(parent, parent_spent)?;
+    let anchor = parent.output[anchor_vout as usize].clone();
+    let mut child = child_skeleton(parent, anchor_vout);
+
+    // What the parent lacks at the target feerate, less what the anchor brings in
+    let parent_deficit = (fee_rate * parent_vsize as f64).ceil() as i64
+        - parent_fee.to_sat() as i64
+        - anchor.value.to_sat() as i64;
+    let selection = coinselect::select_coins(
+        utxos,
+        Amount::from_sat(parent_deficit.max(0) as u64),
+        child.weight().to_wu(),
+        &change_script,
+        fee_rate,
+    )?;
+
+    let mut spent = vec![anchor];
+    for &i in &selection.inputs {
+        child.input.push(TxIn {
+            previous_output: utxos[i].outpoint,
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            ..Default::default()
+        });
+        spent.push(utxos[i].txout.clone());
+    }
+    if selection.change {
+        child.output[0].script_pubkey = change_script;
+        let child_vsize =
+            fees::estimate_signed_vsize(&child, &spent, &coinselect::redeem_scripts(utxos))?;
+        let package_fee = (fee_rate * (parent_vsize + child_vsize) as f64).ceil() as u64;
+        let inputs: Amount = spent.iter().map(|out| out.value).sum();
+        let Some(change) = (inputs + parent_fee).checked_sub(Amount::from_sat(package_fee)) else {
+            bail!("selected inputs cannot pay for the package");
+        };
+        if change < child.output[0].script_pubkey.minimal_non_dust() {
+            bail!("change of {} would be dust", change);
+        }
+        child.output[0].value = change;
+    }
+
+    Ok((child, selection.inputs))
+}
@@ -0,0 +1,272 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// An approver as given in the `--approvers` file, with one of the keys.
+#[derive(Debug, Deserialize)]
+struct ApproverEntry {
+    name: String,
+    /// minisign public key (base64, as in the last line of a `.pub` file).
+    minisign: Option<String>,
+    /// OpenPGP key fingerprint, looked up in the GnuPG keyring.
+    gpg: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApproversFile {
+    threshold: usize,
+    approvers: Vec<ApproverEntry>,
+}
+
+enum Key {
+    Minisign(String),
+    Gpg(String),
+}
+
+struct Approver {
+    name: String,
+    key: Key,
+}
+
+/// Who may approve a batch, and how many of them must, before it is signed.
+///
+/// Approvals are detached signatures over the run's manifest (as `--mirror`
+/// prints it), made with minisign or GPG and checked with the `minisign` and
+/// `gpg` programs.
+pub struct Approvers {
+    threshold: usize,
+    approvers: Vec<Approver>,
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+impl Approvers {
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path).context("Failed to read approvers file")?;
+        Self::parse(&content)
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let file: ApproversFile =
+            serde_json::from_str(content).context("Failed to parse approvers file")?;
+        let mut approvers = Vec::new();
+        for entry in file.approvers {
+            let key = match (entry.minisign, entry.gpg) {
+                (Some(key), None) => Key::Minisign(key.trim().to_string()),
+                (None, Some(fingerprint)) => Key::Gpg(normalize_fingerprint(&fingerprint)),
+                _ => bail!(
+                    "Approver {} must have either a minisign or a gpg key",
+                    entry.name
+                ),
+            };
+            approvers.push(Approver {
+                name: entry.name,
+                key,
+            });
+        }
+        if file.threshold == 0 || file.threshold > approvers.len() {
+            bail!(
+                "Approval threshold {} is not between 1 and the number of approvers ({})",
+                file.threshold,
+                approvers.len()
+            );
+        }
+        Ok(Approvers {
+            threshold: file.threshold,
+            approvers,
+        })
+    }
+
+    /// Check that enough distinct approvers signed `manifest`, returning their names.
+    ///
+    /// Each signature file counts for the first approver whose key it verifies
+    /// with; files verifying with none are reported and ignored.
+    pub fn check(&self, manifest: &[u8], signatures: &[PathBuf]) -> Result<Vec<String>> {
+        // The programs verify a file: it is created afresh (0600) in a
+        // directory of our own (0700), so that nobody can swap it between them
+        let dir = tempfile::Builder::new()
+            .prefix("sign-txs-approval-")
+            .tempdir()
+            .context("Failed to create a directory for the manifest")?;
+        let mut file = tempfile::NamedTempFile::new_in(dir.path())
+            .context("Failed to create the manifest file for verification")?;
+        file.write_all(manifest)
+            .and_then(|()| file.flush())
+            .context("Failed to write the manifest for verification")?;
+        self.approve(signatures, |signature| self.signers(file.path(), signature))
+    }
+
+    /// Count the approvers of `signatures`, given those whose keys each one
+    /// verifies with, and check there are enough of them.
+    fn approve<'a>(
+        &'a self,
+        signatures: &[PathBuf],
+        mut signers: impl FnMut(&Path) -> Result<Vec<&'a Approver>>,
+    ) -> Result<Vec<String>> {
+        let mut approved: Vec<String> = Vec::new();
+        for signature in signatures {
+            match signers(signature)?.first() {
+                Some(approver) if approved.contains(&approver.name) => eprintln!(
+                    "  {}: another approval by {}, counted once",
+                    signature.display(),
+                    approver.name
+                ),
+                Some(approver) => {
+                    eprintln!("  {}: approved by {}", signature.display(), approver.name);
+                    approved.push(approver.name.clone());
+                }
+                None => eprintln!(
+                    "  Warning: {} is not a valid signature of the manifest by an approver",
+                    signature.display()
+                ),
+            }
+        }
+
+        if approved.len() < self.threshold {
+            bail!(
+                "Manifest approved by {} of the {} required approver(s){}",
+                approved.len(),
+                self.threshold,
+                if approved.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", approved.join(", "))
+                }
+            );
+        }
+        Ok(approved)
+    }
+
+    /// The approvers whose keys `signature` verifies `manifest` with, in the approvers file's order.
+    fn signers(&self, manifest: &Path, signature: &Path) -> Result<Vec<&Approver>> {
+        let content = std::fs::read(signature).with_context(|| {
+            format!("Failed to read approval signature {}", signature.display())
+        })?;
+        if content.starts_with(b"untrusted comment:") {
+            return Ok(self
+                .approvers
+                .iter()
+                .filter(|approver| match &approver.key {
+                    Key::Minisign(key) => minisign_verifies(key, manifest, signature),
+                    Key::Gpg(_) => false,
+                })
+                .collect());
+        }
+        let fingerprints = gpg_signers(manifest, signature)?;
+        Ok(self
+            .approvers
+            .iter()
+            .filter(|approver| match &approver.key {
+                Key::Gpg(fingerprint) => fingerprints.contains(fingerprint),
+                Key::Minisign(_) => false,
+            })
+            .collect())
+    }
+}
+
+fn minisign_verifies(key: &str, manifest: &Path, signature: &Path) -> bool {
+    Command::new("minisign")
+        .args(["-V", "-q", "-P", key, "-m"])
+        .arg(manifest)
+        .arg("-x")
+        .arg(signature)
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Fingerprints (of the signing key and its primary key) of the valid signatures in a GPG signature file.
+fn gpg_signers(manifest: &Path, signature: &Path) -> Result<Vec<String>> {
+    let output = Command::new("gpg")
+        .args(["--batch", "--status-fd", "1", "--verify"])
+        .arg(signature)
+        .arg(manifest)
+        .output()
+        .context("Failed to execute gpg")?;
+    // [GNUPG:] VALIDSIG <fingerprint> <date> ... <primary key fingerprint>
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .flat_map(|fields| {
+            let mut fields = fields.split_whitespace();
+            [fields.next(), fields.last()]
+        })
+        .flatten()
+        .map(normalize_fingerprint)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APPROVERS: &str = r#"{
+        "threshold": 2,
+        "approvers": [
+            { "name": "alice", "minisign": "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3" },
+            { "name": "bob", "gpg": "6F2A 1B04 93C8 7E51 D0A2  4B1C 90E3 F5A8 2C17 D6E4" },
+            { "name": "carol", "gpg": "0D4B 8C1E 52A7 F390 6E1D  B27C 44A9 0F85 E3C6 1B72" }
+        ]
+    }"#;
+
+    /// Approve with signature files named after the approvers they verify with, e.g. `alice+bob`.
+    fn approve(approvers: &Approvers, signatures: &[&str]) -> Result<Vec<String>> {
+        let signatures: Vec<PathBuf> = signatures.iter().map(PathBuf::from).collect();
+        approvers.approve(&signatures, |signature| {
+            let names: Vec<&str> = signature.to_str().unwrap().split('+').collect();
+            Ok(approvers
+                .approvers
+                .iter()
+                .filter(|approver| names.contains(&approver.name.as_str()))
+                .collect())
+        })
+    }
+
+    #[test]
+    fn threshold_is_checked_on_load() {
+        assert!(
+            Approvers::parse(&APPROVERS.replace("\"threshold\": 2", "\"threshold\": 0")).is_err()
+        );
+        assert!(
+            Approvers::parse(&APPROVERS.replace("\"threshold\": 2", "\"threshold\": 4")).is_err()
+        );
+        let approvers = Approvers::parse(APPROVERS).unwrap();
+        assert_eq!(approvers.threshold, 2);
+        assert!(matches!(&approvers.approvers[1].key, Key::Gpg(fingerprint)
+            if fingerprint == "6F2A1B0493C87E51D0A24B1C90E3F5A82C17D6E4"));
+    }
+
+    #[test]
+    fn threshold_counts_distinct_approvers() {
+        let approvers = Approvers::parse(APPROVERS).unwrap();
+        assert_eq!(
+            approve(&approvers, &["alice", "carol"]).unwrap(),
+            ["alice", "carol"]
+        );
+        assert!(approve(&approvers, &["alice"]).is_err());
+        assert!(approve(&approvers, &["alice", "alice"]).is_err());
+        assert!(approve(&approvers, &["alice", "mallory"]).is_err());
+    }
+
+    #[test]
+    fn signature_matching_several_approvers_counts_once() {
+        let approvers = Approvers::parse(APPROVERS).unwrap();
+        assert!(approve(&approvers, &["alice+bob"]).is_err());
+        assert_eq!(
+            approve(&approvers, &["bob+carol", "carol"]).unwrap(),
+            ["bob", "carol"]
+        );
+        assert_eq!(
+            approve(&approvers, &["alice+bob", "bob"]).unwrap(),
+            ["alice", "bob"]
+        );
+    }
+}
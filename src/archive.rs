@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::{Transaction, TxOut};
+
+use crate::psbt;
+
+/// Write a transaction to `<dir>/<txid>.hex`, and as a finalized PSBT to `<dir>/<txid>.psbt` if asked.
+///
+/// A labelled transaction's files are named `<label>-<txid>`, with characters
+/// other than letters, digits, `.`, `_` and `-` of the label replaced by `_`.
+pub fn write(
+    dir: &Path,
+    tx: &Transaction,
+    label: Option<&str>,
+    spent: Option<&[TxOut]>,
+    with_psbt: bool,
+) -> Result<()> {
+    std::fs::create_dir_all(dir).context("Failed to create archive directory")?;
+    let txid = match label {
+        Some(label) => {
+            let label: String = label
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+                    _ => '_',
+                })
+                .collect();
+            format!("{}-{}", label, tx.compute_txid())
+        }
+        None => tx.compute_txid().to_string(),
+    };
+    let path = dir.join(format!("{}.hex", txid));
+    std::fs::write(&path, serialize_hex(tx) + "\n")
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    if with_psbt {
+        let path = dir.join(format!("{}.psbt", txid));
+        std::fs::write(&path, psbt::from_tx(tx, spent)?.to_string() + "\n")
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
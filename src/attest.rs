@@ -0,0 +1,56 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::Transaction;
+use serde::Serialize;
+
+use crate::node::Node;
+
+/// A statement, signed with a key of the signing wallet, that its holder
+/// approved exactly the batch whose transactions are listed.
+///
+/// The message signed names the SHA-256 of the manifest (the txids, one per
+/// line, each followed by a newline), so anyone with the batch can check it
+/// with `verifymessage`, without trusting this file's own hash.
+#[derive(Debug, Serialize)]
+pub struct Attestation {
+    /// Txids of the batch's transactions, in batch order.
+    pub txids: Vec<String>,
+    /// SHA-256 of the manifest, in hex.
+    pub manifest_hash: String,
+    /// Address whose key signed the message.
+    pub address: String,
+    pub message: String,
+    /// `signmessage` signature, in base64.
+    pub signature: String,
+}
+
+/// The manifest of a batch: its txids, one per line.
+pub fn manifest(txs: &[&Transaction]) -> String {
+    txs.iter()
+        .map(|tx| format!("{}\n", tx.compute_txid()))
+        .collect()
+}
+
+/// Sign the manifest of `txs` with the wallet key of `address` (`signmessage`,
+/// which takes legacy P2PKH addresses only).
+pub fn attest(node: &Node, txs: &[&Transaction], address: &str) -> Result<Attestation> {
+    let manifest_hash = sha256::Hash::hash(manifest(txs).as_bytes()).to_string();
+    let message = format!("sign-txs batch {}", manifest_hash);
+    let signature = node
+        .run_wallet(&["signmessage", address, &message])
+        .context("Failed to sign the attestation")?;
+    Ok(Attestation {
+        txids: txs.iter().map(|tx| tx.compute_txid().to_string()).collect(),
+        manifest_hash,
+        address: address.to_string(),
+        message,
+        signature,
+    })
+}
+
+pub fn write(path: &Path, attestation: &Attestation) -> Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(attestation)? + "\n")
+        .with_context(|| format!("Failed to write the attestation to {}", path.display()))
+}
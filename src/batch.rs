@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::emit::BASE64_FIELD;
+
+/// A transaction batch as read from the input file.
+///
+/// Besides the plain `[{ "bitcoin": "<hex>" }, ...]` format, this accepts the
+/// output of `charms spell prove` and the Charms prover as-is: a bare array of
+/// hex strings, or an object wrapping the transactions (under `txs` or
+/// `transactions`) alongside proof metadata. Whatever shape was read is the
+/// shape written back, with everything except the transaction hexes untouched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Batch {
+    List(Vec<Entry>),
+    Wrapped {
+        #[serde(alias = "transactions")]
+        txs: Vec<Entry>,
+        #[serde(flatten)]
+        rest: Map<String, Value>,
+    },
+}
+
+/// Entry fields sign-txs reads or adds, as opposed to metadata passed through.
+const OWN_FIELDS: &[&str] = &[
+    "id",
+    "index",
+    "label",
+    "wallet",
+    "payjoin",
+    "sequences",
+    "version",
+    "template",
+    BASE64_FIELD,
+];
+
+/// A single transaction of a batch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Entry {
+    Hex(String),
+    Tx(TxEntry),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxEntry {
+    /// Absent for entries of other chains (e.g. `cardano`), which are passed through.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitcoin: Option<String>,
+    /// Fields other than the transaction itself (e.g. proof metadata), passed through.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Batch {
+    pub fn entries(&self) -> &[Entry] {
+        match self {
+            Batch::List(entries) => entries,
+            Batch::Wrapped { txs, .. } => txs,
+        }
+    }
+
+    pub fn entries_mut(&mut self) -> &mut [Entry] {
+        match self {
+            Batch::List(entries) => entries,
+            Batch::Wrapped { txs, .. } => txs,
+        }
+    }
+
+    /// A field of the batch besides its entries, for batches given as an object.
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Batch::List(_) => None,
+            Batch::Wrapped { rest, .. } => rest.get(name),
+        }
+    }
+
+    /// Identifiers of the entries, their `id` field or else their `index` field, in order.
+    ///
+    /// Entries carrying neither (such as those sign-txs inserts) are left out.
+    pub fn ids(&self) -> Vec<&Value> {
+        self.entries()
+            .iter()
+            .filter_map(|entry| entry.field("id").or_else(|| entry.field("index")))
+            .collect()
+    }
+
+    /// Remove all the entries, leaving the rest of the batch (e.g. proof metadata) as is.
+    pub fn take_entries(&mut self) -> Vec<Entry> {
+        match self {
+            Batch::List(entries) => std::mem::take(entries),
+            Batch::Wrapped { txs, .. } => std::mem::take(txs),
+        }
+    }
+
+    /// Add an entry at the end.
+    pub fn push(&mut self, entry: Entry) {
+        match self {
+            Batch::List(entries) => entries.push(entry),
+            Batch::Wrapped { txs, .. } => txs.push(entry),
+        }
+    }
+
+    /// Add an entry at `index`, shifting the following ones.
+    pub fn insert(&mut self, index: usize, entry: Entry) {
+        match self {
+            Batch::List(entries) => entries.insert(index, entry),
+            Batch::Wrapped { txs, .. } => txs.insert(index, entry),
+        }
+    }
+}
+
+impl Entry {
+    /// Raw bitcoin transaction hex of this entry, if it is a bitcoin entry.
+    pub fn hex(&self) -> Option<&str> {
+        match self {
+            Entry::Hex(hex) => Some(hex),
+            Entry::Tx(tx) => tx.bitcoin.as_deref(),
+        }
+    }
+
+    /// Chain a non-bitcoin entry belongs to, as named by its first field.
+    pub fn chain(&self) -> &str {
+        match self {
+            Entry::Tx(TxEntry {
+                bitcoin: None,
+                extra,
+            }) => extra.keys().next().map_or("unknown", String::as_str),
+            _ => "bitcoin",
+        }
+    }
+
+    /// A metadata field of the entry.
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Entry::Hex(_) => None,
+            Entry::Tx(tx) => tx.extra.get(name),
+        }
+    }
+
+    /// The entry's `label`, naming what the transaction is for in logs, statistics and the archive.
+    pub fn label(&self) -> Option<&str> {
+        self.field("label").and_then(Value::as_str)
+    }
+
+    /// The entry's `wallet`, the node wallet signing it instead of the default one.
+    pub fn wallet(&self) -> Option<&str> {
+        self.field("wallet").and_then(Value::as_str)
+    }
+
+    /// Whether the entry carries metadata (e.g. a spell and its proof) besides the hex.
+    ///
+    /// Fields sign-txs itself reads or adds (`label`, `index`, `wallet`...)
+    /// don't count: they say nothing about the transaction's witnesses.
+    pub fn has_metadata(&self) -> bool {
+        matches!(self, Entry::Tx(tx) if tx.extra.keys().any(|key| !OWN_FIELDS.contains(&key.as_str())))
+    }
+
+    /// Set a metadata field, turning a bare hex entry into an object.
+    pub fn set_field(&mut self, name: &str, value: Value) {
+        if let Entry::Hex(hex) = self {
+            *self = Entry::Tx(TxEntry {
+                bitcoin: Some(std::mem::take(hex)),
+                extra: Map::new(),
+            });
+        }
+        if let Entry::Tx(tx) = self {
+            tx.extra.insert(name.to_string(), value);
+        }
+    }
+
+    pub fn remove_field(&mut self, name: &str) {
+        if let Entry::Tx(tx) = self {
+            tx.extra.shift_remove(name);
+        }
+    }
+
+    pub fn set_hex(&mut self, hex: String) {
+        match self {
+            Entry::Hex(h) => *h = hex,
+            Entry::Tx(tx) => tx.bitcoin = Some(hex),
+        }
+    }
+}
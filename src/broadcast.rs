@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::batch::Entry;
+use crate::node::Node;
+
+/// Queue file used when none is given.
+pub const DEFAULT_QUEUE: &str = "sign-txs-queue.json";
+
+/// A transaction whose broadcast failed for a reason that may go away, waiting to be retried.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Queued {
+    pub txid: String,
+    pub hex: String,
+    /// Error of the last attempt.
+    pub error: String,
+    pub attempts: u32,
+    /// Unix time the transaction was first queued.
+    pub queued_at: u64,
+}
+
+/// Whether a broadcast error may go away by itself: the node being unreachable,
+/// or inputs missing because their (unconfirmed) parents aren't in its mempool yet.
+fn is_transient(error: &str) -> bool {
+    [
+        "Could not connect",
+        "couldn't connect",
+        "Failed to execute",
+        "timed out",
+        "Loading block index",
+        "Verifying blocks",
+        "missing-inputs",
+        "bad-txns-inputs-missingorspent",
+    ]
+    .iter()
+    .any(|pattern| error.contains(pattern))
+}
+
+/// Whether the node already has the transaction, which counts as sent.
+fn is_known(error: &str) -> bool {
+    error.contains("txn-already-known")
+        || error.contains("txn-already-in-mempool")
+        || error.contains("Transaction already in block chain")
+        || error.contains("Transaction outputs already in utxo set")
+}
+
+/// A transaction the node refused.
+#[derive(Debug, Serialize)]
+pub struct Rejection {
+    /// Position in the batch, from 1.
+    pub index: usize,
+    pub txid: String,
+    pub error: String,
+}
+
+/// What came of sending a batch.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// `submitpackage`, or `sendrawtransaction` if the transactions were sent one by one.
+    pub method: &'static str,
+    pub accepted: Vec<String>,
+    pub rejected: Vec<Rejection>,
+    /// Transactions added to the queue, to retry with `flush-queue`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub queued: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PackageResult {
+    package_msg: String,
+    /// By wtxid.
+    #[serde(rename = "tx-results", default)]
+    tx_results: HashMap<String, PackageTxResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PackageTxResult {
+    txid: String,
+    error: Option<String>,
+}
+
+pub enum Outcome {
+    Sent(String),
+    /// The transaction wasn't sent, but may be later.
+    Retry(String),
+    Failed(String),
+}
+
+/// Send one transaction with `sendrawtransaction`.
+pub fn send(node: &Node, hex: &str) -> Outcome {
+    match node.run(&["sendrawtransaction", hex]) {
+        Ok(txid) => Outcome::Sent(txid),
+        Err(e) => {
+            let error = format!("{:#}", e).trim().to_string();
+            if is_known(&error) {
+                Outcome::Sent(txid(hex).unwrap_or_default())
+            } else if is_transient(&error) {
+                Outcome::Retry(error)
+            } else {
+                Outcome::Failed(error)
+            }
+        }
+    }
+}
+
+fn txid(hex: &str) -> Result<String> {
+    let tx: bitcoin::Transaction = bitcoin::consensus::encode::deserialize_hex(hex)?;
+    Ok(tx.compute_txid().to_string())
+}
+
+/// Transactions waiting in the queue file, none if it doesn't exist.
+pub fn load(path: &Path) -> Result<Vec<Queued>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read queue {}", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse queue {}", path.display()))
+}
+
+/// Write the queue file, removing it once empty.
+pub fn save(path: &Path, queue: &[Queued]) -> Result<()> {
+    if queue.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove queue {}", path.display()))?;
+        }
+        return Ok(());
+    }
+    std::fs::write(path, serde_json::to_string_pretty(queue)? + "\n")
+        .with_context(|| format!("Failed to write queue {}", path.display()))
+}
+
+/// Send the batch transactions in order, adding those failing for a transient reason to
+/// the queue at `path`.
+///
+/// Entries of other chains are skipped, as are those in `sent` (by position,
+/// with their txid), already sent on their own, which count as accepted.
+pub fn batch(
+    node: &Node,
+    entries: &[Entry],
+    path: &Path,
+    sent: &[(usize, String)],
+) -> Result<Report> {
+    let mut queue = load(path)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut report = Report {
+        method: "sendrawtransaction",
+        ..Default::default()
+    };
+    for (i, entry) in entries.iter().enumerate() {
+        let Some(hex) = entry.hex() else {
+            continue;
+        };
+        if let Some((_, txid)) = sent.iter().find(|(j, _)| *j == i) {
+            eprintln!("  Transaction {}: already sent {}", i + 1, txid);
+            report.accepted.push(txid.clone());
+            continue;
+        }
+        match send(node, hex) {
+            Outcome::Sent(txid) => {
+                eprintln!("  Transaction {}: sent {}", i + 1, txid);
+                report.accepted.push(txid);
+            }
+            Outcome::Retry(error) => {
+                eprintln!("  Transaction {}: queued for retry: {}", i + 1, error);
+                let txid = txid(hex)?;
+                queue.retain(|queued| queued.txid != txid);
+                queue.push(Queued {
+                    txid: txid.clone(),
+                    hex: hex.to_string(),
+                    error,
+                    attempts: 1,
+                    queued_at: now,
+                });
+                report.queued.push(txid);
+            }
+            Outcome::Failed(error) => {
+                eprintln!("  Transaction {}: rejected: {}", i + 1, error);
+                report.rejected.push(Rejection {
+                    index: i + 1,
+                    txid: txid(hex).unwrap_or_default(),
+                    error,
+                });
+            }
+        }
+    }
+    save(path, &queue)?;
+    Ok(report)
+}
+
+/// Send the batch transactions together with `submitpackage`, so parents
+/// paying too little on their own are carried by their children.
+///
+/// If the node refuses the package as a whole, being too old to know
+/// `submitpackage` or the batch not being a child with its parents, they are
+/// sent in order with `sendrawtransaction` instead, as [`batch`] does.
+pub fn package(node: &Node, entries: &[Entry], path: &Path) -> Result<Report> {
+    let txs: Vec<(usize, &str)> = entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, entry)| entry.hex().map(|hex| (i, hex)))
+        .collect();
+    if txs.len() < 2 {
+        return batch(node, entries, path, &[]);
+    }
+    let hexes: Vec<&str> = txs.iter().map(|&(_, hex)| hex).collect();
+    let output = match node.run(&["submitpackage", &serde_json::to_string(&hexes)?]) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!(
+                "  submitpackage failed, sending the transactions in order: {}",
+                format!("{:#}", e).trim()
+            );
+            return batch(node, entries, path, &[]);
+        }
+    };
+    let result: PackageResult =
+        serde_json::from_str(&output).context("Failed to parse submitpackage result")?;
+
+    let mut report = Report {
+        method: "submitpackage",
+        ..Default::default()
+    };
+    for (i, hex) in txs {
+        let txid = txid(hex)?;
+        let tx_result = result
+            .tx_results
+            .values()
+            .find(|result| result.txid == txid);
+        match tx_result.map(|result| &result.error) {
+            Some(None) => {
+                eprintln!("  Transaction {}: accepted {}", i + 1, txid);
+                report.accepted.push(txid);
+            }
+            Some(Some(error)) => {
+                eprintln!("  Transaction {}: rejected: {}", i + 1, error);
+                report.rejected.push(Rejection {
+                    index: i + 1,
+                    txid,
+                    error: error.clone(),
+                });
+            }
+            // Left out when the package failed before getting to it
+            None => {
+                eprintln!("  Transaction {}: rejected: {}", i + 1, result.package_msg);
+                report.rejected.push(Rejection {
+                    index: i + 1,
+                    txid,
+                    error: result.package_msg.clone(),
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Retry the transactions of the queue at `path` in the order they were queued, keeping
+/// those still failing for a transient reason. Returns how many were sent, kept and dropped.
+pub fn flush(node: &Node, path: &Path) -> Result<(usize, usize, usize)> {
+    let queue = load(path)?;
+    let (mut sent, mut dropped) = (0, 0);
+    let mut kept = Vec::new();
+    for mut queued in queue {
+        match send(node, &queued.hex) {
+            Outcome::Sent(_) => {
+                eprintln!("  {}: sent", queued.txid);
+                sent += 1;
+            }
+            Outcome::Retry(error) => {
+                queued.attempts += 1;
+                eprintln!(
+                    "  {}: still failing after {} attempts: {}",
+                    queued.txid, queued.attempts, error
+                );
+                queued.error = error;
+                kept.push(queued);
+            }
+            Outcome::Failed(error) => {
+                eprintln!(
+                    "  {}: rejected, dropped from the queue: {}",
+                    queued.txid, error
+                );
+                dropped += 1;
+            }
+        }
+    }
+    save(path, &kept)?;
+    Ok((sent, kept.len(), dropped))
+}
+
+/// Confirmations of a transaction, from the wallet or else the node, `None` if neither knows it.
+fn confirmations(node: &Node, txid: &str) -> Option<i64> {
+    let info = node
+        .run_wallet(&["gettransaction", txid])
+        .or_else(|_| node.run(&["getrawtransaction", txid, "true"]))
+        .ok()?;
+    let info: serde_json::Value = serde_json::from_str(&info).ok()?;
+    Some(info["confirmations"].as_i64().unwrap_or(0))
+}
+
+/// Wait for a sent transaction to be in the mempool (`confirmations` 0) or to
+/// have that many confirmations, checking every `interval`.
+///
+/// Fails if it leaves the mempool without confirming (evicted or replaced), or
+/// on `timeout`.
+pub fn wait(
+    node: &Node,
+    txid: &str,
+    confirmations_needed: u32,
+    interval: Duration,
+    timeout: Duration,
+) -> Result<()> {
+    let started = Instant::now();
+    loop {
+        let in_mempool = node.run(&["getmempoolentry", txid]).is_ok();
+        let confirmed = confirmations(node, txid).unwrap_or(0);
+        if confirmed >= 1 && confirmed >= i64::from(confirmations_needed) {
+            eprintln!("  {} has {} confirmation(s)", txid, confirmed);
+            return Ok(());
+        }
+        if confirmed < 1 && !in_mempool {
+            bail!("{} left the mempool without confirming", txid);
+        }
+        if confirmations_needed == 0 {
+            eprintln!("  {} accepted into the mempool", txid);
+            return Ok(());
+        }
+        if started.elapsed() >= timeout {
+            bail!(
+                "{} still has {} of {} confirmation(s) after {}s",
+                txid,
+                confirmed.max(0),
+                confirmations_needed,
+                timeout.as_secs()
+            );
+        }
+        std::thread::sleep(interval);
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::script::PushBytesBuf;
+use bitcoin::transaction::Version;
+use bitcoin::{
+    absolute, Address, Amount, Denomination, Network, OutPoint, ScriptBuf, Sequence, Transaction,
+    TxIn, TxOut, Txid,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::batch::{Batch, Entry, TxEntry};
+use crate::coinselect::{self, Utxo};
+use crate::fees;
+use crate::node::Node;
+use crate::rewrite;
+
+/// Largest OP_RETURN payload relayed by default (`-datacarriersize` of 83 script bytes).
+pub const MAX_OP_RETURN_PAYLOAD: usize = 80;
+
+/// Transactions to build, as read by `sign-txs build`: a single spec or a list.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum BuildSpec {
+    List(Vec<TxSpec>),
+    Single(TxSpec),
+}
+
+/// A transaction to build.
+#[derive(Debug, Deserialize)]
+pub struct TxSpec {
+    /// Outpoints to spend; inputs (and change) are picked when absent.
+    inputs: Option<Vec<InputSpec>>,
+    /// Outputs in `createrawtransaction` form: `[{ "<address>": <btc> }, { "data": "<hex>" }]`.
+    outputs: Value,
+    /// Feerate in sat/vB when picking inputs.
+    fee_rate: Option<f64>,
+    /// Where change goes when inputs are picked from a given UTXO set rather than by the wallet.
+    change_address: Option<String>,
+    #[serde(default)]
+    locktime: u32,
+    /// Signal BIP 125 replaceability.
+    #[serde(default = "default_replaceable")]
+    replaceable: bool,
+    /// Payload of an OP_RETURN output added after `outputs`, e.g. to tag a deployment.
+    op_return: Option<OpReturn>,
+}
+
+/// An OP_RETURN payload, as `{ "hex": "..." }` or `{ "text": "..." }` (UTF-8).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OpReturn {
+    Hex(String),
+    Text(String),
+}
+
+impl OpReturn {
+    fn bytes(&self) -> Result<Vec<u8>> {
+        let bytes = match self {
+            OpReturn::Hex(hex) => {
+                Vec::<u8>::from_hex(hex).context("op_return hex payload is not valid hex")?
+            }
+            OpReturn::Text(text) => text.as_bytes().to_vec(),
+        };
+        if bytes.is_empty() {
+            bail!("op_return payload is empty");
+        }
+        if bytes.len() > MAX_OP_RETURN_PAYLOAD {
+            bail!(
+                "op_return payload is {} bytes, more than the {} relayed by default",
+                bytes.len(),
+                MAX_OP_RETURN_PAYLOAD
+            );
+        }
+        Ok(bytes)
+    }
+}
+
+fn default_replaceable() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct InputSpec {
+    txid: String,
+    vout: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<u32>,
+}
+
+/// Result of `fundrawtransaction`.
+#[derive(Debug, Deserialize)]
+pub struct Funded {
+    pub hex: String,
+    fee: f64,
+    changepos: i64,
+}
+
+impl Funded {
+    /// Position of the change output, if one was added.
+    pub fn change(&self) -> Option<usize> {
+        usize::try_from(self.changepos).ok()
+    }
+}
+
+impl std::fmt::Display for Funded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.change() {
+            Some(pos) => write!(
+                f,
+                "funded by the wallet, fee {} BTC, change at output {}",
+                self.fee, pos
+            ),
+            None => write!(f, "funded by the wallet, fee {} BTC, no change", self.fee),
+        }
+    }
+}
+
+impl TxSpec {
+    /// Add the `op_return` payload to `outputs` as a `data` output, turning them into a list.
+    fn attach_op_return(&mut self) -> Result<()> {
+        let Some(op_return) = &self.op_return else {
+            return Ok(());
+        };
+        let payload = op_return.bytes()?;
+        let mut outputs: Vec<Value> = match &self.outputs {
+            Value::Object(map) => map
+                .iter()
+                .map(|(key, value)| json!({ key: value }))
+                .collect(),
+            Value::Array(items) => items.clone(),
+            _ => bail!("outputs must be an object or a list of objects"),
+        };
+        if outputs.iter().any(|output| output.get("data").is_some()) {
+            bail!("op_return can't be combined with a data output");
+        }
+        outputs.push(json!({ "data": payload.to_lower_hex_string() }));
+        self.outputs = Value::Array(outputs);
+        eprintln!("  Adding a {}-byte OP_RETURN output", payload.len());
+        Ok(())
+    }
+}
+
+impl BuildSpec {
+    fn specs(self) -> Vec<TxSpec> {
+        match self {
+            BuildSpec::List(specs) => specs,
+            BuildSpec::Single(spec) => vec![spec],
+        }
+    }
+}
+
+/// Build unsigned transactions from their specs, as a batch ready for signing.
+///
+/// With a UTXO set, transactions are built locally and their inputs picked
+/// from it by [`coinselect`], without any node or wallet call; UTXOs spent by
+/// one transaction are not offered to the next. Otherwise the node builds
+/// them and the wallet funds them.
+pub fn build(
+    node: &Node,
+    spec: BuildSpec,
+    mut utxos: Option<Vec<Utxo>>,
+    network: Option<Network>,
+) -> Result<Batch> {
+    let mut entries = Vec::new();
+    for (i, mut spec) in spec.specs().into_iter().enumerate() {
+        eprintln!("\nBuilding transaction {}...", i + 1);
+        let hex = spec
+            .attach_op_return()
+            .and_then(|()| match &mut utxos {
+                Some(utxos) => build_local(&spec, utxos, network),
+                None => build_tx(node, &spec),
+            })
+            .with_context(|| format!("Failed to build transaction {}", i + 1))?;
+        entries.push(Entry::Tx(TxEntry {
+            bitcoin: Some(hex),
+            extra: Map::new(),
+        }));
+    }
+    Ok(Batch::List(entries))
+}
+
+fn build_tx(node: &Node, spec: &TxSpec) -> Result<String> {
+    let inputs = spec.inputs.as_deref().unwrap_or_default();
+    let hex = node.run(&[
+        "createrawtransaction",
+        &serde_json::to_string(inputs)?,
+        &spec.outputs.to_string(),
+        &spec.locktime.to_string(),
+        &spec.replaceable.to_string(),
+    ])?;
+
+    if spec.inputs.is_some() {
+        if spec.fee_rate.is_some() {
+            bail!("fee_rate only applies to transactions funded from the wallet (no inputs)");
+        }
+        eprintln!("  Built with {} input(s)", inputs.len());
+        return Ok(hex);
+    }
+
+    let mut options = json!({ "replaceable": spec.replaceable });
+    if let Some(fee_rate) = spec.fee_rate {
+        options["fee_rate"] = json!(fee_rate);
+    }
+    let funded = fund(node, &hex, &options)?;
+    eprintln!("  {}", funded);
+    Ok(funded.hex)
+}
+
+fn build_local(spec: &TxSpec, utxos: &mut Vec<Utxo>, network: Option<Network>) -> Result<String> {
+    // Same sequences as createrawtransaction
+    let sequence = if spec.replaceable {
+        Sequence::ENABLE_RBF_NO_LOCKTIME
+    } else if spec.locktime != 0 {
+        Sequence::ENABLE_LOCKTIME_NO_RBF
+    } else {
+        Sequence::MAX
+    };
+    let mut tx = Transaction {
+        version: Version::TWO,
+        lock_time: absolute::LockTime::from_consensus(spec.locktime),
+        input: Vec::new(),
+        output: parse_outputs(&spec.outputs, network)?,
+    };
+
+    if let Some(inputs) = &spec.inputs {
+        if spec.fee_rate.is_some() {
+            bail!("fee_rate only applies to transactions whose inputs are picked (no inputs)");
+        }
+        for input in inputs {
+            let txid = Txid::from_str(&input.txid)
+                .with_context(|| format!("Invalid txid {}", input.txid))?;
+            tx.input.push(TxIn {
+                previous_output: OutPoint::new(txid, input.vout),
+                sequence: input.sequence.map_or(sequence, Sequence),
+                ..Default::default()
+            });
+        }
+        eprintln!("  Built with {} input(s)", inputs.len());
+        return Ok(rewrite::encode_tx(&tx));
+    }
+
+    let fee_rate = spec
+        .fee_rate
+        .context("fee_rate is required to pick inputs from a UTXO set")?;
+    let change_address = spec
+        .change_address
+        .as_deref()
+        .context("change_address is required to pick inputs from a UTXO set")?;
+    let change_script = parse_address(change_address, network)?.script_pubkey();
+
+    let target = tx.output.iter().map(|out| out.value).sum();
+    let selection =
+        coinselect::select_coins(utxos, target, tx.weight().to_wu(), &change_script, fee_rate)?;
+    let spent: Vec<TxOut> = selection
+        .inputs
+        .iter()
+        .map(|&i| utxos[i].txout.clone())
+        .collect();
+    tx.input = selection
+        .inputs
+        .iter()
+        .map(|&i| TxIn {
+            previous_output: utxos[i].outpoint,
+            sequence,
+            ..Default::default()
+        })
+        .collect();
+    if selection.change {
+        tx.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: change_script,
+        });
+        let change_index = tx.output.len() - 1;
+        let redeem_scripts = coinselect::redeem_scripts(utxos);
+        fees::retarget_change(&mut tx, &spent, &redeem_scripts, change_index, fee_rate)?;
+    }
+    eprintln!(
+        "  Picked {} UTXO(s), fee {}, {}",
+        spent.len(),
+        fees::fee(&tx, &spent)?,
+        if selection.change {
+            "with change"
+        } else {
+            "no change"
+        }
+    );
+
+    let mut picked = selection.inputs;
+    picked.sort_unstable();
+    for i in picked.into_iter().rev() {
+        utxos.remove(i);
+    }
+    Ok(rewrite::encode_tx(&tx))
+}
+
+/// Spend all of `utxos` to `destination`, the fee at `fee_rate` coming out of the amount swept.
+pub fn sweep(
+    utxos: &[Utxo],
+    destination: &Address,
+    fee_rate: f64,
+    replaceable: bool,
+) -> Result<String> {
+    if utxos.is_empty() {
+        bail!("no UTXOs to sweep");
+    }
+    let sequence = if replaceable {
+        Sequence::ENABLE_RBF_NO_LOCKTIME
+    } else {
+        Sequence::MAX
+    };
+    let spent: Vec<TxOut> = utxos.iter().map(|utxo| utxo.txout.clone()).collect();
+    let mut tx = Transaction {
+        version: Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: utxos
+            .iter()
+            .map(|utxo| TxIn {
+                previous_output: utxo.outpoint,
+                sequence,
+                ..Default::default()
+            })
+            .collect(),
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: destination.script_pubkey(),
+        }],
+    };
+    let redeem_scripts = coinselect::redeem_scripts(utxos);
+    let (_, swept) = fees::retarget_change(&mut tx, &spent, &redeem_scripts, 0, fee_rate)?;
+    eprintln!(
+        "  Sweeping {} UTXO(s): {} to {}, fee {}",
+        utxos.len(),
+        swept,
+        destination,
+        fees::fee(&tx, &spent)?
+    );
+    Ok(rewrite::encode_tx(&tx))
+}
+
+pub fn parse_address(address: &str, network: Option<Network>) -> Result<Address> {
+    let unchecked =
+        Address::from_str(address).with_context(|| format!("Invalid address {}", address))?;
+    match network {
+        Some(network) => unchecked
+            .require_network(network)
+            .with_context(|| format!("Address {} is not for {}", address, network)),
+        None => Ok(unchecked.assume_checked()),
+    }
+}
+
+/// Outputs given in `createrawtransaction` form.
+fn parse_outputs(outputs: &Value, network: Option<Network>) -> Result<Vec<TxOut>> {
+    let pairs: Vec<(&String, &Value)> = match outputs {
+        Value::Object(map) => map.iter().collect(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| item.as_object().context("outputs must be objects"))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect(),
+        _ => bail!("outputs must be an object or a list of objects"),
+    };
+
+    pairs
+        .into_iter()
+        .map(|(key, value)| {
+            if key == "data" {
+                let data = value
+                    .as_str()
+                    .and_then(|hex| Vec::<u8>::from_hex(hex).ok())
+                    .context("data output must be hex")?;
+                let data = PushBytesBuf::try_from(data).context("data output is too large")?;
+                return Ok(TxOut {
+                    value: Amount::ZERO,
+                    script_pubkey: ScriptBuf::new_op_return(data),
+                });
+            }
+            let value = match value {
+                Value::Number(amount) => amount.as_f64().map(Amount::from_btc),
+                Value::String(amount) => Some(Amount::from_str_in(amount, Denomination::Bitcoin)),
+                _ => None,
+            }
+            .with_context(|| format!("Invalid amount for {}", key))?
+            .with_context(|| format!("Invalid amount for {}", key))?;
+            Ok(TxOut {
+                value,
+                script_pubkey: parse_address(key, network)?.script_pubkey(),
+            })
+        })
+        .collect()
+}
+
+/// Add inputs and change to a transaction with the wallet's `fundrawtransaction`.
+pub fn fund(node: &Node, hex: &str, options: &Value) -> Result<Funded> {
+    let output = node.run_wallet(&["fundrawtransaction", hex, &options.to_string()])?;
+    serde_json::from_str(&output).context("Failed to parse fundrawtransaction result")
+}
+
+/// Fund the template entries of a batch (those with a `template` field) with the wallet.
+///
+/// `template` is either `true` or an object of `fundrawtransaction` options,
+/// which take precedence over `options`. The field is removed once the entry
+/// is funded. Change is inserted among the outputs, so inputs of other batch
+/// transactions spending a template's outputs are repaired like for any other
+/// rewrite. Returns the number of funded entries.
+pub fn fund_templates(
+    node: &Node,
+    entries: &mut [Entry],
+    options: &Map<String, Value>,
+) -> Result<usize> {
+    let mut templates = BTreeMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let mut entry_options = options.clone();
+        match entry.field("template") {
+            None | Some(Value::Bool(false)) => continue,
+            Some(Value::Bool(true)) => {}
+            Some(Value::Object(overrides)) => entry_options.extend(overrides.clone()),
+            Some(other) => bail!("Invalid template field in transaction {}: {}", i + 1, other),
+        }
+        if entry.hex().is_none() {
+            bail!("Template transaction {} has no bitcoin transaction", i + 1);
+        }
+        templates.insert(i, Value::Object(entry_options));
+    }
+    if templates.is_empty() {
+        return Ok(0);
+    }
+
+    rewrite::rewrite_unsigned(entries, |i, tx| {
+        let Some(options) = templates.get(&i) else {
+            return Ok((0..tx.output.len()).collect());
+        };
+        let funded = fund(node, &rewrite::encode_tx(tx), options)
+            .with_context(|| format!("Failed to fund transaction {}", i + 1))?;
+        eprintln!("  Transaction {}: {}", i + 1, funded);
+        *tx = rewrite::decode_tx(&funded.hex).context("Failed to parse funded transaction")?;
+        let change = funded.change();
+        Ok((0..tx.output.len() - usize::from(change.is_some()))
+            .map(|vout| match change {
+                Some(pos) if vout >= pos => vout + 1,
+                _ => vout,
+            })
+            .collect())
+    })?;
+
+    for &i in templates.keys() {
+        entries[i].remove_field("template");
+    }
+    Ok(templates.len())
+}
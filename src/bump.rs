@@ -0,0 +1,80 @@
+use anyhow::{Context, Result};
+use bitcoin::{Amount, SignedAmount, Transaction};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::change::ChangeDetector;
+use crate::node::Node;
+use crate::rewrite;
+
+/// Default minimum feerate increase of a replacement (`-incrementalrelayfee`), in sat/vB.
+const INCREMENTAL_RELAY_FEE: u64 = 1;
+
+#[derive(Debug, Deserialize)]
+struct WalletTx {
+    confirmations: i64,
+    /// Negative for transactions the wallet sent, in BTC.
+    fee: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BumpResult {
+    psbt: String,
+    origfee: f64,
+    fee: f64,
+}
+
+/// Txid of a transaction if the wallet knows it and it is still unconfirmed.
+pub fn unconfirmed_wallet_txid(node: &Node, hex: &str) -> Result<Option<String>> {
+    let txid = rewrite::decode_tx(hex)?.compute_txid().to_string();
+    // gettransaction fails for transactions the wallet doesn't know
+    let Ok(output) = node.run_wallet(&["gettransaction", &txid]) else {
+        return Ok(None);
+    };
+    let info: WalletTx =
+        serde_json::from_str(&output).context("Failed to parse wallet transaction")?;
+    Ok((info.confirmations <= 0).then_some(txid))
+}
+
+/// What bumping wallet transaction `txid` to `fee_rate` must take from other
+/// UTXOs of the wallet: the fee increase (at least the incremental relay fee
+/// over its size) less what its change outputs can give up.
+pub fn need(node: &Node, txid: &str, tx: &Transaction, fee_rate: f64) -> Result<Amount> {
+    let info: WalletTx = serde_json::from_str(&node.run_wallet(&["gettransaction", txid])?)
+        .context("Failed to parse wallet transaction")?;
+    let fee = SignedAmount::from_btc(-info.fee.unwrap_or_default())?.unsigned_abs();
+    let vsize = tx.vsize() as u64;
+    let new_fee = Amount::from_sat(
+        ((fee_rate * vsize as f64).ceil() as u64).max(fee.to_sat() + INCREMENTAL_RELAY_FEE * vsize),
+    );
+    let change: Amount = ChangeDetector::Wallet
+        .change_outputs(node, tx)?
+        .into_iter()
+        .map(|i| tx.output[i].value)
+        .sum();
+    Ok(new_fee
+        .checked_sub(fee)
+        .unwrap_or_default()
+        .checked_sub(change)
+        .unwrap_or_default())
+}
+
+/// Replace a wallet transaction by a higher-fee version with `psbtbumpfee`.
+///
+/// The wallet builds the replacement (lowering its change or adding inputs),
+/// which is then signed with the wallet like any PSBT. Nothing is broadcast.
+pub fn bump(node: &Node, txid: &str, fee_rate: Option<f64>) -> Result<String> {
+    let mut options = json!({});
+    if let Some(fee_rate) = fee_rate {
+        options["fee_rate"] = json!(fee_rate);
+    }
+    let output = node.run_wallet(&["psbtbumpfee", txid, &options.to_string()])?;
+    let bumped: BumpResult =
+        serde_json::from_str(&output).context("Failed to parse psbtbumpfee result")?;
+    eprintln!(
+        "  Wallet bumped the fee from {} BTC to {} BTC",
+        bumped.origfee, bumped.fee
+    );
+    node.sign_psbt(&bumped.psbt)
+        .context("Failed to sign the replacement")
+}
@@ -0,0 +1,62 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+/// `value` as JSON written the same way every time, for output files kept
+/// under version control: keys sorted at every level, two-space indentation,
+/// fractional numbers in plain decimal (never with an exponent, and with a
+/// `.0` when whole), and a trailing newline.
+pub fn to_string<T: Serialize>(value: &T) -> Result<String> {
+    let mut out = String::new();
+    write_value(&mut out, &serde_json::to_value(value)?, 0)?;
+    out.push('\n');
+    Ok(out)
+}
+
+fn write_value(out: &mut String, value: &Value, depth: usize) -> Result<()> {
+    match value {
+        Value::Array(items) if !items.is_empty() => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                out.push_str(if i == 0 { "\n" } else { ",\n" });
+                indent(out, depth + 1);
+                write_value(out, item, depth + 1)?;
+            }
+            out.push('\n');
+            indent(out, depth);
+            out.push(']');
+        }
+        Value::Object(map) if !map.is_empty() => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                out.push_str(if i == 0 { "\n" } else { ",\n" });
+                indent(out, depth + 1);
+                out.push_str(&serde_json::to_string(key)?);
+                out.push_str(": ");
+                write_value(out, &map[key], depth + 1)?;
+            }
+            out.push('\n');
+            indent(out, depth);
+            out.push('}');
+        }
+        Value::Number(number) if number.is_f64() => {
+            let value = number.as_f64().expect("checked");
+            // Display for f64 never uses an exponent, unlike serde_json
+            let text = value.to_string();
+            out.push_str(&text);
+            if !text.contains('.') {
+                out.push_str(".0");
+            }
+        }
+        _ => out.push_str(&serde_json::to_string(value)?),
+    }
+    Ok(())
+}
+
+fn indent(out: &mut String, depth: usize) {
+    for _ in 0..depth {
+        out.push_str("  ");
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bitcoin::hashes::{sha256, Hash};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Where node calls are answered from instead of the node.
+#[derive(Debug, Clone)]
+pub enum Cassette {
+    /// Answer every call with the response recorded in this directory.
+    Replay(PathBuf),
+    /// Make calls as usual, recording them in this directory.
+    Record(PathBuf),
+}
+
+/// Placeholder for secrets left out of recordings.
+const REDACTED: &str = "<redacted>";
+
+/// Positions of secret arguments (passphrases, private keys), by RPC method.
+fn secret_args(method: &str) -> &'static [usize] {
+    match method {
+        "walletpassphrase" | "encryptwallet" | "importprivkey" | "signmessagewithprivkey" => &[1],
+        // Descriptors may carry private keys
+        "importdescriptors" => &[1],
+        "walletpassphrasechange" => &[1, 2],
+        "signrawtransactionwithkey" => &[2],
+        _ => &[],
+    }
+}
+
+/// Whether the response to a call is a secret (private keys).
+fn secret_output(args: &[String]) -> bool {
+    match args.first().map(String::as_str) {
+        Some("dumpprivkey" | "dumpmasterprivkey") => true,
+        Some("listdescriptors") => args.get(1).is_some_and(|private| private == "true"),
+        _ => false,
+    }
+}
+
+/// The arguments of a call as recorded, with secrets replaced.
+fn redact(args: &[&str]) -> Vec<String> {
+    let secret = secret_args(args.first().copied().unwrap_or_default());
+    args.iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            if secret.contains(&i) {
+                REDACTED.to_string()
+            } else {
+                arg.to_string()
+            }
+        })
+        .collect()
+}
+
+/// A recorded call: the wallet it went to, its arguments (without the CLI
+/// options), and what it returned.
+#[derive(Debug, Serialize, Deserialize)]
+struct Recording {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wallet: Option<String>,
+    args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// File holding the response to a call: the RPC method, and a hash of all the
+/// (redacted) arguments, after `-rpcwallet=<WALLET>` for a call to a wallet.
+fn path(dir: &Path, wallet: Option<&str>, args: &[String]) -> PathBuf {
+    let hashed: Vec<String> = wallet
+        .map(|wallet| format!("-rpcwallet={}", wallet))
+        .into_iter()
+        .chain(args.iter().cloned())
+        .collect();
+    let hash = sha256::Hash::hash(hashed.join("\0").as_bytes());
+    let method = args.first().map_or("none", String::as_str);
+    dir.join(format!("{}-{}.json", method, &hash.to_string()[..16]))
+}
+
+/// Save the response to a call to `wallet` (the default one if `None`), secrets redacted.
+pub fn record(
+    dir: &Path,
+    wallet: Option<&str>,
+    args: &[&str],
+    result: &Result<String>,
+) -> Result<()> {
+    let args = redact(args);
+    let (output, error) = match result {
+        Ok(_) if secret_output(&args) => (Some(REDACTED.to_string()), None),
+        Ok(output) => (Some(output.clone()), None),
+        Err(e) => (None, Some(format!("{:#}", e))),
+    };
+    let path = path(dir, wallet, &args);
+    let recording = Recording {
+        wallet: wallet.map(str::to_string),
+        args,
+        output,
+        error,
+    };
+    std::fs::create_dir_all(dir).context("Failed to create recording directory")?;
+    std::fs::write(&path, serde_json::to_string_pretty(&recording)? + "\n")
+        .with_context(|| format!("Failed to write recording {}", path.display()))
+}
+
+/// Answer a call to `wallet` with its recorded response.
+///
+/// Wallet signing calls that weren't recorded are stubbed, returning the
+/// transaction or PSBT as given, so a run can be replayed from chain data
+/// alone (the output then carries no new signatures).
+pub fn replay(dir: &Path, wallet: Option<&str>, args: &[&str]) -> Result<String> {
+    let path = path(dir, wallet, &redact(args));
+    if !path.exists() {
+        match args {
+            ["signrawtransactionwithwallet", hex, ..] => {
+                return Ok(json!({ "hex": hex, "complete": true }).to_string());
+            }
+            ["walletprocesspsbt", psbt, ..] => {
+                return Ok(json!({ "psbt": psbt, "complete": true }).to_string());
+            }
+            _ => {}
+        }
+    }
+    let content = std::fs::read_to_string(&path).with_context(|| {
+        format!(
+            "No recorded response for {} ({})",
+            args.join(" "),
+            path.display()
+        )
+    })?;
+    let recording: Recording = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse recording {}", path.display()))?;
+    if recording.wallet.as_deref() != wallet || recording.args != redact(args) {
+        bail!("Recording {} is for another call", path.display());
+    }
+    match (recording.output, recording.error) {
+        (_, Some(error)) => Err(anyhow!(error)),
+        (Some(output), None) => Ok(output),
+        (None, None) => Err(anyhow!("Recording {} has no response", path.display())),
+    }
+}
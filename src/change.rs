@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use bitcoin::{Address, Script, ScriptBuf, Transaction};
+use miniscript::{Descriptor, DescriptorPublicKey};
+use serde::Deserialize;
+
+use crate::node::Node;
+
+#[derive(Debug, Deserialize)]
+struct AddressInfo {
+    #[serde(default)]
+    ismine: bool,
+    #[serde(default)]
+    ischange: bool,
+}
+
+/// Addresses derived from a ranged change descriptor, as many as a wallet's default keypool.
+const DESCRIPTOR_RANGE: u32 = 1000;
+
+/// How change outputs are recognized.
+pub enum ChangeDetector {
+    /// Outputs paying to this address.
+    Address(ScriptBuf),
+    /// Outputs paying to an address of this descriptor.
+    Descriptor(HashSet<ScriptBuf>),
+    /// Outputs the wallet considers its own change (`getaddressinfo`).
+    Wallet,
+}
+
+impl ChangeDetector {
+    /// Parse `auto` (ask the wallet), a change descriptor or a change address.
+    ///
+    /// Ranged descriptors stand for their first 1000 addresses, on every path of multipath ones.
+    pub fn parse(spec: &str) -> Result<Self> {
+        if spec == "auto" {
+            return Ok(ChangeDetector::Wallet);
+        }
+        if spec.contains('(') {
+            let descriptor = Descriptor::<DescriptorPublicKey>::from_str(spec)
+                .with_context(|| format!("Invalid change descriptor {}", spec))?;
+            let mut scripts = HashSet::new();
+            for descriptor in descriptor
+                .into_single_descriptors()
+                .context("Invalid change descriptor")?
+            {
+                let range = match descriptor.has_wildcard() {
+                    true => 0..DESCRIPTOR_RANGE,
+                    false => 0..1,
+                };
+                for index in range {
+                    let derived = descriptor.at_derivation_index(index).with_context(|| {
+                        format!("Failed to derive change descriptor at index {}", index)
+                    })?;
+                    scripts.insert(derived.script_pubkey());
+                }
+            }
+            return Ok(ChangeDetector::Descriptor(scripts));
+        }
+        let address = Address::from_str(spec)
+            .with_context(|| format!("Invalid change address {}", spec))?
+            .assume_checked();
+        Ok(ChangeDetector::Address(address.script_pubkey()))
+    }
+
+    pub fn is_change(&self, node: &Node, script: &Script) -> Result<bool> {
+        match self {
+            ChangeDetector::Address(change) => Ok(script == change.as_script()),
+            ChangeDetector::Descriptor(scripts) => Ok(scripts.contains(script)),
+            ChangeDetector::Wallet => {
+                let Some(info) = node.address_info(script)? else {
+                    return Ok(false);
+                };
+                let info: AddressInfo =
+                    serde_json::from_str(&info).context("Failed to parse address info")?;
+                Ok(info.ismine && info.ischange)
+            }
+        }
+    }
+
+    /// Indices of the change outputs of a transaction.
+    pub fn change_outputs(&self, node: &Node, tx: &Transaction) -> Result<Vec<usize>> {
+        let mut change = Vec::new();
+        for (i, output) in tx.output.iter().enumerate() {
+            if self.is_change(node, &output.script_pubkey)? {
+                change.push(i);
+            }
+        }
+        Ok(change)
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::secp256k1::rand::rngs::StdRng;
+use bitcoin::secp256k1::rand::{Rng, SeedableRng};
+
+/// Environment variable that must be set to `1` for `--chaos` to be accepted,
+/// so faults can't be injected into a production run by a stray flag.
+pub const ENABLE_VAR: &str = "SIGN_TXS_ENABLE_CHAOS";
+
+/// Faults injected into node calls at random, to test how orchestration
+/// around the binary copes with calls failing partway through a batch.
+///
+/// Each call first fails outright with probability `failure`, or else hangs
+/// for `delay` and then fails as a timeout with probability `timeout`; a call
+/// that goes through has its response garbled with probability `malformed`.
+pub struct Chaos {
+    failure: f64,
+    timeout: f64,
+    malformed: f64,
+    delay: Duration,
+    rng: Mutex<StdRng>,
+}
+
+impl Chaos {
+    /// Parse `failure=<RATE>,timeout=<RATE>,malformed=<RATE>,delay=<SECS>,seed=<N>`,
+    /// every part optional; rates are probabilities from 0 to 1.
+    ///
+    /// With a seed, the same calls get the same faults from run to run.
+    pub fn parse(spec: &str) -> Result<Self> {
+        if std::env::var(ENABLE_VAR).as_deref() != Ok("1") {
+            bail!(
+                "--chaos injects faults on purpose, set {}=1 to allow it",
+                ENABLE_VAR
+            );
+        }
+        let mut chaos = Chaos {
+            failure: 0.0,
+            timeout: 0.0,
+            malformed: 0.0,
+            delay: Duration::from_secs(30),
+            rng: Mutex::new(StdRng::from_entropy()),
+        };
+        for part in spec.split(',').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .with_context(|| format!("Invalid chaos setting {}, expected KEY=VALUE", part))?;
+            let rate = || -> Result<f64> {
+                let rate: f64 = value
+                    .parse()
+                    .with_context(|| format!("Invalid chaos rate {}", part))?;
+                if !(0.0..=1.0).contains(&rate) {
+                    bail!("Chaos rate {} is not between 0 and 1", part);
+                }
+                Ok(rate)
+            };
+            match key {
+                "failure" => chaos.failure = rate()?,
+                "timeout" => chaos.timeout = rate()?,
+                "malformed" => chaos.malformed = rate()?,
+                "delay" => {
+                    chaos.delay = Duration::from_secs(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid chaos delay {}", part))?,
+                    )
+                }
+                "seed" => {
+                    let seed = value
+                        .parse()
+                        .with_context(|| format!("Invalid chaos seed {}", part))?;
+                    chaos.rng = Mutex::new(StdRng::seed_from_u64(seed));
+                }
+                _ => bail!("Unknown chaos setting {}", key),
+            }
+        }
+        Ok(chaos)
+    }
+
+    fn roll(&self, rate: f64) -> bool {
+        rate > 0.0 && self.rng.lock().expect("not poisoned").gen_bool(rate)
+    }
+
+    /// Make the call `args` with `run`, unless a fault is injected instead.
+    pub fn call(&self, args: &[&str], run: impl FnOnce() -> Result<String>) -> Result<String> {
+        let method = args.first().copied().unwrap_or_default();
+        if self.roll(self.failure) {
+            eprintln!("  [chaos] failing {}", method);
+            bail!("{} failed: injected failure", method);
+        }
+        if self.roll(self.timeout) {
+            eprintln!("  [chaos] timing {} out after {:?}", method, self.delay);
+            std::thread::sleep(self.delay);
+            bail!("{} timed out: injected timeout", method);
+        }
+        let output = run()?;
+        if self.roll(self.malformed) {
+            eprintln!("  [chaos] garbling the response to {}", method);
+            // Cut short, as a response read halfway would be
+            let mut cut = output.len() / 2;
+            while !output.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            return Ok(format!("{}\u{fffd}", &output[..cut]));
+        }
+        Ok(output)
+    }
+}
@@ -0,0 +1,305 @@
+use std::cmp::Reverse;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::{Amount, OutPoint, Script, ScriptBuf, TxOut, Txid};
+use serde::Deserialize;
+
+use crate::fees::{self, RedeemScripts};
+use crate::node::Node;
+
+/// Most branches branch-and-bound explores before falling back to largest-first.
+const BNB_MAX_TRIES: usize = 100_000;
+/// Spending weight assumed for change outputs of a type that can't be estimated (that of P2WPKH).
+const DEFAULT_CHANGE_SPEND_WEIGHT: u64 = 272;
+
+#[derive(Debug, Deserialize)]
+struct UtxoEntry {
+    txid: String,
+    vout: u32,
+    amount: f64,
+    #[serde(rename = "scriptPubKey")]
+    script_pubkey: String,
+    #[serde(rename = "redeemScript")]
+    redeem_script: Option<String>,
+}
+
+/// An output available for coin selection.
+#[derive(Debug, Clone)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    /// For P2SH outputs, as `listunspent` gives it.
+    pub redeem_script: Option<ScriptBuf>,
+}
+
+/// Redeem scripts of the P2SH outputs among `utxos`.
+pub fn redeem_scripts(utxos: &[Utxo]) -> RedeemScripts {
+    utxos
+        .iter()
+        .filter_map(|utxo| {
+            let redeem_script = utxo.redeem_script.clone()?;
+            Some((utxo.txout.script_pubkey.clone(), redeem_script))
+        })
+        .collect()
+}
+
+/// Read a UTXO set in `listunspent` form (`txid`, `vout`, `amount` in BTC,
+/// `scriptPubKey`, and `redeemScript` for P2SH outputs).
+pub fn load_utxos(path: &str) -> Result<Vec<Utxo>> {
+    let content = std::fs::read_to_string(path).context("Failed to read UTXO file")?;
+    parse_utxos(&content).context("Failed to parse UTXO file")
+}
+
+/// Spendable UTXOs of the wallet with at least `min_conf` confirmations,
+/// optionally only those paying to `addresses`.
+pub fn list_unspent(node: &Node, min_conf: u32, addresses: &[String]) -> Result<Vec<Utxo>> {
+    let output = node.run_wallet(&[
+        "listunspent",
+        &min_conf.to_string(),
+        "9999999",
+        &serde_json::to_string(addresses)?,
+    ])?;
+    parse_utxos(&output).context("Failed to parse listunspent result")
+}
+
+fn parse_utxos(content: &str) -> Result<Vec<Utxo>> {
+    let entries: Vec<UtxoEntry> = serde_json::from_str(content)?;
+    entries
+        .into_iter()
+        .map(|entry| {
+            let txid = Txid::from_str(&entry.txid)
+                .with_context(|| format!("Invalid txid {}", entry.txid))?;
+            Ok(Utxo {
+                outpoint: OutPoint::new(txid, entry.vout),
+                txout: TxOut {
+                    value: Amount::from_btc(entry.amount)?,
+                    script_pubkey: ScriptBuf::from_hex(&entry.script_pubkey).with_context(
+                        || format!("Invalid scriptPubKey for {}:{}", txid, entry.vout),
+                    )?,
+                },
+                redeem_script: entry
+                    .redeem_script
+                    .map(|hex| {
+                        ScriptBuf::from_hex(&hex).with_context(|| {
+                            format!("Invalid redeemScript for {}:{}", txid, entry.vout)
+                        })
+                    })
+                    .transpose()?,
+            })
+        })
+        .collect()
+}
+
+/// UTXOs picked to fund a transaction.
+#[derive(Debug)]
+pub struct Selection {
+    /// Indices into the UTXO set.
+    pub inputs: Vec<usize>,
+    /// Whether the excess is worth a change output (otherwise it goes to fees).
+    pub change: bool,
+}
+
+/// Fee for `weight` weight units at `fee_rate` sat/vB.
+pub fn fee_for(weight: u64, fee_rate: f64) -> u64 {
+    (fee_rate * weight as f64 / 4.0).ceil() as u64
+}
+
+/// Pick UTXOs paying `target` plus fees at `fee_rate`.
+///
+/// UTXOs are valued at their effective value (minus the fee of spending them).
+/// Branch-and-bound looks for a changeless selection whose excess is below
+/// the cost of creating and later spending a change output, keeping the one
+/// wasting the least; failing that, the largest UTXOs are added until change
+/// can be paid. `base_weight` is the weight of the transaction without inputs
+/// or change. UTXOs whose spending size can't be estimated are not used.
+pub fn select_coins(
+    utxos: &[Utxo],
+    target: Amount,
+    base_weight: u64,
+    change_script: &Script,
+    fee_rate: f64,
+) -> Result<Selection> {
+    let mut pool: Vec<(usize, u64)> = utxos
+        .iter()
+        .enumerate()
+        .filter_map(|(i, utxo)| {
+            let weight = fees::signed_input_weight(
+                &utxo.txout.script_pubkey,
+                utxo.redeem_script.as_deref(),
+            )?;
+            let value = utxo.txout.value.to_sat();
+            let effective = value.checked_sub(fee_for(weight, fee_rate))?;
+            (effective > 0).then_some((i, effective))
+        })
+        .collect();
+    pool.sort_by_key(|&(_, effective)| Reverse(effective));
+
+    // Segwit marker and flag included, as most inputs will be segwit
+    let needed = target.to_sat() + fee_for(base_weight + 2, fee_rate);
+    let change_output_weight = TxOut {
+        value: Amount::ZERO,
+        script_pubkey: change_script.into(),
+    }
+    .weight()
+    .to_wu();
+    let change_fee = fee_for(change_output_weight, fee_rate);
+    let change_spend_fee = fee_for(
+        fees::signed_input_weight(change_script, None).unwrap_or(DEFAULT_CHANGE_SPEND_WEIGHT),
+        fee_rate,
+    );
+    let cost_of_change = change_fee + change_spend_fee;
+
+    if let Some(inputs) = branch_and_bound(&pool, needed, cost_of_change) {
+        return Ok(Selection {
+            inputs,
+            change: false,
+        });
+    }
+
+    // Largest first, until a change output that isn't dust can be paid
+    let min_change = change_script.minimal_non_dust().to_sat();
+    let mut inputs = Vec::new();
+    let mut total = 0;
+    for &(i, effective) in &pool {
+        inputs.push(i);
+        total += effective;
+        if total >= needed + change_fee + min_change {
+            return Ok(Selection {
+                inputs,
+                change: true,
+            });
+        }
+    }
+    if total >= needed {
+        return Ok(Selection {
+            inputs,
+            change: false,
+        });
+    }
+    bail!(
+        "insufficient funds: need {} (with fees at {} sat/vB), UTXOs provide {}",
+        Amount::from_sat(needed),
+        fee_rate,
+        Amount::from_sat(total)
+    )
+}
+
+/// Depth-first search for the changeless selection with the least excess over `needed`.
+fn branch_and_bound(pool: &[(usize, u64)], needed: u64, cost_of_change: u64) -> Option<Vec<usize>> {
+    struct Search<'a> {
+        pool: &'a [(usize, u64)],
+        /// Sum of the effective values from each position to the end.
+        remaining: Vec<u64>,
+        needed: u64,
+        upper: u64,
+        tries: usize,
+        selected: Vec<usize>,
+        best: Option<(u64, Vec<usize>)>,
+    }
+
+    impl Search<'_> {
+        fn run(&mut self, pos: usize, total: u64) {
+            self.tries += 1;
+            if self.tries > BNB_MAX_TRIES || total > self.upper {
+                return;
+            }
+            if total >= self.needed {
+                let excess = total - self.needed;
+                if self.best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                    self.best = Some((excess, self.selected.clone()));
+                }
+                return;
+            }
+            if pos == self.pool.len() || total + self.remaining[pos] < self.needed {
+                return;
+            }
+            let (i, value) = self.pool[pos];
+            self.selected.push(i);
+            self.run(pos + 1, total + value);
+            self.selected.pop();
+            self.run(pos + 1, total);
+        }
+    }
+
+    let mut remaining = vec![0; pool.len() + 1];
+    for pos in (0..pool.len()).rev() {
+        remaining[pos] = remaining[pos + 1] + pool[pos].1;
+    }
+    let mut search = Search {
+        pool,
+        remaining,
+        needed,
+        upper: needed + cost_of_change,
+        tries: 0,
+        selected: Vec::new(),
+        best: None,
+    };
+    search.run(0, 0);
+    search.best.map(|(_, selected)| selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::WPubkeyHash;
+
+    use super::*;
+
+    fn p2wpkh() -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros())
+    }
+
+    fn utxo(value: u64, script_pubkey: ScriptBuf, redeem_script: Option<ScriptBuf>) -> Utxo {
+        Utxo {
+            outpoint: OutPoint::new(Txid::all_zeros(), value as u32),
+            txout: TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey,
+            },
+            redeem_script,
+        }
+    }
+
+    /// P2WPKH UTXOs of 10,000, 20,000 and 50,000 sats, each 68 sats to spend at 1 sat/vB.
+    fn utxos() -> Vec<Utxo> {
+        [10_000, 20_000, 50_000]
+            .into_iter()
+            .map(|value| utxo(value, p2wpkh(), None))
+            .collect()
+    }
+
+    #[test]
+    fn exact_match_needs_no_change() {
+        // 9,932 + 19,932 effective, less the segwit marker and flag
+        let target = Amount::from_sat(9_932 + 19_932 - 1);
+        let mut selection = select_coins(&utxos(), target, 0, &p2wpkh(), 1.0).unwrap();
+        selection.inputs.sort_unstable();
+        assert_eq!(selection.inputs, vec![0, 1]);
+        assert!(!selection.change);
+    }
+
+    #[test]
+    fn largest_first_with_change() {
+        let selection =
+            select_coins(&utxos(), Amount::from_sat(60_000), 0, &p2wpkh(), 1.0).unwrap();
+        assert_eq!(selection.inputs, vec![2, 1]);
+        assert!(selection.change);
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        assert!(select_coins(&utxos(), Amount::from_sat(80_000), 0, &p2wpkh(), 1.0).is_err());
+    }
+
+    #[test]
+    fn p2sh_needs_its_redeem_script() {
+        let p2sh = ScriptBuf::new_p2sh(&p2wpkh().script_hash());
+        let target = Amount::from_sat(1_000);
+        let unknown = [utxo(10_000, p2sh.clone(), None)];
+        assert!(select_coins(&unknown, target, 0, &p2wpkh(), 1.0).is_err());
+        let known = [utxo(10_000, p2sh, Some(p2wpkh()))];
+        let selection = select_coins(&known, target, 0, &p2wpkh(), 1.0).unwrap();
+        assert_eq!(selection.inputs, vec![0]);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::batch::Batch;
+use crate::canonical;
+use crate::rewrite;
+
+/// Several batch files processed as one (`--batch-file`), sharing prevout
+/// lookups, the dependency graph (a file's transactions may spend another
+/// file's outputs), wallet UTXOs picked for anchor children, and the run's
+/// summaries, archive, ledger and attestation.
+///
+/// Their entries are signed as a single batch, in file order, then split
+/// back into batches of the shapes read.
+pub struct Combined {
+    parts: Vec<Part>,
+}
+
+struct Part {
+    path: PathBuf,
+    /// The batch as read, without its entries.
+    shell: Batch,
+    /// Number of entries read.
+    len: usize,
+}
+
+/// A batch file of the session, once written.
+#[derive(Debug, Serialize)]
+pub struct Written {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub transactions: usize,
+    /// Txids of its bitcoin transactions, in batch order.
+    pub txids: Vec<String>,
+}
+
+impl Combined {
+    /// Read the batch files, returning them and their entries as one batch.
+    ///
+    /// With `index_entries`, each entry gets its position in its own file.
+    /// Entry identifiers must be unique within each file only.
+    pub fn load(paths: &[PathBuf], index_entries: bool) -> Result<(Combined, Batch)> {
+        let mut names = HashSet::new();
+        let mut parts = Vec::new();
+        let mut combined = Batch::List(Vec::new());
+        for path in paths {
+            let name = path
+                .file_name()
+                .with_context(|| format!("Batch file {} has no file name", path.display()))?;
+            if !names.insert(name.to_os_string()) {
+                bail!(
+                    "Batch files share the name {}, their outputs would overwrite each other",
+                    name.to_string_lossy()
+                );
+            }
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read batch file {}", path.display()))?;
+            let mut batch: Batch = serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse batch file {}", path.display()))?;
+            if index_entries {
+                for (i, entry) in batch.entries_mut().iter_mut().enumerate() {
+                    if entry.field("index").is_none() {
+                        entry.set_field("index", i.into());
+                    }
+                }
+            }
+            let mut unique = HashSet::new();
+            if let Some(duplicate) = batch.ids().iter().find(|id| !unique.insert(id.to_string())) {
+                bail!(
+                    "Duplicate entry identifier {} in batch file {}",
+                    duplicate,
+                    path.display()
+                );
+            }
+            let entries = batch.take_entries();
+            eprintln!("  {}: {} transaction(s)", path.display(), entries.len());
+            parts.push(Part {
+                path: path.clone(),
+                shell: batch,
+                len: entries.len(),
+            });
+            for entry in entries {
+                combined.push(entry);
+            }
+        }
+        Ok((Combined { parts }, combined))
+    }
+
+    /// A field of each batch file that has it besides its entries, with the file's path.
+    pub fn fields(&self, name: &str) -> Vec<(&Path, &Value)> {
+        self.parts
+            .iter()
+            .filter_map(|part| Some((part.path.as_path(), part.shell.field(name)?)))
+            .collect()
+    }
+
+    /// Split the processed batch into the files' batches and write them to
+    /// `dir`, each under the name of the file it was read from.
+    ///
+    /// `origin` is the position each entry was read at, `None` for those
+    /// inserted while processing (anchor children), which go with the entry
+    /// before them, their parent. With `canonical`, they are written as
+    /// `--canonical-output` prints.
+    pub fn write(
+        self,
+        batch: &Batch,
+        origin: &[Option<usize>],
+        dir: &Path,
+        canonical: bool,
+    ) -> Result<Vec<Written>> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        let mut parts = self.parts;
+        let mut part = 0;
+        let mut start = 0;
+        for (entry, origin) in batch.entries().iter().zip(origin) {
+            if let Some(position) = origin {
+                while *position >= start + parts[part].len {
+                    start += parts[part].len;
+                    part += 1;
+                }
+            }
+            parts[part].shell.push(entry.clone());
+        }
+
+        let mut written = Vec::new();
+        for part in parts {
+            let output = dir.join(part.path.file_name().expect("checked when loaded"));
+            let json = match canonical {
+                true => canonical::to_string(&part.shell)?,
+                false => serde_json::to_string_pretty(&part.shell)? + "\n",
+            };
+            std::fs::write(&output, json)
+                .with_context(|| format!("Failed to write {}", output.display()))?;
+            let txids = rewrite::parse_batch(part.shell.entries())?
+                .iter()
+                .flatten()
+                .map(|tx| tx.compute_txid().to_string())
+                .collect();
+            written.push(Written {
+                input: part.path,
+                output,
+                transactions: part.shell.entries().len(),
+                txids,
+            });
+        }
+        Ok(written)
+    }
+}
@@ -0,0 +1,159 @@
+use bitcoin::{Amount, Transaction};
+use serde::Serialize;
+
+/// A transaction as it is in one of the outputs compared.
+pub struct Side<'a> {
+    pub tx: &'a Transaction,
+    pub fee: Option<Amount>,
+}
+
+impl<'a> Side<'a> {
+    /// The transactions of an output, with their fees where known.
+    pub fn list(txs: &'a [Option<Transaction>], fees: &[Option<Amount>]) -> Vec<Option<Self>> {
+        txs.iter()
+            .zip(fees)
+            .map(|(tx, &fee)| tx.as_ref().map(|tx| Side { tx, fee }))
+            .collect()
+    }
+}
+
+/// How a transaction of the new output differs from the previous one.
+#[derive(Debug, Serialize)]
+pub struct Difference {
+    /// `unchanged`, `resigned` (same txid, different signatures), `changed`,
+    /// `added` or `removed`.
+    pub status: &'static str,
+    /// Position in the previous output, from 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_index: Option<usize>,
+    /// Position in the new output, from 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_txid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_txid: Option<String>,
+    /// Satoshis, new minus old, if both fees are known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_delta: Option<i64>,
+    /// Virtual bytes, new minus old.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vsize_delta: Option<i64>,
+}
+
+/// Every transaction of both outputs, paired up, with a count of each status.
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub unchanged: usize,
+    pub resigned: usize,
+    pub changed: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub transactions: Vec<Difference>,
+}
+
+/// Pair the transactions of two outputs of a batch and report how they differ.
+///
+/// Identical transactions, then transactions with the same txid, are paired
+/// wherever they are in the batches, so reordering alone changes nothing. Those left are paired by
+/// position, as regenerating a transaction usually keeps its place, and any
+/// remaining are added or removed.
+pub fn compare(old: &[Option<Side>], new: &[Option<Side>]) -> Comparison {
+    let txid = |side: &Side| side.tx.compute_txid();
+    let mut old_paired: Vec<Option<usize>> = vec![None; old.len()];
+    let mut new_paired: Vec<Option<usize>> = vec![None; new.len()];
+    // Identical transactions first, so a re-signed copy doesn't take the place of the original
+    let same_tx = |old: &Side, new: &Side| old.tx == new.tx;
+    let same_txid = |old: &Side, new: &Side| txid(old) == txid(new);
+    for matches in [&same_tx as &dyn Fn(&Side, &Side) -> bool, &same_txid] {
+        for (j, side) in new.iter().enumerate() {
+            let Some(side) = side.as_ref().filter(|_| new_paired[j].is_none()) else {
+                continue;
+            };
+            if let Some(i) = (0..old.len()).find(|&i| {
+                old_paired[i].is_none() && old[i].as_ref().is_some_and(|old| matches(old, side))
+            }) {
+                old_paired[i] = Some(j);
+                new_paired[j] = Some(i);
+            }
+        }
+    }
+    for j in 0..new.len().min(old.len()) {
+        if new[j].is_some()
+            && old[j].is_some()
+            && new_paired[j].is_none()
+            && old_paired[j].is_none()
+        {
+            old_paired[j] = Some(j);
+            new_paired[j] = Some(j);
+        }
+    }
+
+    let mut transactions = Vec::new();
+    for (j, side) in new.iter().enumerate() {
+        let Some(side) = side else {
+            continue;
+        };
+        let Some(i) = new_paired[j] else {
+            transactions.push(Difference {
+                status: "added",
+                old_index: None,
+                new_index: Some(j + 1),
+                old_txid: None,
+                new_txid: Some(txid(side).to_string()),
+                fee_delta: None,
+                vsize_delta: None,
+            });
+            continue;
+        };
+        let previous = old[i].as_ref().expect("paired transactions exist");
+        let status = if previous.tx == side.tx {
+            "unchanged"
+        } else if txid(previous) == txid(side) {
+            "resigned"
+        } else {
+            "changed"
+        };
+        transactions.push(Difference {
+            status,
+            old_index: Some(i + 1),
+            new_index: Some(j + 1),
+            old_txid: Some(txid(previous).to_string()),
+            new_txid: Some(txid(side).to_string()),
+            fee_delta: previous
+                .fee
+                .zip(side.fee)
+                .map(|(old, new)| new.to_sat() as i64 - old.to_sat() as i64),
+            vsize_delta: Some(side.tx.vsize() as i64 - previous.tx.vsize() as i64),
+        });
+    }
+    for (i, side) in old.iter().enumerate() {
+        let Some(side) = side.as_ref().filter(|_| old_paired[i].is_none()) else {
+            continue;
+        };
+        transactions.push(Difference {
+            status: "removed",
+            old_index: Some(i + 1),
+            new_index: None,
+            old_txid: Some(txid(side).to_string()),
+            new_txid: None,
+            fee_delta: None,
+            vsize_delta: None,
+        });
+    }
+
+    let count = |status| {
+        transactions
+            .iter()
+            .filter(|difference| difference.status == status)
+            .count()
+    };
+    Comparison {
+        unchanged: count("unchanged"),
+        resigned: count("resigned"),
+        changed: count("changed"),
+        added: count("added"),
+        removed: count("removed"),
+        transactions,
+    }
+}
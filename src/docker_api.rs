@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use bollard::container::LogOutput;
+use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
+use bollard::Docker;
+use futures_util::TryStreamExt;
+use tokio::runtime::Runtime;
+
+/// The Docker Engine API, called directly with bollard instead of through
+/// the `docker` CLI (`--docker-api`), for environments with the daemon's
+/// socket but no CLI.
+///
+/// bollard is async: its calls are driven to completion on a runtime of
+/// our own, so that the rest of the wallet calls stay blocking.
+#[derive(Debug, Clone)]
+pub struct DockerApi {
+    docker: Docker,
+    runtime: Arc<Runtime>,
+}
+
+/// Output of a command run in a container.
+pub struct ExecOutput {
+    pub exit_code: i64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl DockerApi {
+    /// The daemon `DOCKER_HOST` points to, the local socket if unset: `unix://`,
+    /// or `tcp://` with TLS if `DOCKER_TLS_VERIFY` is set, with the client
+    /// certificate, key and CA in `DOCKER_CERT_PATH` (`~/.docker` by default).
+    pub fn from_env() -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("Failed to start the Docker API runtime")?;
+        // The client's connection pool lives on the runtime it is made in
+        let docker = runtime
+            .block_on(async { Docker::connect_with_defaults() })
+            .context("Failed to set up the Docker API client (DOCKER_HOST)")?;
+        Ok(DockerApi {
+            docker,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Run `command` in `container` with `POST /containers/{id}/exec`, returning its output once it exits.
+    pub fn exec(&self, container: &str, command: &[&str]) -> Result<ExecOutput> {
+        self.runtime.block_on(async {
+            let created = self
+                .docker
+                .create_exec(
+                    container,
+                    CreateExecOptions {
+                        attach_stdout: Some(true),
+                        attach_stderr: Some(true),
+                        tty: Some(false),
+                        cmd: Some(command.to_vec()),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .with_context(|| format!("Failed to create exec in container {}", container))?;
+
+            let started = self
+                .docker
+                .start_exec(
+                    &created.id,
+                    Some(StartExecOptions {
+                        detach: false,
+                        tty: false,
+                        output_capacity: None,
+                    }),
+                )
+                .await
+                .with_context(|| format!("Failed to start exec in container {}", container))?;
+            let StartExecResults::Attached { mut output, .. } = started else {
+                bail!("Exec in container {} started detached", container);
+            };
+            let mut stdout = Vec::new();
+            let mut stderr = Vec::new();
+            while let Some(frame) = output
+                .try_next()
+                .await
+                .with_context(|| format!("Failed to read exec output in container {}", container))?
+            {
+                match frame {
+                    LogOutput::StdErr { message } => stderr.extend_from_slice(&message),
+                    LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                        stdout.extend_from_slice(&message)
+                    }
+                    LogOutput::StdIn { .. } => {}
+                }
+            }
+
+            let inspected = self
+                .docker
+                .inspect_exec(&created.id)
+                .await
+                .with_context(|| format!("Failed to inspect exec in container {}", container))?;
+            Ok(ExecOutput {
+                exit_code: inspected.exit_code.context("Exec has no exit code")?,
+                stdout: String::from_utf8_lossy(&stdout).trim().to_string(),
+                stderr: String::from_utf8_lossy(&stderr).trim().to_string(),
+            })
+        })
+    }
+}
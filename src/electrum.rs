@@ -0,0 +1,255 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::consensus::encode::deserialize_hex;
+use bitcoin::Transaction;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// How long a request may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An Electrum server (ElectrumX, Fulcrum, electrs) answering prevout lookups
+/// (`--electrum`), so the node needs no transaction index.
+///
+/// One connection is opened on the first lookup and kept for the run, a
+/// connection the server closed being replaced once.
+pub struct Electrum {
+    address: String,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    connection: Mutex<Option<Connection>>,
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+    next_id: u64,
+}
+
+/// Server certificates checked against `--electrum-cert`: self-signed ones
+/// used as is (which webpki refuses, their being CAs) are accepted when they
+/// are the very certificate given, others must have been issued by it.
+#[derive(Debug)]
+struct Pinned {
+    pinned: Vec<CertificateDer<'static>>,
+    webpki: Arc<WebPkiServerVerifier>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    id: Option<u64>,
+    result: Option<Value>,
+    error: Option<Value>,
+}
+
+impl Electrum {
+    /// A server at `host:port`, over TLS if `tls`, its certificate checked
+    /// against the web's root CAs, or, if given, against the `cert` PEM file:
+    /// the server's own (self-signed) certificate or the CA that issued it.
+    pub fn new(address: &str, tls: bool, cert: Option<&Path>) -> Result<Self> {
+        let tls = match (tls, cert) {
+            (false, None) => None,
+            (false, Some(_)) => bail!("--electrum-cert needs --electrum-tls"),
+            (true, cert) => {
+                let provider = Arc::new(rustls::crypto::ring::default_provider());
+                let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+                    .with_safe_default_protocol_versions()
+                    .context("Failed to set up TLS")?;
+                let config = match cert {
+                    Some(path) => {
+                        let pinned = CertificateDer::pem_file_iter(path)
+                            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                            .with_context(|| {
+                                format!("Failed to read certificates from {}", path.display())
+                            })?;
+                        if pinned.is_empty() {
+                            bail!("No certificate in {}", path.display());
+                        }
+                        let mut roots = rustls::RootCertStore::empty();
+                        roots.add_parsable_certificates(pinned.iter().cloned());
+                        let webpki =
+                            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+                                .build()
+                                .with_context(|| {
+                                    format!("Failed to use the certificates in {}", path.display())
+                                })?;
+                        builder
+                            .dangerous()
+                            .with_custom_certificate_verifier(Arc::new(Pinned { pinned, webpki }))
+                            .with_no_client_auth()
+                    }
+                    None => {
+                        let roots = rustls::RootCertStore {
+                            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                        };
+                        builder.with_root_certificates(roots).with_no_client_auth()
+                    }
+                };
+                Some(Arc::new(config))
+            }
+        };
+        Ok(Electrum {
+            address: address.to_string(),
+            tls,
+            connection: Mutex::new(None),
+        })
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        let tcp = TcpStream::connect(&self.address)
+            .with_context(|| format!("Failed to connect to Electrum server {}", self.address))?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        let stream: Box<dyn Stream> = match &self.tls {
+            Some(config) => {
+                let host = self
+                    .address
+                    .rsplit_once(':')
+                    .map_or(self.address.as_str(), |(host, _)| host)
+                    .trim_start_matches('[')
+                    .trim_end_matches(']');
+                let name = ServerName::try_from(host.to_string())
+                    .with_context(|| format!("Invalid Electrum server name {}", host))?;
+                let tls = rustls::ClientConnection::new(config.clone(), name)
+                    .context("Failed to set up TLS")?;
+                Box::new(rustls::StreamOwned::new(tls, tcp))
+            }
+            None => Box::new(tcp),
+        };
+        let mut connection = Connection {
+            stream: BufReader::new(stream),
+            next_id: 0,
+        };
+        // Servers expect the protocol to be negotiated first
+        connection
+            .request("server.version", json!(["sign-txs", "1.4"]))
+            .with_context(|| {
+                format!("Failed to negotiate with Electrum server {}", self.address)
+            })?;
+        Ok(connection)
+    }
+
+    /// Call `method`, on the kept connection or a new one.
+    fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let mut connection = self.connection.lock().expect("not poisoned");
+        if let Some(open) = connection.as_mut() {
+            match open.request(method, params.clone()) {
+                Ok(result) => return Ok(result),
+                // Closed while idle: try again once on a new connection
+                Err(e) if e.is::<std::io::Error>() => *connection = None,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut open = self.connect()?;
+        let result = open.request(method, params);
+        *connection = Some(open);
+        result
+    }
+
+    /// The transaction `txid` (`blockchain.transaction.get`), with its outputs
+    /// in the form of verbose `getrawtransaction`, values in BTC.
+    pub fn transaction(&self, txid: &str) -> Result<String> {
+        let hex = self.request("blockchain.transaction.get", json!([txid, false]))?;
+        let hex = hex
+            .as_str()
+            .with_context(|| format!("Invalid Electrum response for {}", txid))?;
+        let tx: Transaction = deserialize_hex(hex)
+            .with_context(|| format!("Failed to parse transaction {} from Electrum", txid))?;
+        if tx.compute_txid().to_string() != txid {
+            bail!("Electrum server returned another transaction for {}", txid);
+        }
+        let vout: Vec<Value> = tx
+            .output
+            .iter()
+            .enumerate()
+            .map(|(n, output)| {
+                json!({
+                    "n": n,
+                    "value": output.value.to_btc(),
+                    "scriptPubKey": { "hex": output.script_pubkey.to_hex_string() },
+                })
+            })
+            .collect();
+        Ok(json!({ "txid": txid, "vout": vout }).to_string())
+    }
+}
+
+impl Connection {
+    fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let stream = self.stream.get_mut();
+        stream.write_all(format!("{}\n", request).as_bytes())?;
+        stream.flush()?;
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            let response: Response = serde_json::from_str(&line)
+                .with_context(|| format!("Failed to parse Electrum {} response", method))?;
+            // Notifications have no id
+            if response.id != Some(id) {
+                continue;
+            }
+            if let Some(error) = response.error {
+                bail!("Electrum {} failed: {}", method, error);
+            }
+            return response
+                .result
+                .with_context(|| format!("Electrum {} returned no result", method));
+        }
+    }
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.pinned.iter().any(|cert| cert == end_entity) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        self.webpki
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use bitcoin::base64::engine::general_purpose::STANDARD;
+use bitcoin::base64::Engine;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hex::FromHex;
+
+use crate::batch::Entry;
+use crate::{psbt, rewrite};
+
+/// Field carrying the transaction in base64, next to `bitcoin`.
+pub const BASE64_FIELD: &str = "bitcoin_base64";
+
+/// Add each transaction in base64 to its entry, returning how many were.
+///
+/// Entries whose `bitcoin` isn't a hex transaction (such as incomplete ones
+/// output as PSBTs) are left as they are.
+pub fn add_base64(entries: &mut [Entry]) -> usize {
+    let mut count = 0;
+    for entry in entries {
+        let Some(bytes) = entry.hex().and_then(|hex| Vec::<u8>::from_hex(hex).ok()) else {
+            continue;
+        };
+        entry.set_field(BASE64_FIELD, STANDARD.encode(bytes).into());
+        count += 1;
+    }
+    count
+}
+
+/// Write each bitcoin transaction, consensus-serialized, to `<dir>/<txid>.bin`.
+///
+/// Incomplete transactions output as PSBTs are left out.
+pub fn write_bin(dir: &Path, entries: &[Entry]) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    let mut paths = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let Some(hex) = entry.hex().filter(|hex| !psbt::is_base64(hex)) else {
+            continue;
+        };
+        let tx = rewrite::decode_tx(hex)
+            .with_context(|| format!("Failed to parse transaction {}", i + 1))?;
+        let path = dir.join(format!("{}.bin", tx.compute_txid()));
+        std::fs::write(&path, serialize(&tx))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
@@ -0,0 +1,133 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use bitcoin::Amount;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// How long a request may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Most times a request is retried after being rate limited (HTTP 429).
+const MAX_RETRIES: u32 = 5;
+/// Wait before retrying a rate-limited request whose response has no `Retry-After`.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// Least time between requests to mempool.space, within its public API's limits.
+const MEMPOOL_SPACE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// An Esplora instance answering prevout lookups (`--esplora-url`), so the
+/// node needs no transaction index, or only the wallet node is needed.
+///
+/// mempool.space serves the same API (`--mempool-space-url`), its requests
+/// spaced out to stay within the public instance's rate limits.
+#[derive(Clone)]
+pub struct Esplora {
+    agent: ureq::Agent,
+    url: String,
+    /// Least time between requests, and when the last one was made.
+    interval: Duration,
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EsploraTx {
+    txid: String,
+    vout: Vec<EsploraOutput>,
+}
+
+/// An output as Esplora gives it: unblinded Elements outputs and bitcoin
+/// ones have a `value` in satoshis, blinded ones a `valuecommitment`.
+#[derive(Debug, Deserialize)]
+struct EsploraOutput {
+    scriptpubkey: String,
+    value: Option<u64>,
+    valuecommitment: Option<String>,
+}
+
+impl Esplora {
+    /// `url` is the API's base, e.g. `https://blockstream.info/api`.
+    pub fn new(url: &str) -> Self {
+        Esplora {
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            url: url.trim_end_matches('/').to_string(),
+            interval: Duration::ZERO,
+            last: Arc::default(),
+        }
+    }
+
+    /// `url` is the API's base on mempool.space or an instance of it, e.g.
+    /// `https://mempool.space/signet/api`.
+    pub fn mempool_space(url: &str) -> Self {
+        Esplora {
+            interval: MEMPOOL_SPACE_INTERVAL,
+            ..Esplora::new(url)
+        }
+    }
+
+    /// `GET` `url`, waiting out rate limits: the spacing between requests, and
+    /// the `Retry-After` of rate-limited responses.
+    fn get(&self, url: &str) -> Result<String> {
+        let mut retries = 0;
+        loop {
+            {
+                let mut last = self.last.lock().expect("not poisoned");
+                if let Some(wait) = last.and_then(|last| self.interval.checked_sub(last.elapsed()))
+                {
+                    std::thread::sleep(wait);
+                }
+                *last = Some(Instant::now());
+            }
+            let response = match self.agent.get(url).call() {
+                Err(ureq::Error::Status(429, response)) if retries < MAX_RETRIES => response,
+                Err(ureq::Error::Status(429, _)) => {
+                    bail!(
+                        "Failed to fetch {}: still rate limited after {} retries",
+                        url,
+                        retries
+                    )
+                }
+                response => {
+                    return response
+                        .with_context(|| format!("Failed to fetch {}", url))?
+                        .into_string()
+                        .with_context(|| format!("Failed to read {}", url));
+                }
+            };
+            let wait = response
+                .header("Retry-After")
+                .and_then(|seconds| seconds.trim().parse().ok())
+                .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+            eprintln!(
+                "  Rate limited by {}, retrying in {}s",
+                self.url,
+                wait.as_secs()
+            );
+            std::thread::sleep(wait);
+            retries += 1;
+        }
+    }
+
+    /// The transaction `txid` (`GET /tx/:txid`), with its outputs in the form
+    /// of verbose `getrawtransaction`, values in BTC.
+    pub fn transaction(&self, txid: &str) -> Result<String> {
+        let body = self.get(&format!("{}/tx/{}", self.url, txid))?;
+        let tx: EsploraTx = serde_json::from_str(&body)
+            .with_context(|| format!("Failed to parse the Esplora response for {}", txid))?;
+        let vout: Vec<Value> = tx
+            .vout
+            .into_iter()
+            .enumerate()
+            .map(|(n, output)| {
+                let mut entry = json!({ "n": n, "scriptPubKey": { "hex": output.scriptpubkey } });
+                if let Some(value) = output.value {
+                    entry["value"] = json!(Amount::from_sat(value).to_btc());
+                }
+                if let Some(commitment) = output.valuecommitment {
+                    entry["valuecommitment"] = json!(commitment);
+                }
+                entry
+            })
+            .collect();
+        Ok(json!({ "txid": tx.txid, "vout": vout }).to_string())
+    }
+}
@@ -0,0 +1,44 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::Psbt;
+
+/// Have an external program sign a PSBT.
+///
+/// The program is run through `sh -c`, gets the base64 PSBT on stdin and the
+/// inputs it is asked to sign (indexes, comma-separated) in `SIGN_TXS_INPUTS`,
+/// and must print the PSBT back in base64 on stdout with its signatures added,
+/// as partial signatures or final scriptSigs and witnesses, exiting with 0.
+/// Its stderr is passed through.
+pub fn sign(cmd: &str, psbt: &Psbt, inputs: &[usize]) -> Result<Psbt> {
+    let inputs: Vec<String> = inputs.iter().map(usize::to_string).collect();
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("SIGN_TXS_INPUTS", inputs.join(","))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Failed to execute {}", cmd))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(format!("{}\n", psbt).as_bytes())
+        .with_context(|| format!("Failed to write the PSBT to {}", cmd))?;
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Failed to wait for {}", cmd))?;
+    if !output.status.success() {
+        bail!("{} failed ({})", cmd, output.status);
+    }
+    let signed = Psbt::from_str(String::from_utf8_lossy(&output.stdout).trim())
+        .with_context(|| format!("{} did not output a base64 PSBT", cmd))?;
+    if signed.unsigned_tx.compute_txid() != psbt.unsigned_tx.compute_txid() {
+        bail!("{} returned a PSBT of another transaction", cmd);
+    }
+    Ok(signed)
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure, Context, Result};
+use bitcoin::{Amount, Script, ScriptBuf, Transaction, TxOut};
+use serde::Deserialize;
+
+use crate::anchor;
+use crate::node::Node;
+use crate::rewrite;
+
+/// Witness weight of a P2WPKH spend: item count, 72-byte signature, 33-byte key.
+const P2WPKH_WITNESS_WEIGHT: u64 = 1 + 1 + 72 + 1 + 33;
+/// Witness weight of a Taproot keypath spend: item count, 64-byte signature.
+const P2TR_KEYPATH_WITNESS_WEIGHT: u64 = 1 + 1 + 64;
+/// Size of a P2PKH scriptSig: 72-byte signature, 33-byte key.
+const P2PKH_SCRIPT_SIG_SIZE: u64 = 1 + 72 + 1 + 33;
+/// Size of the scriptSig of a P2SH-wrapped P2WPKH spend.
+const P2SH_P2WPKH_SCRIPT_SIG_SIZE: u64 = 1 + 22;
+
+/// Redeem scripts of P2SH outputs, by their scriptPubKey: what a P2SH spend
+/// looks like can't be told from the output alone.
+pub type RedeemScripts = HashMap<ScriptBuf, ScriptBuf>;
+
+#[derive(Debug, Deserialize)]
+struct AddressInfo {
+    /// The redeem script, for P2SH addresses the wallet can solve.
+    hex: Option<String>,
+}
+
+/// Redeem scripts the wallet knows (`getaddressinfo`) of the P2SH outputs among `spent`.
+pub fn redeem_scripts<'a>(
+    node: &Node,
+    spent: impl IntoIterator<Item = &'a TxOut>,
+) -> Result<RedeemScripts> {
+    let mut scripts = RedeemScripts::new();
+    for output in spent {
+        let script = &output.script_pubkey;
+        if !script.is_p2sh() || scripts.contains_key(script) {
+            continue;
+        }
+        let Some(info) = node.address_info(script)? else {
+            continue;
+        };
+        let info: AddressInfo =
+            serde_json::from_str(&info).context("Failed to parse address info")?;
+        if let Some(hex) = info.hex {
+            let redeem_script = ScriptBuf::from_hex(&hex)
+                .with_context(|| format!("Invalid redeem script {}", hex))?;
+            scripts.insert(script.clone(), redeem_script);
+        }
+    }
+    Ok(scripts)
+}
+
+/// Size of the scriptSig and weight of the witness (with its item count) spending `script`.
+///
+/// `None` for scripts whose spending size can't be known from the script
+/// alone, and for P2SH unless `redeem_script` wraps P2WPKH.
+fn signature_size(script: &Script, redeem_script: Option<&Script>) -> Option<(u64, u64)> {
+    if script.is_p2wpkh() {
+        Some((0, P2WPKH_WITNESS_WEIGHT))
+    } else if script.is_p2tr() {
+        Some((0, P2TR_KEYPATH_WITNESS_WEIGHT))
+    } else if script.is_p2pkh() {
+        Some((P2PKH_SCRIPT_SIG_SIZE, 0))
+    } else if script.is_p2sh() {
+        redeem_script
+            .filter(|redeem_script| redeem_script.is_p2wpkh())
+            .map(|_| (P2SH_P2WPKH_SCRIPT_SIG_SIZE, P2WPKH_WITNESS_WEIGHT))
+    } else if anchor::is_p2a(script) {
+        Some((0, 0))
+    } else {
+        None
+    }
+}
+
+/// Weight of a signed input spending `script` (outpoint, sequence, scriptSig
+/// and witness), with `redeem_script` if it is P2SH.
+pub fn signed_input_weight(script: &Script, redeem_script: Option<&Script>) -> Option<u64> {
+    let (script_sig_size, witness_weight) = signature_size(script, redeem_script)?;
+    Some((32 + 4 + 4 + 1 + script_sig_size) * 4 + witness_weight.max(1))
+}
+
+/// Estimated virtual size of a transaction once all its inputs are signed.
+///
+/// Inputs that already carry a signature count as they are; unsigned ones are
+/// estimated from the type of the output they spend (`spent`, in input order).
+/// Scripts whose spending size can't be known from the output alone (P2WSH,
+/// P2SH other than wrapped P2WPKH, or whose redeem script isn't in
+/// `redeem_scripts`) are rejected.
+pub fn estimate_signed_vsize(
+    tx: &Transaction,
+    spent: &[TxOut],
+    redeem_scripts: &RedeemScripts,
+) -> Result<u64> {
+    let mut weight = tx.weight().to_wu();
+    let mut has_witness = tx.input.iter().any(|input| !input.witness.is_empty());
+
+    for (i, (input, prevout)) in tx.input.iter().zip(spent).enumerate() {
+        if !input.witness.is_empty() || !input.script_sig.is_empty() {
+            continue;
+        }
+        let script = &prevout.script_pubkey;
+        let redeem_script = redeem_scripts.get(script).map(ScriptBuf::as_script);
+        let Some((script_sig_size, witness_weight)) = signature_size(script, redeem_script) else {
+            bail!(
+                "cannot estimate the signed size of input {} ({})",
+                i,
+                script
+            );
+        };
+        // An empty witness is already counted as one byte in segwit serialization,
+        // and the scriptSig length prefix stays one byte for these sizes
+        weight += script_sig_size * 4 + witness_weight.saturating_sub(1);
+        has_witness |= witness_weight > 0;
+    }
+
+    if has_witness && tx.input.iter().all(|input| input.witness.is_empty()) {
+        // Segwit marker and flag, plus one empty-witness byte per input
+        weight += 2 + tx.input.len() as u64;
+    }
+    Ok(weight.div_ceil(4))
+}
+
+/// Total fee of a transaction given the outputs its inputs spend.
+pub fn fee(tx: &Transaction, spent: &[TxOut]) -> Result<Amount> {
+    let inputs: Amount = spent.iter().map(|out| out.value).sum();
+    let outputs: Amount = tx.output.iter().map(|out| out.value).sum();
+    match inputs.checked_sub(outputs) {
+        Some(fee) => Ok(fee),
+        None => bail!("outputs ({}) exceed inputs ({})", outputs, inputs),
+    }
+}
+
+/// Set the change output so the signed transaction pays `fee_rate` (sat/vB).
+///
+/// Returns the previous and new change amounts.
+pub fn retarget_change(
+    tx: &mut Transaction,
+    spent: &[TxOut],
+    redeem_scripts: &RedeemScripts,
+    change_index: usize,
+    fee_rate: f64,
+) -> Result<(Amount, Amount)> {
+    let vsize = estimate_signed_vsize(tx, spent, redeem_scripts)?;
+    let target_fee = Amount::from_sat((fee_rate * vsize as f64).ceil() as u64);
+
+    let inputs: Amount = spent.iter().map(|out| out.value).sum();
+    let others: Amount = tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != change_index)
+        .map(|(_, out)| out.value)
+        .sum();
+    let Some(change) = inputs
+        .checked_sub(others)
+        .and_then(|available| available.checked_sub(target_fee))
+    else {
+        bail!(
+            "inputs cannot cover the outputs and a fee of {} at {} sat/vB",
+            target_fee,
+            fee_rate
+        );
+    };
+
+    let change_script: &Script = &tx.output[change_index].script_pubkey;
+    ensure!(
+        change >= change_script.minimal_non_dust(),
+        "change of {} would be dust at {} sat/vB",
+        change,
+        fee_rate
+    );
+
+    let previous = std::mem::replace(&mut tx.output[change_index].value, change);
+    Ok((previous, change))
+}
+
+/// Fee and size of a batch transaction, alone and with its in-batch ancestors.
+pub struct PackageFee {
+    pub fee: Amount,
+    pub vsize: u64,
+    pub ancestor_fee: Amount,
+    pub ancestor_vsize: u64,
+}
+
+impl PackageFee {
+    pub fn fee_rate(&self) -> f64 {
+        self.fee.to_sat() as f64 / self.vsize as f64
+    }
+
+    /// Feerate of the transaction together with its ancestors, as miners evaluate it.
+    pub fn ancestor_fee_rate(&self) -> f64 {
+        self.ancestor_fee.to_sat() as f64 / self.ancestor_vsize as f64
+    }
+}
+
+/// Fees of the batch transactions, given the outputs each spends (`None` if unknown).
+///
+/// Transactions that are not fully signed yet are counted at their estimated signed size.
+pub fn package_fees(
+    txs: &[Option<Transaction>],
+    spent: &[Option<Vec<TxOut>>],
+    redeem_scripts: &RedeemScripts,
+) -> Result<Vec<Option<PackageFee>>> {
+    let mut own = Vec::with_capacity(txs.len());
+    for (tx, spent) in txs.iter().zip(spent) {
+        own.push(match (tx, spent) {
+            (Some(tx), Some(spent)) => {
+                let vsize =
+                    estimate_signed_vsize(tx, spent, redeem_scripts).unwrap_or(tx.vsize() as u64);
+                Some((fee(tx, spent)?, vsize))
+            }
+            _ => None,
+        });
+    }
+
+    Ok((0..txs.len())
+        .map(|i| {
+            let (fee, vsize) = own[i]?;
+            let mut package = PackageFee {
+                fee,
+                vsize,
+                ancestor_fee: fee,
+                ancestor_vsize: vsize,
+            };
+            for j in rewrite::ancestors(txs, i) {
+                let (fee, vsize) = own[j]?;
+                package.ancestor_fee += fee;
+                package.ancestor_vsize += vsize;
+            }
+            Some(package)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::transaction::Version;
+    use bitcoin::{absolute, OutPoint, PubkeyHash, ScriptHash, TxIn, Txid, WPubkeyHash};
+
+    use super::*;
+
+    /// An unsigned transaction spending `spent` to a P2WPKH output.
+    fn unsigned(spent: &[TxOut]) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: (0..spent.len())
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(Txid::all_zeros(), vout as u32),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+            }],
+        }
+    }
+
+    fn spending(script_pubkey: ScriptBuf) -> Vec<TxOut> {
+        vec![TxOut {
+            value: Amount::from_sat(20_000),
+            script_pubkey,
+        }]
+    }
+
+    #[test]
+    fn signed_vsize_of_p2wpkh() {
+        let spent = spending(ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()));
+        let vsize = estimate_signed_vsize(&unsigned(&spent), &spent, &RedeemScripts::new());
+        assert_eq!(vsize.unwrap(), 110);
+    }
+
+    #[test]
+    fn signed_vsize_of_p2pkh() {
+        let spent = spending(ScriptBuf::new_p2pkh(&PubkeyHash::all_zeros()));
+        let vsize = estimate_signed_vsize(&unsigned(&spent), &spent, &RedeemScripts::new());
+        assert_eq!(vsize.unwrap(), 189);
+    }
+
+    #[test]
+    fn signed_vsize_of_p2sh_p2wpkh() {
+        let redeem_script = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let script_pubkey = ScriptBuf::new_p2sh(&redeem_script.script_hash());
+        let spent = spending(script_pubkey.clone());
+        let redeem_scripts = RedeemScripts::from([(script_pubkey, redeem_script)]);
+        let vsize = estimate_signed_vsize(&unsigned(&spent), &spent, &redeem_scripts);
+        assert_eq!(vsize.unwrap(), 133);
+    }
+
+    #[test]
+    fn signed_vsize_of_mixed_inputs() {
+        let mut spent = spending(ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()));
+        spent.extend(spending(ScriptBuf::new_p2pkh(&PubkeyHash::all_zeros())));
+        let vsize = estimate_signed_vsize(&unsigned(&spent), &spent, &RedeemScripts::new());
+        // 230 bytes, and a 108-byte witness, the empty one of the P2PKH input, marker and flag
+        assert_eq!(vsize.unwrap(), (230 * 4 + 108 + 1 + 2_u64).div_ceil(4));
+    }
+
+    #[test]
+    fn signed_vsize_of_unknown_p2sh() {
+        let spent = spending(ScriptBuf::new_p2sh(&ScriptHash::all_zeros()));
+        assert!(estimate_signed_vsize(&unsigned(&spent), &spent, &RedeemScripts::new()).is_err());
+    }
+
+    #[test]
+    fn signed_input_weights() {
+        let p2wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        assert_eq!(signed_input_weight(&p2wpkh, None), Some(272));
+        let p2sh = ScriptBuf::new_p2sh(&p2wpkh.script_hash());
+        assert_eq!(signed_input_weight(&p2sh, Some(&p2wpkh)), Some(364));
+        assert_eq!(signed_input_weight(&p2sh, None), None);
+        let p2pkh = ScriptBuf::new_p2pkh(&PubkeyHash::all_zeros());
+        // With the empty witness it has in a segwit transaction
+        assert_eq!(signed_input_weight(&p2pkh, None), Some(593));
+    }
+}
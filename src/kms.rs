@@ -0,0 +1,196 @@
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::base64::engine::general_purpose::STANDARD;
+use bitcoin::base64::Engine;
+use bitcoin::hashes::Hash;
+use bitcoin::script::{Builder, PushBytesBuf};
+use bitcoin::secp256k1::{ecdsa, Message, Secp256k1};
+use bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use bitcoin::{PublicKey, ScriptBuf, Transaction, TxOut, Witness};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PublicKeyResult {
+    key_spec: String,
+    /// DER SubjectPublicKeyInfo, base64.
+    public_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SignResult {
+    /// DER ECDSA signature, base64.
+    signature: String,
+}
+
+/// secp256k1 keys held in AWS KMS, signing through the `aws` CLI.
+///
+/// KMS only signs digests, so sighashes are computed here and the witnesses
+/// or scriptSigs assembled from the signatures it returns. It has no Schnorr
+/// signing, so only P2WPKH, P2SH-P2WPKH and P2PKH inputs of the keys
+/// (compressed) can be signed this way.
+pub struct Kms {
+    cmd: String,
+    keys: Vec<(String, PublicKey)>,
+}
+
+impl Kms {
+    /// Fetch the public keys of the given KMS keys (ids, ARNs or aliases).
+    pub fn load(cmd: &str, key_ids: &[String]) -> Result<Self> {
+        let mut keys = Vec::new();
+        for key_id in key_ids {
+            let output = run(cmd, &["kms", "get-public-key", "--key-id", key_id])?;
+            let result: PublicKeyResult = serde_json::from_str(&output)
+                .with_context(|| format!("Failed to parse public key of KMS key {}", key_id))?;
+            if result.key_spec != "ECC_SECG_P256K1" {
+                bail!(
+                    "KMS key {} is a {} key, not secp256k1 (ECC_SECG_P256K1)",
+                    key_id,
+                    result.key_spec
+                );
+            }
+            let der = STANDARD
+                .decode(&result.public_key)
+                .with_context(|| format!("Invalid public key of KMS key {}", key_id))?;
+            // The uncompressed point ends the SubjectPublicKeyInfo
+            let point = der
+                .len()
+                .checked_sub(65)
+                .map(|start| &der[start..])
+                .with_context(|| format!("Invalid public key of KMS key {}", key_id))?;
+            let pubkey = PublicKey::new(
+                bitcoin::secp256k1::PublicKey::from_slice(point)
+                    .with_context(|| format!("Invalid public key of KMS key {}", key_id))?,
+            );
+            eprintln!("  KMS key {}: {}", key_id, pubkey);
+            keys.push((key_id.clone(), pubkey));
+        }
+        Ok(Kms {
+            cmd: cmd.to_string(),
+            keys,
+        })
+    }
+
+    /// The KMS key able to spend an output with this scriptPubKey, if any.
+    pub fn key_for(&self, script_pubkey: &ScriptBuf) -> Option<&(String, PublicKey)> {
+        self.keys.iter().find(|(_, pubkey)| {
+            let Ok(wpkh) = pubkey.wpubkey_hash() else {
+                return false;
+            };
+            *script_pubkey == ScriptBuf::new_p2wpkh(&wpkh)
+                || *script_pubkey
+                    == ScriptBuf::new_p2sh(&ScriptBuf::new_p2wpkh(&wpkh).script_hash())
+                || *script_pubkey == ScriptBuf::new_p2pkh(&pubkey.pubkey_hash())
+        })
+    }
+
+    /// Sign the given inputs with their KMS keys (`SIGHASH_ALL`), returning how many were.
+    ///
+    /// `spent` holds the outputs spent by every input of the transaction, in
+    /// order. Inputs no KMS key can spend are left alone.
+    pub fn sign(&self, tx: &mut Transaction, inputs: &[usize], spent: &[TxOut]) -> Result<usize> {
+        let secp = Secp256k1::verification_only();
+        let mut signed = Vec::new();
+        let mut cache = SighashCache::new(&*tx);
+        for &i in inputs {
+            let script_pubkey = &spent[i].script_pubkey;
+            let Some((key_id, pubkey)) = self.key_for(script_pubkey) else {
+                continue;
+            };
+            let wpkh = pubkey.wpubkey_hash().expect("KMS keys are compressed");
+            let digest = if script_pubkey.is_p2pkh() {
+                cache
+                    .legacy_signature_hash(i, script_pubkey, EcdsaSighashType::All.to_u32())
+                    .map(|sighash| sighash.to_byte_array())
+                    .map_err(anyhow::Error::from)
+            } else {
+                cache
+                    .p2wpkh_signature_hash(
+                        i,
+                        &ScriptBuf::new_p2wpkh(&wpkh),
+                        spent[i].value,
+                        EcdsaSighashType::All,
+                    )
+                    .map(|sighash| sighash.to_byte_array())
+                    .map_err(anyhow::Error::from)
+            }
+            .with_context(|| format!("Failed to compute sighash for input {}", i))?;
+
+            eprintln!("  Input {}: signing with KMS key {}...", i, key_id);
+            let output = run(
+                &self.cmd,
+                &[
+                    "kms",
+                    "sign",
+                    "--key-id",
+                    key_id,
+                    "--message",
+                    &STANDARD.encode(digest),
+                    "--message-type",
+                    "DIGEST",
+                    "--signing-algorithm",
+                    "ECDSA_SHA_256",
+                ],
+            )?;
+            let result: SignResult = serde_json::from_str(&output)
+                .with_context(|| format!("Failed to parse KMS signature for input {}", i))?;
+            let der = STANDARD
+                .decode(&result.signature)
+                .with_context(|| format!("Invalid KMS signature for input {}", i))?;
+            let mut signature = ecdsa::Signature::from_der(&der)
+                .with_context(|| format!("Invalid KMS signature for input {}", i))?;
+            // KMS doesn't produce low-S signatures, which are the only standard ones
+            signature.normalize_s();
+            secp.verify_ecdsa(&Message::from_digest(digest), &signature, &pubkey.inner)
+                .with_context(|| {
+                    format!(
+                        "KMS signature for input {} doesn't verify with {}",
+                        i, pubkey
+                    )
+                })?;
+            let signature = bitcoin::ecdsa::Signature::sighash_all(signature);
+
+            let (script_sig, witness) = if script_pubkey.is_p2pkh() {
+                let script_sig = Builder::new()
+                    .push_slice(PushBytesBuf::try_from(signature.to_vec())?)
+                    .push_key(pubkey)
+                    .into_script();
+                (script_sig, Witness::new())
+            } else if script_pubkey.is_p2sh() {
+                let redeem_script =
+                    PushBytesBuf::try_from(ScriptBuf::new_p2wpkh(&wpkh).into_bytes())?;
+                let script_sig = Builder::new().push_slice(redeem_script).into_script();
+                (script_sig, Witness::p2wpkh(&signature, &pubkey.inner))
+            } else {
+                (ScriptBuf::new(), Witness::p2wpkh(&signature, &pubkey.inner))
+            };
+            signed.push((i, script_sig, witness));
+        }
+
+        let count = signed.len();
+        for (i, script_sig, witness) in signed {
+            tx.input[i].script_sig = script_sig;
+            tx.input[i].witness = witness;
+        }
+        Ok(count)
+    }
+}
+
+fn run(cmd: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(cmd)
+        .args(args)
+        .args(["--output", "json"])
+        .output()
+        .with_context(|| format!("Failed to execute {}", cmd))?;
+    if !output.status.success() {
+        bail!(
+            "{} {} failed: {}",
+            cmd,
+            args[..2].join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
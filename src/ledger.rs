@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use bitcoin::{OutPoint, Transaction};
+use serde::{Deserialize, Serialize};
+
+use crate::node::Node;
+use crate::psbt;
+
+/// An outpoint spent by a transaction signed in an earlier run.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reservation {
+    /// Txid of the spending transaction without its scriptSigs, which signing doesn't change.
+    pub txid: String,
+    /// Unix time the spending transaction was signed.
+    pub signed_at: u64,
+}
+
+/// Outpoints spent by transactions signed across runs, kept until the outpoints are
+/// spent on chain or in the mempool, by those transactions or others.
+pub struct Ledger {
+    reservations: BTreeMap<String, Reservation>,
+}
+
+impl Ledger {
+    /// Read the ledger at `path`, empty if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        let reservations = if path.exists() {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read ledger {}", path.display()))?;
+            serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse ledger {}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Ledger { reservations })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(
+            path,
+            serde_json::to_string_pretty(&self.reservations)? + "\n",
+        )
+        .with_context(|| format!("Failed to write ledger {}", path.display()))
+    }
+
+    /// Drop the reservations of outpoints the node no longer has as unspent
+    /// (`gettxout`, mempool included), returning how many were.
+    pub fn prune(&mut self, node: &Node) -> Result<usize> {
+        let before = self.reservations.len();
+        let mut spent = Vec::new();
+        for outpoint in self.reservations.keys() {
+            let parsed: OutPoint = outpoint
+                .parse()
+                .with_context(|| format!("Invalid outpoint {} in ledger", outpoint))?;
+            let output = node.run(&[
+                "gettxout",
+                &parsed.txid.to_string(),
+                &parsed.vout.to_string(),
+                "true",
+            ])?;
+            if output.trim().is_empty() {
+                spent.push(outpoint.clone());
+            }
+        }
+        for outpoint in spent {
+            self.reservations.remove(&outpoint);
+        }
+        Ok(before - self.reservations.len())
+    }
+
+    /// Inputs of `tx` already reserved by another transaction, with the reserving txid.
+    pub fn conflicts(&self, tx: &Transaction) -> Vec<(OutPoint, &str)> {
+        let txid = psbt::unsigned_txid(tx).to_string();
+        tx.input
+            .iter()
+            .filter_map(|input| {
+                let reservation = self.reservations.get(&input.previous_output.to_string())?;
+                (reservation.txid != txid)
+                    .then_some((input.previous_output, reservation.txid.as_str()))
+            })
+            .collect()
+    }
+
+    /// Reserve the inputs of `tx`, replacing reservations by other transactions.
+    pub fn reserve(&mut self, tx: &Transaction) -> Result<()> {
+        let txid = psbt::unsigned_txid(tx).to_string();
+        let signed_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for input in &tx.input {
+            self.reservations.insert(
+                input.previous_output.to_string(),
+                Reservation {
+                    txid: txid.clone(),
+                    signed_at,
+                },
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! Signing batches of transactions with a bitcoind (or elementsd) wallet.
+//!
+//! The `sign-txs` binary is a command line over this library. To sign without
+//! spawning it, give [`sign::sign_transaction`] a [`node::Node`] and, to look
+//! prevouts up and sign elsewhere than the node's wallet, a [`sign::Signer`]:
+//!
+//! ```no_run
+//! use sign_txs::anchor::Anchors;
+//! use sign_txs::node::{ChainType, Node};
+//! use sign_txs::sign::{self, Signers};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let node = Node::docker(ChainType::Bitcoin, "bitcoind");
+//! let raw_tx = "0200000001...";
+//! let signers = Signers::default();
+//! let signed = sign::sign_transaction(&node, raw_tx, 0, false, &signers, &Anchors::new(&[]))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`sign::sign_batch`] signs a whole batch, reporting its progress to a
+//! callback and stopping early once a [`sign::Cancellation`] is cancelled.
+
+/// Diagnostics go to stderr, and are copied to the log file with `--log-file`,
+/// unless [`log::capture`] keeps them back.
+macro_rules! eprintln {
+    () => {{
+        if !$crate::log::captured(format_args!("")) {
+            std::eprintln!();
+            $crate::log::write(format_args!(""));
+        }
+    }};
+    ($($arg:tt)*) => {{
+        if !$crate::log::captured(format_args!($($arg)*)) {
+            std::eprintln!($($arg)*);
+            $crate::log::write(format_args!($($arg)*));
+        }
+    }};
+}
+
+pub mod accounting;
+pub mod analysis;
+pub mod anchor;
+pub mod approval;
+pub mod archive;
+pub mod attest;
+pub mod batch;
+pub mod broadcast;
+pub mod build;
+pub mod bump;
+pub mod canonical;
+pub mod cassette;
+pub mod change;
+pub mod chaos;
+pub mod coinselect;
+pub mod combined;
+pub mod compare;
+pub mod docker_api;
+pub mod electrum;
+pub mod emit;
+pub mod esplora;
+pub mod external;
+pub mod fees;
+pub mod kms;
+pub mod ledger;
+pub mod log;
+pub mod mempool;
+pub mod metrics;
+pub mod mirror;
+pub mod node;
+pub mod nostr;
+pub mod not_before;
+pub mod payjoin;
+pub mod plan;
+pub mod policy;
+pub mod preflight;
+pub mod psbt;
+pub mod recover;
+pub mod regtest;
+pub mod rest;
+pub mod rewrite;
+pub mod rpc;
+pub mod sandbox;
+pub mod session;
+pub mod sighash;
+pub mod sign;
+pub mod simulate;
+pub mod spell;
+pub mod stats;
+pub mod taproot;
+pub mod verify;
+pub mod window;
+pub mod witness;
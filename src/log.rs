@@ -0,0 +1,156 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+/// The log file diagnostics are copied to, if any.
+static LOG: Mutex<Option<LogFile>> = Mutex::new(None);
+
+thread_local! {
+    /// Diagnostics of this thread kept back by [`capture`], if it is running.
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: u32,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_size: u64, keep: u32) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    /// Shift `<path>.1` to `<path>.2` and so on, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |n: u32| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        for n in (1..self.keep).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        *self = LogFile::open(&self.path, self.max_size, self.keep)?;
+        Ok(())
+    }
+}
+
+/// Copy diagnostics to `path` from now on, rotating it once it reaches `max_size` bytes.
+///
+/// A log file older than `max_age` (by its creation time, where the file
+/// system records it) is rotated before being written to. `keep` rotated
+/// files are kept, as `<path>.1` (the newest) to `<path>.<keep>`.
+pub fn init(path: &Path, max_size: u64, max_age: Option<Duration>, keep: u32) -> Result<()> {
+    let mut log = LogFile::open(path, max_size, keep)?;
+    let expired = max_age.is_some_and(|max_age| {
+        log.file
+            .metadata()
+            .and_then(|metadata| metadata.created())
+            .ok()
+            .and_then(|created| created.elapsed().ok())
+            .is_some_and(|age| age > max_age)
+    });
+    if log.size > 0 && (expired || log.size >= max_size) {
+        log.rotate().context("Failed to rotate log file")?;
+    }
+    *LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(log);
+    Ok(())
+}
+
+/// Append a diagnostic to the log file, each line prefixed with the time.
+///
+/// Failing to write the log doesn't fail the run: the diagnostic is on stderr anyway.
+pub fn write(args: fmt::Arguments) {
+    let mut log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(log) = log.as_mut() else {
+        return;
+    };
+    let time = timestamp(SystemTime::now());
+    let mut entry = String::new();
+    for line in args.to_string().lines() {
+        if line.is_empty() {
+            entry.push('\n');
+        } else {
+            entry.push_str(&format!("{} {}\n", time, line));
+        }
+    }
+    if log.file.write_all(entry.as_bytes()).is_ok() {
+        log.size += entry.len() as u64;
+    }
+    if log.size >= log.max_size {
+        let _ = log.rotate();
+    }
+}
+
+/// Run `f`, keeping back the diagnostics it prints on this thread, and return them with its result.
+///
+/// Transactions signed concurrently (`--jobs`) print theirs in one block
+/// each this way, instead of interleaved.
+pub fn capture<T>(f: impl FnOnce() -> T) -> (Vec<String>, T) {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let lines = CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default());
+    (lines, result)
+}
+
+/// Keep a diagnostic back if this thread's are being captured, returning whether it was.
+pub fn captured(args: fmt::Arguments) -> bool {
+    CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+        Some(lines) => {
+            lines.push(args.to_string());
+            true
+        }
+        None => false,
+    })
+}
+
+/// UTC time in RFC 3339 form, to the second.
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
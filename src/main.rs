@@ -1,234 +1,3561 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{self, Read};
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context, Result};
-use clap::Parser;
-use serde::{Deserialize, Serialize};
+use bitcoin::consensus::encode::deserialize_hex;
+use bitcoin::{Amount, Network, OutPoint, ScriptBuf, Transaction, TxOut};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use serde_json::Value;
 
-const BTC_CLI: &str = "bitcoin-cli";
+/// Diagnostics go to stderr, and are copied to the log file with `--log-file`.
+macro_rules! eprintln {
+    () => {{
+        std::eprintln!();
+        sign_txs::log::write(format_args!(""));
+    }};
+    ($($arg:tt)*) => {{
+        std::eprintln!($($arg)*);
+        sign_txs::log::write(format_args!($($arg)*));
+    }};
+}
+
+use sign_txs::{
+    accounting, anchor, approval, archive, attest, batch, broadcast, build, bump, canonical,
+    cassette, change, chaos, coinselect, combined, compare, docker_api, electrum, emit, esplora,
+    fees, kms, ledger, log, mempool, mirror, node, nostr, not_before, payjoin, plan, policy,
+    preflight, psbt, recover, regtest, rest, rewrite, rpc, sandbox, sighash, sign, simulate, stats,
+    taproot, verify, window,
+};
+
+use anchor::Anchors;
+use approval::Approvers;
+use batch::{Batch, Entry, TxEntry};
+use cassette::Cassette;
+use change::ChangeDetector;
+use chaos::Chaos;
+use combined::Combined;
+use kms::Kms;
+use ledger::Ledger;
+use mirror::Manifest;
+use node::{ChainType, ContainerRuntime, Node};
+use not_before::NotBefore;
+use payjoin::PayjoinParams;
+use policy::SignerPolicy;
+use recover::Recovery;
+use sandbox::Sandbox;
+use sign::{
+    decode_transaction, finalize, prevout_txout, sign_transaction, spent_outputs, BatchOutputs,
+    Presigned, Routed, Signer, Signers, Wallets,
+};
+use stats::TxStats;
+use taproot::TaprootKeys;
+use window::WindowPolicy;
+
+/// How signed transactions are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Hex,
+    /// Base64 PSBTs for transactions still missing signatures, hex for the others
+    Psbt,
+}
+
+/// Encodings signed transactions are written in besides the output's hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Emit {
+    /// The `bitcoin` field of each entry, always written
+    Hex,
+    /// A `bitcoin_base64` field next to `bitcoin`
+    Base64,
+    /// Consensus-serialized TXID.bin files in --emit-dir
+    Bin,
+}
 
 #[derive(Parser)]
 #[command(name = "sign-txs")]
 #[command(about = "Sign Bitcoin transactions from a JSON file or stdin")]
 struct Args {
-    /// Input JSON file containing transactions (reads from stdin if not provided)
-    input_file: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    sign: SignArgs,
 
     /// Docker container ID running bitcoind with the wallet (uses local bitcoin-cli if not provided)
-    #[arg(long, env = "BITCOIND_CONTAINER")]
+    #[arg(long, env = "BITCOIND_CONTAINER", global = true)]
     bitcoind_container: Option<String>,
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TxEntry {
-    bitcoin: String,
-}
+    /// Container engine running --bitcoind-container
+    #[arg(
+        long,
+        value_enum,
+        default_value = "docker",
+        env = "CONTAINER_RUNTIME",
+        global = true
+    )]
+    container_runtime: ContainerRuntime,
 
-#[derive(Debug, Deserialize)]
-struct DecodeResult {
-    vin: Vec<VinEntry>,
-}
+    /// Host running bitcoind with the wallet, reached over SSH (user@host), instead of a container
+    #[arg(
+        long,
+        value_name = "USER@HOST",
+        env = "SSH_HOST",
+        global = true,
+        conflicts_with = "bitcoind_container"
+    )]
+    ssh_host: Option<String>,
 
-#[derive(Debug, Deserialize)]
-struct VinEntry {
-    txid: String,
-    vout: u32,
-    txinwitness: Option<Vec<String>>,
-}
+    /// Private key to authenticate to --ssh-host with (ssh -i), instead of the SSH agent or default keys
+    #[arg(long, value_name = "PATH", requires = "ssh_host", global = true)]
+    ssh_key: Option<PathBuf>,
 
-#[derive(Debug, Deserialize)]
-struct TxInfo {
-    vout: Vec<VoutEntry>,
-}
+    /// Kubernetes pod running bitcoind with the wallet, reached with kubectl exec, instead of a container or SSH host
+    #[arg(
+        long,
+        value_name = "POD",
+        env = "K8S_POD",
+        global = true,
+        conflicts_with_all = ["bitcoind_container", "ssh_host"]
+    )]
+    k8s_pod: Option<String>,
 
-#[derive(Debug, Deserialize)]
-struct VoutEntry {
-    value: f64,
-    #[serde(rename = "scriptPubKey")]
-    script_pubkey: ScriptPubKey,
-}
+    /// Namespace of --k8s-pod (default: the current kubectl context's)
+    #[arg(long, value_name = "NAMESPACE", requires = "k8s_pod", global = true)]
+    k8s_namespace: Option<String>,
 
-#[derive(Debug, Deserialize)]
-struct ScriptPubKey {
-    hex: String,
-}
+    /// Container of --k8s-pod running bitcoind (default: the pod's default container)
+    #[arg(long, value_name = "CONTAINER", requires = "k8s_pod", global = true)]
+    k8s_container: Option<String>,
 
-#[derive(Debug, Serialize)]
-struct PrevOut {
-    txid: String,
-    vout: u32,
-    amount: f64,
-    #[serde(rename = "scriptPubKey")]
-    script_pubkey: String,
-}
+    /// Only run the node's programs (bitcoin-cli, docker, ssh...) from these absolute paths, never from PATH (repeatable)
+    #[arg(long, value_name = "PATH", global = true)]
+    allow_exec: Vec<PathBuf>,
+
+    /// Start the node's programs with an empty environment, but for HOME, the Docker, SSH agent and kubectl variables and --keep-env
+    #[arg(long, global = true)]
+    clear_env: bool,
+
+    /// Variable to pass on to the node's programs with --clear-env (repeatable)
+    #[arg(long, value_name = "VAR", requires = "clear_env", global = true)]
+    keep_env: Vec<String>,
+
+    /// Run the node's programs as this user (and group, the user's ID by default), when sign-txs runs as root
+    #[arg(long, value_name = "UID[:GID]", global = true)]
+    run_as: Option<String>,
+
+    /// Run every wallet call in one shell kept open in --bitcoind-container, instead of a docker exec per call
+    #[arg(long, requires = "bitcoind_container", global = true)]
+    docker_session: bool,
+
+    /// Reach --bitcoind-container through the Docker Engine API at DOCKER_HOST (or the local socket) instead of the docker CLI
+    #[arg(
+        long,
+        requires = "bitcoind_container",
+        conflicts_with = "docker_session",
+        global = true
+    )]
+    docker_api: bool,
+
+    /// Decode transactions with the node (decoderawtransaction) as well as locally, failing if they disagree
+    #[arg(long, global = true)]
+    check_decode: bool,
+
+    /// Write the output JSON with sorted keys, numbers in plain decimal and a trailing newline, so it is the same for the same content
+    #[arg(long, global = true)]
+    canonical_output: bool,
+
+    /// Kind of node to sign against (elements uses elements-cli, e.g. for Liquid)
+    #[arg(long, value_enum, default_value = "bitcoin", global = true)]
+    chain_type: ChainType,
+
+    /// Network the node must be on (main, test, testnet4, signet, regtest); checked before signing
+    #[arg(long, global = true)]
+    chain: Option<String>,
+
+    /// Challenge of a custom signet (implies --chain signet)
+    #[arg(long, global = true)]
+    signet_challenge: Option<String>,
+
+    /// Seconds bitcoin-cli waits for a response (-rpcclienttimeout; 0 for no timeout)
+    #[arg(long, value_name = "SECS", global = true)]
+    rpc_client_timeout: Option<u64>,
+
+    /// Host of the node's RPC server (-rpcconnect)
+    #[arg(long, value_name = "HOST", global = true)]
+    rpc_connect: Option<String>,
+
+    /// Port of the node's RPC server (-rpcport)
+    #[arg(long, value_name = "PORT", global = true)]
+    rpc_port: Option<u16>,
+
+    /// URL of the node's JSON-RPC server, called directly instead of through bitcoin-cli (wallet calls still go to --bitcoind-container if set)
+    #[arg(long, value_name = "URL", global = true)]
+    rpc_url: Option<String>,
 
-#[derive(Debug, Deserialize)]
-struct SignResult {
-    hex: String,
-    complete: bool,
-    errors: Option<Vec<serde_json::Value>>,
+    /// Esplora API to look up prevouts with instead of the node, e.g. https://blockstream.info/api
+    #[arg(long, value_name = "URL", env = "ESPLORA_URL", global = true)]
+    esplora_url: Option<String>,
+
+    /// mempool.space API to look up prevouts with instead of the node, e.g. https://mempool.space/signet/api
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "MEMPOOL_SPACE_URL",
+        conflicts_with = "esplora_url",
+        global = true
+    )]
+    mempool_space_url: Option<String>,
+
+    /// Electrum server (ElectrumX, Fulcrum, electrs) to look up prevouts with instead of the node
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        env = "ELECTRUM",
+        conflicts_with_all = ["esplora_url", "mempool_space_url"],
+        global = true
+    )]
+    electrum: Option<String>,
+
+    /// The node's REST interface (-rest) to look up prevouts with instead of RPC, e.g. http://127.0.0.1:8332
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "REST_URL",
+        conflicts_with_all = ["esplora_url", "mempool_space_url", "electrum"],
+        global = true
+    )]
+    rest_url: Option<String>,
+
+    /// Connect to --electrum over TLS
+    #[arg(long, requires = "electrum", global = true)]
+    electrum_tls: bool,
+
+    /// PEM file of the certificate --electrum-tls trusts instead of the web's root CAs: the server's own (self-signed) one or its CA's
+    #[arg(long, value_name = "FILE", requires = "electrum_tls", global = true)]
+    electrum_cert: Option<PathBuf>,
+
+    /// As a last resort, look prevouts neither getrawtransaction nor gettxout finds up among the wallet's outputs in the UTXO set, with scantxoutset (slow: the whole UTXO set is read, once per run)
+    #[arg(long, global = true)]
+    scan_utxo_set: bool,
+
+    /// User for --rpc-url
+    #[arg(long, value_name = "USER", requires = "rpc_url", global = true)]
+    rpc_user: Option<String>,
+
+    /// Password for --rpc-url
+    #[arg(
+        long,
+        env = "RPC_PASSWORD",
+        hide_env_values = true,
+        value_name = "PASSWORD",
+        global = true
+    )]
+    rpc_password: Option<String>,
+
+    /// Extra option passed to every CLI invocation, e.g. -signetseednode=<host> (repeatable)
+    #[arg(
+        long = "cli-arg",
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        global = true
+    )]
+    cli_args: Vec<String>,
+
+    /// Wallet to use on a node with several loaded: -rpcwallet=<NAME> is passed to every call, local, in the container, over SSH, in the pod or over JSON-RPC
+    #[arg(long, value_name = "NAME", env = "WALLET", global = true)]
+    wallet: Option<String>,
+
+    /// Answer node calls with the responses recorded in this directory instead of calling the node (wallet signing is stubbed if not recorded)
+    #[arg(long, value_name = "DIR", global = true)]
+    replay: Option<PathBuf>,
+
+    /// Copy diagnostics to this file, each line timestamped
+    #[arg(long, value_name = "PATH", global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it reaches this size
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 10_000_000,
+        global = true
+    )]
+    log_max_size: u64,
+
+    /// Rotate the log file at startup if it is older than this
+    #[arg(long, value_name = "HOURS", global = true)]
+    log_max_age: Option<u64>,
+
+    /// Rotated log files to keep (PATH.1 being the newest)
+    #[arg(long, value_name = "N", default_value_t = 5, global = true)]
+    log_keep: u32,
+
+    /// Record every node call and its response in this directory (secrets redacted), for --replay
+    #[arg(long, value_name = "DIR", global = true, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Inject faults into node calls for testing: failure=RATE,timeout=RATE,malformed=RATE,delay=SECS,seed=N (needs SIGN_TXS_ENABLE_CHAOS=1)
+    #[arg(long, value_name = "SPEC", global = true, hide = true)]
+    chaos: Option<String>,
 }
 
-fn run_btc_cli(args: &[&str]) -> Result<String> {
-    let output = Command::new(BTC_CLI)
-        .args(args)
-        .output()
-        .with_context(|| format!("Failed to execute {}", BTC_CLI))?;
+/// What to sign and how: the options of `sign`, also taken without a subcommand.
+#[derive(clap::Args)]
+struct SignArgs {
+    /// Input JSON file containing transactions (reads from stdin if not provided)
+    input_file: Option<String>,
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("{} failed: {}", BTC_CLI, stderr);
-    }
+    /// Raw transaction hex to sign instead of reading a batch (repeatable, signed in order)
+    #[arg(long = "tx", value_name = "HEX", conflicts_with = "input_file")]
+    txs: Vec<String>,
+
+    /// Batch file to sign as one session with the others (repeatable), sharing prevouts, dependencies and summaries
+    #[arg(
+        long = "batch-file",
+        value_name = "PATH",
+        conflicts_with_all = ["input_file", "txs"],
+        requires = "session_dir"
+    )]
+    batch_files: Vec<PathBuf>,
+
+    /// Directory the session's signed batches are written to, under the names of their --batch-file
+    #[arg(long, value_name = "DIR", requires = "batch_files")]
+    session_dir: Option<PathBuf>,
+
+    /// JSON file with internal keys (and merkle roots) for Taproot outputs to sign locally via the keypath
+    #[arg(long)]
+    taproot_keys: Option<String>,
+
+    /// Feerate (sat/vB) for funding template entries with fundrawtransaction (wallet estimate if not provided)
+    #[arg(long, value_name = "SAT_PER_VB")]
+    fund_fee_rate: Option<f64>,
+
+    /// Change output type for funding template entries (legacy, p2sh-segwit, bech32, bech32m)
+    #[arg(long)]
+    change_type: Option<String>,
+
+    /// Lock the UTXOs picked to fund template entries in the wallet
+    #[arg(long)]
+    lock_unspents: bool,
+
+    /// Add an index field to every entry, its position in the input, carried to the output (bare hex entries become objects)
+    #[arg(long)]
+    index_entries: bool,
+
+    /// Remove existing scriptSigs and witnesses and sign every input afresh (entries carrying metadata excepted)
+    #[arg(long)]
+    resign_all: bool,
+
+    /// Verify existing signatures locally and sign again only the inputs whose signatures are invalid
+    #[arg(long, conflicts_with = "resign_all")]
+    resign_invalid: bool,
+
+    /// AWS KMS key (id, ARN or alias) of a secp256k1 key signing the P2WPKH, P2SH-P2WPKH and P2PKH inputs it owns; repeatable
+    #[arg(long, value_name = "KEY_ID")]
+    kms_key: Vec<String>,
+
+    /// AWS CLI (v2) used for --kms-key
+    #[arg(long, value_name = "PROGRAM", default_value = "aws")]
+    aws_cli: String,
+
+    /// External program signing the inputs the wallet can't: it gets a base64 PSBT on stdin and prints it back signed on stdout
+    #[arg(long, value_name = "PROGRAM")]
+    signer_cmd: Option<String>,
 
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    /// Sign with each of these wallets of the node in turn (comma-separated), each given the transaction as the one before left it, until it is complete, e.g. the cosigners of a multisig
+    #[arg(
+        long,
+        value_name = "NAMES",
+        value_delimiter = ',',
+        conflicts_with_all = ["wallet", "import_descriptors"]
+    )]
+    wallets: Vec<String>,
+
+    /// Sign up to N transactions per wallet call (one docker exec, or one JSON-RPC batch request with --rpc-url), ahead of the rest of signing
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    wallet_batch_size: usize,
+
+    /// Sign up to N transactions at a time, those spending outputs of others in the batch once these are signed; the output keeps the batch order
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    jobs: usize,
+
+    /// JSON array of descriptors (or importdescriptors requests) to import when the wallet can't sign inputs for lack of their scripts, then retry
+    #[arg(long, value_name = "PATH")]
+    import_descriptors: Option<String>,
+
+    /// Rescan the chain from this height after importing --import-descriptors
+    #[arg(long, value_name = "HEIGHT", requires = "import_descriptors")]
+    rescan_from: Option<u32>,
+
+    /// JSON file of the keys expected to sign, by script type: signatures from other keys fail the run (e.g. the wrong wallet was loaded)
+    #[arg(long, value_name = "PATH")]
+    signer_policy: Option<String>,
+
+    /// JSON file of the approvers (minisign or GPG keys) and how many of them must sign the run's manifest (as --mirror prints it) before it is signed
+    #[arg(long, value_name = "PATH", requires = "approval")]
+    approvers: Option<String>,
+
+    /// Detached minisign or GPG signature of the run's manifest by an approver (repeatable)
+    #[arg(long, value_name = "PATH", requires = "approvers")]
+    approval: Vec<PathBuf>,
+
+    /// JSON file of the days and hours signing is allowed, refusing to sign outside them
+    #[arg(long, value_name = "PATH")]
+    window_policy: Option<String>,
+
+    /// Token allowing to sign outside the --window-policy windows
+    #[arg(
+        long,
+        env = "SIGN_TXS_WINDOW_OVERRIDE",
+        hide_env_values = true,
+        value_name = "TOKEN"
+    )]
+    window_override: Option<String>,
+
+    /// Hold the batch until its not-before condition (in the batch or the --window-policy) is met, then sign, instead of refusing
+    #[arg(long)]
+    wait_not_before: bool,
+
+    /// Set the version of unsigned transactions before signing (1, 2, or 3 for TRUC), unless their entry has a version field
+    #[arg(long, value_name = "N")]
+    tx_version: Option<i32>,
+
+    /// Sort inputs and outputs of unsigned transactions as specified by BIP 69 before signing
+    #[arg(long)]
+    bip69: bool,
+
+    /// Randomize the output order of unsigned transactions before signing
+    #[arg(long, conflicts_with = "bip69")]
+    shuffle_outputs: bool,
+
+    /// Do everything up to signing, print the manifest of what would be signed and stop, for a second reviewer to compare with the signer's
+    #[arg(long, conflicts_with_all = ["shuffle_outputs", "anchor_fee_rate", "use_bumpfee"])]
+    mirror: bool,
+
+    /// Write the manifest of what is about to be signed to this file before signing
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<PathBuf>,
+
+    /// Refuse to sign unless the manifest of what is about to be signed has this hash, e.g. from a reviewer's --mirror run
+    #[arg(long, value_name = "HASH")]
+    expect_manifest: Option<String>,
+
+    /// Add a wallet-funded child for every unspent pay-to-anchor output, paying for the package at this feerate (sat/vB)
+    #[arg(long, value_name = "SAT_PER_VB")]
+    anchor_fee_rate: Option<f64>,
+
+    /// Print a before/after table of fees for transactions changed by rewriting or signing (always done when transactions were rewritten)
+    #[arg(long)]
+    fee_report: bool,
+
+    /// Replace entries that are unconfirmed wallet transactions by a fee-bumped version from the wallet (psbtbumpfee) instead of signing them
+    #[arg(long)]
+    use_bumpfee: bool,
+
+    /// Feerate for --use-bumpfee replacements (sat/vB); the wallet's estimate if not provided
+    #[arg(long, value_name = "SAT_PER_VB", requires = "use_bumpfee")]
+    bump_fee_rate: Option<f64>,
+
+    /// Check that the batch fits within the mempool's ancestor/descendant limits, counting unconfirmed parents already in the mempool
+    #[arg(long)]
+    check_mempool_limits: bool,
+
+    /// Warn about transactions paying less than the node's current mempool minimum feerate (getmempoolinfo)
+    #[arg(long)]
+    check_min_fee: bool,
+
+    /// Fail instead of warning when a transaction pays less than the mempool minimum feerate (implies --check-min-fee)
+    #[arg(long)]
+    refuse_below_min_fee: bool,
+
+    /// Run the signed batch through testmempoolaccept as a package, reporting each transaction's acceptance and feerates, and fail if any is rejected
+    #[arg(long)]
+    validate: bool,
+
+    /// On regtest, send the signed batch with sendrawtransaction and mine N blocks (to ADDRESS, or a new wallet address)
+    #[arg(long, num_args = 1..=2, value_names = ["N", "ADDRESS"])]
+    regtest_mine: Vec<String>,
+
+    /// Send the signed batch with sendrawtransaction, queueing transactions that fail for a transient reason (node unreachable, missing inputs) for flush-queue
+    #[arg(long, conflicts_with = "regtest_mine")]
+    broadcast: bool,
+
+    /// Queue file for --broadcast
+    #[arg(long, value_name = "PATH", default_value = broadcast::DEFAULT_QUEUE)]
+    queue: PathBuf,
+
+    /// Output transactions still missing signatures as base64 PSBTs (psbt), for the next cosigner, instead of hex
+    #[arg(
+        long,
+        value_enum,
+        default_value = "hex",
+        conflicts_with_all = ["broadcast", "regtest_mine"]
+    )]
+    output_format: OutputFormat,
+
+    /// Encodings to write signed transactions in (comma-separated): hex, base64 (a bitcoin_base64 field), bin (files in --emit-dir)
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "hex")]
+    emit: Vec<Emit>,
+
+    /// Directory --emit bin writes TXID.bin files to
+    #[arg(long, value_name = "DIR")]
+    emit_dir: Option<PathBuf>,
+
+    /// With --broadcast, send transaction N (--canary=N, from 1; the first if not given) alone first, and the rest only once it is in the mempool or confirmed
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1",
+        requires = "broadcast"
+    )]
+    canary: Option<usize>,
+
+    /// Confirmations the --canary transaction needs before the rest is sent (0: mempool acceptance)
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "canary")]
+    canary_confirmations: u32,
+
+    /// How long to wait for the --canary transaction's confirmations before giving up
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 3_600,
+        requires = "canary"
+    )]
+    canary_timeout: u64,
+
+    /// Ledger of outpoints spent by transactions signed in earlier runs: warn when the batch spends one again, and record the batch's
+    #[arg(long, value_name = "PATH")]
+    ledger: Option<PathBuf>,
+
+    /// Fail instead of warning when the batch spends an outpoint reserved in the ledger by another transaction
+    #[arg(long, requires = "ledger")]
+    refuse_ledger_conflicts: bool,
+
+    /// Snapshot the wallet's UTXOs (listunspent) before and after the run, including any broadcast, and write what was consumed and created to this JSON file
+    #[arg(long, value_name = "PATH")]
+    utxo_delta: Option<PathBuf>,
+
+    /// Write per-transaction metrics (sizes, fee, inputs signed, duration, status) to this file, as CSV if it ends in .csv and JSON otherwise, and per-backend call metrics next to it (STEM-backends.EXT)
+    #[arg(long, value_name = "PATH")]
+    stats_out: Option<PathBuf>,
+
+    /// Write every signed transaction to its own file in this directory, named by txid (TXID.hex)
+    #[arg(long, value_name = "DIR")]
+    archive_dir: Option<PathBuf>,
+
+    /// Also write each transaction to the archive as a finalized PSBT (TXID.psbt)
+    #[arg(long, requires = "archive_dir")]
+    archive_psbt: bool,
+
+    /// Sign a statement naming the batch's txids with the wallet key of --attest-address, and write it to this JSON file for counterparties to check
+    #[arg(long, value_name = "PATH", requires = "attest_address")]
+    attest: Option<PathBuf>,
+
+    /// Legacy (P2PKH) wallet address whose key signs the --attest statement
+    #[arg(long, value_name = "ADDRESS", requires = "attest")]
+    attest_address: Option<String>,
+
+    /// Target feerate for CPFP chains in the batch (sat/vB): report package feerates and warn about transactions no child lifts to it
+    #[arg(long, value_name = "SAT_PER_VB")]
+    package_feerate: Option<f64>,
+
+    /// Adjust the change output of unsigned transactions to pay this feerate (sat/vB) once signed
+    #[arg(long, value_name = "SAT_PER_VB", requires = "change_address")]
+    retarget_feerate: Option<f64>,
+
+    /// Change output to adjust for --retarget-feerate: an address, a descriptor, or "auto" to ask the wallet
+    #[arg(
+        long,
+        value_name = "ADDRESS|DESCRIPTOR|auto",
+        requires = "retarget_feerate"
+    )]
+    change_address: Option<String>,
 }
 
-fn run_docker_btc(container: &str, args: &[&str]) -> Result<String> {
-    let mut cmd_args = vec!["exec", container, BTC_CLI];
-    cmd_args.extend(args);
+/// Signing is also what runs without a subcommand.
+#[derive(Subcommand)]
+enum Command {
+    /// Sign the transactions of a batch (the default)
+    Sign(Box<SignArgs>),
+    /// Decode every transaction of a batch with the node and output them as a JSON array
+    Decode {
+        /// Input JSON file containing transactions (reads from stdin if not provided)
+        input_file: Option<String>,
+    },
+    /// Check the signatures of every transaction of a batch locally, reporting the inputs missing or failing them
+    Verify {
+        /// Input JSON file containing transactions (reads from stdin if not provided)
+        input_file: Option<String>,
+        /// Also run the batch through testmempoolaccept as a package, reporting each transaction's acceptance and feerates
+        #[arg(long)]
+        validate: bool,
+    },
+    /// Map every input of a batch to the signers holding its keys, from their descriptors and chain data only, and output the signing plan
+    Plan {
+        /// Input JSON file containing transactions (reads from stdin if not provided)
+        input_file: Option<String>,
 
-    let output = Command::new("docker")
-        .args(&cmd_args)
-        .output()
-        .context("Failed to execute docker")?;
+        /// JSON file of the descriptors the inputs may spend and each signer's master key fingerprint
+        #[arg(long, value_name = "PATH")]
+        signers: PathBuf,
+    },
+    /// Print the sighash digests signing each unsigned input will sign, e.g. for an HSM to pre-authorize them
+    PreviewSighashes {
+        /// Input JSON file containing transactions (reads from stdin if not provided)
+        input_file: Option<String>,
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("docker exec {BTC_CLI} failed: {}", stderr);
-    }
+        /// Don't have the wallet add the scripts it knows (P2SH and P2WSH inputs need them, as do taproot script paths)
+        #[arg(long)]
+        no_wallet: bool,
+    },
+    /// Send the transactions of a signed batch together with submitpackage, or in order if the node refuses the package
+    Broadcast {
+        /// Input JSON file containing signed transactions (reads from stdin if not provided)
+        input_file: Option<String>,
+
+        /// Queue file for transactions to retry with flush-queue
+        #[arg(long, value_name = "PATH", default_value = broadcast::DEFAULT_QUEUE)]
+        queue: PathBuf,
+    },
+    /// Build unsigned transactions from a JSON spec and output them in the batch format
+    Build {
+        /// JSON spec of the transactions to build (reads from stdin if not provided)
+        spec_file: Option<String>,
+
+        /// UTXO set (listunspent JSON) to pick inputs from locally instead of funding with the wallet
+        #[arg(long)]
+        utxos: Option<String>,
+    },
+    /// Build and sign a transaction spending all (or filtered) wallet UTXOs to one address
+    Sweep {
+        /// Address receiving everything, minus the fee
+        #[arg(long)]
+        to: String,
+
+        /// Feerate of the sweep transaction (sat/vB)
+        #[arg(long, value_name = "SAT_PER_VB")]
+        fee_rate: f64,
 
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        /// Only sweep UTXOs with at least this many confirmations
+        #[arg(long, default_value_t = 1)]
+        min_conf: u32,
+
+        /// Only sweep UTXOs paying to this address (repeatable)
+        #[arg(long = "from-address", value_name = "ADDRESS")]
+        from_addresses: Vec<String>,
+
+        /// Only sweep UTXOs of at least this amount (BTC)
+        #[arg(long, value_name = "BTC")]
+        min_amount: Option<f64>,
+
+        /// UTXO set (listunspent JSON) to sweep instead of the wallet's
+        #[arg(long)]
+        utxos: Option<String>,
+    },
+    /// Write every transaction of a batch to a PSBT file, for cosigners using GUI wallets such as Sparrow or Electrum
+    ExportPsbt {
+        /// Input JSON file containing transactions (reads from stdin if not provided)
+        input_file: Option<String>,
+
+        /// Directory to write the PSBT files to
+        #[arg(long, value_name = "DIR")]
+        out_dir: PathBuf,
+
+        /// Don't have the wallet add the scripts and key origins it knows
+        #[arg(long)]
+        no_wallet: bool,
+
+        /// Name the files as short as a Coldcard lists them (NN-TXID8.psbt), e.g. to write them to its SD card
+        #[arg(long)]
+        coldcard: bool,
+    },
+    /// Merge signatures from PSBT and transaction files (e.g. written back by a Coldcard) into a batch
+    ImportPsbt {
+        /// Input JSON file containing transactions (reads from stdin if not provided)
+        input_file: Option<String>,
+
+        /// Directory with the signed files (.psbt, or .txn with a finalized transaction in hex)
+        #[arg(long, value_name = "DIR")]
+        from: PathBuf,
+    },
+    /// Send the PSBTs of a batch to cosigners as encrypted Nostr direct messages (experimental, uses nak)
+    NostrSend {
+        /// Input JSON file containing transactions (reads from stdin if not provided)
+        input_file: Option<String>,
+
+        #[command(flatten)]
+        nostr: NostrArgs,
+
+        /// Don't have the wallet add the scripts and key origins it knows
+        #[arg(long)]
+        no_wallet: bool,
+    },
+    /// Merge the signed PSBTs cosigners sent back as Nostr direct messages into a batch (experimental, uses nak)
+    NostrCollect {
+        /// Input JSON file containing transactions (reads from stdin if not provided)
+        input_file: Option<String>,
+
+        #[command(flatten)]
+        nostr: NostrArgs,
+
+        /// Only look at messages sent after this Unix time (default: the last 7 days)
+        #[arg(long, value_name = "UNIX_TIME")]
+        since: Option<u64>,
+    },
+    /// Retry sending the transactions --broadcast queued, keeping those still failing for a transient reason
+    FlushQueue {
+        /// Queue file
+        #[arg(long, value_name = "PATH", default_value = broadcast::DEFAULT_QUEUE)]
+        queue: PathBuf,
+    },
+    /// Run a signed batch through a model of the node's mempool, reporting which transactions it would accept and in what order, without sending anything
+    Simulate {
+        /// Input JSON file containing signed transactions (reads from stdin if not provided)
+        input_file: Option<String>,
+    },
+    /// Compare the output of a previous run with a new one, reporting which transactions changed and their fee and size deltas
+    Compare {
+        /// Output of the previous run
+        old_file: String,
+
+        /// Output of the new run
+        new_file: String,
+    },
+    /// Sign, validate, broadcast and mine a dependent batch in a new wallet on a regtest node, reporting each stage
+    Selftest,
+    /// On regtest, pay addresses from the wallet (mining as needed) and output the funded outputs
+    Faucet {
+        /// JSON object of addresses and amounts in BTC (reads from stdin if not provided)
+        amounts_file: Option<String>,
+
+        /// Blocks to mine after sending, to confirm the funding
+        #[arg(long, default_value_t = 1)]
+        confirmations: u32,
+    },
 }
 
-fn decode_transaction(raw_tx: &str) -> Result<DecodeResult> {
-    let output = run_btc_cli(&["decoderawtransaction", raw_tx])?;
-    serde_json::from_str(&output).context("Failed to parse decoded transaction")
+/// How to reach cosigners over Nostr.
+#[derive(clap::Args)]
+struct NostrArgs {
+    /// Relay to publish to and read from (repeatable)
+    #[arg(long = "relay", value_name = "URL", required = true)]
+    relays: Vec<String>,
+
+    /// Public key of a cosigner, as npub or hex (repeatable)
+    #[arg(long = "cosigner", value_name = "NPUB", required = true)]
+    cosigners: Vec<String>,
+
+    /// Our Nostr secret key, as nsec or hex
+    #[arg(
+        long,
+        value_name = "NSEC",
+        env = "NOSTR_SECRET_KEY",
+        hide_env_values = true
+    )]
+    nostr_secret_key: String,
+
+    /// nak command used to encrypt, publish and fetch messages
+    #[arg(long, default_value = "nak")]
+    nak: String,
 }
 
-fn get_prevout_info(txid: &str, vout: u32) -> Result<Option<(f64, String)>> {
-    let output = run_btc_cli(&["getrawtransaction", txid, "true"])?;
-    let tx_info: TxInfo =
-        serde_json::from_str(&output).context("Failed to parse transaction info")?;
+impl NostrArgs {
+    fn connect(&self) -> Result<(nostr::Nostr, Vec<String>)> {
+        let nostr = nostr::Nostr::new(&self.nak, &self.nostr_secret_key, self.relays.clone())?;
+        let cosigners = self
+            .cosigners
+            .iter()
+            .map(|key| nostr::parse_public_key(key))
+            .collect::<Result<_>>()?;
+        Ok((nostr, cosigners))
+    }
+}
 
-    if let Some(vout_entry) = tx_info.vout.get(vout as usize) {
-        Ok(Some((
-            vout_entry.value,
-            vout_entry.script_pubkey.hex.clone(),
-        )))
-    } else {
-        Ok(None)
+/// Print JSON output to stdout, canonically written with `--canonical-output`.
+fn print_json<T: Serialize>(value: &T, canonical: bool) -> Result<()> {
+    match canonical {
+        true => print!("{}", canonical::to_string(value)?),
+        false => println!("{}", serde_json::to_string_pretty(value)?),
     }
+    Ok(())
 }
 
-fn sign_transaction(container: Option<&str>, raw_tx: &str, tx_index: usize) -> Result<String> {
-    eprintln!("\nProcessing transaction {}...", tx_index + 1);
+/// Outputs of the batch transactions, by outpoint.
+fn batch_outputs(txs: &[Option<Transaction>]) -> HashMap<OutPoint, TxOut> {
+    let mut outputs = HashMap::new();
+    for tx in txs.iter().flatten() {
+        let txid = tx.compute_txid();
+        for (vout, output) in tx.output.iter().enumerate() {
+            outputs.insert(OutPoint::new(txid, vout as u32), output.clone());
+        }
+    }
+    outputs
+}
 
-    // Decode the transaction to get inputs
-    let decoded = decode_transaction(raw_tx)?;
+/// Outputs spent by a transaction's inputs, from the batch or else from the chain.
+fn resolve_spent(
+    node: &Node,
+    batch_outputs: &HashMap<OutPoint, TxOut>,
+    tx: &Transaction,
+) -> Result<Vec<TxOut>> {
+    tx.input
+        .iter()
+        .map(|input| match batch_outputs.get(&input.previous_output) {
+            Some(output) => Ok(output.clone()),
+            None => {
+                let outpoint = input.previous_output;
+                prevout_txout(node, &outpoint.txid.to_string(), outpoint.vout)
+            }
+        })
+        .collect()
+}
 
-    // Build prevouts array for all inputs that need signing
-    let mut prevouts: Vec<PrevOut> = Vec::new();
+/// Remove the signatures of the batch inputs that fail local verification, so they are signed again.
+///
+/// Valid signatures, including partial multisig ones from other signers, are
+/// kept. Entries carrying metadata are only reported: their witnesses are
+/// covered by it. Returns the number of stripped inputs.
+fn strip_invalid_signatures(node: &Node, entries: &mut [Entry]) -> Result<usize> {
+    let txs = rewrite::parse_batch(entries)?;
+    let outputs = batch_outputs(&txs);
+    let mut stripped = 0;
+    for (i, tx) in txs.into_iter().enumerate() {
+        let Some(mut tx) = tx.filter(rewrite::is_signed) else {
+            continue;
+        };
+        let spent = resolve_spent(node, &outputs, &tx)
+            .with_context(|| format!("Failed to resolve prevouts of transaction {}", i + 1))?;
+        let invalid = verify::strip_invalid(&mut tx, &spent);
+        for (vin, reason) in &invalid {
+            eprintln!(
+                "  Transaction {} input {}: invalid signature ({})",
+                i + 1,
+                vin,
+                reason
+            );
+        }
+        if invalid.is_empty() {
+            continue;
+        }
+        if entries[i].has_metadata() {
+            eprintln!(
+                "  Transaction {}: left as is, its witnesses are covered by its metadata",
+                i + 1
+            );
+            continue;
+        }
+        entries[i].set_hex(rewrite::encode_tx(&tx));
+        stripped += invalid.len();
+    }
+    Ok(stripped)
+}
 
-    for (i, input) in decoded.vin.iter().enumerate() {
-        // Check if this input has witness data (already signed)
-        if input.txinwitness.is_some() {
-            eprintln!("  Input {}: already signed, skipping", i);
+/// Check the signatures signing added to the batch (`unsigned` being the batch before) against the signer policy.
+fn check_signers(
+    node: &Node,
+    policy: &SignerPolicy,
+    unsigned: &[Option<Transaction>],
+    entries: &[Entry],
+) -> Result<Vec<String>> {
+    let signed = rewrite::parse_batch(entries)?;
+    let outputs = batch_outputs(&signed);
+    let mut violations = Vec::new();
+    for (i, (before, after)) in unsigned.iter().zip(&signed).enumerate() {
+        let (Some(before), Some(after)) = (before, after) else {
             continue;
+        };
+        let spent = resolve_spent(node, &outputs, after)
+            .with_context(|| format!("Failed to resolve prevouts of transaction {}", i + 1))?;
+        for violation in policy.check(node, before, after, &spent)? {
+            violations.push(format!("Transaction {} {}", i + 1, violation));
         }
+    }
+    Ok(violations)
+}
 
-        eprintln!(
-            "  Input {}: {}:{} - fetching prevout info...",
-            i, input.txid, input.vout
-        );
+/// Add a child for every pay-to-anchor output no batch transaction spends yet.
+///
+/// Children are funded from the wallet's UTXOs (not already spent in the
+/// batch) so that each parent and its child together pay `fee_rate`, and are
+/// placed right after their parent. Returns the positions children were
+/// inserted at, in insertion order.
+fn add_anchor_children(node: &Node, batch: &mut Batch, fee_rate: f64) -> Result<Vec<usize>> {
+    let txs = rewrite::parse_batch(batch.entries())?;
+    let outputs = batch_outputs(&txs);
+    let batch_spent: HashSet<OutPoint> = txs
+        .iter()
+        .flatten()
+        .flat_map(|tx| &tx.input)
+        .map(|input| input.previous_output)
+        .collect();
+    let mut utxos = coinselect::list_unspent(node, 1, &[])?;
+    utxos.retain(|utxo| !batch_spent.contains(&utxo.outpoint));
 
-        // Get the previous output info from the remote node
-        match get_prevout_info(&input.txid, input.vout)? {
-            Some((amount, script_pubkey)) => {
-                eprintln!(
-                    "  Input {}: amount={}, scriptPubKey={}",
-                    i, amount, script_pubkey
-                );
-                prevouts.push(PrevOut {
-                    txid: input.txid.clone(),
-                    vout: input.vout,
-                    amount,
-                    script_pubkey,
-                });
+    let mut inserted = Vec::new();
+    for (i, tx) in txs.iter().enumerate().rev() {
+        let Some(parent) = tx else {
+            continue;
+        };
+        let txid = parent.compute_txid();
+        for (vout, output) in parent.output.iter().enumerate() {
+            let outpoint = OutPoint::new(txid, vout as u32);
+            if !anchor::is_p2a(&output.script_pubkey) || batch_spent.contains(&outpoint) {
+                continue;
             }
-            None => {
-                eprintln!(
-                    "  Input {}: prevout not found on chain, may be from earlier tx in batch",
-                    i
+            let parent_spent = resolve_spent(node, &outputs, parent)
+                .with_context(|| format!("Failed to resolve prevouts of transaction {}", i + 1))?;
+            // The child commits to the parent's txid, which signing must not change
+            if parent
+                .input
+                .iter()
+                .zip(&parent_spent)
+                .any(|(input, spent)| {
+                    input.script_sig.is_empty()
+                        && input.witness.is_empty()
+                        && !spent.script_pubkey.is_witness_program()
+                })
+            {
+                bail!(
+                    "Transaction {} has unsigned non-segwit inputs, its txid will change when signed",
+                    i + 1
                 );
             }
+            let change_address = node.run_wallet(&["getrawchangeaddress"])?;
+            let change_script = build::parse_address(&change_address, None)?.script_pubkey();
+            let redeem_scripts = fees::redeem_scripts(node, &parent_spent)?;
+            let (child, picked) = anchor::build_child(
+                parent,
+                &parent_spent,
+                &redeem_scripts,
+                vout as u32,
+                fee_rate,
+                &utxos,
+                change_script,
+            )
+            .with_context(|| format!("Failed to build anchor child of transaction {}", i + 1))?;
+            eprintln!(
+                "  Transaction {}: child spending anchor {} with {} wallet input(s)",
+                i + 1,
+                vout,
+                picked.len()
+            );
+            let mut picked = picked;
+            picked.sort_unstable();
+            for j in picked.into_iter().rev() {
+                utxos.remove(j);
+            }
+            batch.insert(
+                i + 1,
+                Entry::Tx(TxEntry {
+                    bitcoin: Some(rewrite::encode_tx(&child)),
+                    extra: Default::default(),
+                }),
+            );
+            inserted.push(i + 1);
         }
     }
+    Ok(inserted)
+}
 
-    if prevouts.is_empty() {
-        eprintln!("  No inputs to sign, returning original transaction");
-        return Ok(raw_tx.to_string());
+/// Check the wallet can fund the anchor children `anchor_fee_rate` adds and
+/// the wallet transactions `bump_fee_rate` bumps, before anything is built or signed.
+fn preflight_funds(
+    node: &Node,
+    batch: &Batch,
+    anchor_fee_rate: Option<f64>,
+    bump_fee_rate: Option<f64>,
+) -> Result<()> {
+    let txs = rewrite::parse_batch(batch.entries())?;
+    let outputs = batch_outputs(&txs);
+    let batch_spent: HashSet<OutPoint> = txs
+        .iter()
+        .flatten()
+        .flat_map(|tx| &tx.input)
+        .map(|input| input.previous_output)
+        .collect();
+
+    let mut needs = Vec::new();
+    for (i, tx) in txs.iter().enumerate() {
+        let Some(tx) = tx else {
+            continue;
+        };
+        if let Some(fee_rate) = anchor_fee_rate {
+            let txid = tx.compute_txid();
+            for (vout, output) in tx.output.iter().enumerate() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                if !anchor::is_p2a(&output.script_pubkey) || batch_spent.contains(&outpoint) {
+                    continue;
+                }
+                let spent = resolve_spent(node, &outputs, tx).with_context(|| {
+                    format!("Failed to resolve prevouts of transaction {}", i + 1)
+                })?;
+                needs.push(preflight::Need {
+                    purpose: format!("Anchor child of transaction {}", i + 1),
+                    amount: anchor::child_need(
+                        tx,
+                        &spent,
+                        &fees::redeem_scripts(node, &spent)?,
+                        vout as u32,
+                        fee_rate,
+                    )?,
+                });
+            }
+        }
+        let Some(fee_rate) = bump_fee_rate else {
+            continue;
+        };
+        let hex = rewrite::encode_tx(tx);
+        if let Some(txid) = bump::unconfirmed_wallet_txid(node, &hex)? {
+            needs.push(preflight::Need {
+                purpose: format!("Fee bump of transaction {}", i + 1),
+                amount: bump::need(node, &txid, tx, fee_rate)?,
+            });
+        }
     }
+    if needs.is_empty() {
+        return Ok(());
+    }
+    let fee_rate = anchor_fee_rate
+        .unwrap_or_default()
+        .max(bump_fee_rate.unwrap_or_default());
+    preflight::check(node, &needs, &batch_spent, fee_rate)
+}
+
+/// Fee and virtual size (estimated once signed) of each batch transaction, if its prevouts can be found.
+fn tx_fees(node: &Node, txs: &[Option<Transaction>]) -> Vec<Option<(Amount, u64)>> {
+    let outputs = batch_outputs(txs);
+    txs.iter()
+        .map(|tx| {
+            let tx = tx.as_ref()?;
+            let spent = resolve_spent(node, &outputs, tx).ok()?;
+            let fee = fees::fee(tx, &spent).ok()?;
+            let redeem_scripts = fees::redeem_scripts(node, &spent).unwrap_or_default();
+            let vsize = fees::estimate_signed_vsize(tx, &spent, &redeem_scripts)
+                .unwrap_or(tx.vsize() as u64);
+            Some((fee, vsize))
+        })
+        .collect()
+}
+
+/// Metrics of every entry of the signed batch.
+///
+/// `before` holds the transactions as they were before signing (empty for
+/// Elements batches, which are not decoded locally) and `outcomes` what the
+/// signing loop did with each entry.
+fn batch_stats(
+    node: &Node,
+    before: &[Option<Transaction>],
+    entries: &[Entry],
+    anchors: &Anchors,
+    outcomes: &[(&'static str, Duration)],
+) -> Result<Vec<TxStats>> {
+    let after = match node.chain_type {
+        ChainType::Bitcoin => rewrite::parse_batch(entries)?,
+        ChainType::Elements => Vec::new(),
+    };
+    let fees = tx_fees(node, &after);
+    let is_signed =
+        |input: &bitcoin::TxIn| !input.witness.is_empty() || !input.script_sig.is_empty();
+
+    Ok(outcomes
+        .iter()
+        .enumerate()
+        .map(|(i, &(status, duration))| {
+            let mut stats = TxStats {
+                index: i + 1,
+                status,
+                duration_ms: duration.as_millis(),
+                label: entries[i].label().map(str::to_string),
+                ..Default::default()
+            };
+            let Some(tx) = after.get(i).and_then(Option::as_ref) else {
+                return stats;
+            };
+            stats.txid = Some(tx.compute_txid().to_string());
+            stats.inputs = Some(tx.input.len());
+            stats.size = Some(tx.total_size());
+            stats.vsize = Some(tx.vsize());
+            stats.weight = Some(tx.weight().to_wu());
+            if let Some(before) = before.get(i).and_then(Option::as_ref) {
+                stats.inputs_signed = Some(
+                    before
+                        .input
+                        .iter()
+                        .zip(&tx.input)
+                        .filter(|(before, after)| !is_signed(before) && is_signed(after))
+                        .count(),
+                );
+            }
+            if status == "signed" {
+                let complete = tx.input.iter().all(|input| {
+                    let outpoint = input.previous_output;
+                    is_signed(input)
+                        || anchors
+                            .get(&outpoint.txid.to_string(), outpoint.vout)
+                            .is_some()
+                });
+                stats.status = if complete { "complete" } else { "incomplete" };
+            }
+            if let Some((fee, vsize)) = fees[i] {
+                stats.fee = Some(fee.to_sat());
+                stats.fee_rate = Some(fee.to_sat() as f64 / vsize as f64);
+            }
+            stats
+        })
+        .collect())
+}
 
-    eprintln!("  Signing {} input(s) with wallet...", prevouts.len());
+/// Print a before/after table of fees for every transaction whose txid, fee, or size changed.
+///
+/// `origin` gives the position in `before` of each entry of `after_entries`
+/// (`None` for entries added to the batch).
+fn report_fee_changes(
+    node: &Node,
+    before: &[Option<Transaction>],
+    after_entries: &[Entry],
+    origin: &[Option<usize>],
+) -> Result<()> {
+    let after = rewrite::parse_batch(after_entries)?;
+    let before_fees = tx_fees(node, before);
+    let after_fees = tx_fees(node, &after);
 
-    // Sign with wallet (either via Docker or local bitcoin-cli)
-    let prevouts_json = serde_json::to_string(&prevouts)?;
-    let sign_output = match container {
-        Some(c) => run_docker_btc(c, &["signrawtransactionwithwallet", raw_tx, &prevouts_json])?,
-        None => run_btc_cli(&["signrawtransactionwithwallet", raw_tx, &prevouts_json])?,
+    let describe = |fees: Option<(Amount, u64)>| match fees {
+        Some((fee, vsize)) => format!(
+            "{:>8} sat {:>5} vB {:>7.2} sat/vB",
+            fee.to_sat(),
+            vsize,
+            fee.to_sat() as f64 / vsize as f64
+        ),
+        None => format!("{:>33}", "unknown"),
     };
 
-    let sign_result: SignResult =
-        serde_json::from_str(&sign_output).context("Failed to parse sign result")?;
+    let mut rows = Vec::new();
+    for (i, tx) in after.iter().enumerate() {
+        let Some(tx) = tx else {
+            continue;
+        };
+        let previous = origin[i].and_then(|j| before[j].as_ref().map(|tx| (j, tx)));
+        let (before_fee, note) = match previous {
+            None => (None, "added"),
+            Some((j, previous)) if previous.compute_txid() != tx.compute_txid() => {
+                (before_fees[j], "txid changed")
+            }
+            Some((j, _)) if before_fees[j] != after_fees[i] => (before_fees[j], ""),
+            Some(_) => continue,
+        };
+        rows.push(format!(
+            "  {:>4}  {}  {}  {}",
+            i + 1,
+            describe(before_fee),
+            describe(after_fees[i]),
+            note
+        ));
+    }
+
+    if rows.is_empty() {
+        eprintln!("  No transaction changed");
+        return Ok(());
+    }
+    eprintln!("  {:>4}  {:<33}  after", "tx", "before");
+    for row in rows {
+        eprintln!("{}", row);
+    }
+    Ok(())
+}
+
+/// Warn about every transaction paying less than `min_fee_rate`, returning how many do.
+///
+/// A transaction below it still passes if a child in the batch brings it up
+/// to the minimum together with its ancestors, as it can then be submitted with
+/// that child as a package.
+fn check_min_fee(node: &Node, entries: &[Entry], min_fee_rate: f64) -> Result<usize> {
+    let txs = rewrite::parse_batch(entries)?;
+    let outputs = batch_outputs(&txs);
+    let spent: Vec<Option<Vec<TxOut>>> = txs
+        .iter()
+        .enumerate()
+        .map(|(i, tx)| {
+            resolve_spent(node, &outputs, tx.as_ref()?)
+                .inspect_err(|e| eprintln!("  Transaction {}: fee unknown: {:#}", i + 1, e))
+                .ok()
+        })
+        .collect();
+    let redeem_scripts = fees::redeem_scripts(node, spent.iter().flatten().flatten())?;
+    let packages = fees::package_fees(&txs, &spent, &redeem_scripts)?;
 
-    if sign_result.complete {
-        eprintln!("  Transaction fully signed");
-    } else if let Some(errors) = &sign_result.errors {
+    let mut below = 0;
+    for (i, package) in packages.iter().enumerate() {
+        let Some(package) = package else {
+            continue;
+        };
+        if package.fee_rate() >= min_fee_rate {
+            continue;
+        }
+        let lifted = (0..txs.len())
+            .filter(|&j| rewrite::ancestors(&txs, j).contains(&i))
+            .filter_map(|j| packages[j].as_ref())
+            .any(|child| child.ancestor_fee_rate() >= min_fee_rate);
+        if lifted {
+            continue;
+        }
         eprintln!(
-            "  Warning: Transaction not fully signed. Errors: {}",
-            serde_json::to_string_pretty(errors)?
+            "  Warning: transaction {} pays {:.2} sat/vB, below the mempool minimum of {:.2} sat/vB",
+            i + 1,
+            package.fee_rate(),
+            min_fee_rate
         );
+        below += 1;
     }
-
-    Ok(sign_result.hex)
+    Ok(below)
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+/// Print the feerate of every transaction with in-batch relatives, alone and with its ancestors.
+///
+/// A transaction below `target` is fine if one of its in-batch descendants
+/// lifts it: miners take a child along with its ancestors, at their combined
+/// feerate. A warning is printed for those no descendant lifts to `target`.
+fn report_package_fees(node: &Node, entries: &[Entry], target: f64) -> Result<()> {
+    let txs = rewrite::parse_batch(entries)?;
+    let outputs = batch_outputs(&txs);
+    let related: Vec<usize> = (0..txs.len())
+        .filter(|&i| {
+            !rewrite::ancestors(&txs, i).is_empty()
+                || (0..txs.len()).any(|j| rewrite::ancestors(&txs, j).contains(&i))
+        })
+        .collect();
+    if related.is_empty() {
+        eprintln!("  No transactions depend on each other in the batch");
+        return Ok(());
+    }
 
-    // Read input from file or stdin
-    let (content, source) = match &args.input_file {
-        Some(path) => {
-            let content = std::fs::read_to_string(path).context("Failed to read input file")?;
-            (content, path.as_str())
+    let spent: Vec<Option<Vec<TxOut>>> = txs
+        .iter()
+        .enumerate()
+        .map(|(i, tx)| {
+            let tx = tx.as_ref().filter(|_| related.contains(&i))?;
+            resolve_spent(node, &outputs, tx)
+                .inspect_err(|e| eprintln!("  Transaction {}: fee unknown: {:#}", i + 1, e))
+                .ok()
+        })
+        .collect();
+    let redeem_scripts = fees::redeem_scripts(node, spent.iter().flatten().flatten())?;
+    let packages = fees::package_fees(&txs, &spent, &redeem_scripts)?;
+
+    for &i in &related {
+        let Some(package) = &packages[i] else {
+            continue;
+        };
+        eprintln!(
+            "  Transaction {}: fee {}, {} vB, {:.2} sat/vB; with ancestors {:.2} sat/vB",
+            i + 1,
+            package.fee,
+            package.vsize,
+            package.fee_rate(),
+            package.ancestor_fee_rate()
+        );
+    }
+    for &i in &related {
+        let Some(package) = &packages[i] else {
+            continue;
+        };
+        if package.ancestor_fee_rate() >= target {
+            continue;
         }
-        None => {
-            let mut content = String::new();
-            io::stdin()
-                .read_to_string(&mut content)
-                .context("Failed to read from stdin")?;
-            (content, "stdin")
+        let best = (0..txs.len())
+            .filter(|&j| rewrite::ancestors(&txs, j).contains(&i))
+            .filter_map(|j| {
+                packages[j]
+                    .as_ref()
+                    .map(|package| (j, package.ancestor_fee_rate()))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        match best {
+            Some((_, fee_rate)) if fee_rate >= target => {}
+            Some((j, fee_rate)) => eprintln!(
+                "  Warning: transaction {} is below {} sat/vB and its best child package (with transaction {}) only reaches {:.2} sat/vB",
+                i + 1,
+                target,
+                j + 1,
+                fee_rate
+            ),
+            None => eprintln!(
+                "  Warning: transaction {} is below {} sat/vB ({:.2} sat/vB with ancestors) and no child lifts it",
+                i + 1,
+                target,
+                package.ancestor_fee_rate()
+            ),
         }
-    };
-    let txs: Vec<TxEntry> = serde_json::from_str(&content).context("Failed to parse input JSON")?;
+    }
+    Ok(())
+}
 
-    eprintln!("Reading transactions from: {}", source);
-    eprintln!("Found {} transaction(s) to process", txs.len());
+/// Set the change output of every unsigned transaction so it pays `fee_rate` once signed.
+///
+/// Prevouts created earlier in the batch are taken from the (possibly already
+/// retargeted) batch transactions, the rest are looked up on chain.
+fn retarget_fees(
+    node: &Node,
+    entries: &mut [Entry],
+    fee_rate: f64,
+    change: &ChangeDetector,
+) -> Result<usize> {
+    let mut batch_outputs = batch_outputs(&rewrite::parse_batch(entries)?);
+
+    rewrite::rewrite_unsigned(entries, |i, tx| {
+        let spent = resolve_spent(node, &batch_outputs, tx)
+            .with_context(|| format!("Failed to resolve prevouts of transaction {}", i + 1))?;
+
+        let change_index = match change.change_outputs(node, tx)?[..] {
+            [index] => index,
+            [] => {
+                eprintln!("  Transaction {}: no change output, fee left as is", i + 1);
+                return Ok((0..tx.output.len()).collect());
+            }
+            _ => bail!("Transaction {} has more than one change output", i + 1),
+        };
+        let redeem_scripts = fees::redeem_scripts(node, &spent)?;
+        let (previous, new) =
+            fees::retarget_change(tx, &spent, &redeem_scripts, change_index, fee_rate)
+                .with_context(|| format!("Failed to retarget transaction {}", i + 1))?;
+        eprintln!(
+            "  Transaction {}: change output {} {} -> {} (fee {})",
+            i + 1,
+            change_index,
+            previous,
+            new,
+            fees::fee(tx, &spent)?
+        );
+
+        let txid = tx.compute_txid();
+        for (vout, output) in tx.output.iter().enumerate() {
+            batch_outputs.insert(OutPoint::new(txid, vout as u32), output.clone());
+        }
+        Ok((0..tx.output.len()).collect())
+    })
+}
 
-    // Process each transaction
-    let mut signed_txs: Vec<TxEntry> = Vec::new();
+/// A PSBT for every bitcoin transaction of the batch, with its position.
+///
+/// Each PSBT carries the outputs its inputs spend, and the transactions they
+/// come from when the node or batch has them; with `use_wallet`, the wallet
+/// adds the scripts and key origins it knows, which multisig cosigners need
+/// to recognize their keys.
+fn batch_psbts(
+    node: &Node,
+    entries: &[Entry],
+    use_wallet: bool,
+) -> Result<Vec<(usize, bitcoin::Psbt)>> {
+    let txs = rewrite::parse_batch(entries)?;
+    let outputs = batch_outputs(&txs);
+    let mut parents: HashMap<bitcoin::Txid, Transaction> = txs
+        .iter()
+        .flatten()
+        .map(|tx| (tx.compute_txid(), tx.clone()))
+        .collect();
+    for input in txs.iter().flatten().flat_map(|tx| &tx.input) {
+        let txid = input.previous_output.txid;
+        if parents.contains_key(&txid) {
+            continue;
+        }
+        if let Ok(parent) = node
+            .run(&["getrawtransaction", &txid.to_string()])
+            .and_then(|hex| rewrite::decode_tx(&hex))
+        {
+            parents.insert(txid, parent);
+        }
+    }
 
+    let mut psbts = Vec::new();
     for (i, tx) in txs.iter().enumerate() {
-        let signed_hex = sign_transaction(args.bitcoind_container.as_deref(), &tx.bitcoin, i)?;
-        signed_txs.push(TxEntry {
-            bitcoin: signed_hex,
+        let Some(tx) = tx else {
+            continue;
+        };
+        let spent = resolve_spent(node, &outputs, tx)
+            .inspect_err(|e| eprintln!("  Transaction {}: prevouts unknown: {:#}", i + 1, e))
+            .ok();
+        let mut psbt = psbt::from_tx(tx, spent.as_deref())?;
+        psbt::add_parents(&mut psbt, &parents);
+        if use_wallet {
+            match psbt::wallet_update(node, &psbt) {
+                Ok(updated) => psbt = updated,
+                Err(e) => eprintln!(
+                    "  Transaction {}: wallet could not add key information: {:#}",
+                    i + 1,
+                    e
+                ),
+            }
+        }
+        psbts.push((i, psbt));
+    }
+    Ok(psbts)
+}
+
+/// Write the PSBTs of the batch to `<dir>/<NN>-<TXID>.psbt` in binary form.
+///
+/// `NN` is the position in the batch, so cosigners can tell the order; with
+/// `short_names`, only the first 8 digits of the txid are used.
+fn export_psbts(
+    node: &Node,
+    entries: &[Entry],
+    dir: &Path,
+    use_wallet: bool,
+    short_names: bool,
+) -> Result<Vec<PathBuf>> {
+    let psbts = batch_psbts(node, entries, use_wallet)?;
+    std::fs::create_dir_all(dir).context("Failed to create PSBT directory")?;
+    let mut paths = Vec::new();
+    for (i, psbt) in psbts {
+        let txid = psbt.unsigned_tx.compute_txid().to_string();
+        let txid = if short_names { &txid[..8] } else { &txid };
+        let path = dir.join(format!("{:02}-{}.psbt", i + 1, txid));
+        psbt::write(&path, &psbt)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Replace the base64 PSBTs of the batch with their transactions, returning the PSBTs by unsigned txid.
+///
+/// The transactions carry the final scriptSigs and witnesses the PSBTs have,
+/// so the batch reads as usual; the PSBTs themselves are what gets signed, so
+/// their partial signatures and key origins aren't lost.
+fn unwrap_psbts(entries: &mut [Entry]) -> Result<HashMap<bitcoin::Txid, bitcoin::Psbt>> {
+    let mut psbts = HashMap::new();
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let Some(encoded) = entry.hex().filter(|hex| psbt::is_base64(hex)) else {
+            continue;
+        };
+        let psbt = bitcoin::Psbt::from_str(encoded)
+            .with_context(|| format!("Failed to parse the PSBT of transaction {}", i + 1))?;
+        eprintln!("  Transaction {} is a PSBT", i + 1);
+        entry.set_hex(rewrite::encode_tx(&psbt::to_tx(&psbt)));
+        psbts.insert(psbt.unsigned_tx.compute_txid(), psbt);
+    }
+    Ok(psbts)
+}
+
+/// Sign the batch's transactions ahead, `jobs` at a time, returning the
+/// diagnostics and signed hex of those signed, by position.
+///
+/// Transactions spending outputs of others in the batch wait until these are
+/// signed, and their outputs added to `outputs`. Entries signed another way
+/// (PSBTs, payjoins), and those spending their outputs, are left for signing
+/// in order, as are those failing here, which are signed again then to
+/// report the error.
+#[allow(clippy::too_many_arguments)]
+fn sign_concurrently(
+    node: &Node,
+    entries: &[Entry],
+    txs: &[Option<Transaction>],
+    order: &[usize],
+    psbts: &HashMap<bitcoin::Txid, bitcoin::Psbt>,
+    signers: &Signers,
+    anchors: &Anchors,
+    outputs: &BatchOutputs,
+    jobs: usize,
+) -> HashMap<usize, (Vec<String>, String)> {
+    let positions: HashMap<bitcoin::Txid, usize> = txs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, tx)| Some((tx.as_ref()?.compute_txid(), i)))
+        .collect();
+    // How many batch transactions deep each transaction signed here spends from
+    let mut depths: Vec<Option<usize>> = vec![None; entries.len()];
+    for &i in order {
+        let Some(tx) = &txs[i] else {
+            continue;
+        };
+        if entries[i].field("payjoin").is_some()
+            || entries[i].wallet().is_some()
+            || psbts.contains_key(&psbt::unsigned_txid(tx))
+        {
+            continue;
+        }
+        depths[i] = tx.input.iter().try_fold(0, |depth, input| {
+            match positions.get(&input.previous_output.txid) {
+                Some(&parent) => depths[parent].map(|parent| depth.max(parent + 1)),
+                None => Some(depth),
+            }
+        });
+    }
+
+    let mut signed = HashMap::new();
+    for depth in 0.. {
+        let level: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|&i| depths[i] == Some(depth))
+            .collect();
+        if level.is_empty() {
+            break;
+        }
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..jobs.min(level.len()) {
+                scope.spawn(|| {
+                    while let Some(&i) = level.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let Some(raw_tx) = entries[i].hex() else {
+                            continue;
+                        };
+                        let metadata = entries[i].has_metadata();
+                        let (lines, result) = log::capture(|| {
+                            sign_transaction(node, raw_tx, i, metadata, signers, anchors)
+                        });
+                        results
+                            .lock()
+                            .expect("not poisoned")
+                            .push((i, lines, result));
+                    }
+                });
+            }
         });
+        for (i, lines, result) in results.into_inner().expect("not poisoned") {
+            let Ok(signed_hex) = result else {
+                continue;
+            };
+            if let Ok(tx) = rewrite::decode_tx(&signed_hex) {
+                outputs.add(&tx);
+            }
+            signed.insert(i, (lines, signed_hex));
+        }
     }
+    signed
+}
 
-    eprintln!("\nAll transactions processed. Output:\n");
+/// Sign a PSBT entry with `walletprocesspsbt` and finalize the inputs that are complete.
+fn sign_psbt_entry(node: &Node, psbt: &bitcoin::Psbt, tx_index: usize) -> Result<bitcoin::Psbt> {
+    eprintln!("\nProcessing transaction {} (PSBT)...", tx_index + 1);
+    let mut tx = psbt::to_tx(psbt);
+    eprintln!("  Signing with walletprocesspsbt...");
+    let signed = finalize(node, &psbt::wallet_sign(node, psbt)?)?;
+    let count = psbt::apply_final(&mut tx, &signed);
+    let unsigned = tx
+        .input
+        .iter()
+        .filter(|input| input.script_sig.is_empty() && input.witness.is_empty())
+        .count();
+    if unsigned == 0 {
+        eprintln!("  {} input(s) signed, transaction complete", count);
+    } else {
+        eprintln!(
+            "  Warning: {} input(s) signed, {} still missing signatures",
+            count, unsigned
+        );
+    }
+    Ok(signed)
+}
+
+/// Replace the transactions still missing signatures with base64 PSBTs for
+/// the next cosigner, returning how many were.
+///
+/// Complete inputs are final in the PSBT. The wallet (that of the entry's
+/// `wallet` field, if any, from `wallets`) signs the others again, so its
+/// signatures of incomplete multisig inputs are there as partial signatures,
+/// along with the scripts and key origins it knows. PSBT entries keep the
+/// PSBT they were signed as, with the partial signatures they had.
+fn output_psbts(
+    node: &Node,
+    wallets: &HashMap<String, Node>,
+    entries: &mut [Entry],
+    signed_psbts: &HashMap<bitcoin::Txid, bitcoin::Psbt>,
+) -> Result<usize> {
+    let txs = rewrite::parse_batch(entries)?;
+    let outputs = batch_outputs(&txs);
+    let mut count = 0;
+    for (i, (entry, tx)) in entries.iter_mut().zip(&txs).enumerate() {
+        let Some(tx) = tx else {
+            continue;
+        };
+        let spent = resolve_spent(node, &outputs, tx)
+            .with_context(|| format!("Failed to resolve prevouts of transaction {}", i + 1))?;
+        let incomplete: Vec<usize> = (0..tx.input.len())
+            .filter(|&index| !anchor::is_p2a(&spent[index].script_pubkey))
+            .filter(|&index| {
+                !matches!(
+                    verify::check_input(tx, index, &spent),
+                    verify::InputCheck::Valid | verify::InputCheck::Unknown
+                )
+            })
+            .collect();
+        if incomplete.is_empty() {
+            continue;
+        }
+
+        let psbt = match signed_psbts.get(&psbt::unsigned_txid(tx)) {
+            Some(psbt) => psbt.clone(),
+            None => {
+                let mut partial = tx.clone();
+                for &index in &incomplete {
+                    partial.input[index].script_sig = ScriptBuf::new();
+                    partial.input[index].witness = bitcoin::Witness::new();
+                }
+                let wallet = entry.wallet().map_or(node, |wallet| &wallets[wallet]);
+                psbt::wallet_sign(wallet, &psbt::from_tx(&partial, Some(&spent))?)?
+            }
+        };
+        eprintln!(
+            "  Transaction {}: {} input(s) missing signatures, written as a PSBT",
+            i + 1,
+            incomplete.len()
+        );
+        entry.set_hex(psbt.to_string());
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// A signed transaction or PSBT coming back from a cosigner.
+enum Signed {
+    Tx(Transaction),
+    Psbt(bitcoin::Psbt),
+}
+
+/// Merge the signatures in the `.psbt` and `.txn` files of `dir` into the batch, returning how many inputs got signed.
+fn import_signed(node: &Node, entries: &mut [Entry], dir: &Path) -> Result<usize> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<_>>()?;
+    files.sort();
+
+    let mut signed = Vec::new();
+    for path in files {
+        let name = path.display().to_string();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("txn") => {
+                let hex = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", name))?;
+                signed.push((name, Signed::Tx(rewrite::decode_tx(hex.trim())?)));
+            }
+            Some("psbt") => signed.push((name, Signed::Psbt(psbt::read(&path)?))),
+            _ => {}
+        }
+    }
+    merge_signed(node, entries, signed)
+}
+
+/// Merge signed transactions and PSBTs into the batch, returning how many inputs got signed.
+///
+/// Each is matched to the entry with the same txid (without scriptSigs).
+/// Signatures are only taken for inputs the PSBT completes (`finalizepsbt`);
+/// partial signatures of multisig inputs still missing cosigners are left out.
+fn merge_signed(
+    node: &Node,
+    entries: &mut [Entry],
+    signed: Vec<(String, Signed)>,
+) -> Result<usize> {
+    let mut txs = rewrite::parse_batch(entries)?;
+    let positions: HashMap<bitcoin::Txid, usize> = txs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, tx)| Some((psbt::unsigned_txid(tx.as_ref()?), i)))
+        .collect();
+
+    let mut count = 0;
+    for (name, signed) in signed {
+        let (txid, finalized) = match signed {
+            Signed::Tx(tx) => (psbt::unsigned_txid(&tx), psbt::from_tx(&tx, None)?),
+            Signed::Psbt(psbt) => {
+                if !psbt::has_signatures(&psbt) {
+                    eprintln!("  {}: no signatures, ignored", name);
+                    continue;
+                }
+                let finalized = finalize(node, &psbt)
+                    .with_context(|| format!("Failed to finalize {}", name))?;
+                (psbt.unsigned_tx.compute_txid(), finalized)
+            }
+        };
+        let Some(&i) = positions.get(&txid) else {
+            eprintln!("  {}: no transaction {} in the batch, ignored", name, txid);
+            continue;
+        };
+        let tx = txs[i].as_mut().expect("matched transactions are decoded");
+        let inputs = psbt::apply_final(tx, &finalized);
+        eprintln!(
+            "  {}: {} input(s) of transaction {} signed",
+            name,
+            inputs,
+            i + 1
+        );
+        if inputs > 0 {
+            entries[i].set_hex(rewrite::encode_tx(tx));
+            count += inputs;
+        }
+    }
+    Ok(count)
+}
+
+/// Report the outcome of a self-test stage, failing the self-test with it.
+fn stage<T>(name: &str, result: Result<T>) -> Result<T> {
+    match &result {
+        Ok(_) => eprintln!("  {}: pass", name),
+        Err(e) => eprintln!("  {}: FAIL - {:#}", name, e),
+    }
+    result.with_context(|| format!("Self-test failed at stage {}", name))
+}
+
+/// Take a dependent batch through signing, validation, broadcast and mining on regtest.
+///
+/// Everything happens in a fresh wallet, unloaded at the end.
+fn run_selftest(node: &Node) -> Result<()> {
+    if node.chain_type != ChainType::Bitcoin {
+        bail!("selftest is only supported on bitcoin");
+    }
+    eprintln!("Running self-test...");
+    stage("regtest", regtest::ensure_regtest(node))?;
+
+    let wallet_name = format!(
+        "sign-txs-selftest-{}",
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+    );
+    stage(
+        "wallet",
+        node.run_wallet(&["createwallet", &wallet_name])
+            .map(|_| eprintln!("  Created wallet {}", wallet_name)),
+    )?;
+    let wallet = node.with_wallet(&wallet_name);
+    let result = selftest_stages(&wallet);
+    if let Err(e) = node.run_wallet(&["unloadwallet", &wallet_name]) {
+        eprintln!(
+            "  Warning: could not unload wallet {}: {:#}",
+            wallet_name, e
+        );
+    }
+    result?;
+
+    eprintln!("\nSelf-test passed");
+    Ok(())
+}
+
+fn selftest_stages(wallet: &Node) -> Result<()> {
+    let new_address = || -> Result<bitcoin::Address> {
+        build::parse_address(
+            &wallet.run_wallet(&["getnewaddress"])?,
+            Some(Network::Regtest),
+        )
+    };
+
+    let funding = stage(
+        "fund",
+        (|| {
+            let mut amounts = serde_json::Map::new();
+            amounts.insert(new_address()?.to_string(), 1.0.into());
+            amounts.insert(new_address()?.to_string(), 0.1.into());
+            // Left unconfirmed, so the batch chains on mempool transactions too
+            regtest::fund(wallet, &amounts, 0)
+        })(),
+    )?;
+
+    let txs = stage(
+        "build",
+        (|| regtest::dependent_batch(&funding[0], &funding[1], [&new_address()?, &new_address()?]))(
+        ),
+    )?;
+    let batch_txs: Vec<Option<Transaction>> = txs.iter().cloned().map(Some).collect();
+    let anchors = Anchors::new(&batch_txs);
+
+    let signed = stage(
+        "sign",
+        txs.iter()
+            .enumerate()
+            .map(|(i, tx)| {
+                let hex = sign_transaction(
+                    wallet,
+                    &bitcoin::consensus::encode::serialize_hex(tx),
+                    i,
+                    false,
+                    &Signers::default(),
+                    &anchors,
+                )?;
+                let signed: Transaction = deserialize_hex(&hex)?;
+                let unsigned = signed.input.iter().any(|input| {
+                    let outpoint = input.previous_output;
+                    input.witness.is_empty()
+                        && anchors
+                            .get(&outpoint.txid.to_string(), outpoint.vout)
+                            .is_none()
+                });
+                if unsigned {
+                    bail!("transaction {} is not fully signed", i + 1);
+                }
+                Ok(hex)
+            })
+            .collect::<Result<Vec<String>>>(),
+    )?;
+
+    stage(
+        "validate",
+        (|| {
+            let hexes: Vec<&str> = signed.iter().map(String::as_str).collect();
+            for (i, acceptance) in mempool::test_accept(wallet, &hexes)?.iter().enumerate() {
+                if !acceptance.allowed {
+                    bail!(
+                        "transaction {} ({}) rejected: {}",
+                        i + 1,
+                        acceptance.txid,
+                        acceptance
+                            .reject_reason
+                            .as_deref()
+                            .or(acceptance.package_error.as_deref())
+                            .unwrap_or("unknown reason")
+                    );
+                }
+            }
+            Ok(())
+        })(),
+    )?;
+
+    let entries: Vec<Entry> = signed.into_iter().map(Entry::Hex).collect();
+    let txids = stage("broadcast", regtest::send(wallet, &entries))?;
+    stage("mine", regtest::mine(wallet, 1, None))?;
+    stage(
+        "confirm",
+        txids.iter().try_for_each(|txid| {
+            if regtest::confirmations(wallet, txid)? < 1 {
+                bail!("transaction {} is not confirmed", txid);
+            }
+            Ok(())
+        }),
+    )?;
+    Ok(())
+}
+
+/// Replace a signed transaction by its PayJoin version, keeping the original on failure.
+fn run_payjoin(node: &Node, signed_hex: String, params: &PayjoinParams) -> Result<String> {
+    eprintln!("  Performing PayJoin with {}...", params.endpoint);
+    let decoded = decode_transaction(node, &signed_hex)?;
+    let spent = spent_outputs(node, &decoded)?;
+    match payjoin::payjoin(node, &signed_hex, &spent, params) {
+        Ok(hex) => {
+            eprintln!("  PayJoin transaction signed");
+            Ok(hex)
+        }
+        Err(e) => {
+            eprintln!(
+                "  Warning: PayJoin failed, keeping the original transaction: {:#}",
+                e
+            );
+            Ok(signed_hex)
+        }
+    }
+}
+
+/// Summarize a broadcast, failing if any transaction was rejected.
+fn report_broadcast(report: &broadcast::Report, queue: &Path) -> Result<()> {
+    eprintln!(
+        "{} sent, {} queued, {} rejected",
+        report.accepted.len(),
+        report.queued.len(),
+        report.rejected.len()
+    );
+    if !report.queued.is_empty() {
+        eprintln!(
+            "Queued transactions are in {}; run `sign-txs flush-queue` to retry them",
+            queue.display()
+        );
+    }
+    if !report.rejected.is_empty() {
+        bail!(
+            "{} transaction(s) rejected by the node",
+            report.rejected.len()
+        );
+    }
+    Ok(())
+}
+
+/// Summarize the calls made to each backend, writing them next to the
+/// statistics with `--stats-out`.
+fn report_backends(node: &Node, stats_out: Option<&Path>) -> Result<()> {
+    let backends = node.metrics.backends();
+    if backends.is_empty() {
+        return Ok(());
+    }
+    eprintln!("\nBackend calls:");
+    for backend in &backends {
+        eprintln!(
+            "  {}: {} call(s), {} error(s) ({:.1}%), mean {} ms, p95 {} ms, max {} ms, {} ms in all",
+            backend.backend,
+            backend.calls,
+            backend.errors,
+            backend.error_rate * 100.0,
+            backend.mean_ms,
+            backend.p95_ms,
+            backend.max_ms,
+            backend.total_ms
+        );
+    }
+    if let Some(path) = stats_out {
+        let path = stats::backends_path(path);
+        stats::write_backends(&path, &backends)?;
+        eprintln!("Backend metrics written to {}", path.display());
+    }
+    Ok(())
+}
+
+/// The bitcoin transactions of a batch, with their positions from 1.
+fn batch_hexes(entries: &[Entry]) -> Vec<(usize, &str)> {
+    entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, entry)| entry.hex().map(|hex| (i + 1, hex)))
+        .collect()
+}
+
+/// Print what testmempoolaccept made of each transaction, returning how many it rejected.
+fn report_validation(validations: &[mempool::Validation]) -> usize {
+    for validation in validations {
+        let outcome = match (&validation.reason, validation.fee, validation.fee_rate) {
+            (_, Some(fee), Some(fee_rate)) if validation.allowed => {
+                let effective = validation
+                    .effective_fee_rate
+                    .filter(|effective| (effective - fee_rate).abs() >= 0.005)
+                    .map_or(String::new(), |effective| {
+                        format!(", {:.2} sat/vB with its package", effective)
+                    });
+                format!(
+                    "accepted, fee {} sat, {:.2} sat/vB{}",
+                    fee, fee_rate, effective
+                )
+            }
+            _ if validation.allowed => "accepted".to_string(),
+            (Some(reason), _, _) => format!("rejected: {}", reason),
+            (None, _, _) => "rejected".to_string(),
+        };
+        eprintln!(
+            "  Transaction {} ({}): {}",
+            validation.index, validation.txid, outcome
+        );
+    }
+    validations
+        .iter()
+        .filter(|validation| !validation.allowed)
+        .count()
+}
+
+/// Read input from a file, or from stdin if none is given, along with where it came from.
+fn read_input(path: Option<&str>) -> Result<(String, &str)> {
+    match path {
+        Some(path) => {
+            let content = std::fs::read_to_string(path).context("Failed to read input file")?;
+            Ok((content, path))
+        }
+        None => {
+            let mut content = String::new();
+            io::stdin()
+                .read_to_string(&mut content)
+                .context("Failed to read from stdin")?;
+            Ok((content, "stdin"))
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if let Some(path) = &args.log_file {
+        let max_age = args
+            .log_max_age
+            .map(|hours| Duration::from_secs(hours * 3_600));
+        log::init(path, args.log_max_size, max_age, args.log_keep)?;
+    }
+
+    let result = run(args);
+    if let Err(e) = &result {
+        log::write(format_args!("Error: {:?}", e));
+    }
+    result
+}
+
+fn run(args: Args) -> Result<()> {
+    let chain = match (&args.chain, &args.signet_challenge) {
+        (Some(chain), Some(_)) if chain != "signet" => {
+            bail!("--signet-challenge requires --chain signet")
+        }
+        (None, Some(_)) => Some("signet".to_string()),
+        (chain, _) => chain.clone(),
+    };
+
+    let mut cli_args = Vec::new();
+    if let Some(chain) = &chain {
+        cli_args.push(format!("-chain={}", chain));
+    }
+    if let Some(challenge) = &args.signet_challenge {
+        cli_args.push(format!("-signetchallenge={}", challenge));
+    }
+    if let Some(timeout) = args.rpc_client_timeout {
+        cli_args.push(format!("-rpcclienttimeout={}", timeout));
+    }
+    if let Some(host) = &args.rpc_connect {
+        cli_args.push(format!("-rpcconnect={}", host));
+    }
+    if let Some(port) = args.rpc_port {
+        cli_args.push(format!("-rpcport={}", port));
+    }
+    cli_args.extend(args.cli_args.iter().cloned());
+
+    for path in &args.allow_exec {
+        sandbox::check_allowed(path)?;
+    }
+    let sandbox = Sandbox {
+        allowed: (!args.allow_exec.is_empty()).then(|| args.allow_exec.clone()),
+        clear_env: args.clear_env,
+        keep_env: sandbox::DEFAULT_KEEP_ENV
+            .iter()
+            .map(|name| name.to_string())
+            .chain(args.keep_env.iter().cloned())
+            .collect(),
+        user: args
+            .run_as
+            .as_deref()
+            .map(sandbox::parse_user)
+            .transpose()?,
+    };
+
+    if args.electrum.is_some() && args.chain_type != ChainType::Bitcoin {
+        bail!("--electrum is only supported on bitcoin");
+    }
+
+    let canonical = args.canonical_output;
+    let node = Node {
+        chain_type: args.chain_type,
+        container: args.bitcoind_container.clone(),
+        runtime: args.container_runtime,
+        docker_api: args
+            .docker_api
+            .then(docker_api::DockerApi::from_env)
+            .transpose()?,
+        ssh: args.ssh_host.clone().map(|host| node::Ssh {
+            host,
+            key: args.ssh_key.clone(),
+        }),
+        k8s: args.k8s_pod.clone().map(|pod| node::K8s {
+            pod,
+            namespace: args.k8s_namespace.clone(),
+            container: args.k8s_container.clone(),
+        }),
+        cli_args,
+        rpc: args.rpc_url.as_deref().map(|url| {
+            rpc::Rpc::new(
+                url,
+                args.rpc_user.as_deref(),
+                args.rpc_password.as_deref(),
+                args.rpc_client_timeout,
+            )
+        }),
+        esplora: match (&args.esplora_url, &args.mempool_space_url) {
+            (Some(url), _) => Some(esplora::Esplora::new(url)),
+            (None, Some(url)) => Some(esplora::Esplora::mempool_space(url)),
+            (None, None) => None,
+        },
+        electrum: args
+            .electrum
+            .as_deref()
+            .map(|address| {
+                electrum::Electrum::new(address, args.electrum_tls, args.electrum_cert.as_deref())
+            })
+            .transpose()?
+            .map(Arc::new),
+        rest: args.rest_url.as_deref().map(rest::Rest::new),
+        cassette: match (&args.replay, &args.record) {
+            (Some(dir), _) => Some(Cassette::Replay(dir.clone())),
+            (None, Some(dir)) => Some(Cassette::Record(dir.clone())),
+            (None, None) => None,
+        },
+        chaos: args
+            .chaos
+            .as_deref()
+            .map(Chaos::parse)
+            .transpose()?
+            .map(Arc::new),
+        sandbox,
+        session: args.docker_session.then(Default::default),
+        check_decode: args.check_decode,
+        address_infos: Default::default(),
+        transactions: Default::default(),
+        scan_utxo_set: args.scan_utxo_set,
+        scanned: Default::default(),
+        metrics: Default::default(),
+    };
+    let node = match &args.wallet {
+        Some(wallet) => node.with_wallet(wallet),
+        None => node,
+    };
+
+    if let Some(rpc) = node.rpc.as_ref().filter(|_| node.cassette.is_none()) {
+        let elapsed = rpc.warm_up()?;
+        eprintln!("Connected to the RPC server in {} ms", elapsed.as_millis());
+    }
+
+    if let Some(chain) = &chain {
+        node.check_network(chain, args.signet_challenge.as_deref())?;
+        eprintln!("Backend is on the expected network: {}", chain);
+    }
+
+    let network = chain
+        .as_deref()
+        .and_then(|chain| Network::from_core_arg(chain).ok());
+    match &args.command {
+        Some(Command::Build { spec_file, utxos }) => {
+            let (content, source) = read_input(spec_file.as_deref())?;
+            let spec: build::BuildSpec =
+                serde_json::from_str(&content).context("Failed to parse build spec")?;
+            eprintln!("Reading build spec from: {}", source);
+            let utxos = utxos.as_deref().map(coinselect::load_utxos).transpose()?;
+            let batch = build::build(&node, spec, utxos, network)?;
+            eprintln!("\nAll transactions built. Output:\n");
+            print_json(&batch, canonical)?;
+            return Ok(());
+        }
+        Some(Command::Sweep {
+            to,
+            fee_rate,
+            min_conf,
+            from_addresses,
+            min_amount,
+            utxos,
+        }) => {
+            let destination = build::parse_address(to, network)?;
+            let mut utxos = match utxos {
+                Some(path) => {
+                    let scripts = from_addresses
+                        .iter()
+                        .map(|address| Ok(build::parse_address(address, network)?.script_pubkey()))
+                        .collect::<Result<Vec<_>>>()?;
+                    let mut utxos = coinselect::load_utxos(path)?;
+                    utxos.retain(|utxo| {
+                        scripts.is_empty() || scripts.contains(&utxo.txout.script_pubkey)
+                    });
+                    utxos
+                }
+                None => coinselect::list_unspent(&node, *min_conf, from_addresses)?,
+            };
+            if let Some(min_amount) = min_amount {
+                let min_amount = Amount::from_btc(*min_amount)?;
+                utxos.retain(|utxo| utxo.txout.value >= min_amount);
+            }
+
+            eprintln!("\nBuilding sweep transaction...");
+            let hex = build::sweep(&utxos, &destination, *fee_rate, true)
+                .context("Failed to build sweep transaction")?;
+            let signed_hex = sign_transaction(
+                &node,
+                &hex,
+                0,
+                false,
+                &Signers::default(),
+                &Anchors::new(&[]),
+            )?;
+            let batch = Batch::List(vec![Entry::Tx(TxEntry {
+                bitcoin: Some(signed_hex),
+                extra: Default::default(),
+            })]);
+            eprintln!("\nSweep transaction signed. Output:\n");
+            print_json(&batch, canonical)?;
+            return Ok(());
+        }
+        Some(Command::Faucet {
+            amounts_file,
+            confirmations,
+        }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("faucet is only supported on bitcoin");
+            }
+            regtest::ensure_regtest(&node)?;
+            let (content, source) = read_input(amounts_file.as_deref())?;
+            let amounts: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(&content).context("Failed to parse faucet amounts")?;
+            eprintln!("Reading faucet amounts from: {}", source);
+            eprintln!("\nFunding {} address(es)...", amounts.len());
+            let funding = regtest::fund(&node, &amounts, *confirmations)?;
+            eprintln!("\nAll addresses funded. Output:\n");
+            print_json(&funding, canonical)?;
+            return Ok(());
+        }
+        Some(Command::ExportPsbt {
+            input_file,
+            out_dir,
+            no_wallet,
+            coldcard,
+        }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("PSBT export is only supported on bitcoin");
+            }
+            let (content, source) = read_input(input_file.as_deref())?;
+            let batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+            let paths = export_psbts(&node, batch.entries(), out_dir, !no_wallet, *coldcard)?;
+            eprintln!("\nWrote {} PSBT file(s):", paths.len());
+            for path in paths {
+                eprintln!("  {}", path.display());
+            }
+            return Ok(());
+        }
+        Some(Command::ImportPsbt { input_file, from }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("PSBT import is only supported on bitcoin");
+            }
+            let (content, source) = read_input(input_file.as_deref())?;
+            let mut batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+            let signed = import_signed(&node, batch.entries_mut(), from)?;
+            eprintln!(
+                "\nSigned {} input(s) from {}. Output:\n",
+                signed,
+                from.display()
+            );
+            print_json(&batch, canonical)?;
+            return Ok(());
+        }
+        Some(Command::NostrSend {
+            input_file,
+            nostr,
+            no_wallet,
+        }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("Nostr coordination is only supported on bitcoin");
+            }
+            let (nostr, cosigners) = nostr.connect()?;
+            let (content, source) = read_input(input_file.as_deref())?;
+            let batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+            eprintln!("\nSending PSBTs as {}...", nostr.public_key);
+            for (i, psbt) in batch_psbts(&node, batch.entries(), !no_wallet)? {
+                let complete = psbt.inputs.iter().all(|input| {
+                    input.final_script_witness.is_some() || input.final_script_sig.is_some()
+                });
+                if complete {
+                    eprintln!("  Transaction {}: already signed, not sent", i + 1);
+                    continue;
+                }
+                for cosigner in &cosigners {
+                    let id = nostr.send_dm(cosigner, &psbt.to_string())?;
+                    eprintln!("  Transaction {} to {}: event {}", i + 1, cosigner, id);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::NostrCollect {
+            input_file,
+            nostr,
+            since,
+        }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("Nostr coordination is only supported on bitcoin");
+            }
+            let (nostr, cosigners) = nostr.connect()?;
+            let (content, source) = read_input(input_file.as_deref())?;
+            let mut batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+
+            let since = match since {
+                Some(since) => *since,
+                None => SystemTime::now()
+                    .duration_since(UNIX_EPOCH)?
+                    .as_secs()
+                    .saturating_sub(7 * 86_400),
+            };
+            eprintln!("\nCollecting messages to {}...", nostr.public_key);
+            let signed = nostr
+                .receive_dms(&cosigners, since)?
+                .into_iter()
+                .filter_map(
+                    |(sender, message)| match bitcoin::Psbt::from_str(message.trim()) {
+                        Ok(psbt) => Some((format!("message from {}", sender), Signed::Psbt(psbt))),
+                        Err(_) => {
+                            eprintln!("  Message from {} is not a PSBT, ignored", sender);
+                            None
+                        }
+                    },
+                )
+                .collect();
+            let count = merge_signed(&node, batch.entries_mut(), signed)?;
+            eprintln!("\nSigned {} input(s) from Nostr messages. Output:\n", count);
+            print_json(&batch, canonical)?;
+            return Ok(());
+        }
+        Some(Command::FlushQueue { queue }) => {
+            eprintln!("Retrying transactions queued in {}...", queue.display());
+            let (sent, kept, dropped) = broadcast::flush(&node, queue)?;
+            eprintln!(
+                "\n{} sent, {} still queued, {} dropped",
+                sent, kept, dropped
+            );
+            return Ok(());
+        }
+        Some(Command::Simulate { input_file }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("Simulation is only supported on bitcoin");
+            }
+            let (content, source) = read_input(input_file.as_deref())?;
+            let batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+            eprintln!("Simulating {} transaction(s)...", batch.entries().len());
+            let simulation = simulate::run(&node, &rewrite::parse_batch(batch.entries())?)?;
+            for verdict in &simulation.transactions {
+                match &verdict.reason {
+                    None => eprintln!(
+                        "  Transaction {}: accepted as #{}, {} sat at {:.2} sat/vB",
+                        verdict.index,
+                        verdict.position.unwrap_or_default(),
+                        verdict.fee.unwrap_or_default(),
+                        verdict.fee_rate.unwrap_or_default()
+                    ),
+                    Some(reason) => {
+                        eprintln!("  Transaction {}: rejected, {}", verdict.index, reason)
+                    }
+                }
+                for warning in &verdict.warnings {
+                    eprintln!("    Warning: {}", warning);
+                }
+            }
+            print_json(&simulation, canonical)?;
+            let rejected = simulation
+                .transactions
+                .iter()
+                .filter(|verdict| !verdict.accepted)
+                .count();
+            if rejected > 0 {
+                bail!("{} transaction(s) would be rejected", rejected);
+            }
+            if !simulation.in_order {
+                bail!(
+                    "The batch would only be accepted reordered as {:?}, some transactions come before their parents",
+                    simulation.order
+                );
+            }
+            eprintln!("\nAll transactions would be accepted, in batch order");
+            return Ok(());
+        }
+        Some(Command::Compare { old_file, new_file }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("Comparing outputs is only supported on bitcoin");
+            }
+            let read = |path: &str| -> Result<Vec<Option<Transaction>>> {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path))?;
+                let batch: Batch = serde_json::from_str(&content)
+                    .with_context(|| format!("Failed to parse {}", path))?;
+                rewrite::parse_batch(batch.entries())
+            };
+            let fees = |txs: &[Option<Transaction>]| -> Vec<Option<Amount>> {
+                tx_fees(&node, txs)
+                    .into_iter()
+                    .map(|fee| fee.map(|(fee, _)| fee))
+                    .collect()
+            };
+            let old_txs = read(old_file)?;
+            let new_txs = read(new_file)?;
+            let (old_fees, new_fees) = (fees(&old_txs), fees(&new_txs));
+            let comparison = compare::compare(
+                &compare::Side::list(&old_txs, &old_fees),
+                &compare::Side::list(&new_txs, &new_fees),
+            );
+            for difference in &comparison.transactions {
+                let position = match (difference.old_index, difference.new_index) {
+                    (Some(old), Some(new)) if old != new => {
+                        format!("Transaction {} (was {})", new, old)
+                    }
+                    (_, Some(new)) => format!("Transaction {}", new),
+                    (Some(old), None) => format!("Previous transaction {}", old),
+                    (None, None) => continue,
+                };
+                let mut deltas = Vec::new();
+                if let Some(delta) = difference.fee_delta.filter(|&delta| delta != 0) {
+                    deltas.push(format!("fee {:+} sat", delta));
+                }
+                if let Some(delta) = difference.vsize_delta.filter(|&delta| delta != 0) {
+                    deltas.push(format!("vsize {:+} vB", delta));
+                }
+                let txids = match (&difference.old_txid, &difference.new_txid) {
+                    (Some(old), Some(new)) if old != new => format!("{} -> {}", old, new),
+                    (Some(txid), _) | (None, Some(txid)) => txid.clone(),
+                    (None, None) => String::new(),
+                };
+                eprintln!(
+                    "  {}: {} {}{}",
+                    position,
+                    difference.status,
+                    txids,
+                    if deltas.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" ({})", deltas.join(", "))
+                    }
+                );
+            }
+            eprintln!(
+                "\n{} unchanged, {} re-signed, {} changed, {} added, {} removed",
+                comparison.unchanged,
+                comparison.resigned,
+                comparison.changed,
+                comparison.added,
+                comparison.removed
+            );
+            print_json(&comparison, canonical)?;
+            return Ok(());
+        }
+        Some(Command::Decode { input_file }) => {
+            let (content, source) = read_input(input_file.as_deref())?;
+            let batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+            let mut decoded = Vec::new();
+            for (i, entry) in batch.entries().iter().enumerate() {
+                let Some(hex) = entry.hex() else {
+                    decoded.push(Value::Null);
+                    continue;
+                };
+                let output = node
+                    .run(&["decoderawtransaction", hex])
+                    .with_context(|| format!("Failed to decode transaction {}", i + 1))?;
+                decoded.push(
+                    serde_json::from_str(&output).context("Failed to parse decoded transaction")?,
+                );
+            }
+            eprintln!("Decoded {} transaction(s)", decoded.len());
+            print_json(&decoded, canonical)?;
+            return Ok(());
+        }
+        Some(Command::Verify {
+            input_file,
+            validate,
+        }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("Verifying signatures is only supported on bitcoin");
+            }
+            let (content, source) = read_input(input_file.as_deref())?;
+            let batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+            eprintln!("Verifying {} transaction(s)...", batch.entries().len());
+            let txs = rewrite::parse_batch(batch.entries())?;
+            let outputs = batch_outputs(&txs);
+            let mut reports = Vec::new();
+            for (i, tx) in txs.iter().enumerate() {
+                let Some(tx) = tx else {
+                    continue;
+                };
+                let spent = resolve_spent(&node, &outputs, tx).with_context(|| {
+                    format!("Failed to resolve prevouts of transaction {}", i + 1)
+                })?;
+                let report = verify::report(i + 1, tx, &spent);
+                eprintln!("  Transaction {}: {}", i + 1, report.status);
+                for input in &report.inputs {
+                    eprintln!("    Input {}: {}", input.index, input.problem);
+                }
+                for input in &report.unverified {
+                    eprintln!(
+                        "    Input {}: taproot script path spend, not verified",
+                        input
+                    );
+                }
+                reports.push(report);
+            }
+            let mut rejected = 0;
+            if *validate {
+                eprintln!("\nValidating with testmempoolaccept...");
+                let validations = mempool::validate(&node, &batch_hexes(batch.entries()))?;
+                rejected = report_validation(&validations);
+                for validation in validations {
+                    if let Some(report) = reports
+                        .iter_mut()
+                        .find(|report| report.index == validation.index)
+                    {
+                        report.mempool = Some(validation);
+                    }
+                }
+            }
+            print_json(&reports, canonical)?;
+            let failing = reports
+                .iter()
+                .filter(|report| report.status != "complete")
+                .count();
+            if failing > 0 {
+                bail!("{} transaction(s) not completely signed", failing);
+            }
+            if rejected > 0 {
+                bail!("{} transaction(s) rejected by testmempoolaccept", rejected);
+            }
+            eprintln!("\nAll transactions are completely signed");
+            return Ok(());
+        }
+        Some(Command::Plan {
+            input_file,
+            signers,
+        }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("Signing plans are only supported on bitcoin");
+            }
+            let signers = plan::Signers::load(signers)?;
+            let (content, source) = read_input(input_file.as_deref())?;
+            let batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+            let txs = rewrite::parse_batch(batch.entries())?;
+            let outputs = batch_outputs(&txs);
+            let mut planned = Vec::new();
+            for (i, tx) in txs.iter().enumerate() {
+                let Some(tx) = tx else {
+                    continue;
+                };
+                let spent = resolve_spent(&node, &outputs, tx)
+                    .inspect_err(|e| {
+                        eprintln!("  Transaction {}: prevouts unknown: {:#}", i + 1, e)
+                    })
+                    .ok();
+                planned.push((i, tx, spent));
+            }
+            let plan = signers.plan(&planned)?;
+            for tx in &plan.transactions {
+                eprintln!("  Transaction {} ({}):", tx.index, tx.txid);
+                for input in &tx.inputs {
+                    match (&input.policy, input.signed) {
+                        (_, true) => eprintln!("    Input {}: already signed", input.input),
+                        (Some(policy), false) => {
+                            eprintln!("    Input {}: {}", input.input, policy)
+                        }
+                        (None, false) => {
+                            eprintln!("    Input {}: no descriptor matches", input.input)
+                        }
+                    }
+                }
+            }
+            for (signer, needed) in &plan.signers {
+                let positions: Vec<String> = needed.iter().map(usize::to_string).collect();
+                eprintln!("  {} signs transaction(s) {}", signer, positions.join(", "));
+            }
+            print_json(&plan, canonical)?;
+            if !plan.unmatched.is_empty() {
+                bail!(
+                    "{} unsigned input(s) spend outputs no descriptor matches: {}",
+                    plan.unmatched.len(),
+                    plan.unmatched.join(", ")
+                );
+            }
+            return Ok(());
+        }
+        Some(Command::PreviewSighashes {
+            input_file,
+            no_wallet,
+        }) => {
+            if node.chain_type != ChainType::Bitcoin {
+                bail!("Sighash preview is only supported on bitcoin");
+            }
+            let (content, source) = read_input(input_file.as_deref())?;
+            let batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+            let mut previews = Vec::new();
+            for (i, psbt) in batch_psbts(&node, batch.entries(), !no_wallet)? {
+                let preview = sighash::preview(i, &psbt);
+                eprintln!("  Transaction {} ({}):", i + 1, preview.txid);
+                for sighash in &preview.sighashes {
+                    eprintln!(
+                        "    Input {}: {} {} {}",
+                        sighash.input, sighash.path, sighash.sighash_type, sighash.digest
+                    );
+                }
+                for input in &preview.skipped {
+                    eprintln!("    Input {}: no digest, {}", input.input, input.reason);
+                }
+                previews.push(preview);
+            }
+            print_json(&previews, canonical)?;
+            return Ok(());
+        }
+        Some(Command::Broadcast { input_file, queue }) => {
+            let (content, source) = read_input(input_file.as_deref())?;
+            let batch: Batch =
+                serde_json::from_str(&content).context("Failed to parse input JSON")?;
+            eprintln!("Reading transactions from: {}", source);
+            eprintln!("Sending {} transaction(s)...", batch.entries().len());
+            let report = broadcast::package(&node, batch.entries(), queue)?;
+            print_json(&report, canonical)?;
+            return report_broadcast(&report, queue);
+        }
+        Some(Command::Selftest) => return run_selftest(&node),
+        Some(Command::Sign(_)) | None => {}
+    }
+
+    // Signing, with the options of `sign` or, without a subcommand, the top-level ones
+    let args = match args.command {
+        Some(Command::Sign(sign)) => *sign,
+        _ => args.sign,
+    };
+
+    let regtest_mine = match args.regtest_mine.as_slice() {
+        [] => None,
+        [blocks, address @ ..] => {
+            let blocks: u32 = blocks
+                .parse()
+                .context("Invalid block count for --regtest-mine")?;
+            regtest::ensure_regtest(&node)?;
+            Some((blocks, address.first().map(String::as_str)))
+        }
+    };
+
+    let window_policy = args
+        .window_policy
+        .as_deref()
+        .map(WindowPolicy::load)
+        .transpose()?;
+    if let Some(policy) = &window_policy {
+        policy.check(args.window_override.as_deref())?;
+    }
+
+    let approvers = args.approvers.as_deref().map(Approvers::load).transpose()?;
+
+    let signer_policy = args
+        .signer_policy
+        .as_deref()
+        .map(SignerPolicy::load)
+        .transpose()?;
+
+    let recovery = args
+        .import_descriptors
+        .as_deref()
+        .map(|path| Recovery::load(path, args.rescan_from))
+        .transpose()?;
+
+    let taproot_keys = args
+        .taproot_keys
+        .as_deref()
+        .map(TaprootKeys::load)
+        .transpose()?;
+
+    let kms = match node.chain_type {
+        _ if args.kms_key.is_empty() => None,
+        ChainType::Bitcoin => {
+            eprintln!("Fetching AWS KMS public keys...");
+            Some(Kms::load(&args.aws_cli, &args.kms_key)?)
+        }
+        ChainType::Elements => {
+            eprintln!("AWS KMS keys are not available for Elements transactions");
+            None
+        }
+    };
+
+    match (args.emit.contains(&Emit::Bin), &args.emit_dir) {
+        (true, None) => bail!("--emit bin needs --emit-dir for the files"),
+        (false, Some(_)) => bail!("--emit-dir is only used with --emit bin"),
+        _ => {}
+    }
+
+    let mut combined = None;
+    let (mut batch, source) = if !args.batch_files.is_empty() {
+        eprintln!(
+            "Reading {} batch files as one session...",
+            args.batch_files.len()
+        );
+        let (files, batch) = Combined::load(&args.batch_files, args.index_entries)?;
+        combined = Some(files);
+        (batch, "batch files")
+    } else if args.txs.is_empty() {
+        let (content, source) = read_input(args.input_file.as_deref())?;
+        let batch: Batch = serde_json::from_str(&content).context("Failed to parse input JSON")?;
+        (batch, source)
+    } else {
+        let entries = args
+            .txs
+            .iter()
+            .map(|hex| {
+                Entry::Tx(TxEntry {
+                    bitcoin: Some(hex.clone()),
+                    extra: Default::default(),
+                })
+            })
+            .collect();
+        (Batch::List(entries), "command line")
+    };
+
+    eprintln!("Reading transactions from: {}", source);
+    eprintln!("Found {} transaction(s) to process", batch.entries().len());
+
+    let mut not_before = window_policy.as_ref().and_then(|policy| policy.not_before);
+    let declared: Vec<(String, &Value)> = match &combined {
+        Some(files) => files
+            .fields("not_before")
+            .into_iter()
+            .map(|(path, value)| (path.display().to_string(), value))
+            .collect(),
+        None => batch
+            .field("not_before")
+            .map(|value| (source.to_string(), value))
+            .into_iter()
+            .collect(),
+    };
+    for (source, value) in declared {
+        let declared = NotBefore::parse(value).with_context(|| format!("In {}", source))?;
+        not_before = Some(not_before.unwrap_or_default().and(declared));
+    }
+    if let Some(not_before) = not_before {
+        match args.wait_not_before {
+            true => {
+                not_before.wait(&node)?;
+                // The wait may have outlasted the window signing started in
+                if let Some(policy) = &window_policy {
+                    policy.check(args.window_override.as_deref())?;
+                }
+            }
+            false => not_before.check(&node)?,
+        }
+    }
+
+    if args.index_entries {
+        for (i, entry) in batch.entries_mut().iter_mut().enumerate() {
+            if entry.field("index").is_none() {
+                entry.set_field("index", i.into());
+            }
+        }
+    }
+    // Identifiers the output must carry in the same order, so entries can't be mixed up
+    let input_ids: Vec<Value> = batch.ids().into_iter().cloned().collect();
+    // Those of a session only need to be unique within their file, as checked when read
+    let mut unique = HashSet::new();
+    match input_ids.iter().find(|id| !unique.insert(id.to_string())) {
+        Some(duplicate) if combined.is_none() => {
+            bail!("Duplicate entry identifier {} in the batch", duplicate)
+        }
+        _ => {}
+    }
+
+    // Entries naming a wallet are signed with it, on the same node
+    let mut wallets: HashMap<String, Node> = HashMap::new();
+    for (i, entry) in batch.entries().iter().enumerate() {
+        match (entry.field("wallet"), entry.wallet()) {
+            (Some(_), None) => bail!(
+                "Invalid wallet field in transaction {}, expected a wallet name",
+                i + 1
+            ),
+            (_, Some(wallet)) => {
+                wallets
+                    .entry(wallet.to_string())
+                    .or_insert_with(|| node.with_wallet(wallet));
+            }
+            (None, None) => {}
+        }
+    }
+
+    // Without its private keys, a wallet's inputs can only go to their signers as PSBTs
+    let uses_default = batch.entries().iter().any(|entry| entry.wallet().is_none());
+    let watch_only = match uses_default && !args.mirror && args.wallets.is_empty() {
+        true => !node
+            .private_keys_enabled()
+            .context("Failed to check whether the wallet can sign")?,
+        false => false,
+    };
+    if watch_only {
+        eprintln!("The wallet has private keys disabled (watch-only), so it can't sign");
+    }
+    let mut watch_only_wallets = HashSet::new();
+    for (name, wallet) in &wallets {
+        let enabled = wallet
+            .private_keys_enabled()
+            .with_context(|| format!("Failed to check whether wallet {} can sign", name))?;
+        if !enabled {
+            eprintln!(
+                "Wallet {} has private keys disabled (watch-only), so it can't sign",
+                name
+            );
+            watch_only_wallets.insert(name.clone());
+        }
+    }
+    let mut output_format = args.output_format;
+    if watch_only || !watch_only_wallets.is_empty() {
+        if node.chain_type == ChainType::Elements {
+            bail!("A wallet is watch-only, and PSBTs are not available for Elements transactions");
+        }
+        if args.broadcast || !args.regtest_mine.is_empty() {
+            bail!("A wallet is watch-only, the transactions can't be signed to be sent");
+        }
+        eprintln!("  Transactions missing signatures will be written as PSBTs for their signers");
+        output_format = OutputFormat::Psbt;
+    }
+
+    let signers = Signers {
+        wallet: None,
+        taproot_keys: taproot_keys.as_ref(),
+        recovery: recovery.as_ref(),
+        kms: kms.as_ref(),
+        signer_cmd: args.signer_cmd.as_deref(),
+        watch_only,
+    };
+
+    let psbts = unwrap_psbts(batch.entries_mut())?;
+
+    let utxos_before = match &args.utxo_delta {
+        Some(_) if node.chain_type == ChainType::Bitcoin => {
+            Some(accounting::Snapshot::take(&node).context("Failed to snapshot wallet UTXOs")?)
+        }
+        Some(_) => {
+            eprintln!("The UTXO delta is not available for Elements wallets");
+            None
+        }
+        None => None,
+    };
+
+    // The batch as read, and where each entry came from, to report fee changes at the end
+    let input_txs = match node.chain_type {
+        ChainType::Bitcoin => rewrite::parse_batch(batch.entries())?,
+        ChainType::Elements => Vec::new(),
+    };
+    let mut origin: Vec<Option<usize>> = (0..batch.entries().len()).map(Some).collect();
+    let mut rewritten = false;
+
+    if args.resign_all {
+        eprintln!("\nRemoving existing signatures to sign every input afresh...");
+        match node.chain_type {
+            ChainType::Bitcoin => {
+                let count = rewrite::strip_signatures(batch.entries_mut())?;
+                eprintln!("  Stripped {} transaction(s)", count);
+            }
+            ChainType::Elements => eprintln!("  Not available for Elements transactions"),
+        }
+    }
+
+    if args.resign_invalid {
+        eprintln!("\nVerifying existing signatures...");
+        match node.chain_type {
+            ChainType::Bitcoin => {
+                let count = strip_invalid_signatures(&node, batch.entries_mut())?;
+                eprintln!("  {} input(s) to sign again", count);
+            }
+            ChainType::Elements => eprintln!("  Not available for Elements transactions"),
+        }
+    }
+
+    if batch
+        .entries()
+        .iter()
+        .any(|entry| entry.field("template").is_some())
+    {
+        eprintln!("\nFunding template transactions with the wallet...");
+        let mut options = serde_json::Map::new();
+        if let Some(fee_rate) = args.fund_fee_rate {
+            options.insert("fee_rate".into(), fee_rate.into());
+        }
+        if let Some(change_type) = &args.change_type {
+            options.insert("change_type".into(), change_type.clone().into());
+        }
+        if args.lock_unspents {
+            options.insert("lockUnspents".into(), true.into());
+        }
+        let count = build::fund_templates(&node, batch.entries_mut(), &options)?;
+        eprintln!("  Funded {} transaction(s)", count);
+        rewritten |= count > 0;
+    }
+
+    if batch
+        .entries()
+        .iter()
+        .any(|entry| entry.field("sequences").is_some())
+    {
+        eprintln!("\nSetting input sequences of unsigned transactions...");
+        let count = rewrite::set_sequences(batch.entries_mut())?;
+        eprintln!("  Changed {} transaction(s)", count);
+        rewritten |= count > 0;
+    }
+
+    if args.tx_version.is_some()
+        || batch
+            .entries()
+            .iter()
+            .any(|entry| entry.field("version").is_some())
+    {
+        eprintln!("\nSetting the version of unsigned transactions...");
+        let count = rewrite::set_versions(batch.entries_mut(), args.tx_version)?;
+        eprintln!("  Changed {} transaction(s)", count);
+        rewritten |= count > 0;
+    }
+
+    if args.bip69 {
+        eprintln!("\nApplying BIP 69 ordering to unsigned transactions...");
+        let count = rewrite::rewrite_unsigned(batch.entries_mut(), |_, tx| Ok(rewrite::bip69(tx)))?;
+        eprintln!("  Reordered {} transaction(s)", count);
+        rewritten |= count > 0;
+    }
+
+    if args.shuffle_outputs {
+        eprintln!("\nShuffling outputs of unsigned transactions...");
+        let count = rewrite::rewrite_unsigned(batch.entries_mut(), |_, tx| {
+            Ok(rewrite::shuffle_outputs(tx))
+        })?;
+        eprintln!("  Shuffled {} transaction(s)", count);
+        rewritten |= count > 0;
+    }
+
+    if node.chain_type == ChainType::Bitcoin
+        && (args.anchor_fee_rate.is_some() || args.bump_fee_rate.is_some())
+    {
+        eprintln!("\nChecking the wallet can fund fee bumps and anchor children...");
+        preflight_funds(&node, &batch, args.anchor_fee_rate, args.bump_fee_rate)?;
+    }
+
+    if let Some(fee_rate) = args.anchor_fee_rate {
+        eprintln!("\nAdding children for unspent pay-to-anchor outputs...");
+        let inserted = add_anchor_children(&node, &mut batch, fee_rate)?;
+        eprintln!("  Added {} child transaction(s)", inserted.len());
+        for &i in &inserted {
+            origin.insert(i, None);
+        }
+        rewritten |= !inserted.is_empty();
+    }
+
+    if let (Some(fee_rate), Some(change_address)) = (args.retarget_feerate, &args.change_address) {
+        eprintln!(
+            "\nRetargeting unsigned transactions to {} sat/vB...",
+            fee_rate
+        );
+        let change = ChangeDetector::parse(change_address)?;
+        let count = retarget_fees(&node, batch.entries_mut(), fee_rate, &change)?;
+        eprintln!("  Retargeted {} transaction(s)", count);
+        rewritten |= count > 0;
+    }
+
+    // Process each transaction, replacing its hex in place
+    let mut skipped: BTreeMap<String, usize> = BTreeMap::new();
+    // Elements transactions can't be decoded locally, and have no anchors
+    let txs = match node.chain_type {
+        ChainType::Bitcoin => rewrite::parse_batch(batch.entries())?,
+        ChainType::Elements => Vec::new(),
+    };
+    let anchors = Anchors::new(&txs);
+
+    // Parents are signed before their children, whatever the batch order
+    let order = match node.chain_type {
+        ChainType::Bitcoin => rewrite::topological_order(&txs)?,
+        ChainType::Elements => (0..batch.entries().len()).collect(),
+    };
+    if order.windows(2).any(|pair| pair[0] > pair[1]) {
+        let positions: Vec<String> = order.iter().map(|i| (i + 1).to_string()).collect();
+        eprintln!(
+            "\nSigning in dependency order, parents first: {}",
+            positions.join(", ")
+        );
+    }
+
+    let ledger = match &args.ledger {
+        Some(path) if node.chain_type == ChainType::Bitcoin => {
+            let mut ledger = Ledger::load(path)?;
+            eprintln!(
+                "\nChecking the batch against the ledger {}...",
+                path.display()
+            );
+            let released = ledger.prune(&node)?;
+            if released > 0 {
+                eprintln!("  Released {} outpoint(s) spent since", released);
+            }
+            let mut conflicts = 0;
+            for (i, tx) in txs.iter().enumerate() {
+                let Some(tx) = tx else { continue };
+                for (outpoint, txid) in ledger.conflicts(tx) {
+                    eprintln!(
+                        "  Warning: transaction {} spends {}, already spent by signed transaction {}",
+                        i + 1,
+                        outpoint,
+                        txid
+                    );
+                    conflicts += 1;
+                }
+            }
+            if conflicts == 0 {
+                eprintln!("  No outpoint spent by an earlier batch");
+            } else if args.refuse_ledger_conflicts {
+                bail!(
+                    "{} input(s) spend outpoints of transactions signed earlier, refusing to double-spend",
+                    conflicts
+                );
+            }
+            Some((ledger, path))
+        }
+        Some(_) => {
+            eprintln!("\nThe ledger is not available for Elements transactions");
+            None
+        }
+        None => None,
+    };
+
+    if args.mirror
+        || args.manifest.is_some()
+        || args.expect_manifest.is_some()
+        || approvers.is_some()
+    {
+        if node.chain_type != ChainType::Bitcoin {
+            bail!("Manifests are only supported on bitcoin");
+        }
+        let outputs = batch_outputs(&txs);
+        let mut described = Vec::new();
+        for (i, tx) in txs.iter().enumerate() {
+            let Some(tx) = tx else { continue };
+            let spent = resolve_spent(&node, &outputs, tx)
+                .with_context(|| format!("Failed to resolve prevouts of transaction {}", i + 1))?;
+            described.push((i, tx, spent));
+        }
+        let manifest = Manifest::new(&described);
+        let hash = manifest.hash()?;
+        eprintln!(
+            "\nManifest of the {} transaction(s) to sign: {}",
+            described.len(),
+            hash
+        );
+        if let Some(path) = &args.manifest {
+            std::fs::write(path, manifest.to_json()?)
+                .with_context(|| format!("Failed to write manifest {}", path.display()))?;
+            eprintln!("  Written to {}", path.display());
+        }
+        if args.mirror {
+            print!("{}", manifest.to_json()?);
+            eprintln!("\nMirror run, nothing was signed");
+            return Ok(());
+        }
+        match &args.expect_manifest {
+            Some(expected) if !expected.eq_ignore_ascii_case(&hash) => bail!(
+                "The manifest of what is to be signed is {}, not the expected {}",
+                hash,
+                expected
+            ),
+            Some(_) => eprintln!("  Matches the expected manifest"),
+            None => {}
+        }
+        if let Some(approvers) = &approvers {
+            eprintln!("Checking manifest approvals...");
+            let approved = approvers.check(manifest.to_json()?.as_bytes(), &args.approval)?;
+            eprintln!("  Manifest approved by {}", approved.join(", "));
+        }
+    }
+
+    // Inputs spending outputs of other batch transactions find them here rather than on chain
+    let outputs = BatchOutputs::new(signers.wallet.unwrap_or(&node), &txs);
+    // With --wallets, the batch's inputs are signed by each wallet in turn
+    let cosigners = (!args.wallets.is_empty()).then(|| Wallets {
+        wallets: args
+            .wallets
+            .iter()
+            .map(|wallet| (wallet.clone(), node.with_wallet(wallet)))
+            .collect(),
+    });
+    let in_turn = cosigners.as_ref().map(|wallets| Routed {
+        prevouts: &outputs,
+        wallet: wallets,
+    });
+    let signers = Signers {
+        wallet: Some(match &in_turn {
+            Some(in_turn) => in_turn,
+            None => &outputs,
+        }),
+        ..signers
+    };
+
+    // A PayJoin changes the txid, so nothing in the batch may spend the transaction's outputs
+    let mut payjoins = HashMap::new();
+    for (i, entry) in batch.entries().iter().enumerate() {
+        let Some(field) = entry.field("payjoin") else {
+            continue;
+        };
+        let params = PayjoinParams::parse(field)
+            .with_context(|| format!("Invalid payjoin field in transaction {}", i + 1))?;
+        if let Some(j) = (0..txs.len()).find(|&j| rewrite::ancestors(&txs, j).contains(&i)) {
+            bail!(
+                "Transaction {} can't be paid with PayJoin: transaction {} spends its outputs",
+                i + 1,
+                j + 1
+            );
+        }
+        payjoins.insert(i, params);
+    }
+
+    // The canary goes out alone, ahead of the transactions it could spend from
+    let canary = args
+        .canary
+        .filter(|_| args.broadcast)
+        .and_then(|n| n.checked_sub(1));
+    let canary_parent = canary
+        .filter(|&i| i < txs.len())
+        .and_then(|i| rewrite::ancestors(&txs, i).into_iter().min());
+    if let (Some(i), Some(parent)) = (canary, canary_parent) {
+        bail!(
+            "Canary transaction {} spends outputs of transaction {}, which would only be sent after it",
+            i + 1,
+            parent + 1
+        );
+    }
+
+    let presigned = match args.wallet_batch_size {
+        0 | 1 => None,
+        _ if watch_only => None,
+        _ if !args.wallets.is_empty() => {
+            eprintln!("\n--wallet-batch-size is ignored with --wallets");
+            None
+        }
+        _ if args.use_bumpfee => {
+            eprintln!("\n--wallet-batch-size is ignored with --use-bumpfee");
+            None
+        }
+        batch_size => {
+            let raw_txs: Vec<&str> = batch
+                .entries()
+                .iter()
+                .filter(|entry| entry.wallet().is_none())
+                .filter_map(Entry::hex)
+                .filter(|hex| !psbt::is_base64(hex))
+                .collect();
+            eprintln!(
+                "\nSigning {} transaction(s) with the wallet, {} per call...",
+                raw_txs.len(),
+                batch_size
+            );
+            Some(Presigned::sign(
+                &node, &raw_txs, &signers, &anchors, batch_size,
+            )?)
+        }
+    };
+    let signers = Signers {
+        wallet: presigned
+            .as_ref()
+            .map(|presigned| presigned as &dyn Signer)
+            .or(signers.wallet),
+        ..signers
+    };
+
+    let mut concurrent = match (args.jobs, node.chain_type) {
+        (0 | 1, _) => HashMap::new(),
+        _ if args.use_bumpfee => {
+            eprintln!("\n--jobs is ignored with --use-bumpfee");
+            HashMap::new()
+        }
+        (_, ChainType::Elements) => {
+            eprintln!("\n--jobs is not available for Elements transactions");
+            HashMap::new()
+        }
+        (jobs, ChainType::Bitcoin) => {
+            eprintln!("\nSigning transactions {} at a time...", jobs);
+            let signed = sign_concurrently(
+                &node,
+                batch.entries(),
+                &txs,
+                &order,
+                &psbts,
+                &signers,
+                &anchors,
+                &outputs,
+                jobs,
+            );
+            eprintln!("  Signed {} transaction(s) ahead", signed.len());
+            signed
+        }
+    };
+
+    // PSBT entries as signed, with the partial signatures of their incomplete inputs
+    let mut signed_psbts = HashMap::new();
+
+    // What happened to each entry and how long it took, for --stats-out
+    let mut outcomes: Vec<(&'static str, Duration)> =
+        vec![("skipped", Duration::ZERO); batch.entries().len()];
+
+    for &i in &order {
+        let tx = &mut batch.entries_mut()[i];
+        let started = Instant::now();
+        if let Some(label) = tx.label() {
+            eprintln!("\nTransaction {} is labelled {}", i + 1, label);
+        }
+        let Some(raw_tx) = tx.hex() else {
+            eprintln!("\nSkipping transaction {} ({} entry)", i + 1, tx.chain());
+            *skipped.entry(tx.chain().to_string()).or_default() += 1;
+            continue;
+        };
+        let routed = tx.wallet().map(|wallet| {
+            eprintln!("\nTransaction {} goes to wallet {}", i + 1, wallet);
+            &wallets[wallet]
+        });
+        let entry_node = routed.unwrap_or(&node);
+        let route = routed.map(|wallet| Routed {
+            prevouts: signers.wallet.unwrap_or(&node),
+            wallet,
+        });
+        let entry_signers = match &route {
+            Some(route) => Signers {
+                wallet: Some(route),
+                watch_only: tx
+                    .wallet()
+                    .is_some_and(|wallet| watch_only_wallets.contains(wallet)),
+                ..signers
+            },
+            None => signers,
+        };
+        let wallet_txid = match node.chain_type {
+            ChainType::Bitcoin if args.use_bumpfee => {
+                bump::unconfirmed_wallet_txid(entry_node, raw_tx)?
+            }
+            _ => None,
+        };
+        if let Some(txid) = wallet_txid {
+            eprintln!(
+                "\nBumping the fee of wallet transaction {} ({})...",
+                i + 1,
+                txid
+            );
+            let replacement = bump::bump(entry_node, &txid, args.bump_fee_rate)
+                .with_context(|| format!("Failed to bump transaction {}", i + 1))?;
+            tx.set_hex(replacement);
+            outcomes[i] = ("bumped", started.elapsed());
+            continue;
+        }
+        // Elements transactions can't be decoded locally, and are never PSBTs
+        let psbt = if psbts.is_empty() {
+            None
+        } else {
+            psbts.get(&psbt::unsigned_txid(&rewrite::decode_tx(raw_tx)?))
+        };
+        let mut signed_hex = match psbt {
+            Some(psbt) => {
+                let signed = sign_psbt_entry(entry_node, psbt, i)?;
+                let hex = rewrite::encode_tx(&psbt::to_tx(&signed));
+                signed_psbts.insert(signed.unsigned_tx.compute_txid(), signed);
+                hex
+            }
+            // Diagnostics of transactions signed ahead are printed in the usual order
+            None => match concurrent.remove(&i) {
+                Some((lines, signed_hex)) => {
+                    for line in lines {
+                        eprintln!("{}", line);
+                    }
+                    signed_hex
+                }
+                None => sign_transaction(
+                    entry_node,
+                    raw_tx,
+                    i,
+                    tx.has_metadata(),
+                    &entry_signers,
+                    &anchors,
+                )?,
+            },
+        };
+        if let Some(params) = payjoins.get(&i) {
+            signed_hex = run_payjoin(entry_node, signed_hex, params)?;
+        }
+        // Also indexed by the signed txid, which differs for non-witness inputs;
+        // inputs of later entries spending the unsigned txid are left as they are
+        if node.chain_type == ChainType::Bitcoin {
+            outputs.add(&rewrite::decode_tx(&signed_hex)?);
+        }
+        tx.set_hex(signed_hex);
+        outcomes[i] = ("signed", started.elapsed());
+    }
+
+    if !skipped.is_empty() {
+        let summary: Vec<String> = skipped
+            .iter()
+            .map(|(chain, count)| format!("{} {}", count, chain))
+            .collect();
+        eprintln!(
+            "\nPassed through non-bitcoin entries: {}",
+            summary.join(", ")
+        );
+    }
+
+    if let Some(policy) = &signer_policy {
+        eprintln!("\nChecking signers against the signer policy...");
+        match node.chain_type {
+            ChainType::Bitcoin => {
+                let violations = check_signers(&node, policy, &txs, batch.entries())?;
+                if !violations.is_empty() {
+                    for violation in &violations {
+                        eprintln!("  {}", violation);
+                    }
+                    bail!(
+                        "{} signature(s) from keys the signer policy doesn't expect, is the right wallet loaded?",
+                        violations.len()
+                    );
+                }
+                eprintln!("  All new signatures are from expected keys");
+            }
+            ChainType::Elements => eprintln!("  Not available for Elements transactions"),
+        }
+    }
+
+    if let Some(path) = &args.stats_out {
+        let stats = batch_stats(&node, &txs, batch.entries(), &anchors, &outcomes)?;
+        stats::write(path, &stats)?;
+        eprintln!("\nStatistics written to {}", path.display());
+    }
+
+    if let Some(dir) = &args.archive_dir {
+        match node.chain_type {
+            ChainType::Bitcoin => {
+                let signed = rewrite::parse_batch(batch.entries())?;
+                let outputs = batch_outputs(&signed);
+                for (tx, entry) in signed.iter().zip(batch.entries()) {
+                    let Some(tx) = tx else {
+                        continue;
+                    };
+                    let spent = resolve_spent(&node, &outputs, tx).ok();
+                    archive::write(dir, tx, entry.label(), spent.as_deref(), args.archive_psbt)?;
+                }
+                eprintln!(
+                    "\nArchived {} transaction(s) in {}",
+                    signed.iter().flatten().count(),
+                    dir.display()
+                );
+            }
+            ChainType::Elements => {
+                eprintln!("\nArchiving is not available for Elements transactions")
+            }
+        }
+    }
+
+    if let Some(target) = args.package_feerate {
+        eprintln!("\nPackage feerates (target {} sat/vB):", target);
+        match node.chain_type {
+            ChainType::Bitcoin => report_package_fees(&node, batch.entries(), target)?,
+            ChainType::Elements => eprintln!("  Not available for Elements transactions"),
+        }
+    }
+
+    if (args.fee_report || rewritten) && node.chain_type == ChainType::Bitcoin {
+        eprintln!("\nFees before and after rewriting and signing:");
+        report_fee_changes(&node, &input_txs, batch.entries(), &origin)?;
+    }
+
+    if args.check_mempool_limits && node.chain_type == ChainType::Bitcoin {
+        eprintln!("\nChecking mempool chain limits...");
+        let violations = mempool::check_limits(&node, &rewrite::parse_batch(batch.entries())?)?;
+        if !violations.is_empty() {
+            for violation in &violations {
+                eprintln!("  {}", violation);
+            }
+            bail!(
+                "{} mempool chain limit(s) would be exceeded, transactions would be rejected",
+                violations.len()
+            );
+        }
+        eprintln!("  Within limits");
+    }
+
+    if (args.check_min_fee || args.refuse_below_min_fee) && node.chain_type == ChainType::Bitcoin {
+        let min_fee_rate = mempool::min_fee_rate(&node)?;
+        eprintln!(
+            "\nChecking feerates against the mempool minimum ({:.2} sat/vB)...",
+            min_fee_rate
+        );
+        let below = check_min_fee(&node, batch.entries(), min_fee_rate)?;
+        if below == 0 {
+            eprintln!("  All transactions pay at least the minimum");
+        } else if args.refuse_below_min_fee {
+            bail!(
+                "{} transaction(s) below the mempool minimum feerate would be rejected",
+                below
+            );
+        }
+    }
+
+    if args.validate {
+        eprintln!("\nValidating with testmempoolaccept...");
+        match node.chain_type {
+            ChainType::Bitcoin => {
+                let validations = mempool::validate(&node, &batch_hexes(batch.entries()))?;
+                let rejected = report_validation(&validations);
+                if rejected > 0 {
+                    bail!(
+                        "{} transaction(s) rejected by testmempoolaccept, refusing to output the batch",
+                        rejected
+                    );
+                }
+            }
+            ChainType::Elements => eprintln!("  Not available for Elements transactions"),
+        }
+    }
+
+    let packages = anchor::packages(&txs);
+    if !packages.is_empty() {
+        eprintln!("\nTransactions to submit together as packages (submitpackage):");
+        for (parent, children) in packages {
+            if children.is_empty() {
+                eprintln!(
+                    "  Warning: transaction {} has a pay-to-anchor output no transaction in the batch spends",
+                    parent + 1
+                );
+                continue;
+            }
+            let children: Vec<String> = children.iter().map(|i| (i + 1).to_string()).collect();
+            eprintln!(
+                "  transaction {} with anchor child(ren) {}",
+                parent + 1,
+                children.join(", ")
+            );
+        }
+    }
+
+    if batch.ids().into_iter().ne(&input_ids) {
+        bail!("Entry identifiers changed while processing the batch, refusing to output it");
+    }
+
+    if let (Some(path), Some(address)) = (&args.attest, &args.attest_address) {
+        eprintln!("\nAttesting the batch with {}...", address);
+        match node.chain_type {
+            ChainType::Bitcoin => {
+                let signed = rewrite::parse_batch(batch.entries())?;
+                let txs: Vec<&Transaction> = signed.iter().flatten().collect();
+                let attestation = attest::attest(&node, &txs, address)?;
+                attest::write(path, &attestation)?;
+                eprintln!(
+                    "  Signed manifest {} of {} transaction(s), written to {}",
+                    attestation.manifest_hash,
+                    txs.len(),
+                    path.display()
+                );
+            }
+            ChainType::Elements => eprintln!("  Not available for Elements transactions"),
+        }
+    }
+
+    if output_format == OutputFormat::Psbt {
+        eprintln!("\nWriting incomplete transactions as PSBTs...");
+        match node.chain_type {
+            ChainType::Bitcoin => {
+                let count = output_psbts(&node, &wallets, batch.entries_mut(), &signed_psbts)?;
+                eprintln!("  {} transaction(s) written as PSBTs", count);
+            }
+            ChainType::Elements => eprintln!("  Not available for Elements transactions"),
+        }
+    }
+
+    if args.emit.contains(&Emit::Base64) {
+        let count = emit::add_base64(batch.entries_mut());
+        eprintln!("\nAdded {} transaction(s) in base64", count);
+    }
+    if let Some(dir) = args
+        .emit_dir
+        .as_ref()
+        .filter(|_| args.emit.contains(&Emit::Bin))
+    {
+        match node.chain_type {
+            ChainType::Bitcoin => {
+                let paths = emit::write_bin(dir, batch.entries())?;
+                eprintln!(
+                    "\nWrote {} binary transaction(s) to {}",
+                    paths.len(),
+                    dir.display()
+                );
+            }
+            ChainType::Elements => {
+                eprintln!("\nBinary files are not available for Elements transactions")
+            }
+        }
+    }
+
+    // Reserved only once nothing can stop the batch from being output
+    if let Some((mut ledger, path)) = ledger {
+        let signed = rewrite::parse_batch(batch.entries())?;
+        for tx in signed.iter().flatten() {
+            ledger.reserve(tx)?;
+        }
+        ledger.save(path)?;
+        eprintln!(
+            "\nRecorded the inputs of {} transaction(s) in the ledger",
+            signed.iter().flatten().count()
+        );
+    }
+
+    match (combined, &args.session_dir) {
+        (Some(files), Some(dir)) => {
+            eprintln!("\nAll transactions processed. Writing the session's batches...");
+            let written = files.write(&batch, &origin, dir, canonical)?;
+            for file in &written {
+                eprintln!(
+                    "  {}: {} transaction(s) to {}",
+                    file.input.display(),
+                    file.transactions,
+                    file.output.display()
+                );
+            }
+            eprintln!("\nSession record:\n");
+            print_json(&written, canonical)?;
+        }
+        _ => {
+            eprintln!("\nAll transactions processed. Output:\n");
+
+            // Output signed transactions in the same shape as the input
+            print_json(&batch, canonical)?;
+        }
+    }
+
+    // Transactions already sent, by position, with their txid
+    let mut sent = Vec::new();
+    if let Some(canary) = args.canary.filter(|_| args.broadcast) {
+        let hex = canary
+            .checked_sub(1)
+            .and_then(|i| batch.entries().get(i))
+            .and_then(Entry::hex)
+            .with_context(|| format!("No bitcoin transaction {} to send as canary", canary))?;
+        eprintln!("\nSending canary transaction {}...", canary);
+        let txid = match broadcast::send(&node, hex) {
+            broadcast::Outcome::Sent(txid) => txid,
+            broadcast::Outcome::Retry(error) | broadcast::Outcome::Failed(error) => bail!(
+                "Canary transaction {} rejected, not sending the rest: {}",
+                canary,
+                error
+            ),
+        };
+        eprintln!("  Sent {}, waiting...", txid);
+        broadcast::wait(
+            &node,
+            &txid,
+            args.canary_confirmations,
+            Duration::from_secs(10),
+            Duration::from_secs(args.canary_timeout),
+        )
+        .with_context(|| format!("Canary transaction {} failed, not sending the rest", canary))?;
+        sent.push((canary - 1, txid));
+    }
+
+    if args.broadcast {
+        eprintln!("\nSending transactions...");
+        let report = broadcast::batch(&node, batch.entries(), &args.queue, &sent)?;
+        report_broadcast(&report, &args.queue)?;
+    }
+
+    if let Some((blocks, address)) = regtest_mine {
+        eprintln!("\nSending transactions...");
+        for txid in regtest::send(&node, batch.entries())? {
+            eprintln!("  {}", txid);
+        }
+        let hashes = regtest::mine(&node, blocks, address)?;
+        eprintln!(
+            "Mined {} block(s), tip {}",
+            hashes.len(),
+            hashes.last().map_or("unchanged", String::as_str)
+        );
+    }
+
+    if let (Some(before), Some(path)) = (utxos_before, &args.utxo_delta) {
+        let after = accounting::Snapshot::take(&node).context("Failed to snapshot wallet UTXOs")?;
+        let delta = before.delta(&after, &rewrite::parse_batch(batch.entries())?);
+        accounting::write(path, &delta)?;
+        eprintln!(
+            "\nWallet UTXOs: {} consumed ({} sat), {} created ({} sat), net {} sat; written to {}",
+            delta.consumed.len(),
+            delta.consumed_value,
+            delta.created.len(),
+            delta.created_value,
+            delta.net_value,
+            path.display()
+        );
+    }
 
-    // Output signed transactions
-    println!("{}", serde_json::to_string_pretty(&signed_txs)?);
+    report_backends(&node, args.stats_out.as_deref())?;
 
     Ok(())
 }
@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use anyhow::{bail, Context, Result};
+use bitcoin::{Amount, Transaction, Txid};
+use serde::{Deserialize, Serialize};
+
+use crate::node::Node;
+use crate::rewrite;
+
+/// Most transactions `testmempoolaccept` takes at once (`MAX_PACKAGE_COUNT`).
+const MAX_PACKAGE_COUNT: usize = 25;
+
+/// Default mempool chain limits of Bitcoin Core (`-limitancestorcount` and friends).
+const ANCESTOR_COUNT_LIMIT: u64 = 25;
+const DESCENDANT_COUNT_LIMIT: u64 = 25;
+const ANCESTOR_SIZE_LIMIT: u64 = 101_000;
+const DESCENDANT_SIZE_LIMIT: u64 = 101_000;
+
+#[derive(Debug, Deserialize)]
+struct MempoolEntry {
+    ancestorcount: u64,
+    ancestorsize: u64,
+    descendantcount: u64,
+    descendantsize: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct MempoolInfo {
+    /// BTC/kvB
+    mempoolminfee: f64,
+}
+
+/// Feerate (sat/vB) below which the node's mempool currently turns transactions away.
+///
+/// This is the minimum relay feerate, or more while the mempool is full.
+pub fn min_fee_rate(node: &Node) -> Result<f64> {
+    let info: MempoolInfo = serde_json::from_str(&node.run(&["getmempoolinfo"])?)
+        .context("Failed to parse mempool info")?;
+    Ok(info.mempoolminfee * 100_000.0)
+}
+
+/// Result of `testmempoolaccept` for one transaction.
+#[derive(Debug, Deserialize)]
+pub struct Acceptance {
+    pub txid: String,
+    /// Absent when the package failed before getting to this transaction.
+    #[serde(default)]
+    pub allowed: bool,
+    #[serde(rename = "reject-reason")]
+    pub reject_reason: Option<String>,
+    #[serde(rename = "package-error")]
+    pub package_error: Option<String>,
+    pub vsize: Option<u64>,
+    /// Present for allowed transactions.
+    pub fees: Option<AcceptanceFees>,
+}
+
+/// Fees of a transaction `testmempoolaccept` allowed.
+#[derive(Debug, Deserialize)]
+pub struct AcceptanceFees {
+    /// BTC
+    pub base: f64,
+    /// BTC/kvB, of the transaction with the package transactions it was
+    /// evaluated with (Bitcoin Core 25 and later).
+    #[serde(rename = "effective-feerate")]
+    pub effective_feerate: Option<f64>,
+}
+
+/// What `testmempoolaccept` made of a batch transaction.
+#[derive(Debug, Serialize)]
+pub struct Validation {
+    /// Position in the batch, from 1.
+    pub index: usize,
+    pub txid: String,
+    pub allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Satoshis.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<u64>,
+    /// sat/vB, of the transaction alone.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_rate: Option<f64>,
+    /// sat/vB, with the parents or children it is evaluated with as a package.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_fee_rate: Option<f64>,
+}
+
+/// Run transactions through `testmempoolaccept` as a package, without broadcasting them.
+pub fn test_accept(node: &Node, hexes: &[&str]) -> Result<Vec<Acceptance>> {
+    let output = node.run(&["testmempoolaccept", &serde_json::to_string(hexes)?])?;
+    serde_json::from_str(&output).context("Failed to parse testmempoolaccept result")
+}
+
+/// Run the batch transactions (`(index, hex)`, index from 1) through
+/// `testmempoolaccept` as a package, reporting what the node would make of each.
+///
+/// The node takes at most 25 transactions at once, so larger batches are
+/// tested in groups of 25, in batch order; a transaction whose parent is in an
+/// earlier group is then rejected for missing inputs.
+pub fn validate(node: &Node, entries: &[(usize, &str)]) -> Result<Vec<Validation>> {
+    let mut validations = Vec::new();
+    for group in entries.chunks(MAX_PACKAGE_COUNT) {
+        let hexes: Vec<&str> = group.iter().map(|&(_, hex)| hex).collect();
+        let acceptances = test_accept(node, &hexes)?;
+        if acceptances.len() != group.len() {
+            bail!(
+                "testmempoolaccept returned {} result(s) for {} transaction(s)",
+                acceptances.len(),
+                group.len()
+            );
+        }
+        for (&(index, _), acceptance) in group.iter().zip(acceptances) {
+            let fee = acceptance
+                .fees
+                .as_ref()
+                .map(|fees| Amount::from_btc(fees.base))
+                .transpose()
+                .context("Invalid fee in testmempoolaccept result")?
+                .map(Amount::to_sat);
+            validations.push(Validation {
+                index,
+                txid: acceptance.txid,
+                allowed: acceptance.allowed,
+                reason: acceptance.reject_reason.or(acceptance.package_error),
+                fee,
+                fee_rate: fee
+                    .zip(acceptance.vsize)
+                    .map(|(fee, vsize)| fee as f64 / vsize as f64),
+                effective_fee_rate: acceptance
+                    .fees
+                    .and_then(|fees| fees.effective_feerate)
+                    .map(|rate| rate * 100_000.0),
+            });
+        }
+    }
+    Ok(validations)
+}
+
+/// Check that the batch transactions fit within the mempool's chain limits.
+///
+/// Unconfirmed parents outside the batch are looked up with
+/// `getmempoolentry`; each batch transaction then counts them and their
+/// ancestors, plus its in-batch ancestors, against the ancestor limits, and
+/// each mempool parent counts the batch transactions descending from it
+/// against the descendant limits. Ancestors shared by several mempool parents
+/// are counted once per parent, so counts may be overestimated. Returns a
+/// description of every limit that would be exceeded.
+pub fn check_limits(node: &Node, txs: &[Option<Transaction>]) -> Result<Vec<String>> {
+    let batch_txids: HashSet<Txid> = txs.iter().flatten().map(|tx| tx.compute_txid()).collect();
+
+    // Mempool parents outside the batch (anything else is confirmed or unknown)
+    let mut entries: BTreeMap<Txid, MempoolEntry> = BTreeMap::new();
+    for tx in txs.iter().flatten() {
+        for input in &tx.input {
+            let txid = input.previous_output.txid;
+            if batch_txids.contains(&txid) || entries.contains_key(&txid) {
+                continue;
+            }
+            let Ok(output) = node.run(&["getmempoolentry", &txid.to_string()]) else {
+                continue;
+            };
+            let entry: MempoolEntry =
+                serde_json::from_str(&output).context("Failed to parse mempool entry")?;
+            entries.insert(txid, entry);
+        }
+    }
+
+    let mempool_parents = |i: usize| -> BTreeSet<Txid> {
+        txs[i]
+            .iter()
+            .flat_map(|tx| &tx.input)
+            .map(|input| input.previous_output.txid)
+            .filter(|txid| entries.contains_key(txid))
+            .collect()
+    };
+
+    let ancestor_sets: Vec<HashSet<usize>> =
+        (0..txs.len()).map(|i| rewrite::ancestors(txs, i)).collect();
+    let mut violations = Vec::new();
+    let mut descendants: BTreeMap<Txid, (u64, u64, Vec<usize>)> = BTreeMap::new();
+    for (i, tx) in txs.iter().enumerate() {
+        let Some(tx) = tx else {
+            continue;
+        };
+        let batch_ancestors = &ancestor_sets[i];
+
+        let batch_descendants: Vec<usize> = (0..txs.len())
+            .filter(|&j| ancestor_sets[j].contains(&i))
+            .collect();
+        if 1 + batch_descendants.len() as u64 > DESCENDANT_COUNT_LIMIT {
+            violations.push(format!(
+                "transaction {} would have {} descendants including itself in the batch (limit {})",
+                i + 1,
+                1 + batch_descendants.len(),
+                DESCENDANT_COUNT_LIMIT
+            ));
+        }
+
+        let mut parents = mempool_parents(i);
+        for &j in batch_ancestors {
+            parents.extend(mempool_parents(j));
+        }
+
+        let mut count = 1 + batch_ancestors.len() as u64;
+        let mut size = tx.vsize() as u64
+            + batch_ancestors
+                .iter()
+                .filter_map(|&j| txs[j].as_ref())
+                .map(|tx| tx.vsize() as u64)
+                .sum::<u64>();
+        for parent in &parents {
+            count += entries[parent].ancestorcount;
+            size += entries[parent].ancestorsize;
+            let (count, size, children) = descendants.entry(*parent).or_default();
+            *count += 1;
+            *size += tx.vsize() as u64;
+            children.push(i);
+        }
+
+        let through = || {
+            let parents: Vec<String> = parents.iter().map(Txid::to_string).collect();
+            if parents.is_empty() {
+                String::new()
+            } else {
+                format!(", through mempool parent(s) {}", parents.join(", "))
+            }
+        };
+        if count > ANCESTOR_COUNT_LIMIT {
+            violations.push(format!(
+                "transaction {} would have {} ancestors including itself (limit {}){}",
+                i + 1,
+                count,
+                ANCESTOR_COUNT_LIMIT,
+                through()
+            ));
+        }
+        if size > ANCESTOR_SIZE_LIMIT {
+            violations.push(format!(
+                "transaction {} would have {} vB of ancestors including itself (limit {}){}",
+                i + 1,
+                size,
+                ANCESTOR_SIZE_LIMIT,
+                through()
+            ));
+        }
+    }
+
+    for (parent, (added, added_size, children)) in descendants {
+        let entry = &entries[&parent];
+        let count = entry.descendantcount + added;
+        let size = entry.descendantsize + added_size;
+        let children: Vec<String> = children.iter().map(|i| (i + 1).to_string()).collect();
+        if count > DESCENDANT_COUNT_LIMIT {
+            violations.push(format!(
+                "mempool transaction {} would have {} descendants including itself (limit {}) with batch transaction(s) {}",
+                parent,
+                count,
+                DESCENDANT_COUNT_LIMIT,
+                children.join(", ")
+            ));
+        }
+        if size > DESCENDANT_SIZE_LIMIT {
+            violations.push(format!(
+                "mempool transaction {} would have {} vB of descendants including itself (limit {}) with batch transaction(s) {}",
+                parent,
+                size,
+                DESCENDANT_SIZE_LIMIT,
+                children.join(", ")
+            ));
+        }
+    }
+
+    Ok(violations)
+}
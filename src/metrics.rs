@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// Requests made to each backend during the run (the local CLI, a container,
+/// JSON-RPC, Esplora...), for the end-of-run summary and `--stats-out`.
+///
+/// A request is a round trip: several wallet calls batched into one
+/// (`--wallet-batch-size`) count once.
+#[derive(Debug, Default)]
+pub struct Metrics(Mutex<BTreeMap<String, Samples>>);
+
+#[derive(Debug, Default)]
+struct Samples {
+    durations: Vec<Duration>,
+    errors: u64,
+}
+
+/// What a backend was asked and how it fared.
+#[derive(Debug, Serialize)]
+pub struct BackendStats {
+    pub backend: String,
+    pub calls: usize,
+    pub errors: u64,
+    /// Share of calls that failed, from 0 to 1.
+    pub error_rate: f64,
+    /// Time spent waiting for the backend, over all calls.
+    pub total_ms: u128,
+    pub mean_ms: u128,
+    pub p50_ms: u128,
+    pub p95_ms: u128,
+    pub max_ms: u128,
+}
+
+impl Metrics {
+    /// Make a request to `backend` with `run`, timing it.
+    pub fn time<T>(&self, backend: &str, run: impl FnOnce() -> Result<T>) -> Result<T> {
+        let started = Instant::now();
+        let result = run();
+        let elapsed = started.elapsed();
+        let mut backends = self.0.lock().expect("not poisoned");
+        let samples = backends.entry(backend.to_string()).or_default();
+        samples.durations.push(elapsed);
+        samples.errors += u64::from(result.is_err());
+        result
+    }
+
+    /// Statistics of each backend called, by name.
+    pub fn backends(&self) -> Vec<BackendStats> {
+        let backends = self.0.lock().expect("not poisoned");
+        backends
+            .iter()
+            .map(|(backend, samples)| {
+                let mut durations: Vec<u128> =
+                    samples.durations.iter().map(Duration::as_millis).collect();
+                durations.sort_unstable();
+                let calls = durations.len();
+                let total_ms = durations.iter().sum();
+                // Nearest rank
+                let percentile = |p: usize| durations[(calls * p).div_ceil(100).max(1) - 1];
+                BackendStats {
+                    backend: backend.clone(),
+                    calls,
+                    errors: samples.errors,
+                    error_rate: samples.errors as f64 / calls as f64,
+                    total_ms,
+                    mean_ms: total_ms / calls as u128,
+                    p50_ms: percentile(50),
+                    p95_ms: percentile(95),
+                    max_ms: durations[calls - 1],
+                }
+            })
+            .collect()
+    }
+}
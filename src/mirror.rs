@@ -0,0 +1,101 @@
+use anyhow::Result;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{Amount, Transaction, TxOut};
+use serde::Serialize;
+
+use crate::canonical;
+
+/// What a run is about to sign, once the batch is rewritten and every
+/// prevout looked up: each bitcoin transaction, unsigned, with the outputs
+/// its inputs spend.
+///
+/// A second reviewer reproduces it with `--mirror`, which stops there, and
+/// compares its hash with the signer's (`--expect-manifest`).
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub transactions: Vec<TxManifest>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TxManifest {
+    /// Position in the batch, from 1.
+    pub index: usize,
+    pub txid: String,
+    pub inputs: Vec<InputManifest>,
+    pub outputs: Vec<OutputManifest>,
+    pub fee_sat: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InputManifest {
+    pub outpoint: String,
+    pub amount_sat: u64,
+    pub script_pubkey: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputManifest {
+    pub amount_sat: u64,
+    pub script_pubkey: String,
+}
+
+/// The manifest as printed by `--mirror` and written by `--manifest`.
+#[derive(Debug, Serialize)]
+struct Document<'a> {
+    manifest_hash: String,
+    #[serde(flatten)]
+    manifest: &'a Manifest,
+}
+
+impl Manifest {
+    /// The manifest of the bitcoin transactions of the batch, given with
+    /// their position and the outputs their inputs spend, in input order.
+    pub fn new(txs: &[(usize, &Transaction, Vec<TxOut>)]) -> Self {
+        let transactions = txs
+            .iter()
+            .map(|(i, tx, spent)| {
+                let inputs = tx
+                    .input
+                    .iter()
+                    .zip(spent)
+                    .map(|(input, spent)| InputManifest {
+                        outpoint: input.previous_output.to_string(),
+                        amount_sat: spent.value.to_sat(),
+                        script_pubkey: spent.script_pubkey.to_hex_string(),
+                    })
+                    .collect();
+                let outputs = tx
+                    .output
+                    .iter()
+                    .map(|output| OutputManifest {
+                        amount_sat: output.value.to_sat(),
+                        script_pubkey: output.script_pubkey.to_hex_string(),
+                    })
+                    .collect();
+                let spent: Amount = spent.iter().map(|output| output.value).sum();
+                let paid: Amount = tx.output.iter().map(|output| output.value).sum();
+                TxManifest {
+                    index: i + 1,
+                    txid: tx.compute_txid().to_string(),
+                    inputs,
+                    outputs,
+                    fee_sat: spent.to_sat().saturating_sub(paid.to_sat()),
+                }
+            })
+            .collect();
+        Manifest { transactions }
+    }
+
+    /// SHA-256 (hex) of the manifest in canonical JSON, the same on any machine.
+    pub fn hash(&self) -> Result<String> {
+        Ok(sha256::Hash::hash(canonical::to_string(self)?.as_bytes()).to_string())
+    }
+
+    /// The manifest with its hash, in canonical JSON.
+    pub fn to_json(&self) -> Result<String> {
+        canonical::to_string(&Document {
+            manifest_hash: self.hash()?,
+            manifest: self,
+        })
+    }
+}
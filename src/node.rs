@@ -0,0 +1,757 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use bitcoin::Script;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::cassette::{self, Cassette};
+use crate::chaos::Chaos;
+use crate::docker_api::DockerApi;
+use crate::electrum::Electrum;
+use crate::esplora::Esplora;
+use crate::metrics::Metrics;
+use crate::rest::Rest;
+use crate::rpc::Rpc;
+use crate::sandbox::Sandbox;
+use crate::session::{self, Session};
+
+/// Kind of node the transactions are signed against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChainType {
+    Bitcoin,
+    /// Elements-based chains such as Liquid
+    Elements,
+}
+
+impl ChainType {
+    /// Command-line RPC client for this chain.
+    pub fn cli(self) -> &'static str {
+        match self {
+            ChainType::Bitcoin => "bitcoin-cli",
+            ChainType::Elements => "elements-cli",
+        }
+    }
+}
+
+/// Container engine running `--bitcoind-container`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ContainerRuntime {
+    #[default]
+    Docker,
+    /// Podman, rootless or not, with its Docker-compatible `exec`
+    Podman,
+}
+
+impl ContainerRuntime {
+    /// The engine's command-line client.
+    pub fn program(self) -> &'static str {
+        match self {
+            ContainerRuntime::Docker => "docker",
+            ContainerRuntime::Podman => "podman",
+        }
+    }
+}
+
+/// A host running the wallet's node, its CLI run over SSH (`--ssh-host`).
+#[derive(Debug, Clone)]
+pub struct Ssh {
+    /// `user@host`, or a host configured in `~/.ssh/config`.
+    pub host: String,
+    /// Private key to authenticate with (`ssh -i`).
+    pub key: Option<PathBuf>,
+}
+
+impl Ssh {
+    /// `ssh` running `remote` (a shell command line) on the host, never prompting.
+    fn command(&self, sandbox: &Sandbox, remote: &str) -> Result<Command> {
+        let mut command = sandbox.command("ssh")?;
+        if let Some(key) = &self.key {
+            command.arg("-i").arg(key);
+        }
+        command.args(["-o", "BatchMode=yes", &self.host, remote]);
+        Ok(command)
+    }
+}
+
+/// A Kubernetes pod running the wallet's node, its CLI run with `kubectl exec` (`--k8s-pod`).
+#[derive(Debug, Clone)]
+pub struct K8s {
+    pub pod: String,
+    /// Namespace of the pod, the current context's if not given.
+    pub namespace: Option<String>,
+    /// Container in the pod running the node, the pod's default one if not given.
+    pub container: Option<String>,
+}
+
+impl K8s {
+    /// `kubectl exec` into the pod's container, to be followed by the command to run.
+    fn command(&self, sandbox: &Sandbox, stdin: bool) -> Result<Command> {
+        let mut command = sandbox.command("kubectl")?;
+        command.arg("exec");
+        if stdin {
+            command.arg("-i");
+        }
+        if let Some(namespace) = &self.namespace {
+            command.args(["-n", namespace]);
+        }
+        if let Some(container) = &self.container {
+            command.args(["-c", container]);
+        }
+        command.args([&self.pod, "--"]);
+        Ok(command)
+    }
+}
+
+/// How to reach the node: chain queries go through the JSON-RPC server if one
+/// is configured and the local CLI otherwise, while wallet calls go to the
+/// container, pod or SSH host if one is configured.
+pub struct Node {
+    pub chain_type: ChainType,
+    pub container: Option<String>,
+    /// Engine the container runs on (`--container-runtime`).
+    pub runtime: ContainerRuntime,
+    /// Engine API the container is reached with instead of the CLI (`--docker-api`).
+    pub docker_api: Option<DockerApi>,
+    /// Host the wallet calls are run on over SSH, when not in a container.
+    pub ssh: Option<Ssh>,
+    /// Pod the wallet calls are run in, when not in a container or on an SSH host.
+    pub k8s: Option<K8s>,
+    /// Options passed to every CLI invocation ahead of the RPC method (e.g. `-chain=signet`).
+    pub cli_args: Vec<String>,
+    /// JSON-RPC server called instead of the local CLI (`--rpc-url`).
+    pub rpc: Option<Rpc>,
+    /// Esplora instance prevouts are looked up with instead of the node
+    /// (`--esplora-url`, or `--mempool-space-url`).
+    pub esplora: Option<Esplora>,
+    /// Electrum server prevouts are looked up with instead of the node (`--electrum`).
+    pub electrum: Option<Arc<Electrum>>,
+    /// REST interface of the node prevouts are looked up with instead of RPC (`--rest-url`).
+    pub rest: Option<Rest>,
+    /// Recorded responses answering calls instead of the node.
+    pub cassette: Option<Cassette>,
+    /// Faults injected into calls, for testing (`--chaos`).
+    pub chaos: Option<Arc<Chaos>>,
+    /// Restrictions on the programs run to reach the node (`--allow-exec` and the like).
+    pub sandbox: Sandbox,
+    /// Shell in the container running every wallet call (`--docker-session`).
+    pub session: Option<Arc<Session>>,
+    /// Decode transactions with the node as well as locally, as a cross-check (`--check-decode`).
+    pub check_decode: bool,
+    /// The wallet's `getaddressinfo` by scriptPubKey (hex), `None` for scripts without an address.
+    pub address_infos: Mutex<HashMap<String, Option<String>>>,
+    /// Verbose `getrawtransaction` (JSON) by txid, for prevout lookups.
+    pub transactions: Mutex<HashMap<String, String>>,
+    /// Look prevouts nothing else finds up in the UTXO set (`--scan-utxo-set`).
+    pub scan_utxo_set: bool,
+    /// The wallet's unspent outputs found by `scantxoutset`, by outpoint,
+    /// once the UTXO set is scanned.
+    pub scanned: Mutex<Option<HashMap<String, String>>>,
+    /// Calls made to each backend, shared with the node's other wallets.
+    pub metrics: Arc<Metrics>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DecodedScript {
+    address: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProcessResult {
+    psbt: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FinalizeResult {
+    hex: Option<String>,
+    complete: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct WalletInfo {
+    #[serde(default = "enabled")]
+    private_keys_enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+struct ListDescriptors {
+    descriptors: Vec<WalletDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WalletDescriptor {
+    desc: String,
+    range: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScanResult {
+    success: bool,
+    unspents: Vec<ScannedOutput>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScannedOutput {
+    txid: String,
+    vout: u32,
+    #[serde(rename = "scriptPubKey")]
+    script_pubkey: String,
+    amount: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlockchainInfo {
+    chain: String,
+    signet_challenge: Option<String>,
+}
+
+impl Node {
+    /// A node whose chain and wallet are both behind the local CLI.
+    pub fn local(chain_type: ChainType) -> Node {
+        Node {
+            chain_type,
+            container: None,
+            runtime: ContainerRuntime::Docker,
+            docker_api: None,
+            ssh: None,
+            k8s: None,
+            cli_args: Vec::new(),
+            rpc: None,
+            esplora: None,
+            electrum: None,
+            rest: None,
+            cassette: None,
+            chaos: None,
+            sandbox: Sandbox::default(),
+            session: None,
+            check_decode: false,
+            address_infos: Mutex::default(),
+            transactions: Mutex::default(),
+            scan_utxo_set: false,
+            scanned: Mutex::default(),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// A node whose wallet is in a Docker container (see [`ContainerRuntime`] for Podman), chain queries still going to the local CLI.
+    pub fn docker(chain_type: ChainType, container: &str) -> Node {
+        Node {
+            container: Some(container.to_string()),
+            ..Node::local(chain_type)
+        }
+    }
+
+    /// The same node, with wallet calls going to the named wallet (`-rpcwallet`).
+    pub fn with_wallet(&self, wallet: &str) -> Node {
+        let mut cli_args = self.cli_args.clone();
+        cli_args.push(format!("-rpcwallet={}", wallet));
+        Node {
+            chain_type: self.chain_type,
+            container: self.container.clone(),
+            runtime: self.runtime,
+            docker_api: self.docker_api.clone(),
+            ssh: self.ssh.clone(),
+            k8s: self.k8s.clone(),
+            cli_args,
+            rpc: self.rpc.clone(),
+            esplora: self.esplora.clone(),
+            electrum: self.electrum.clone(),
+            rest: self.rest.clone(),
+            cassette: self.cassette.clone(),
+            chaos: self.chaos.clone(),
+            sandbox: self.sandbox.clone(),
+            session: self.session.clone(),
+            check_decode: self.check_decode,
+            address_infos: Mutex::default(),
+            transactions: Mutex::default(),
+            scan_utxo_set: self.scan_utxo_set,
+            scanned: Mutex::default(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Run a chain query with the local CLI.
+    pub fn run(&self, args: &[&str]) -> Result<String> {
+        self.call(args, || self.run_cli(args))
+    }
+
+    /// Run a wallet call, either via Docker, over SSH, in a pod or with the local CLI.
+    pub fn run_wallet(&self, args: &[&str]) -> Result<String> {
+        self.call(args, || match (&self.container, &self.ssh, &self.k8s) {
+            (Some(container), _, _) => self.run_docker(container, args),
+            (None, Some(ssh), _) => self.run_ssh(ssh, args),
+            (None, None, Some(k8s)) => self.run_k8s(k8s, args),
+            (None, None, None) => self.run_cli(args),
+        })
+    }
+
+    /// Run several wallet calls at once, returning their results in order.
+    ///
+    /// In a container they share one `docker exec`, over SSH one connection,
+    /// in a pod one `kubectl exec`, and over JSON-RPC one batch request,
+    /// saving the setup of each.
+    /// Recorded and replayed calls, calls chaos may fault, and calls to the
+    /// local CLI are still made one by one.
+    pub fn run_wallet_many(&self, calls: &[Vec<&str>]) -> Result<Vec<Result<String>>> {
+        match (&self.container, &self.ssh, &self.k8s, &self.rpc) {
+            _ if self.cassette.is_some() || self.chaos.is_some() => {
+                Ok(calls.iter().map(|args| self.run_wallet(args)).collect())
+            }
+            (Some(container), _, _, _) => self.run_docker_many(container, calls),
+            (None, Some(ssh), _, _) => {
+                self.run_script(ssh.command(&self.sandbox, "sh")?, "ssh", calls)
+            }
+            (None, None, Some(k8s), _) => {
+                let mut command = k8s.command(&self.sandbox, true)?;
+                command.arg("sh");
+                self.run_script(command, "kubectl exec", calls)
+            }
+            (None, None, None, Some(rpc)) => self
+                .metrics
+                .time("rpc", || rpc.call_many(self.rpc_wallet(), calls)),
+            (None, None, None, None) => Ok(calls.iter().map(|args| self.run_cli(args)).collect()),
+        }
+    }
+
+    /// Whether wallet calls go to the JSON-RPC server, rather than to a CLI
+    /// taking them on its command line.
+    pub fn wallet_over_rpc(&self) -> bool {
+        self.rpc.is_some() && self.container.is_none() && self.ssh.is_none() && self.k8s.is_none()
+    }
+
+    /// Make a call with `run`, or answer it from the cassette, recording it if
+    /// asked to, unless chaos injects a fault instead.
+    fn call(&self, args: &[&str], run: impl FnOnce() -> Result<String>) -> Result<String> {
+        match &self.chaos {
+            Some(chaos) => chaos.call(args, || self.answer(args, run)),
+            None => self.answer(args, run),
+        }
+    }
+
+    fn answer(&self, args: &[&str], run: impl FnOnce() -> Result<String>) -> Result<String> {
+        match &self.cassette {
+            Some(Cassette::Replay(dir)) => self.metrics.time("cassette", || {
+                cassette::replay(dir, self.rpc_wallet(), args)
+            }),
+            Some(Cassette::Record(dir)) => {
+                let result = run();
+                cassette::record(dir, self.rpc_wallet(), args, &result)?;
+                result
+            }
+            None => run(),
+        }
+    }
+
+    fn run_cli(&self, args: &[&str]) -> Result<String> {
+        if let Some(rpc) = &self.rpc {
+            return self
+                .metrics
+                .time("rpc", || rpc.call(self.rpc_wallet(), args));
+        }
+        let cli = self.chain_type.cli();
+        self.metrics.time(cli, || {
+            let output = self
+                .sandbox
+                .command(cli)?
+                .args(&self.cli_args)
+                .args(args)
+                .output()
+                .with_context(|| format!("Failed to execute {}", cli))?;
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                bail!("{} failed: {}", cli, stderr);
+            }
+
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+    }
+
+    /// Wallet calls go to (the JSON-RPC endpoint, and the key of recordings): the last `-rpcwallet` wins, as with the CLI.
+    fn rpc_wallet(&self) -> Option<&str> {
+        self.cli_args
+            .iter()
+            .rev()
+            .find_map(|arg| arg.strip_prefix("-rpcwallet="))
+    }
+
+    fn run_docker(&self, container: &str, args: &[&str]) -> Result<String> {
+        let cli = self.chain_type.cli();
+        let runtime = self.runtime.program();
+        let mut cmd_args = vec!["exec", container, cli];
+        cmd_args.extend(self.cli_args.iter().map(String::as_str));
+        cmd_args.extend(args);
+
+        if let Some(api) = &self.docker_api {
+            return self.metrics.time(&format!("{} api", runtime), || {
+                let output = api.exec(container, &cmd_args[2..])?;
+                if output.exit_code != 0 {
+                    bail!("{} exec {} failed: {}", runtime, cli, output.stderr);
+                }
+                Ok(output.stdout)
+            });
+        }
+
+        if let Some(session) = &self.session {
+            return self.metrics.time(&format!("{} session", runtime), || {
+                let (success, output) =
+                    session.run(&self.sandbox, self.runtime, container, &cmd_args[2..])?;
+                if !success {
+                    bail!("{} exec {} failed: {}", runtime, cli, output);
+                }
+                Ok(output)
+            });
+        }
+
+        self.metrics.time(&format!("{} exec", runtime), || {
+            let output = self
+                .sandbox
+                .command(runtime)?
+                .args(&cmd_args)
+                .output()
+                .with_context(|| format!("Failed to execute {}", runtime))?;
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                bail!("{} exec {} failed: {}", runtime, cli, stderr);
+            }
+
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+    }
+
+    /// Run a wallet call with the CLI on the SSH host.
+    fn run_ssh(&self, ssh: &Ssh, args: &[&str]) -> Result<String> {
+        let cli = self.chain_type.cli();
+        let command: Vec<&str> = std::iter::once(cli)
+            .chain(self.cli_args.iter().map(String::as_str))
+            .chain(args.iter().copied())
+            .collect();
+        // The host's shell splits the command line again, so each argument is quoted
+        let remote: Vec<String> = command.into_iter().map(session::shell_quote).collect();
+        self.metrics.time("ssh", || {
+            let output = ssh
+                .command(&self.sandbox, &remote.join(" "))?
+                .output()
+                .context("Failed to execute ssh")?;
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                bail!("ssh {} failed: {}", cli, stderr);
+            }
+
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+    }
+
+    /// Run a wallet call with the CLI in the pod.
+    fn run_k8s(&self, k8s: &K8s, args: &[&str]) -> Result<String> {
+        let cli = self.chain_type.cli();
+        self.metrics.time("kubectl exec", || {
+            let output = k8s
+                .command(&self.sandbox, false)?
+                .arg(cli)
+                .args(&self.cli_args)
+                .args(args)
+                .output()
+                .context("Failed to execute kubectl")?;
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                bail!("kubectl exec {} failed: {}", cli, stderr);
+            }
+
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+    }
+
+    fn run_docker_many(&self, container: &str, calls: &[Vec<&str>]) -> Result<Vec<Result<String>>> {
+        if self.session.is_some() || self.docker_api.is_some() {
+            return Ok(calls
+                .iter()
+                .map(|args| self.run_docker(container, args))
+                .collect());
+        }
+        let runtime = self.runtime.program();
+        let mut command = self.sandbox.command(runtime)?;
+        command.args(["exec", "-i", container, "sh"]);
+        self.run_script(command, &format!("{} exec", runtime), calls)
+    }
+
+    /// Run the calls with a shell script read from stdin by `shell` (`sh` in
+    /// the container or on the host, reached with `name`), so their arguments
+    /// don't add up to the command line limit.
+    fn run_script(
+        &self,
+        shell: Command,
+        name: &str,
+        calls: &[Vec<&str>],
+    ) -> Result<Vec<Result<String>>> {
+        self.metrics.time(name, || self.script(shell, name, calls))
+    }
+
+    fn script(
+        &self,
+        mut shell: Command,
+        name: &str,
+        calls: &[Vec<&str>],
+    ) -> Result<Vec<Result<String>>> {
+        let cli = self.chain_type.cli();
+        let mut script = String::new();
+        for args in calls {
+            let command: Vec<&str> = std::iter::once(cli)
+                .chain(self.cli_args.iter().map(String::as_str))
+                .chain(args.iter().copied())
+                .collect();
+            script.push_str(&session::script_line(&command));
+        }
+
+        let mut child = shell
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to execute {}", name))?;
+        // Written from another thread, as the calls' output fills the pipe while it is read
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let writer = std::thread::spawn(move || stdin.write_all(script.as_bytes()));
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("Failed to execute {}", name))?;
+        writer
+            .join()
+            .expect("writing to the shell doesn't panic")
+            .with_context(|| format!("Failed to write to {}", name))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            bail!("{} {} failed: {}", name, cli, stderr);
+        }
+
+        // Each call's output, then a line with the end marker and its exit status
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut results = Vec::new();
+        let mut rest = stdout.as_ref();
+        for _ in calls {
+            let Some((output, after)) = rest.split_once(&format!("\n{} ", session::CALL_END))
+            else {
+                bail!("{} {} ended early", name, cli);
+            };
+            let (status, after) = after.split_once('\n').unwrap_or((after, ""));
+            results.push(match status.trim() {
+                "0" => Ok(output.trim().to_string()),
+                _ => Err(anyhow::anyhow!(
+                    "{} {} failed: {}",
+                    name,
+                    cli,
+                    output.trim()
+                )),
+            });
+            rest = after;
+        }
+        Ok(results)
+    }
+
+    /// The wallet's `getaddressinfo` (JSON) for the address `script` pays to,
+    /// `None` if it has no address.
+    ///
+    /// Answers are kept for the run, as consolidations spend many outputs
+    /// paying to the same address.
+    pub fn address_info(&self, script: &Script) -> Result<Option<String>> {
+        let hex = script.to_hex_string();
+        if let Some(info) = self.address_infos.lock().expect("not poisoned").get(&hex) {
+            return Ok(info.clone());
+        }
+        let decoded: DecodedScript = serde_json::from_str(&self.run(&["decodescript", &hex])?)
+            .context("Failed to parse decoded script")?;
+        let info = match decoded.address {
+            Some(address) => Some(self.run_wallet(&["getaddressinfo", &address])?),
+            None => None,
+        };
+        self.address_infos
+            .lock()
+            .expect("not poisoned")
+            .insert(hex, info.clone());
+        Ok(info)
+    }
+
+    /// The transaction `txid` as verbose `getrawtransaction` gives it (JSON),
+    /// or its outputs in that form when looked up with Esplora or Electrum
+    /// (REST gives the former).
+    ///
+    /// Answers are kept for the run, as children spending several outputs of
+    /// the same parent would otherwise fetch it once for each.
+    pub fn raw_transaction(&self, txid: &str) -> Result<String> {
+        if let Some(info) = self.transactions.lock().expect("not poisoned").get(txid) {
+            return Ok(info.clone());
+        }
+        let args = ["getrawtransaction", txid, "true"];
+        let info = match (&self.esplora, &self.electrum, &self.rest) {
+            // Recorded as the node's answer would be, so either replays the other
+            (Some(esplora), _, _) => self.call(&args, || {
+                self.metrics.time("esplora", || esplora.transaction(txid))
+            })?,
+            (None, Some(electrum), _) => self.call(&args, || {
+                self.metrics.time("electrum", || electrum.transaction(txid))
+            })?,
+            (None, None, Some(rest)) => self.call(&args, || {
+                self.metrics.time("rest", || rest.transaction(txid))
+            })?,
+            (None, None, None) => self.run(&args)?,
+        };
+        self.transactions
+            .lock()
+            .expect("not poisoned")
+            .insert(txid.to_string(), info.clone());
+        Ok(info)
+    }
+
+    /// Whether the wallet holds private keys (`getwalletinfo`), watch-only
+    /// wallets having them disabled.
+    pub fn private_keys_enabled(&self) -> Result<bool> {
+        let info: WalletInfo = serde_json::from_str(&self.run_wallet(&["getwalletinfo"])?)
+            .context("Failed to parse wallet info")?;
+        Ok(info.private_keys_enabled)
+    }
+
+    /// The unspent output `txid:vout`, if it pays to one of the wallet's
+    /// descriptors, found by scanning the UTXO set (`scantxoutset`), with its
+    /// value and script in the form of verbose `getrawtransaction`.
+    ///
+    /// This works on a pruned node whose wallet doesn't know the transaction
+    /// either, but reads the whole UTXO set, taking minutes on mainnet: it is
+    /// scanned once, on the first lookup, for every descriptor at once.
+    /// Outputs created or spent in the mempool are as they were in the last block.
+    pub fn scanned_output(&self, txid: &str, vout: u32) -> Result<Option<String>> {
+        let mut scanned = self.scanned.lock().expect("not poisoned");
+        if scanned.is_none() {
+            *scanned = Some(self.scan_wallet_outputs()?);
+        }
+        Ok(scanned
+            .as_ref()
+            .and_then(|outputs| outputs.get(&format!("{}:{}", txid, vout)))
+            .cloned())
+    }
+
+    fn scan_wallet_outputs(&self) -> Result<HashMap<String, String>> {
+        let listed: ListDescriptors = serde_json::from_str(&self.run_wallet(&["listdescriptors"])?)
+            .context("Failed to parse listdescriptors result")?;
+        let descriptors: Vec<Value> = listed
+            .descriptors
+            .into_iter()
+            .map(|descriptor| match descriptor.range {
+                Some(range) => json!({ "desc": descriptor.desc, "range": range }),
+                None => json!(descriptor.desc),
+            })
+            .collect();
+        eprintln!(
+            "Scanning the UTXO set for the wallet's {} descriptor(s), this may take a while...",
+            descriptors.len()
+        );
+        let result: ScanResult = serde_json::from_str(&self.run(&[
+            "scantxoutset",
+            "start",
+            &Value::Array(descriptors).to_string(),
+        ])?)
+        .context("Failed to parse scantxoutset result")?;
+        if !result.success {
+            bail!("scantxoutset was aborted");
+        }
+        eprintln!("  Found {} unspent output(s)", result.unspents.len());
+        Ok(result
+            .unspents
+            .into_iter()
+            .map(|output| {
+                let entry = json!({
+                    "value": output.amount,
+                    "scriptPubKey": { "hex": output.script_pubkey },
+                });
+                (
+                    format!("{}:{}", output.txid, output.vout),
+                    entry.to_string(),
+                )
+            })
+            .collect())
+    }
+
+    /// Sign a PSBT's inputs with the wallet and finalize it into a transaction.
+    pub fn sign_psbt(&self, psbt: &str) -> Result<String> {
+        let processed: ProcessResult = serde_json::from_str(&self.run_wallet(&[
+            "walletprocesspsbt",
+            psbt,
+            "true",
+            "ALL",
+            "true",
+        ])?)
+        .context("Failed to parse walletprocesspsbt result")?;
+        let finalized: FinalizeResult =
+            serde_json::from_str(&self.run(&["finalizepsbt", &processed.psbt])?)
+                .context("Failed to parse finalizepsbt result")?;
+        match (finalized.hex, finalized.complete) {
+            (Some(hex), true) => Ok(hex),
+            _ => bail!("wallet could not complete the transaction"),
+        }
+    }
+
+    /// Check that both the chain and the wallet backend are on the expected network.
+    ///
+    /// For custom signets the challenge is compared as well, so a node running the
+    /// default signet (or another custom one) is not mistaken for ours.
+    pub fn check_network(&self, chain: &str, signet_challenge: Option<&str>) -> Result<()> {
+        let mut backends = Vec::new();
+        // With Esplora, Electrum or REST, the wallet may be the only RPC there is
+        let lookups = self.esplora.is_some() || self.electrum.is_some() || self.rest.is_some();
+        match &self.rest {
+            Some(rest) => backends.push((
+                "REST",
+                self.call(&["getblockchaininfo"], || {
+                    self.metrics.time("rest", || rest.chain_info())
+                })?,
+            )),
+            None if !lookups => backends.push(("chain", self.run(&["getblockchaininfo"])?)),
+            None => {}
+        }
+        if self.container.is_some() || self.ssh.is_some() || self.k8s.is_some() || lookups {
+            backends.push(("wallet", self.run_wallet(&["getblockchaininfo"])?));
+        }
+
+        for (backend, output) in backends {
+            let info: BlockchainInfo =
+                serde_json::from_str(&output).context("Failed to parse blockchain info")?;
+            if info.chain != chain {
+                bail!(
+                    "{} backend is on {}, expected {}",
+                    backend,
+                    info.chain,
+                    chain
+                );
+            }
+            let Some(expected) = signet_challenge else {
+                continue;
+            };
+            match &info.signet_challenge {
+                Some(actual) if actual.eq_ignore_ascii_case(expected) => {}
+                Some(actual) => bail!(
+                    "{} backend uses signet challenge {}, expected {}",
+                    backend,
+                    actual,
+                    expected
+                ),
+                None => eprintln!(
+                    "Warning: {} backend does not report its signet challenge, cannot verify it",
+                    backend
+                ),
+            }
+        }
+
+        Ok(())
+    }
+}
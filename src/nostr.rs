@@ -0,0 +1,133 @@
+use std::process::Command;
+
+use anyhow::{bail, ensure, Context, Result};
+use bitcoin::bech32;
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::secp256k1::{Keypair, Secp256k1};
+use serde::Deserialize;
+
+/// Kind of NIP-04 encrypted direct messages.
+const DM_KIND: &str = "4";
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    id: String,
+    pubkey: String,
+    content: String,
+}
+
+/// Sends and receives encrypted direct messages through `nak`, the Nostr command-line tool.
+pub struct Nostr {
+    /// `nak` command to run.
+    pub cmd: String,
+    /// Our secret key, in hex.
+    secret_key: String,
+    /// Our public key, in hex.
+    pub public_key: String,
+    pub relays: Vec<String>,
+}
+
+/// A key given in hex or in its NIP-19 bech32 form (`nsec1...`, `npub1...`), as 32 bytes.
+fn parse_key(key: &str, hrp: &str) -> Result<[u8; 32]> {
+    let bytes = if key.starts_with(hrp) {
+        let (found, bytes) = bech32::decode(key).with_context(|| format!("Invalid {} key", hrp))?;
+        ensure!(
+            found.as_str() == hrp,
+            "expected a {} key, got {}",
+            hrp,
+            found
+        );
+        bytes
+    } else {
+        Vec::from_hex(key).with_context(|| format!("Invalid {} key", hrp))?
+    };
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("{} key is not 32 bytes", hrp))
+}
+
+/// A public key given in hex or as an `npub`, in hex.
+pub fn parse_public_key(key: &str) -> Result<String> {
+    Ok(parse_key(key, "npub")?.to_lower_hex_string())
+}
+
+impl Nostr {
+    pub fn new(cmd: &str, secret_key: &str, relays: Vec<String>) -> Result<Self> {
+        let secret = parse_key(secret_key, "nsec")?;
+        let keypair = Keypair::from_seckey_slice(&Secp256k1::new(), &secret)
+            .context("Invalid Nostr secret key")?;
+        Ok(Nostr {
+            cmd: cmd.to_string(),
+            secret_key: secret.to_lower_hex_string(),
+            public_key: keypair.x_only_public_key().0.to_string(),
+            relays,
+        })
+    }
+
+    /// Run `nak`, the secret key passed in its environment rather than on the command line.
+    fn run(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new(&self.cmd)
+            .args(args)
+            .env("NOSTR_SECRET_KEY", &self.secret_key)
+            .output()
+            .with_context(|| format!("Failed to execute {}", self.cmd))?;
+        if !output.status.success() {
+            bail!(
+                "{} failed: {}",
+                self.cmd,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Publish `message` to the relays as a direct message to `to` (hex), returning the event id.
+    pub fn send_dm(&self, to: &str, message: &str) -> Result<String> {
+        let ciphertext = self.run(&["encrypt", "--nip04", "-p", to, message])?;
+        let mut args = vec!["event", "-k", DM_KIND, "-p", to, "-c", &ciphertext];
+        args.extend(self.relays.iter().map(String::as_str));
+        let output = self.run(&args)?;
+        let event: Event = output
+            .lines()
+            .find_map(|line| serde_json::from_str(line).ok())
+            .context("No event in nak output")?;
+        Ok(event.id)
+    }
+
+    /// Direct messages to us from any of `from` (hex) since `since` (Unix time), decrypted, with their sender.
+    ///
+    /// Messages that can't be decrypted are reported and skipped.
+    pub fn receive_dms(&self, from: &[String], since: u64) -> Result<Vec<(String, String)>> {
+        let since = since.to_string();
+        let mut args = vec![
+            "req",
+            "-k",
+            DM_KIND,
+            "-p",
+            &self.public_key,
+            "--since",
+            &since,
+        ];
+        for author in from {
+            args.extend(["-a", author]);
+        }
+        args.extend(self.relays.iter().map(String::as_str));
+
+        let mut messages = Vec::new();
+        let mut seen = Vec::new();
+        for line in self.run(&args)?.lines() {
+            let Ok(event) = serde_json::from_str::<Event>(line) else {
+                continue;
+            };
+            if seen.contains(&event.id) {
+                continue;
+            }
+            seen.push(event.id.clone());
+            match self.run(&["decrypt", "--nip04", "-p", &event.pubkey, &event.content]) {
+                Ok(message) => messages.push((event.pubkey, message)),
+                Err(e) => eprintln!("  Could not decrypt message {}: {:#}", event.id, e),
+            }
+        }
+        Ok(messages)
+    }
+}
@@ -0,0 +1,217 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::node::Node;
+
+/// How often a held batch checks its condition again.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When a batch may be signed at the earliest (`not_before`, in the batch
+/// or the signing window policy): once the chain has reached a block height,
+/// once a time has passed, or once both have.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NotBefore {
+    pub height: Option<u32>,
+    /// Unix time, in seconds.
+    pub time: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotBeforeEntry {
+    height: Option<u32>,
+    time: Option<Time>,
+}
+
+/// Unix time in seconds, or a UTC time as `YYYY-MM-DDTHH:MM:SSZ`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Time {
+    Unix(u64),
+    Utc(String),
+}
+
+impl NotBefore {
+    /// Parse a `not_before` object, e.g. `{"height": 900000}` or
+    /// `{"time": "2026-11-02T09:00:00Z"}`.
+    pub fn parse(value: &Value) -> Result<Self> {
+        let entry = NotBeforeEntry::deserialize(value)
+            .context("Invalid not_before, expected a height and/or a time")?;
+        if entry.height.is_none() && entry.time.is_none() {
+            bail!("Invalid not_before, expected a height and/or a time");
+        }
+        let time = match entry.time {
+            Some(Time::Unix(time)) => Some(time),
+            Some(Time::Utc(time)) => Some(parse_utc(&time)?),
+            None => None,
+        };
+        Ok(NotBefore {
+            height: entry.height,
+            time,
+        })
+    }
+
+    /// Both conditions at once: the later height and the later time.
+    pub fn and(self, other: NotBefore) -> NotBefore {
+        NotBefore {
+            height: self.height.max(other.height),
+            time: self.time.max(other.time),
+        }
+    }
+
+    /// The parts of the condition not met yet.
+    fn pending(&self, node: &Node) -> Result<Vec<String>> {
+        let mut pending = Vec::new();
+        if let Some(height) = self.height {
+            let count: u32 = node
+                .run(&["getblockcount"])?
+                .trim()
+                .parse()
+                .context("Failed to parse block count")?;
+            if count < height {
+                pending.push(format!("block {} (chain at {})", height, count));
+            }
+        }
+        if let Some(time) = self.time {
+            let now = now();
+            if now < time {
+                pending.push(format!("{} (now {})", format_utc(time), format_utc(now)));
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Fail unless the condition is met.
+    pub fn check(&self, node: &Node) -> Result<()> {
+        let pending = self.pending(node)?;
+        if !pending.is_empty() {
+            bail!(
+                "The batch may not be signed before {}",
+                pending.join(" and ")
+            );
+        }
+        eprintln!("Not-before condition met");
+        Ok(())
+    }
+
+    /// Wait until the condition is met, checking it every 30 seconds.
+    pub fn wait(&self, node: &Node) -> Result<()> {
+        let mut held = false;
+        loop {
+            let pending = self.pending(node)?;
+            if pending.is_empty() {
+                eprintln!("Not-before condition met");
+                return Ok(());
+            }
+            if !held {
+                eprintln!("Holding the batch until {}...", pending.join(" and "));
+                held = true;
+            }
+            // Wake up at the time if nothing else is awaited
+            let wait = match (self.height, self.time) {
+                (None, Some(time)) => {
+                    Duration::from_secs(time.saturating_sub(now())).min(POLL_INTERVAL)
+                }
+                _ => POLL_INTERVAL,
+            };
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("after the epoch")
+        .as_secs()
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date of a number of days since 1970-01-01, as `days_from_civil` takes it.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Unix time of a UTC time given as `YYYY-MM-DDTHH:MM:SSZ`.
+fn parse_utc(time: &str) -> Result<u64> {
+    let invalid = || {
+        format!(
+            "Invalid not_before time {}, expected YYYY-MM-DDTHH:MM:SSZ",
+            time
+        )
+    };
+    let (date, clock) = time
+        .strip_suffix('Z')
+        .and_then(|time| time.split_once('T'))
+        .with_context(invalid)?;
+    let date: Vec<i64> = date
+        .split('-')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()
+        .with_context(invalid)?;
+    let clock: Vec<i64> = clock
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()
+        .with_context(invalid)?;
+    let (&[year, month, day], &[hours, minutes, seconds]) = (&date[..], &clock[..]) else {
+        bail!(invalid());
+    };
+    if year < 1970
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || civil_from_days(days_from_civil(year, month, day)) != (year, month, day)
+        || hours >= 24
+        || minutes >= 60
+        || seconds >= 60
+    {
+        bail!(invalid());
+    }
+    Ok(
+        (days_from_civil(year, month, day) * 86_400 + hours * 3_600 + minutes * 60 + seconds)
+            as u64,
+    )
+}
+
+/// A Unix time as `YYYY-MM-DD HH:MM:SS UTC`.
+fn format_utc(time: u64) -> String {
+    let (year, month, day) = civil_from_days((time / 86_400) as i64);
+    let seconds = time % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        seconds % 3_600 / 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,382 @@
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context, Result};
+use bitcoin::consensus::encode::deserialize_hex;
+use bitcoin::hex::DisplayHex;
+use bitcoin::psbt::Psbt;
+use bitcoin::{Amount, Script, Transaction, TxOut, Witness};
+use serde::Deserialize;
+
+use crate::fees::fee;
+use crate::node::Node;
+use crate::witness;
+
+/// PayJoin parameters of a batch entry, from its `payjoin` field.
+#[derive(Debug, Deserialize)]
+pub struct PayjoinParams {
+    /// Receiver endpoint (the `pj=` parameter of its BIP 21 URI).
+    pub endpoint: String,
+    /// Index of our output the receiver may lower to pay for its additional input.
+    pub additional_fee_output_index: Option<usize>,
+    /// Most we contribute to the fee of the PayJoin transaction, in satoshis.
+    #[serde(default)]
+    pub max_additional_fee_contribution: u64,
+    /// Lowest feerate (sat/vB) we accept for the PayJoin transaction.
+    pub min_fee_rate: Option<f64>,
+}
+
+impl PayjoinParams {
+    /// The parameters in a `payjoin` field, with an endpoint BIP 78 allows:
+    /// HTTPS, or an onion address.
+    pub fn parse(field: &serde_json::Value) -> Result<Self> {
+        let params: PayjoinParams = serde_json::from_value(field.clone())?;
+        let url = ureq::post(&params.endpoint)
+            .request_url()
+            .with_context(|| format!("Invalid PayJoin endpoint {}", params.endpoint))?;
+        ensure!(
+            url.scheme() == "https" || url.host().ends_with(".onion"),
+            "PayJoin endpoint {} is neither https nor an onion address",
+            params.endpoint
+        );
+        Ok(params)
+    }
+}
+
+/// Perform the BIP 78 PayJoin handshake for a signed transaction.
+///
+/// The signed transaction is sent to the receiver as the Original PSBT; the
+/// proposal it returns is checked against the original (our inputs and
+/// outputs, what we pay in fees) before our inputs are signed again by the
+/// wallet. Output substitution is always disabled, so every original output
+/// must come back with the same script. `spent` holds the outputs spent by the
+/// original transaction's inputs, in order.
+pub fn payjoin(
+    node: &Node,
+    signed_hex: &str,
+    spent: &[TxOut],
+    params: &PayjoinParams,
+) -> Result<String> {
+    let original: Transaction =
+        deserialize_hex(signed_hex).context("Failed to parse transaction")?;
+    ensure!(
+        original
+            .input
+            .iter()
+            .all(|input| witness_has_no_annex(&input.witness)),
+        "inputs with an annex cannot be re-signed by the wallet"
+    );
+
+    let original_psbt = original_psbt(&original, spent)?;
+    let mut proposal = request_proposal(&original_psbt, params)?;
+
+    // Re-add what we know about our inputs and drop anything the receiver left there
+    for (psbt_input, txin) in proposal.inputs.iter_mut().zip(&proposal.unsigned_tx.input) {
+        if let Some(i) = original
+            .input
+            .iter()
+            .position(|ours| ours.previous_output == txin.previous_output)
+        {
+            *psbt_input = Default::default();
+            psbt_input.witness_utxo = Some(spent[i].clone());
+        }
+    }
+    check_proposal(&original, spent, &proposal, params)?;
+
+    let hex = node
+        .sign_psbt(&proposal.to_string())
+        .context("Failed to sign the PayJoin transaction")?;
+
+    if let Some(min_fee_rate) = params.min_fee_rate {
+        let tx: Transaction = deserialize_hex(&hex)?;
+        let fee = fee(&tx, &proposal_spent(&proposal)?)?;
+        let fee_rate = fee.to_sat() as f64 / tx.vsize() as f64;
+        ensure!(
+            fee_rate >= min_fee_rate,
+            "PayJoin feerate {:.2} sat/vB is below the minimum {}",
+            fee_rate,
+            min_fee_rate
+        );
+    }
+
+    Ok(hex)
+}
+
+fn witness_has_no_annex(witness: &Witness) -> bool {
+    let items: Vec<String> = witness
+        .iter()
+        .map(|item| item.to_lower_hex_string())
+        .collect();
+    witness::annex(&items).is_none()
+}
+
+/// The signed transaction as a finalized PSBT, as BIP 78 wants the original sent.
+fn original_psbt(original: &Transaction, spent: &[TxOut]) -> Result<Psbt> {
+    let mut unsigned = original.clone();
+    for input in &mut unsigned.input {
+        input.script_sig = Default::default();
+        input.witness = Default::default();
+    }
+    let mut psbt = Psbt::from_unsigned_tx(unsigned)?;
+    for ((psbt_input, txin), prevout) in psbt.inputs.iter_mut().zip(&original.input).zip(spent) {
+        psbt_input.witness_utxo = Some(prevout.clone());
+        if !txin.script_sig.is_empty() {
+            psbt_input.final_script_sig = Some(txin.script_sig.clone());
+        }
+        if !txin.witness.is_empty() {
+            psbt_input.final_script_witness = Some(txin.witness.clone());
+        }
+    }
+    Ok(psbt)
+}
+
+fn request_proposal(original: &Psbt, params: &PayjoinParams) -> Result<Psbt> {
+    let mut request = ureq::post(&params.endpoint)
+        .set("Content-Type", "text/plain")
+        .query("v", "1")
+        .query("disableoutputsubstitution", "true");
+    if let Some(index) = params.additional_fee_output_index {
+        request = request
+            .query("additionalfeeoutputindex", &index.to_string())
+            .query(
+                "maxadditionalfeecontribution",
+                &params.max_additional_fee_contribution.to_string(),
+            );
+    }
+    if let Some(min_fee_rate) = params.min_fee_rate {
+        request = request.query("minfeerate", &min_fee_rate.to_string());
+    }
+
+    let response = match request.send_string(&original.to_string()) {
+        Ok(response) => response.into_string()?,
+        Err(ureq::Error::Status(code, response)) => {
+            bail!(
+                "receiver rejected the original transaction ({}): {}",
+                code,
+                response.into_string().unwrap_or_default()
+            )
+        }
+        Err(e) => return Err(e).context("Failed to reach PayJoin receiver"),
+    };
+    Psbt::from_str(response.trim()).context("Failed to parse PayJoin proposal")
+}
+
+/// Outputs spent by a PSBT's inputs, from their UTXO information.
+fn proposal_spent(psbt: &Psbt) -> Result<Vec<TxOut>> {
+    psbt.inputs
+        .iter()
+        .zip(&psbt.unsigned_tx.input)
+        .map(|(input, txin)| {
+            input
+                .witness_utxo
+                .clone()
+                .or_else(|| {
+                    input
+                        .non_witness_utxo
+                        .as_ref()
+                        .and_then(|tx| tx.output.get(txin.previous_output.vout as usize).cloned())
+                })
+                .with_context(|| format!("no UTXO information for input {}", txin.previous_output))
+        })
+        .collect()
+}
+
+/// Sender checks of BIP 78 on a PayJoin proposal.
+fn check_proposal(
+    original: &Transaction,
+    spent: &[TxOut],
+    proposal: &Psbt,
+    params: &PayjoinParams,
+) -> Result<()> {
+    let tx = &proposal.unsigned_tx;
+    ensure!(
+        tx.version == original.version && tx.lock_time == original.lock_time,
+        "proposal changed the transaction version or locktime"
+    );
+
+    // Our inputs are all still there, unchanged; the receiver's are signed and alike
+    let sequence = original.input[0].sequence;
+    for ours in &original.input {
+        let theirs = tx
+            .input
+            .iter()
+            .find(|txin| txin.previous_output == ours.previous_output)
+            .with_context(|| format!("proposal dropped our input {}", ours.previous_output))?;
+        ensure!(
+            theirs.sequence == ours.sequence,
+            "proposal changed the sequence of our input {}",
+            ours.previous_output
+        );
+    }
+    let proposal_spent = proposal_spent(proposal)?;
+    for ((psbt_input, txin), prevout) in proposal.inputs.iter().zip(&tx.input).zip(&proposal_spent)
+    {
+        if original
+            .input
+            .iter()
+            .any(|ours| ours.previous_output == txin.previous_output)
+        {
+            continue;
+        }
+        ensure!(
+            psbt_input.final_script_witness.is_some() || psbt_input.final_script_sig.is_some(),
+            "receiver input {} is not finalized",
+            txin.previous_output
+        );
+        ensure!(
+            txin.sequence == sequence,
+            "receiver input {} uses a different sequence",
+            txin.previous_output
+        );
+        ensure!(
+            spent
+                .iter()
+                .any(|ours| same_script_type(&ours.script_pubkey, &prevout.script_pubkey)),
+            "receiver input {} has a different script type than ours",
+            txin.previous_output
+        );
+    }
+
+    // Every original output is kept, each by a proposal output of its own;
+    // those unchanged are matched first, to tell a lowered one from its twins
+    let mut remaining: Vec<Option<&TxOut>> = tx.output.iter().map(Some).collect();
+    let mut kept: Vec<Option<&TxOut>> = vec![None; original.output.len()];
+    for same_value in [true, false] {
+        for (ours, kept) in original.output.iter().zip(&mut kept) {
+            if kept.is_some() {
+                continue;
+            }
+            *kept = remaining
+                .iter_mut()
+                .find(|theirs| {
+                    theirs.is_some_and(|theirs| {
+                        theirs.script_pubkey == ours.script_pubkey
+                            && (!same_value || theirs.value == ours.value)
+                    })
+                })
+                .and_then(Option::take);
+        }
+    }
+
+    // Only the designated output may pay for fees
+    let mut contribution = Amount::ZERO;
+    for (i, (ours, theirs)) in original.output.iter().zip(kept).enumerate() {
+        let theirs = theirs.with_context(|| format!("proposal dropped output {}", i))?;
+        if theirs.value >= ours.value {
+            continue;
+        }
+        ensure!(
+            params.additional_fee_output_index == Some(i),
+            "proposal lowered output {}",
+            i
+        );
+        contribution = ours.value - theirs.value;
+    }
+    ensure!(
+        contribution.to_sat() <= params.max_additional_fee_contribution,
+        "proposal takes {} in additional fees, more than the allowed {} sat",
+        contribution,
+        params.max_additional_fee_contribution
+    );
+
+    // Whatever we contribute must go to fees, not to the receiver
+    let original_fee = fee(original, spent)?;
+    let proposal_fee = fee(tx, &proposal_spent)?;
+    ensure!(
+        proposal_fee >= original_fee + contribution,
+        "proposal pays {} in fees, less than the original {} plus our contribution {}",
+        proposal_fee,
+        original_fee,
+        contribution
+    );
+
+    eprintln!(
+        "  PayJoin proposal checked: {} receiver input(s), fee {} (ours {})",
+        tx.input.len() - original.input.len(),
+        proposal_fee,
+        contribution
+    );
+    Ok(())
+}
+
+fn same_script_type(a: &Script, b: &Script) -> bool {
+    (a.is_p2wpkh() && b.is_p2wpkh())
+        || (a.is_p2tr() && b.is_p2tr())
+        || (a.is_p2sh() && b.is_p2sh())
+        || (a.is_p2pkh() && b.is_p2pkh())
+        || (a.is_p2wsh() && b.is_p2wsh())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{absolute, transaction, OutPoint, ScriptBuf, TxIn, Txid, WPubkeyHash};
+
+    use super::*;
+
+    fn p2wpkh(byte: u8) -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([byte; 20]))
+    }
+
+    fn output(value: u64, script_pubkey: ScriptBuf) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey,
+        }
+    }
+
+    fn tx(output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                ..Default::default()
+            }],
+            output,
+        }
+    }
+
+    fn check(original: &[TxOut], proposal: &[TxOut], params: &PayjoinParams) -> Result<()> {
+        let spent = [output(10_000, p2wpkh(0))];
+        let original = tx(original.to_vec());
+        let mut proposal = Psbt::from_unsigned_tx(tx(proposal.to_vec())).unwrap();
+        proposal.inputs[0].witness_utxo = Some(spent[0].clone());
+        check_proposal(&original, &spent, &proposal, params)
+    }
+
+    fn params(additional_fee_output_index: Option<usize>) -> PayjoinParams {
+        PayjoinParams {
+            endpoint: "https://receiver.example/pj".to_string(),
+            additional_fee_output_index,
+            max_additional_fee_contribution: 500,
+            min_fee_rate: None,
+        }
+    }
+
+    #[test]
+    fn outputs_are_matched_one_to_one() {
+        let twins = [output(4_000, p2wpkh(1)), output(4_000, p2wpkh(1))];
+        assert!(check(&twins, &twins, &params(None)).is_ok());
+        assert!(check(&twins, &twins[..1], &params(None)).is_err());
+    }
+
+    #[test]
+    fn only_the_designated_twin_is_lowered() {
+        let original = [output(4_000, p2wpkh(1)), output(3_000, p2wpkh(1))];
+        let lowered = [output(4_000, p2wpkh(1)), output(2_800, p2wpkh(1))];
+        assert!(check(&original, &lowered, &params(Some(1))).is_ok());
+        assert!(check(&original, &lowered, &params(Some(0))).is_err());
+        let reordered = [output(2_800, p2wpkh(1)), output(4_000, p2wpkh(1))];
+        assert!(check(&original, &reordered, &params(Some(1))).is_ok());
+    }
+
+    #[test]
+    fn endpoint_must_be_https_or_onion() {
+        let parse =
+            |endpoint: &str| PayjoinParams::parse(&serde_json::json!({ "endpoint": endpoint }));
+        assert!(parse("https://receiver.example/pj").is_ok());
+        assert!(parse("http://receiverabcdef.onion/pj").is_ok());
+        assert!(parse("http://receiver.example/pj").is_err());
+        assert!(parse("http://receiver.onion.example/pj").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use bitcoin::bip32::Fingerprint;
+use bitcoin::hashes::{hash160, ripemd160, sha256};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{PublicKey, ScriptBuf, Transaction, TxOut};
+use miniscript::policy::Liftable;
+use miniscript::{Descriptor, DescriptorPublicKey, ForEachKey, Translator};
+use serde::{Deserialize, Serialize};
+
+/// Addresses derived from each ranged descriptor, as many as a wallet's default keypool.
+const DESCRIPTOR_RANGE: u32 = 1000;
+
+#[derive(Debug, Deserialize)]
+struct SignersFile {
+    descriptors: Vec<String>,
+    /// Master key fingerprint (hex) by signer name.
+    signers: BTreeMap<String, String>,
+}
+
+/// The batch's possible signers: the descriptors the inputs may spend, and
+/// who holds their keys, by master key fingerprint.
+pub struct Signers {
+    descriptors: Vec<Descriptor<DescriptorPublicKey>>,
+    names: HashMap<Fingerprint, String>,
+    /// The descriptor (position in the file) and derivation index each script comes from.
+    scripts: HashMap<ScriptBuf, (usize, u32)>,
+}
+
+/// Who can complete an input, and under what policy.
+#[derive(Debug, Serialize)]
+pub struct InputPlan {
+    pub input: usize,
+    pub outpoint: String,
+    /// Whether the input already has its scriptSig or witness.
+    pub signed: bool,
+    /// Position in the signers file of the descriptor the spent output pays to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descriptor: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub derivation_index: Option<u32>,
+    /// Signers holding a key of the descriptor, in its key order.
+    pub signers: Vec<String>,
+    /// Spending policy, keys named by their signer (or master fingerprint if unknown).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TxPlan {
+    /// Position in the batch, from 1.
+    pub index: usize,
+    pub txid: String,
+    pub inputs: Vec<InputPlan>,
+}
+
+/// The signing plan of a batch: who each input needs, and which transactions each signer is needed for.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub transactions: Vec<TxPlan>,
+    /// Positions (from 1) of the transactions with unsigned inputs each signer holds a key for.
+    pub signers: BTreeMap<String, Vec<usize>>,
+    /// Unsigned inputs no descriptor matches, as `<transaction>:<input>`.
+    pub unmatched: Vec<String>,
+}
+
+impl Signers {
+    /// Read a signers file: `descriptors` (public descriptors, with key
+    /// origins) and `signers` (each signer's master key fingerprint, by name).
+    ///
+    /// Ranged descriptors stand for their first 1000 addresses, on every path of multipath ones.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read signers file {}", path.display()))?;
+        let file: SignersFile =
+            serde_json::from_str(&content).context("Failed to parse signers file")?;
+        let mut names = HashMap::new();
+        for (name, fingerprint) in file.signers {
+            let fingerprint = Fingerprint::from_str(&fingerprint)
+                .with_context(|| format!("Invalid fingerprint for signer {}", name))?;
+            names.insert(fingerprint, name);
+        }
+        let mut descriptors = Vec::new();
+        let mut scripts = HashMap::new();
+        for (position, spec) in file.descriptors.iter().enumerate() {
+            let descriptor = Descriptor::<DescriptorPublicKey>::from_str(spec)
+                .with_context(|| format!("Invalid descriptor {}", spec))?;
+            for single in descriptor
+                .clone()
+                .into_single_descriptors()
+                .with_context(|| format!("Invalid descriptor {}", spec))?
+            {
+                let range = match single.has_wildcard() {
+                    true => 0..DESCRIPTOR_RANGE,
+                    false => 0..1,
+                };
+                for index in range {
+                    let derived = single.at_derivation_index(index).with_context(|| {
+                        format!("Failed to derive descriptor {} at index {}", spec, index)
+                    })?;
+                    scripts
+                        .entry(derived.script_pubkey())
+                        .or_insert((position, index));
+                }
+            }
+            descriptors.push(descriptor);
+        }
+        Ok(Signers {
+            descriptors,
+            names,
+            scripts,
+        })
+    }
+
+    /// Who can complete input `input` of a transaction, spending `spent`.
+    fn plan_input(
+        &self,
+        tx: &Transaction,
+        input: usize,
+        spent: Option<&TxOut>,
+    ) -> Result<InputPlan> {
+        let txin = &tx.input[input];
+        let mut plan = InputPlan {
+            input,
+            outpoint: txin.previous_output.to_string(),
+            signed: !txin.script_sig.is_empty() || !txin.witness.is_empty(),
+            descriptor: None,
+            derivation_index: None,
+            signers: Vec::new(),
+            policy: None,
+        };
+        let Some(spent) = spent else {
+            return Ok(plan);
+        };
+        let Some(&(position, index)) = self.scripts.get(&spent.script_pubkey) else {
+            return Ok(plan);
+        };
+        plan.descriptor = Some(position);
+        plan.derivation_index = Some(index);
+
+        // The path of a multipath descriptor the script was derived on
+        let single = self.descriptors[position]
+            .clone()
+            .into_single_descriptors()?
+            .into_iter()
+            .find(|single| {
+                single
+                    .at_derivation_index(index)
+                    .is_ok_and(|derived| derived.script_pubkey() == spent.script_pubkey)
+            })
+            .context("Descriptor no longer derives the script")?;
+        let derived = single.at_derivation_index(index)?;
+
+        let secp = Secp256k1::verification_only();
+        let mut key_names = HashMap::new();
+        derived.for_each_key(|key| {
+            let fingerprint = key.master_fingerprint();
+            let name = self
+                .names
+                .get(&fingerprint)
+                .cloned()
+                .unwrap_or_else(|| fingerprint.to_string());
+            if self.names.contains_key(&fingerprint) && !plan.signers.contains(&name) {
+                plan.signers.push(name.clone());
+            }
+            if let Ok(key) = key.derive_public_key(&secp) {
+                key_names.insert(key, name);
+            }
+            true
+        });
+        let policy = derived
+            .derived_descriptor(&secp)?
+            .lift()
+            .context("Failed to lift descriptor policy")?;
+        let named = policy
+            .translate_pk(&mut KeyNames(&key_names))
+            .expect("naming keys doesn't fail");
+        plan.policy = Some(named.to_string());
+        Ok(plan)
+    }
+
+    /// The plan of a batch, `spent` being the outputs each transaction's inputs spend, where known.
+    pub fn plan(&self, txs: &[(usize, &Transaction, Option<Vec<TxOut>>)]) -> Result<Plan> {
+        let mut plan = Plan {
+            transactions: Vec::new(),
+            signers: BTreeMap::new(),
+            unmatched: Vec::new(),
+        };
+        for (index, tx, spent) in txs {
+            let mut inputs = Vec::new();
+            for i in 0..tx.input.len() {
+                let input =
+                    self.plan_input(tx, i, spent.as_ref().and_then(|spent| spent.get(i)))?;
+                if !input.signed {
+                    if input.descriptor.is_none() {
+                        plan.unmatched.push(format!("{}:{}", index + 1, i));
+                    }
+                    for signer in &input.signers {
+                        let needed = plan.signers.entry(signer.clone()).or_default();
+                        if needed.last() != Some(&(index + 1)) {
+                            needed.push(index + 1);
+                        }
+                    }
+                }
+                inputs.push(input);
+            }
+            plan.transactions.push(TxPlan {
+                index: index + 1,
+                txid: tx.compute_txid().to_string(),
+                inputs,
+            });
+        }
+        Ok(plan)
+    }
+}
+
+/// Names keys in a policy by their signer.
+struct KeyNames<'a>(&'a HashMap<PublicKey, String>);
+
+impl Translator<PublicKey, String, Infallible> for KeyNames<'_> {
+    fn pk(&mut self, pk: &PublicKey) -> Result<String, Infallible> {
+        Ok(self.0.get(pk).cloned().unwrap_or_else(|| pk.to_string()))
+    }
+
+    fn sha256(&mut self, hash: &sha256::Hash) -> Result<String, Infallible> {
+        Ok(hash.to_string())
+    }
+
+    fn hash256(&mut self, hash: &miniscript::hash256::Hash) -> Result<String, Infallible> {
+        Ok(hash.to_string())
+    }
+
+    fn ripemd160(&mut self, hash: &ripemd160::Hash) -> Result<String, Infallible> {
+        Ok(hash.to_string())
+    }
+
+    fn hash160(&mut self, hash: &hash160::Hash) -> Result<String, Infallible> {
+        Ok(hash.to_string())
+    }
+}
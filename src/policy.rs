@@ -0,0 +1,235 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::bip32::Fingerprint;
+use bitcoin::key::{Secp256k1, TapTweak};
+use bitcoin::secp256k1::XOnlyPublicKey;
+use bitcoin::{PublicKey, Script, Transaction, TxOut};
+use miniscript::descriptor::{DescriptorPublicKey, SinglePub, SinglePubKey};
+use miniscript::{Descriptor, ForEachKey};
+use serde::Deserialize;
+
+use crate::node::Node;
+use crate::verify::{self, SigningKey};
+
+/// A signer policy as given in the `--signer-policy` file.
+#[derive(Debug, Deserialize)]
+struct PolicyEntry {
+    name: String,
+    /// Script type of the inputs the policy applies to (`p2pkh`, `p2sh`, `p2wpkh`, `p2wsh`, `p2tr`); all if absent.
+    script_type: Option<String>,
+    #[serde(default)]
+    pubkeys: Vec<String>,
+    /// Master key fingerprints, as 8 hex digits.
+    #[serde(default)]
+    fingerprints: Vec<String>,
+}
+
+struct Policy {
+    name: String,
+    script_type: Option<String>,
+    pubkeys: Vec<PublicKey>,
+    fingerprints: Vec<Fingerprint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddressInfo {
+    desc: Option<String>,
+}
+
+/// Keys expected to sign, by script type, to catch signatures from the wrong wallet.
+pub struct SignerPolicy {
+    policies: Vec<Policy>,
+}
+
+const SCRIPT_TYPES: [&str; 5] = ["p2pkh", "p2sh", "p2wpkh", "p2wsh", "p2tr"];
+
+fn script_type(script: &Script) -> &'static str {
+    if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2wpkh() {
+        "p2wpkh"
+    } else if script.is_p2wsh() {
+        "p2wsh"
+    } else if script.is_p2tr() {
+        "p2tr"
+    } else {
+        "other"
+    }
+}
+
+impl SignerPolicy {
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path).context("Failed to read signer policy file")?;
+        let entries: Vec<PolicyEntry> =
+            serde_json::from_str(&content).context("Failed to parse signer policy file")?;
+        let mut policies = Vec::new();
+        for entry in entries {
+            if let Some(script_type) = entry
+                .script_type
+                .as_ref()
+                .filter(|script_type| !SCRIPT_TYPES.contains(&script_type.as_str()))
+            {
+                bail!(
+                    "Invalid script type {} in signer policy {}",
+                    script_type,
+                    entry.name
+                );
+            }
+            if entry.pubkeys.is_empty() && entry.fingerprints.is_empty() {
+                bail!(
+                    "Signer policy {} has no pubkeys or fingerprints",
+                    entry.name
+                );
+            }
+            let pubkeys = entry
+                .pubkeys
+                .iter()
+                .map(|key| {
+                    PublicKey::from_str(key).with_context(|| {
+                        format!("Invalid pubkey {} in signer policy {}", key, entry.name)
+                    })
+                })
+                .collect::<Result<_>>()?;
+            let fingerprints = entry
+                .fingerprints
+                .iter()
+                .map(|fingerprint| {
+                    Fingerprint::from_str(fingerprint).with_context(|| {
+                        format!(
+                            "Invalid fingerprint {} in signer policy {}",
+                            fingerprint, entry.name
+                        )
+                    })
+                })
+                .collect::<Result<_>>()?;
+            policies.push(Policy {
+                name: entry.name,
+                script_type: entry.script_type,
+                pubkeys,
+                fingerprints,
+            });
+        }
+        Ok(SignerPolicy { policies })
+    }
+
+    /// Check the signatures added to `tx` (compared to `unsigned`) against the
+    /// first policy matching each input's script type, returning the violations.
+    ///
+    /// `spent` holds the outputs spent by every input of `tx`. Fingerprints are
+    /// looked up in the key origins of the wallet's descriptor for the prevout.
+    pub fn check(
+        &self,
+        node: &Node,
+        unsigned: &Transaction,
+        tx: &Transaction,
+        spent: &[TxOut],
+    ) -> Result<Vec<String>> {
+        let mut violations = Vec::new();
+        for (vin, input) in tx.input.iter().enumerate() {
+            let script = &spent[vin].script_pubkey;
+            let kind = script_type(script);
+            let Some(policy) = self
+                .policies
+                .iter()
+                .find(|policy| policy.script_type.as_deref().is_none_or(|t| t == kind))
+            else {
+                continue;
+            };
+            let before = unsigned
+                .input
+                .iter()
+                .position(|txin| txin.previous_output == input.previous_output)
+                .map(|index| verify::signing_keys(unsigned, index, spent))
+                .unwrap_or_default();
+            let added: Vec<SigningKey> = verify::signing_keys(tx, vin, spent)
+                .into_iter()
+                .filter(|key| !before.contains(key))
+                .collect();
+            if added.is_empty() {
+                continue;
+            }
+            let origins = if policy.fingerprints.is_empty() {
+                Vec::new()
+            } else {
+                wallet_origins(node, script)?
+            };
+            for key in added {
+                if !policy.allows(&key, &origins) {
+                    violations.push(format!(
+                        "input {} signed by {}, not a key of policy {}",
+                        vin,
+                        key_string(&key),
+                        policy.name
+                    ));
+                }
+            }
+        }
+        Ok(violations)
+    }
+}
+
+impl Policy {
+    fn allows(&self, key: &SigningKey, origins: &[(SigningKey, Fingerprint)]) -> bool {
+        let secp = Secp256k1::verification_only();
+        let by_pubkey = self.pubkeys.iter().any(|pubkey| match key {
+            SigningKey::Ecdsa(key) => key == pubkey,
+            SigningKey::Schnorr(output_key) => {
+                // The key itself, or the BIP 86 output key it tweaks to
+                let internal = XOnlyPublicKey::from(pubkey.inner);
+                internal == *output_key
+                    || internal.tap_tweak(&secp, None).0.to_x_only_public_key() == *output_key
+            }
+        });
+        by_pubkey
+            || origins.iter().any(|(origin_key, fingerprint)| {
+                origin_key == key && self.fingerprints.contains(fingerprint)
+            })
+    }
+}
+
+fn key_string(key: &SigningKey) -> String {
+    match key {
+        SigningKey::Ecdsa(key) => key.to_string(),
+        SigningKey::Schnorr(key) => key.to_string(),
+    }
+}
+
+/// Keys of the wallet's descriptor for `script`, with their master fingerprints.
+fn wallet_origins(node: &Node, script: &Script) -> Result<Vec<(SigningKey, Fingerprint)>> {
+    let Some(info) = node.address_info(script)? else {
+        return Ok(Vec::new());
+    };
+    let info: AddressInfo = serde_json::from_str(&info).context("Failed to parse address info")?;
+    let Some(desc) = info.desc else {
+        return Ok(Vec::new());
+    };
+    let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&desc)
+        .with_context(|| format!("Failed to parse wallet descriptor {}", desc))?;
+
+    // Only the internal key signs a taproot key path spend, as the output key
+    if let Descriptor::Tr(tr) = &descriptor {
+        return Ok(XOnlyPublicKey::from_slice(&script.as_bytes()[2..])
+            .map(|output_key| {
+                vec![(
+                    SigningKey::Schnorr(output_key),
+                    tr.internal_key().master_fingerprint(),
+                )]
+            })
+            .unwrap_or_default());
+    }
+    let mut origins = Vec::new();
+    descriptor.for_each_key(|key| {
+        if let DescriptorPublicKey::Single(SinglePub {
+            key: SinglePubKey::FullKey(pubkey),
+            ..
+        }) = key
+        {
+            origins.push((SigningKey::Ecdsa(*pubkey), key.master_fingerprint()));
+        }
+        true
+    });
+    Ok(origins)
+}
@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use bitcoin::{Amount, OutPoint};
+
+use crate::coinselect::{self, Utxo};
+use crate::fees;
+use crate::node::Node;
+
+/// What a fee bump or anchor child has to take from the wallet's UTXOs.
+pub struct Need {
+    /// What it is for, e.g. `anchor child of transaction 2`.
+    pub purpose: String,
+    pub amount: Amount,
+}
+
+/// Value of `utxos` once the fee of spending them at `fee_rate` is paid,
+/// as coin selection counts it.
+fn effective_value(utxos: &[Utxo], fee_rate: f64) -> Amount {
+    utxos
+        .iter()
+        .filter_map(|utxo| {
+            let weight = fees::signed_input_weight(
+                &utxo.txout.script_pubkey,
+                utxo.redeem_script.as_deref(),
+            )?;
+            let fee = Amount::from_sat(coinselect::fee_for(weight, fee_rate));
+            utxo.txout.value.checked_sub(fee)
+        })
+        .sum()
+}
+
+/// Check, before anything is signed, that the wallet's confirmed UTXOs not
+/// already spent by the batch can pay for `needs` at `fee_rate`, failing with
+/// the shortfall rather than midway through the run.
+///
+/// Funds are only summed: a UTXO can't be split between two needs, so
+/// passing is necessary for them all to be funded, not sufficient.
+pub fn check(
+    node: &Node,
+    needs: &[Need],
+    batch_spent: &HashSet<OutPoint>,
+    fee_rate: f64,
+) -> Result<()> {
+    for need in needs {
+        eprintln!("  {}: {}", need.purpose, need.amount);
+    }
+    let total: Amount = needs.iter().map(|need| need.amount).sum();
+    let mut confirmed = coinselect::list_unspent(node, 1, &[])?;
+    confirmed.retain(|utxo| !batch_spent.contains(&utxo.outpoint));
+    let available = effective_value(&confirmed, fee_rate);
+    eprintln!(
+        "  Wallet has {} in {} confirmed UTXO(s), once spent at {} sat/vB",
+        available,
+        confirmed.len(),
+        fee_rate
+    );
+    let Some(short) = total
+        .checked_sub(available)
+        .filter(|short| *short > Amount::ZERO)
+    else {
+        return Ok(());
+    };
+
+    let mut unconfirmed = coinselect::list_unspent(node, 0, &[])?;
+    unconfirmed.retain(|utxo| {
+        !batch_spent.contains(&utxo.outpoint)
+            && !confirmed.iter().any(|c| c.outpoint == utxo.outpoint)
+    });
+    let pending = match unconfirmed.is_empty() {
+        true => String::new(),
+        false => format!(
+            " ({} more is in {} unconfirmed UTXO(s), which aren't used)",
+            effective_value(&unconfirmed, fee_rate),
+            unconfirmed.len()
+        ),
+    };
+    bail!(
+        "Wallet is short of {} for the fee bumps and anchor children: they need {}, its confirmed UTXOs provide {}{}",
+        short,
+        total,
+        available,
+        pending
+    );
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use bitcoin::{Psbt, ScriptBuf, Transaction, TxOut, Txid, Witness};
+use serde::Deserialize;
+
+use crate::node::Node;
+
+#[derive(Debug, Deserialize)]
+struct ProcessResult {
+    psbt: String,
+}
+
+/// A PSBT of a transaction, with the signatures it has as final scriptSigs and witnesses.
+///
+/// `spent` (the outputs spent by the inputs) fills in the witness UTXOs of
+/// segwit inputs when known.
+pub fn from_tx(tx: &Transaction, spent: Option<&[TxOut]>) -> Result<Psbt> {
+    let mut unsigned = tx.clone();
+    for input in &mut unsigned.input {
+        input.script_sig = ScriptBuf::new();
+        input.witness = Witness::new();
+    }
+    let mut psbt = Psbt::from_unsigned_tx(unsigned)?;
+    for (i, (input, txin)) in psbt.inputs.iter_mut().zip(&tx.input).enumerate() {
+        if !txin.script_sig.is_empty() {
+            input.final_script_sig = Some(txin.script_sig.clone());
+        }
+        if !txin.witness.is_empty() {
+            input.final_script_witness = Some(txin.witness.clone());
+        }
+        if let Some(output) = spent
+            .and_then(|spent| spent.get(i))
+            .filter(|output| output.script_pubkey.is_witness_program())
+        {
+            input.witness_utxo = Some(output.clone());
+        }
+    }
+    Ok(psbt)
+}
+
+/// Add the full transactions the inputs spend from (`non_witness_utxo`).
+///
+/// GUI and hardware wallets check input amounts against them, and some refuse
+/// to sign segwit v0 inputs without them. Parents not in `parents` are left out.
+pub fn add_parents(psbt: &mut Psbt, parents: &HashMap<Txid, Transaction>) {
+    for (input, txin) in psbt.inputs.iter_mut().zip(&psbt.unsigned_tx.input) {
+        if input.final_script_witness.is_some() || input.final_script_sig.is_some() {
+            continue;
+        }
+        if let Some(parent) = parents.get(&txin.previous_output.txid) {
+            input.non_witness_utxo = Some(parent.clone());
+        }
+    }
+}
+
+/// Let the wallet fill in what it knows about the inputs and outputs (scripts,
+/// key origins), without signing anything.
+pub fn wallet_update(node: &Node, psbt: &Psbt) -> Result<Psbt> {
+    wallet_process(node, psbt, false)
+}
+
+/// Have the wallet sign the inputs it can (`walletprocesspsbt`), as partial signatures.
+pub fn wallet_sign(node: &Node, psbt: &Psbt) -> Result<Psbt> {
+    wallet_process(node, psbt, true)
+}
+
+fn wallet_process(node: &Node, psbt: &Psbt, sign: bool) -> Result<Psbt> {
+    let processed: ProcessResult = serde_json::from_str(&node.run_wallet(&[
+        "walletprocesspsbt",
+        &psbt.to_string(),
+        if sign { "true" } else { "false" },
+        "ALL",
+        "true",
+    ])?)
+    .context("Failed to parse walletprocesspsbt result")?;
+    Psbt::from_str(&processed.psbt).context("Failed to parse PSBT from the wallet")
+}
+
+/// Whether a batch entry holds a base64 PSBT instead of a transaction hex.
+pub fn is_base64(entry: &str) -> bool {
+    // Base64 of the "psbt" magic and its 0xff separator
+    entry.starts_with("cHNidP8")
+}
+
+/// The PSBT's transaction, with the final scriptSigs and witnesses it has.
+pub fn to_tx(psbt: &Psbt) -> Transaction {
+    let mut tx = psbt.unsigned_tx.clone();
+    apply_final(&mut tx, psbt);
+    tx
+}
+
+/// Read a PSBT file, in binary or base64 form.
+pub fn read(path: &Path) -> Result<Psbt> {
+    let content =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    match Psbt::deserialize(&content) {
+        Ok(psbt) => Ok(psbt),
+        Err(_) => Psbt::from_str(String::from_utf8_lossy(&content).trim())
+            .with_context(|| format!("Failed to parse PSBT {}", path.display())),
+    }
+}
+
+/// Whether any input of the PSBT carries a signature, partial or final.
+pub fn has_signatures(psbt: &Psbt) -> bool {
+    psbt.inputs.iter().any(|input| {
+        !input.partial_sigs.is_empty()
+            || input.tap_key_sig.is_some()
+            || !input.tap_script_sigs.is_empty()
+            || input.final_script_sig.is_some()
+            || input.final_script_witness.is_some()
+    })
+}
+
+/// Txid of the transaction without its scriptSigs, which is what a PSBT's unsigned transaction has.
+pub fn unsigned_txid(tx: &Transaction) -> Txid {
+    let mut unsigned = tx.clone();
+    for input in &mut unsigned.input {
+        input.script_sig = ScriptBuf::new();
+    }
+    unsigned.compute_txid()
+}
+
+/// Copy the final scriptSigs and witnesses of the PSBT into the transaction's
+/// inputs not signed yet, returning how many were.
+pub fn apply_final(tx: &mut Transaction, psbt: &Psbt) -> usize {
+    let mut signed = 0;
+    for (txin, input) in tx.input.iter_mut().zip(&psbt.inputs) {
+        if !txin.witness.is_empty() || !txin.script_sig.is_empty() {
+            continue;
+        }
+        if input.final_script_sig.is_none() && input.final_script_witness.is_none() {
+            continue;
+        }
+        if let Some(script_sig) = &input.final_script_sig {
+            txin.script_sig = script_sig.clone();
+        }
+        if let Some(witness) = &input.final_script_witness {
+            txin.witness = witness.clone();
+        }
+        signed += 1;
+    }
+    signed
+}
+
+/// Write a PSBT in binary form (BIP 174), as GUI and hardware wallets load it.
+pub fn write(path: &Path, psbt: &Psbt) -> Result<()> {
+    std::fs::write(path, psbt.serialize())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{bail, Context, Result};
+use miniscript::descriptor::checksum::desc_checksum;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::node::Node;
+
+#[derive(Debug, Deserialize)]
+struct ImportResult {
+    success: bool,
+    error: Option<Value>,
+}
+
+/// Descriptors to import into the wallet, and where to rescan from, when it
+/// can't sign inputs because it doesn't know their scripts.
+///
+/// This is done at most once per run, the first time it is needed.
+pub struct Recovery {
+    requests: Vec<Value>,
+    rescan_from: Option<u32>,
+    done: AtomicBool,
+}
+
+/// Whether a signing error means the wallet doesn't know the input's script or key.
+pub fn is_unknown_script(message: &str) -> bool {
+    message.contains("missing key") || message.contains("missing scripts")
+}
+
+impl Recovery {
+    /// Read the descriptors file: an array of descriptors, or of `importdescriptors` requests.
+    pub fn load(path: &str, rescan_from: Option<u32>) -> Result<Self> {
+        let content = std::fs::read_to_string(path).context("Failed to read descriptors file")?;
+        let requests: Vec<Value> =
+            serde_json::from_str(&content).context("Failed to parse descriptors file")?;
+        Ok(Recovery {
+            requests,
+            rescan_from,
+            done: AtomicBool::new(false),
+        })
+    }
+
+    /// Import the descriptors and rescan, unless already done in this run. Returns whether it was done now.
+    ///
+    /// Plain descriptors are imported with timestamp `now`, so the import
+    /// itself doesn't rescan: the rescan is `rescanblockchain` from the
+    /// given height, if any.
+    pub fn run(&self, node: &Node) -> Result<bool> {
+        if self.done.swap(true, Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let mut requests = Vec::new();
+        for request in &self.requests {
+            let request = match request {
+                Value::String(desc) if desc.contains('#') => {
+                    json!({ "desc": desc, "timestamp": "now" })
+                }
+                Value::String(desc) => {
+                    let checksum =
+                        desc_checksum(desc).context("Invalid descriptor in descriptors file")?;
+                    json!({ "desc": format!("{}#{}", desc, checksum), "timestamp": "now" })
+                }
+                request => request.clone(),
+            };
+            requests.push(request);
+        }
+
+        eprintln!("  Importing {} descriptor(s)...", requests.len());
+        let results: Vec<ImportResult> = serde_json::from_str(
+            &node.run_wallet(&["importdescriptors", &serde_json::to_string(&requests)?])?,
+        )
+        .context("Failed to parse importdescriptors result")?;
+        for (i, result) in results.iter().enumerate() {
+            if !result.success {
+                bail!(
+                    "Failed to import descriptor {}: {}",
+                    i + 1,
+                    result
+                        .error
+                        .as_ref()
+                        .map_or(String::new(), Value::to_string)
+                );
+            }
+        }
+
+        if let Some(height) = self.rescan_from {
+            eprintln!("  Rescanning the chain from height {}...", height);
+            node.run_wallet(&["rescanblockchain", &height.to_string()])
+                .context("Failed to rescan the chain")?;
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use bitcoin::transaction::Version;
+use bitcoin::{
+    absolute, Address, Amount, Network, OutPoint, Sequence, Transaction, TxIn, TxOut, Txid,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::anchor;
+use crate::batch::Entry;
+use crate::build;
+use crate::node::Node;
+use crate::rewrite;
+
+#[derive(Debug, Deserialize)]
+struct BlockchainInfo {
+    chain: String,
+}
+
+/// Fail unless the node is a regtest one, where blocks can be mined at will.
+pub fn ensure_regtest(node: &Node) -> Result<()> {
+    let info: BlockchainInfo = serde_json::from_str(&node.run(&["getblockchaininfo"])?)
+        .context("Failed to parse blockchain info")?;
+    // Elements regtest chains are named e.g. `elementsregtest`
+    if !info.chain.ends_with("regtest") {
+        bail!(
+            "node is on {}, mining is only possible on regtest",
+            info.chain
+        );
+    }
+    Ok(())
+}
+
+/// Send the batch transactions in order with `sendrawtransaction`, returning their txids.
+///
+/// Entries of other chains are skipped.
+pub fn send(node: &Node, entries: &[Entry]) -> Result<Vec<String>> {
+    let mut txids = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let Some(hex) = entry.hex() else {
+            continue;
+        };
+        let txid = node
+            .run(&["sendrawtransaction", hex])
+            .with_context(|| format!("Failed to send transaction {}", i + 1))?;
+        txids.push(txid);
+    }
+    Ok(txids)
+}
+
+/// Mine `blocks` blocks to `address`, or to a new wallet address, returning the block hashes.
+pub fn mine(node: &Node, blocks: u32, address: Option<&str>) -> Result<Vec<String>> {
+    let address = match address {
+        Some(address) => address.to_string(),
+        None => node.run_wallet(&["getnewaddress"])?,
+    };
+    let output = node.run(&["generatetoaddress", &blocks.to_string(), &address])?;
+    serde_json::from_str(&output).context("Failed to parse generatetoaddress result")
+}
+
+#[derive(Debug, Deserialize)]
+struct WalletConfirmations {
+    confirmations: i64,
+}
+
+/// Confirmations of a wallet transaction (0 while in the mempool).
+pub fn confirmations(node: &Node, txid: &str) -> Result<i64> {
+    let tx: WalletConfirmations =
+        serde_json::from_str(&node.run_wallet(&["gettransaction", txid])?)
+            .context("Failed to parse wallet transaction")?;
+    Ok(tx.confirmations)
+}
+
+/// Blocks before a coinbase output can be spent.
+const COINBASE_MATURITY: u32 = 100;
+/// Most blocks mined to fund the faucet before giving up.
+const MAX_FUNDING_BLOCKS: u32 = 1_000;
+
+#[derive(Debug, Deserialize)]
+struct WalletTransaction {
+    hex: String,
+}
+
+/// An output created by the faucet, in `listunspent` form (usable with `--utxos`).
+#[derive(Debug, Serialize)]
+pub struct Funding {
+    pub txid: String,
+    pub vout: u32,
+    pub address: String,
+    pub amount: f64,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pubkey: String,
+}
+
+/// Pay each address its amount (BTC) in one wallet transaction, then mine `confirmations` blocks.
+///
+/// Blocks are mined to the wallet first until its balance covers the amounts,
+/// which on a fresh chain takes a little over 100 blocks for the first
+/// coinbase to mature.
+pub fn fund(node: &Node, amounts: &Map<String, Value>, confirmations: u32) -> Result<Vec<Funding>> {
+    let mut total = Amount::ZERO;
+    let mut outputs = Vec::new();
+    for (address, amount) in amounts {
+        let amount = amount
+            .as_f64()
+            .and_then(|amount| Amount::from_btc(amount).ok())
+            .with_context(|| format!("Invalid amount for {}", address))?;
+        let script = build::parse_address(address, Some(Network::Regtest))?.script_pubkey();
+        total += amount;
+        outputs.push((address, amount, script));
+    }
+
+    let address = node.run_wallet(&["getnewaddress"])?;
+    let mut mined = 0;
+    loop {
+        let balance: f64 = serde_json::from_str(&node.run_wallet(&["getbalance"])?)
+            .context("Failed to parse wallet balance")?;
+        // Some margin for the fee
+        if Amount::from_btc(balance)? > total + Amount::from_sat(100_000) {
+            break;
+        }
+        if mined >= MAX_FUNDING_BLOCKS {
+            bail!(
+                "wallet balance of {} BTC still short of {} after mining {} blocks",
+                balance,
+                total,
+                mined
+            );
+        }
+        let blocks = if mined == 0 { COINBASE_MATURITY + 1 } else { 1 };
+        mine(node, blocks, Some(&address))?;
+        mined += blocks;
+    }
+    if mined > 0 {
+        eprintln!("  Mined {} block(s) to fund the wallet", mined);
+    }
+
+    let txid = node.run_wallet(&["sendmany", "", &serde_json::to_string(amounts)?])?;
+    let wallet_tx: WalletTransaction =
+        serde_json::from_str(&node.run_wallet(&["gettransaction", &txid])?)
+            .context("Failed to parse wallet transaction")?;
+    let tx = rewrite::decode_tx(&wallet_tx.hex)?;
+    if confirmations > 0 {
+        mine(node, confirmations, Some(&address))?;
+    }
+
+    let mut used = HashSet::new();
+    outputs
+        .into_iter()
+        .map(|(address, amount, script)| {
+            let vout = (0..tx.output.len())
+                .find(|&vout| {
+                    !used.contains(&vout)
+                        && tx.output[vout].script_pubkey == script
+                        && tx.output[vout].value == amount
+                })
+                .with_context(|| format!("No output of {} pays {}", txid, address))?;
+            used.insert(vout);
+            Ok(Funding {
+                txid: txid.clone(),
+                vout: vout as u32,
+                address: address.clone(),
+                amount: amount.to_btc(),
+                script_pubkey: script.to_hex_string(),
+            })
+        })
+        .collect()
+}
+
+/// Fee paid by each transaction of the self-test batch.
+const SELFTEST_FEE: Amount = Amount::from_sat(10_000);
+/// Value of the pay-to-anchor output linking the self-test transactions.
+const SELFTEST_ANCHOR_VALUE: Amount = Amount::from_sat(1_000);
+
+/// A parent and a child spending its pay-to-anchor output, for the self-test.
+///
+/// The parent spends `parent_funding` to `destinations[0]`, the child spends
+/// the anchor along with `child_funding` to `destinations[1]`, so both have a
+/// wallet input to sign.
+pub fn dependent_batch(
+    parent_funding: &Funding,
+    child_funding: &Funding,
+    destinations: [&Address; 2],
+) -> Result<Vec<Transaction>> {
+    let input = |funding: &Funding| -> Result<(TxIn, Amount)> {
+        let txin = TxIn {
+            previous_output: OutPoint::new(Txid::from_str(&funding.txid)?, funding.vout),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            ..Default::default()
+        };
+        Ok((txin, Amount::from_btc(funding.amount)?))
+    };
+
+    let (parent_input, parent_value) = input(parent_funding)?;
+    let parent = Transaction {
+        version: Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![parent_input],
+        output: vec![
+            TxOut {
+                value: parent_value - SELFTEST_FEE - SELFTEST_ANCHOR_VALUE,
+                script_pubkey: destinations[0].script_pubkey(),
+            },
+            TxOut {
+                value: SELFTEST_ANCHOR_VALUE,
+                script_pubkey: anchor::p2a_script(),
+            },
+        ],
+    };
+
+    let (child_input, child_value) = input(child_funding)?;
+    let child = Transaction {
+        version: Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![
+            TxIn {
+                previous_output: OutPoint::new(parent.compute_txid(), 1),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            },
+            child_input,
+        ],
+        output: vec![TxOut {
+            value: child_value + SELFTEST_ANCHOR_VALUE - SELFTEST_FEE,
+            script_pubkey: destinations[1].script_pubkey(),
+        }],
+    };
+
+    Ok(vec![parent, child])
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// How long a request may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The node's REST interface (`-rest`), answering prevout lookups without
+/// RPC credentials (`--rest-url`).
+///
+/// Transactions are served as by `getrawtransaction`, so outside the mempool
+/// the node still needs `-txindex`.
+#[derive(Clone)]
+pub struct Rest {
+    agent: ureq::Agent,
+    url: String,
+}
+
+impl Rest {
+    /// `url` is the node's RPC address, e.g. `http://127.0.0.1:8332`.
+    pub fn new(url: &str) -> Self {
+        Rest {
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let url = format!("{}/rest/{}", self.url, path);
+        self.agent
+            .get(&url)
+            .call()
+            .with_context(|| format!("Failed to fetch {}", url))?
+            .into_string()
+            .with_context(|| format!("Failed to read {}", url))
+    }
+
+    /// The transaction `txid` (`GET /rest/tx/<txid>.json`), in the form of
+    /// verbose `getrawtransaction`.
+    pub fn transaction(&self, txid: &str) -> Result<String> {
+        self.get(&format!("tx/{}.json", txid))
+    }
+
+    /// The node's chain state (`GET /rest/chaininfo.json`), as `getblockchaininfo` gives it.
+    pub fn chain_info(&self) -> Result<String> {
+        self.get("chaininfo.json")
+    }
+}
@@ -0,0 +1,791 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::consensus::encode::{deserialize_hex, serialize, serialize_hex};
+use bitcoin::consensus::Decodable;
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::secp256k1::rand::seq::SliceRandom;
+use bitcoin::secp256k1::rand::thread_rng;
+use bitcoin::transaction::Version;
+use bitcoin::{absolute, OutPoint, Psbt, ScriptBuf, Sequence, Transaction, TxOut, Txid, Witness};
+
+use crate::batch::Entry;
+use crate::psbt;
+
+/// New position of each original output of a rewritten transaction.
+pub type OutputMap = Vec<usize>;
+
+/// Rewrite the unsigned transactions of a batch before signing.
+///
+/// `rewrite` is called on every transaction that carries no signatures yet and
+/// returns where each of its outputs ended up. Because rewriting changes the
+/// txid, inputs of later transactions spending those outputs are repaired to
+/// point at the new txid and output position, in dependency order so repairs
+/// cascade down chains. Transactions that are (even partially) signed are
+/// never touched, and neither are their in-batch ancestors: changing those
+/// would invalidate existing signatures. Returns the number of rewritten
+/// transactions.
+pub fn rewrite_unsigned(
+    entries: &mut [Entry],
+    mut rewrite: impl FnMut(usize, &mut Transaction) -> Result<OutputMap>,
+) -> Result<usize> {
+    let mut txs = parse_batch(entries)?;
+    let frozen = frozen(&txs);
+
+    let mut moved: HashMap<OutPoint, OutPoint> = HashMap::new();
+    let mut rewritten = Vec::new();
+    for i in dependency_order(&txs) {
+        let Some(tx) = txs[i].as_mut() else {
+            continue;
+        };
+        if frozen.contains(&i) {
+            if !is_signed(tx) {
+                eprintln!(
+                    "  Transaction {}: left as is, a signed transaction in the batch depends on it",
+                    i + 1
+                );
+            }
+            continue;
+        }
+
+        let old_txid = tx.compute_txid();
+        for input in &mut tx.input {
+            if let Some(outpoint) = moved.get(&input.previous_output) {
+                input.previous_output = *outpoint;
+            }
+        }
+
+        let output_map = rewrite(i, tx)?;
+        let new_txid = tx.compute_txid();
+        for (vout, new_vout) in output_map.into_iter().enumerate() {
+            moved.insert(
+                OutPoint::new(old_txid, vout as u32),
+                OutPoint::new(new_txid, new_vout as u32),
+            );
+        }
+        rewritten.push(i);
+    }
+
+    for &i in &rewritten {
+        if let Some(tx) = &txs[i] {
+            entries[i].set_hex(encode_tx(tx));
+        }
+    }
+    Ok(rewritten.len())
+}
+
+/// Decoded transactions of a batch (those of PSBT entries with their final
+/// scriptSigs and witnesses), `None` for non-bitcoin entries.
+pub fn parse_batch(entries: &[Entry]) -> Result<Vec<Option<Transaction>>> {
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            entry
+                .hex()
+                .map(|hex| {
+                    if psbt::is_base64(hex) {
+                        Ok(psbt::to_tx(&Psbt::from_str(hex)?))
+                    } else {
+                        decode_tx(hex)
+                    }
+                })
+                .transpose()
+                .with_context(|| format!("Failed to parse transaction {}", i + 1))
+        })
+        .collect()
+}
+
+/// Decode a transaction, including input-less templates.
+///
+/// With no inputs, the legacy serialization `createrawtransaction` produces
+/// reads as a (malformed) segwit one, so it is decoded by hand if the usual
+/// decoding fails.
+pub fn decode_tx(hex: &str) -> Result<Transaction> {
+    let error = match deserialize_hex::<Transaction>(hex) {
+        Ok(tx) => return Ok(tx),
+        Err(e) => e,
+    };
+    let bytes = Vec::<u8>::from_hex(hex)?;
+    let mut reader = bytes.as_slice();
+    let without_inputs = (|| {
+        let version = Version::consensus_decode(&mut reader).ok()?;
+        (u8::consensus_decode(&mut reader).ok()? == 0).then_some(())?;
+        let output = Vec::<TxOut>::consensus_decode(&mut reader).ok()?;
+        let lock_time = absolute::LockTime::consensus_decode(&mut reader).ok()?;
+        reader.is_empty().then_some(Transaction {
+            version,
+            lock_time,
+            input: Vec::new(),
+            output,
+        })
+    })();
+    without_inputs.ok_or(error.into())
+}
+
+/// Encode a transaction, in the legacy serialization if it has no inputs.
+///
+/// The segwit serialization rust-bitcoin uses for input-less transactions is
+/// rejected by the node.
+pub fn encode_tx(tx: &Transaction) -> String {
+    if !tx.input.is_empty() {
+        return serialize_hex(tx);
+    }
+    let mut bytes = serialize(&tx.version);
+    bytes.push(0);
+    bytes.extend(serialize(&tx.output));
+    bytes.extend(serialize(&tx.lock_time));
+    bytes.to_lower_hex_string()
+}
+
+/// Whether any input of the transaction carries a signature.
+pub fn is_signed(tx: &Transaction) -> bool {
+    tx.input
+        .iter()
+        .any(|input| !input.witness.is_empty() || !input.script_sig.is_empty())
+}
+
+/// Remove the scriptSigs and witnesses of every input of the batch, so all are signed afresh.
+///
+/// Entries carrying metadata are left as is: their witnesses (e.g. a spell
+/// envelope) are part of what the metadata commits to. Returns the number of
+/// stripped transactions.
+pub fn strip_signatures(entries: &mut [Entry]) -> Result<usize> {
+    let mut stripped = 0;
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let Some(hex) = entry.hex() else {
+            continue;
+        };
+        let mut tx =
+            decode_tx(hex).with_context(|| format!("Failed to parse transaction {}", i + 1))?;
+        if !is_signed(&tx) {
+            continue;
+        }
+        if entry.has_metadata() {
+            eprintln!(
+                "  Transaction {}: left as is, its witnesses are covered by its metadata",
+                i + 1
+            );
+            continue;
+        }
+        for input in &mut tx.input {
+            input.script_sig = ScriptBuf::new();
+            input.witness = Witness::new();
+        }
+        eprintln!("  Transaction {}: signatures removed", i + 1);
+        entry.set_hex(encode_tx(&tx));
+        stripped += 1;
+    }
+    Ok(stripped)
+}
+
+/// Indices of the batch transactions spent by transaction `i`.
+fn parents(txs: &[Option<Transaction>], by_txid: &HashMap<Txid, usize>, i: usize) -> Vec<usize> {
+    txs[i]
+        .iter()
+        .flat_map(|tx| &tx.input)
+        .filter_map(|input| by_txid.get(&input.previous_output.txid).copied())
+        .collect()
+}
+
+fn txid_index(txs: &[Option<Transaction>]) -> HashMap<Txid, usize> {
+    txs.iter()
+        .enumerate()
+        .filter_map(|(i, tx)| tx.as_ref().map(|tx| (tx.compute_txid(), i)))
+        .collect()
+}
+
+/// Batch transactions that transaction `i` depends on, directly or not.
+pub fn ancestors(txs: &[Option<Transaction>], i: usize) -> HashSet<usize> {
+    let by_txid = txid_index(txs);
+    let mut ancestors = HashSet::new();
+    let mut queue: VecDeque<usize> = parents(txs, &by_txid, i).into();
+    while let Some(j) = queue.pop_front() {
+        if j != i && ancestors.insert(j) {
+            queue.extend(parents(txs, &by_txid, j));
+        }
+    }
+    ancestors
+}
+
+/// Signed transactions and everything in the batch they depend on.
+fn frozen(txs: &[Option<Transaction>]) -> HashSet<usize> {
+    let by_txid = txid_index(txs);
+    let mut frozen = HashSet::new();
+    let mut queue: VecDeque<usize> = txs
+        .iter()
+        .enumerate()
+        .filter(|(_, tx)| tx.as_ref().is_some_and(is_signed))
+        .map(|(i, _)| i)
+        .collect();
+    while let Some(i) = queue.pop_front() {
+        if frozen.insert(i) {
+            queue.extend(parents(txs, &by_txid, i));
+        }
+    }
+    frozen
+}
+
+/// Batch indices ordered so that every transaction comes after its in-batch parents.
+///
+/// Transactions that cannot be ordered (which valid transactions never are)
+/// are appended in batch order.
+pub fn dependency_order(txs: &[Option<Transaction>]) -> Vec<usize> {
+    sort_by_dependencies(txs).0
+}
+
+/// Like [`dependency_order`], failing if transactions spend each other's outputs in a cycle.
+pub fn topological_order(txs: &[Option<Transaction>]) -> Result<Vec<usize>> {
+    let (order, sorted) = sort_by_dependencies(txs);
+    if sorted < order.len() {
+        let unsorted: Vec<String> = order[sorted..]
+            .iter()
+            .map(|i| (i + 1).to_string())
+            .collect();
+        bail!(
+            "Transactions {} spend each other's outputs in a cycle (or depend on transactions that do), they can't be ordered",
+            unsorted.join(", ")
+        );
+    }
+    Ok(order)
+}
+
+/// The dependency order, and how many transactions at its start are in order.
+fn sort_by_dependencies(txs: &[Option<Transaction>]) -> (Vec<usize>, usize) {
+    let by_txid = txid_index(txs);
+    let mut order = Vec::with_capacity(txs.len());
+    let mut done = vec![false; txs.len()];
+    while order.len() < txs.len() {
+        let before = order.len();
+        for i in 0..txs.len() {
+            if !done[i] && parents(txs, &by_txid, i).iter().all(|&p| done[p] || p == i) {
+                done[i] = true;
+                order.push(i);
+            }
+        }
+        if order.len() == before {
+            order.extend((0..txs.len()).filter(|&i| !done[i]));
+            return (order, before);
+        }
+    }
+    let sorted = order.len();
+    (order, sorted)
+}
+
+/// Sort inputs and outputs as specified by BIP 69.
+///
+/// Inputs go by previous txid (as displayed) then output index, outputs by
+/// amount then scriptPubKey bytes.
+pub fn bip69(tx: &mut Transaction) -> OutputMap {
+    tx.input.sort_by(|a, b| {
+        let (a, b) = (&a.previous_output, &b.previous_output);
+        a.txid
+            .to_string()
+            .cmp(&b.txid.to_string())
+            .then(a.vout.cmp(&b.vout))
+    });
+
+    let mut order: Vec<usize> = (0..tx.output.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (a, b) = (&tx.output[a], &tx.output[b]);
+        a.value
+            .cmp(&b.value)
+            .then_with(|| a.script_pubkey.as_bytes().cmp(b.script_pubkey.as_bytes()))
+    });
+    reorder_outputs(tx, &order)
+}
+
+/// Put outputs in a random order, so the change position doesn't reveal which output is ours.
+pub fn shuffle_outputs(tx: &mut Transaction) -> OutputMap {
+    let mut order: Vec<usize> = (0..tx.output.len()).collect();
+    order.shuffle(&mut thread_rng());
+    reorder_outputs(tx, &order)
+}
+
+/// Apply the per-input sequence numbers of entries with a `sequences` field.
+///
+/// `sequences` has an element per input, a sequence number or `null` to keep
+/// the input's; inputs past its end (e.g. added by funding) are kept too.
+/// Only unsigned transactions are changed, and the field is removed from
+/// those; inputs of other batch transactions spending their outputs are
+/// repaired. Returns the number of changed transactions.
+pub fn set_sequences(entries: &mut [Entry]) -> Result<usize> {
+    let mut overrides = BTreeMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let Some(value) = entry.field("sequences") else {
+            continue;
+        };
+        let sequences: Vec<Option<u32>> = serde_json::from_value(value.clone())
+            .with_context(|| format!("Invalid sequences field in transaction {}", i + 1))?;
+        overrides.insert(i, sequences);
+    }
+    if overrides.is_empty() {
+        return Ok(0);
+    }
+
+    let mut applied = Vec::new();
+    rewrite_unsigned(entries, |i, tx| {
+        let outputs = (0..tx.output.len()).collect();
+        let Some(sequences) = overrides.get(&i) else {
+            return Ok(outputs);
+        };
+        if sequences.len() > tx.input.len() {
+            bail!(
+                "Transaction {} has {} input(s) but {} sequences",
+                i + 1,
+                tx.input.len(),
+                sequences.len()
+            );
+        }
+        for (input, sequence) in tx.input.iter_mut().zip(sequences) {
+            if let Some(sequence) = sequence {
+                input.sequence = Sequence(*sequence);
+            }
+        }
+        if tx.version < Version::TWO
+            && tx
+                .input
+                .iter()
+                .any(|input| input.sequence.is_relative_lock_time())
+        {
+            eprintln!(
+                "  Warning: transaction {} is version {}, its relative timelocks are not enforced",
+                i + 1,
+                tx.version.0
+            );
+        }
+        eprintln!("  Transaction {}: sequences set", i + 1);
+        applied.push(i);
+        Ok(outputs)
+    })?;
+
+    for &i in overrides.keys() {
+        if applied.contains(&i) {
+            entries[i].remove_field("sequences");
+        } else {
+            eprintln!(
+                "  Transaction {}: sequences not applied, the transaction is signed or a signed one depends on it",
+                i + 1
+            );
+        }
+    }
+    Ok(applied.len())
+}
+
+/// Largest transaction (in vbytes) TRUC policy (BIP 431) accepts, and largest child of a TRUC parent.
+const TRUC_MAX_VSIZE: u64 = 10_000;
+const TRUC_CHILD_MAX_VSIZE: u64 = 1_000;
+
+/// Set the version of unsigned transactions: that of their `version` field, or `default`.
+///
+/// Versions other than 1 to 3 are nonstandard and refused, as is going below
+/// version 2 when an input's sequence sets a relative timelock, which its
+/// script may require (`OP_CHECKSEQUENCEVERIFY` fails on version 1). Version 3
+/// (TRUC) transactions are checked against the TRUC topology rules within the
+/// batch: parents and children must be TRUC as well, a TRUC parent may only
+/// have one unconfirmed child and a TRUC child one unconfirmed parent, and
+/// sizes stay under the TRUC limits. Only unsigned transactions are changed,
+/// and the field is removed from those; inputs of other batch transactions
+/// spending their outputs are repaired. Returns the number of changed
+/// transactions.
+pub fn set_versions(entries: &mut [Entry], default: Option<i32>) -> Result<usize> {
+    let mut versions = BTreeMap::new();
+    let mut fields = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let version = match entry.field("version") {
+            Some(value) => {
+                fields.push(i);
+                Some(
+                    serde_json::from_value::<i32>(value.clone()).with_context(|| {
+                        format!("Invalid version field in transaction {}", i + 1)
+                    })?,
+                )
+            }
+            None => default,
+        };
+        if let Some(version) = version {
+            if !(1..=3).contains(&version) {
+                bail!(
+                    "Version {} of transaction {} is nonstandard, use 1, 2 or 3",
+                    version,
+                    i + 1
+                );
+            }
+            versions.insert(i, version);
+        }
+    }
+    if versions.is_empty() {
+        return Ok(0);
+    }
+
+    let mut applied = Vec::new();
+    let mut changed = Vec::new();
+    rewrite_unsigned(entries, |i, tx| {
+        let outputs = (0..tx.output.len()).collect();
+        let Some(&version) = versions.get(&i) else {
+            return Ok(outputs);
+        };
+        applied.push(i);
+        let relative = tx
+            .input
+            .iter()
+            .position(|input| input.sequence.is_relative_lock_time());
+        if let Some(vin) = relative.filter(|_| version < 2) {
+            bail!(
+                "Transaction {} can't be version {}: input {} has a relative timelock, which needs version 2",
+                i + 1,
+                version,
+                vin
+            );
+        }
+        if tx.version != Version(version) {
+            eprintln!(
+                "  Transaction {}: version {} -> {}",
+                i + 1,
+                tx.version.0,
+                version
+            );
+            tx.version = Version(version);
+            changed.push(i);
+        }
+        Ok(outputs)
+    })?;
+
+    for i in fields {
+        if applied.contains(&i) {
+            entries[i].remove_field("version");
+        } else {
+            eprintln!(
+                "  Transaction {}: version not applied, the transaction is signed or a signed one depends on it",
+                i + 1
+            );
+        }
+    }
+    check_truc(&parse_batch(entries)?)?;
+    Ok(changed.len())
+}
+
+/// Check the TRUC (version 3) transactions of a batch against the TRUC topology and size rules.
+///
+/// Sizes are those of the transactions as they are, so unsigned ones will
+/// grow by their signatures.
+fn check_truc(txs: &[Option<Transaction>]) -> Result<()> {
+    let by_txid = txid_index(txs);
+    let is_truc = |tx: &Transaction| tx.version == Version(3);
+    let mut children: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (i, tx) in txs.iter().enumerate() {
+        let Some(tx) = tx else { continue };
+        let parents: HashSet<usize> = parents(txs, &by_txid, i).into_iter().collect();
+        for &p in &parents {
+            let parent = txs[p].as_ref().expect("parents are bitcoin transactions");
+            if is_truc(tx) != is_truc(parent) {
+                bail!(
+                    "Transaction {} (version {}) spends transaction {} (version {}): TRUC transactions can only be chained with TRUC transactions",
+                    i + 1,
+                    tx.version.0,
+                    p + 1,
+                    parent.version.0
+                );
+            }
+        }
+        if !is_truc(tx) {
+            continue;
+        }
+        if parents.len() > 1 {
+            bail!(
+                "TRUC transaction {} has {} unconfirmed parents in the batch, at most 1 is allowed",
+                i + 1,
+                parents.len()
+            );
+        }
+        for &p in &parents {
+            children.entry(p).or_default().push(i);
+        }
+        let (limit, role) = if parents.is_empty() {
+            (TRUC_MAX_VSIZE, "TRUC transaction")
+        } else {
+            (TRUC_CHILD_MAX_VSIZE, "TRUC child")
+        };
+        let vsize = tx.weight().to_vbytes_ceil();
+        if vsize > limit {
+            bail!(
+                "Transaction {} is {} vB, over the {} vB limit of a {}",
+                i + 1,
+                vsize,
+                limit,
+                role
+            );
+        }
+    }
+    if let Some((p, children)) = children.iter().find(|(_, children)| children.len() > 1) {
+        bail!(
+            "TRUC transaction {} has {} unconfirmed children in the batch ({}), at most 1 is allowed",
+            p + 1,
+            children.len(),
+            children
+                .iter()
+                .map(|child| format!("transaction {}", child + 1))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Put outputs in the given order (a list of original indices), returning where each went.
+fn reorder_outputs(tx: &mut Transaction, order: &[usize]) -> OutputMap {
+    let mut output_map = vec![0; order.len()];
+    for (new_vout, &vout) in order.iter().enumerate() {
+        output_map[vout] = new_vout;
+    }
+    tx.output = order.iter().map(|&vout| tx.output[vout].clone()).collect();
+    output_map
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, TxIn};
+    use serde_json::json;
+
+    use super::*;
+
+    fn signed_tx() -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[vec![0x30; 71], vec![0x02; 33]]),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: ScriptBuf::from_hex("0014a3c6b1ee4a49d9f2af3b3802974744fba924164a")
+                    .unwrap(),
+            }],
+        }
+    }
+
+    fn entry(fields: serde_json::Value) -> Entry {
+        let mut entry = Entry::Hex(encode_tx(&signed_tx()));
+        for (name, value) in fields.as_object().unwrap() {
+            entry.set_field(name, value.clone());
+        }
+        entry
+    }
+
+    #[test]
+    fn strip_signatures_of_indexed_and_labelled_entries() {
+        let mut entries = vec![
+            entry(json!({ "index": 0, "label": "payroll" })),
+            entry(json!({ "wallet": "treasury" })),
+            entry(json!({ "label": "mint", "spell": "...", "proof": "..." })),
+        ];
+        assert_eq!(strip_signatures(&mut entries).unwrap(), 2);
+        for entry in &entries[..2] {
+            assert!(!is_signed(&decode_tx(entry.hex().unwrap()).unwrap()));
+        }
+        assert!(is_signed(&decode_tx(entries[2].hex().unwrap()).unwrap()));
+    }
+
+    /// Previous outputs of the first example transaction of BIP 69
+    /// (0a6a357e...), in the order the BIP sorts them.
+    const BIP69_INPUTS: [(&str, u32); 17] = [
+        (
+            "0e53ec5dfb2cb8a71fec32dc9a634a35b7e24799295ddd5278217822e0b31f57",
+            0,
+        ),
+        (
+            "26aa6e6d8b9e49bb0630aac301db6757c02e3619feb4ee0eea81eb1672947024",
+            1,
+        ),
+        (
+            "28e0fdd185542f2c6ea19030b0796051e7772b6026dd5ddccd7a2f93b73e6fc2",
+            0,
+        ),
+        (
+            "381de9b9ae1a94d9c17f6a08ef9d341a5ce29e2e60c36a52d333ff6203e58d5d",
+            1,
+        ),
+        (
+            "3b8b2f8efceb60ba78ca8bba206a137f14cb5ea4035e761ee204302d46b98de2",
+            0,
+        ),
+        (
+            "402b2c02411720bf409eff60d05adad684f135838962823f3614cc657dd7bc0a",
+            1,
+        ),
+        (
+            "54ffff182965ed0957dba1239c27164ace5a73c9b62a660c74b7b7f15ff61e7a",
+            1,
+        ),
+        (
+            "643e5f4e66373a57251fb173151e838ccd27d279aca882997e005016bb53d5aa",
+            0,
+        ),
+        (
+            "6c1d56f31b2de4bfc6aaea28396b333102b1f600da9c6d6149e96ca43f1102b1",
+            1,
+        ),
+        (
+            "7a1de137cbafb5c70405455c49c5104ca3057a1f1243e6563bb9245c9c88c191",
+            0,
+        ),
+        (
+            "7d037ceb2ee0dc03e82f17be7935d238b35d1deabf953a892a4507bfbeeb3ba4",
+            1,
+        ),
+        (
+            "a5e899dddb28776ea9ddac0a502316d53a4a3fca607c72f66c470e0412e34086",
+            0,
+        ),
+        (
+            "b4112b8f900a7ca0c8b0e7c4dfad35c6be5f6be46b3458974988e1cdb2fa61b8",
+            0,
+        ),
+        (
+            "bafd65e3c7f3f9fdfdc1ddb026131b278c3be1af90a4a6ffa78c4658f9ec0c85",
+            0,
+        ),
+        (
+            "de0411a1e97484a2804ff1dbde260ac19de841bebad1880c782941aca883b4e9",
+            1,
+        ),
+        (
+            "f0a130a84912d03c1d284974f563c5949ac13f8342b8112edff52971599e6a45",
+            0,
+        ),
+        (
+            "f320832a9d2e2452af63154bc687493484a0e7745ebd3aaf9ca19eb80834ad60",
+            0,
+        ),
+    ];
+
+    fn unsigned_tx(inputs: &[(&str, u32)], outputs: &[(u64, &str)]) -> Transaction {
+        Transaction {
+            version: Version::ONE,
+            lock_time: absolute::LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|&(txid, vout)| TxIn {
+                    previous_output: OutPoint::new(Txid::from_str(txid).unwrap(), vout),
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|&(value, script)| TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: ScriptBuf::from_hex(script).unwrap(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn bip69_sorts_inputs_by_displayed_txid() {
+        // Interleaved, so neither the given nor the reversed order is sorted
+        let mut shuffled = BIP69_INPUTS.to_vec();
+        shuffled.reverse();
+        shuffled.rotate_left(7);
+        let mut tx = unsigned_tx(
+            &shuffled,
+            &[
+                (
+                    40_000_000_000,
+                    "76a9145be32612930b8323add2212a4ec03c1562084f8488ac",
+                ),
+                (
+                    400_057_456,
+                    "76a9144a5fba237213a062f6f57978f796390bdcf8d01588ac",
+                ),
+            ],
+        );
+        let output_map = bip69(&mut tx);
+        let sorted: Vec<(String, u32)> = tx
+            .input
+            .iter()
+            .map(|input| {
+                (
+                    input.previous_output.txid.to_string(),
+                    input.previous_output.vout,
+                )
+            })
+            .collect();
+        let expected: Vec<(String, u32)> = BIP69_INPUTS
+            .iter()
+            .map(|&(txid, vout)| (txid.to_string(), vout))
+            .collect();
+        assert_eq!(sorted, expected);
+        assert_eq!(output_map, vec![1, 0]);
+        assert_eq!(tx.output[0].value, Amount::from_sat(400_057_456));
+    }
+
+    #[test]
+    fn bip69_sorts_by_vout_then_outputs_by_amount_and_script() {
+        // The second example of BIP 69 (28204cad...), with an output of equal amount added
+        let txid = "35288d269cee1941eaebb2ea85e32b42cdb2b04284a56d8b14dcc3f5c65d6055";
+        let mut tx = unsigned_tx(
+            &[(txid, 1), (txid, 0)],
+            &[
+                (2_400_000_000, "41044a656f065871a353f216ca26cef8dde2f03e8c16202d2e8ad769f02032cb86a5eb5e56842e92e19141d60a01928f8dd2c875a390f67c1f6c94cfc617c0ea45afac"),
+                (100_000_000, "41046a0765b5865641ce08dd39690aade26dfbf5511430ca428a3089261361cef170e3929a68aee3d8d4848b0c5111b0a37b82b86ad559fd2a745b44d8e8d9dfdc0cac"),
+                (100_000_000, "0014a3c6b1ee4a49d9f2af3b3802974744fba924164a"),
+            ],
+        );
+        let output_map = bip69(&mut tx);
+        let vouts: Vec<u32> = tx
+            .input
+            .iter()
+            .map(|input| input.previous_output.vout)
+            .collect();
+        assert_eq!(vouts, vec![0, 1]);
+        assert_eq!(output_map, vec![2, 1, 0]);
+        assert_eq!(
+            tx.output[0].script_pubkey.to_hex_string(),
+            "0014a3c6b1ee4a49d9f2af3b3802974744fba924164a"
+        );
+    }
+
+    /// An unsigned version 3 transaction spending output `vout` of `parent`.
+    fn truc_child(parent: &Transaction, vout: u32) -> Transaction {
+        Transaction {
+            version: Version(3),
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(parent.compute_txid(), vout),
+                ..Default::default()
+            }],
+            output: parent.output[..1].to_vec(),
+        }
+    }
+
+    #[test]
+    fn truc_parent_with_one_child() {
+        let mut parent = signed_tx();
+        parent.version = Version(3);
+        let child = truc_child(&parent, 0);
+        check_truc(&[Some(parent), Some(child)]).unwrap();
+    }
+
+    #[test]
+    fn truc_parent_with_sibling_children() {
+        let mut parent = signed_tx();
+        parent.version = Version(3);
+        parent.output.push(parent.output[0].clone());
+        let first = truc_child(&parent, 0);
+        let second = truc_child(&parent, 1);
+        let error = check_truc(&[Some(parent), Some(first), Some(second)]).unwrap_err();
+        assert!(error.to_string().contains("2 unconfirmed children"));
+    }
+
+    #[test]
+    fn versions_of_signed_entries_are_kept() {
+        let mut entries = vec![entry(json!({ "version": 3 }))];
+        assert_eq!(set_versions(&mut entries, None).unwrap(), 0);
+        assert_eq!(entries[0].field("version"), Some(&json!(3)));
+    }
+}
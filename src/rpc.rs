@@ -0,0 +1,177 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use bitcoin::base64::engine::general_purpose::STANDARD;
+use bitcoin::base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    #[serde(default)]
+    id: usize,
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+/// A node's JSON-RPC server, called over HTTP instead of through the CLI.
+///
+/// Connections are kept alive and reused by the next calls, clones (e.g. for
+/// other wallets) sharing them, so a run pays for the TCP and TLS handshakes
+/// once rather than per call.
+#[derive(Clone)]
+pub struct Rpc {
+    agent: ureq::Agent,
+    url: String,
+    authorization: Option<String>,
+}
+
+impl Rpc {
+    /// `timeout` is how long a call may take, none if 0 (as `-rpcclienttimeout`).
+    pub fn new(
+        url: &str,
+        user: Option<&str>,
+        password: Option<&str>,
+        timeout: Option<u64>,
+    ) -> Self {
+        let mut agent = ureq::AgentBuilder::new();
+        if let Some(secs) = timeout.filter(|&secs| secs > 0) {
+            agent = agent.timeout(Duration::from_secs(secs));
+        }
+        Rpc {
+            agent: agent.build(),
+            url: url.trim_end_matches('/').to_string(),
+            authorization: user.map(|user| {
+                let credentials = format!("{}:{}", user, password.unwrap_or_default());
+                format!("Basic {}", STANDARD.encode(credentials))
+            }),
+        }
+    }
+
+    /// Open a connection ahead of the first call, with a call to `uptime`,
+    /// returning how long it took.
+    ///
+    /// This also fails early on an unreachable server or wrong credentials.
+    pub fn warm_up(&self) -> Result<Duration> {
+        let started = Instant::now();
+        self.call(None, &["uptime"])?;
+        Ok(started.elapsed())
+    }
+
+    /// Call an RPC method given as CLI arguments, the result printed as the CLI does.
+    ///
+    /// Arguments that parse as JSON (numbers, booleans, arrays, objects) are
+    /// passed as such, others as strings, which is what the CLI does for the
+    /// parameters of the methods sign-txs calls. Wallet methods go to the
+    /// `wallet` endpoint if one is given.
+    pub fn call(&self, wallet: Option<&str>, args: &[&str]) -> Result<String> {
+        let body = request(args, 0)?;
+        // bitcoind answers RPC errors with an HTTP error status and the error in the body
+        let response: Response = serde_json::from_str(&self.post(wallet, args[0], &body)?)
+            .with_context(|| format!("Failed to parse RPC {} response", args[0]))?;
+        result(args[0], response)
+    }
+
+    /// Make several calls in one JSON-RPC batch request, returning their results in order.
+    pub fn call_many(
+        &self,
+        wallet: Option<&str>,
+        calls: &[Vec<&str>],
+    ) -> Result<Vec<Result<String>>> {
+        let body = calls
+            .iter()
+            .enumerate()
+            .map(|(id, args)| request(args, id))
+            .collect::<Result<Vec<_>>>()?;
+        let mut responses: Vec<Response> =
+            serde_json::from_str(&self.post(wallet, "batch", &Value::Array(body))?)
+                .context("Failed to parse RPC batch response")?;
+        responses.sort_by_key(|response| response.id);
+        if responses.len() != calls.len() {
+            bail!(
+                "RPC batch answered {} of {} calls",
+                responses.len(),
+                calls.len()
+            );
+        }
+        Ok(calls
+            .iter()
+            .zip(responses)
+            .map(|(args, response)| result(args[0], response))
+            .collect())
+    }
+
+    fn post(&self, wallet: Option<&str>, method: &str, body: &Value) -> Result<String> {
+        let url = match wallet {
+            Some(wallet) => format!("{}/wallet/{}", self.url, wallet),
+            None => self.url.clone(),
+        };
+        let mut request = self
+            .agent
+            .post(&url)
+            .set("Content-Type", "application/json");
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        let body = body.to_string();
+        // bitcoind closes connections idle for -rpcservertimeout, which a kept-alive
+        // one may have been by the time it is reused; a new one is tried once
+        let response = match request.clone().send_string(&body) {
+            Err(ureq::Error::Transport(transport)) if transport.kind() == ureq::ErrorKind::Io => {
+                request.send_string(&body)
+            }
+            response => response,
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(ureq::Error::Status(401, _)) => {
+                bail!("RPC {} failed: incorrect rpc user or password", method)
+            }
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => return Err(e).with_context(|| format!("Failed to reach RPC server {}", url)),
+        };
+        Ok(response.into_string()?)
+    }
+}
+
+/// The JSON-RPC request for a call given as CLI arguments.
+fn request(args: &[&str], id: usize) -> Result<Value> {
+    let Some((method, params)) = args.split_first() else {
+        bail!("No RPC method given");
+    };
+    let params: Vec<Value> = params
+        .iter()
+        .map(|param| {
+            serde_json::from_str(param).unwrap_or_else(|_| Value::String(param.to_string()))
+        })
+        .collect();
+    Ok(json!({
+        "jsonrpc": "1.0",
+        "id": id,
+        "method": method,
+        "params": params,
+    }))
+}
+
+/// A call's result, printed as the CLI does.
+fn result(method: &str, response: Response) -> Result<String> {
+    if let Some(error) = response.error {
+        bail!(
+            "RPC {} failed: error code: {}\nerror message:\n{}",
+            method,
+            error.code,
+            error.message
+        );
+    }
+    Ok(match response.result {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(result)) => result,
+        Some(result) => serde_json::to_string_pretty(&result)?,
+    })
+}
@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+/// Restrictions on the programs run to reach the node (the CLI, `docker`,
+/// `podman`, `ssh` and `kubectl`), for signers run in a sensitive context.
+///
+/// The default runs them as the caller does: found on `PATH`, with the
+/// caller's environment and user.
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    /// Absolute paths of the only programs allowed to run (`--allow-exec`);
+    /// a program is run from the path with its name, never looked up on `PATH`.
+    pub allowed: Option<Vec<PathBuf>>,
+    /// Start programs with an empty environment (`--clear-env`), but for `keep_env`.
+    pub clear_env: bool,
+    /// Variables passed on when the environment is cleared (`--keep-env`).
+    pub keep_env: Vec<String>,
+    /// User and group IDs to run programs as (`--run-as`), which needs root.
+    pub user: Option<(u32, u32)>,
+}
+
+impl Sandbox {
+    /// A command running `program` (a name, such as `bitcoin-cli`) within the restrictions.
+    pub fn command(&self, program: &str) -> Result<Command> {
+        let mut command = match &self.allowed {
+            Some(allowed) => {
+                let path = allowed
+                    .iter()
+                    .find(|path| path.file_name().is_some_and(|name| name == program))
+                    .with_context(|| {
+                        format!("Refusing to run {}: not allowed by --allow-exec", program)
+                    })?;
+                Command::new(path)
+            }
+            None => Command::new(program),
+        };
+        if self.clear_env {
+            command.env_clear();
+            for name in &self.keep_env {
+                if let Some(value) = std::env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        if let Some((uid, gid)) = self.user {
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::CommandExt;
+                // Supplementary groups are dropped as well when running as root
+                command.uid(uid).gid(gid);
+            }
+            #[cfg(not(unix))]
+            bail!("--run-as {}:{} is only supported on Unix", uid, gid);
+        }
+        Ok(command)
+    }
+}
+
+/// Check that an `--allow-exec` path is absolute and names a file.
+pub fn check_allowed(path: &Path) -> Result<()> {
+    if !path.is_absolute() {
+        bail!("--allow-exec path {} is not absolute", path.display());
+    }
+    if !path.is_file() {
+        bail!("--allow-exec path {} is not a file", path.display());
+    }
+    Ok(())
+}
+
+/// Parse `UID[:GID]`, the group defaulting to the user ID.
+pub fn parse_user(spec: &str) -> Result<(u32, u32)> {
+    let (uid, gid) = spec.split_once(':').unwrap_or((spec, spec));
+    let uid = uid
+        .parse()
+        .with_context(|| format!("Invalid user ID in --run-as {}", spec))?;
+    let gid = gid
+        .parse()
+        .with_context(|| format!("Invalid group ID in --run-as {}", spec))?;
+    Ok((uid, gid))
+}
+
+/// Variables kept when the environment is cleared besides `--keep-env`, which
+/// the programs need to find their configuration and credentials.
+pub const DEFAULT_KEEP_ENV: &[&str] = &[
+    "HOME",
+    "DOCKER_HOST",
+    "DOCKER_CONFIG",
+    "XDG_RUNTIME_DIR",
+    "SSH_AUTH_SOCK",
+    "KUBECONFIG",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_runs_only_allowed_programs() {
+        let sandbox = Sandbox {
+            allowed: Some(vec![PathBuf::from("/opt/bitcoin/bin/bitcoin-cli")]),
+            ..Default::default()
+        };
+        let command = sandbox.command("bitcoin-cli").unwrap();
+        assert_eq!(command.get_program(), "/opt/bitcoin/bin/bitcoin-cli");
+        assert!(sandbox.command("docker").is_err());
+        assert!(sandbox.command("bitcoin").is_err());
+    }
+
+    #[test]
+    fn command_without_allowlist_uses_path() {
+        let command = Sandbox::default().command("docker").unwrap();
+        assert_eq!(command.get_program(), "docker");
+    }
+
+    #[test]
+    fn user_and_group() {
+        assert_eq!(parse_user("1000").unwrap(), (1000, 1000));
+        assert_eq!(parse_user("1000:100").unwrap(), (1000, 100));
+        assert!(parse_user("bitcoin").is_err());
+        assert!(parse_user("1000:").is_err());
+        assert!(parse_user(":100").is_err());
+        assert!(parse_user("-1").is_err());
+    }
+}
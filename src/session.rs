@@ -0,0 +1,128 @@
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Stdio};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+
+use crate::node::ContainerRuntime;
+use crate::sandbox::Sandbox;
+
+/// Marks the end of each call's output when calls share a shell, followed by its exit status.
+pub const CALL_END: &str = "--sign-txs-call-end--";
+
+/// Quote an argument for `sh`.
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// A line of shell script running `command`, then printing the end marker
+/// and its exit status on a line of their own.
+///
+/// Its stdin is closed, so it can't read the calls after it, and its stderr
+/// goes with its output.
+pub fn script_line(command: &[&str]) -> String {
+    let command: Vec<String> = command.iter().copied().map(shell_quote).collect();
+    format!(
+        "{} </dev/null 2>&1; printf '\\n{} %s\\n' $?\n",
+        command.join(" "),
+        CALL_END
+    )
+}
+
+/// A shell kept running in the container with `docker exec` (or `podman exec`), running the
+/// wallet calls written to its stdin one after the other.
+///
+/// Starting a `docker exec` takes longer than most wallet calls, so large
+/// batches spend most of their time on it when every call has its own. The
+/// shell is started on the first call and exits when the session is dropped.
+#[derive(Default)]
+pub struct Session {
+    shell: Mutex<Option<Shell>>,
+}
+
+struct Shell {
+    /// `docker` or `podman`, for errors.
+    runtime: &'static str,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Session {
+    /// Run `command` in `container`'s shell, returning whether it succeeded and its output.
+    pub fn run(
+        &self,
+        sandbox: &Sandbox,
+        runtime: ContainerRuntime,
+        container: &str,
+        command: &[&str],
+    ) -> Result<(bool, String)> {
+        let mut shell = self.shell.lock().expect("not poisoned");
+        if shell.is_none() {
+            *shell = Some(Shell::start(sandbox, runtime.program(), container)?);
+        }
+        let result = shell
+            .as_mut()
+            .expect("just started")
+            .run(&script_line(command));
+        // A shell that failed is in an unknown state: start another for the next call
+        if result.is_err() {
+            *shell = None;
+        }
+        result
+    }
+}
+
+impl Shell {
+    fn start(sandbox: &Sandbox, runtime: &'static str, container: &str) -> Result<Shell> {
+        let mut child = sandbox
+            .command(runtime)?
+            .args(["exec", "-i", container, "sh"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("Failed to execute {}", runtime))?;
+        let stdin = child.stdin.take();
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        Ok(Shell {
+            runtime,
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    fn run(&mut self, line: &str) -> Result<(bool, String)> {
+        let stdin = self.stdin.as_mut().expect("open until dropped");
+        stdin
+            .write_all(line.as_bytes())
+            .and_then(|()| stdin.flush())
+            .with_context(|| format!("Failed to write to the {} exec session", self.runtime))?;
+
+        let mut output = String::new();
+        loop {
+            let mut line = String::new();
+            if self
+                .stdout
+                .read_line(&mut line)
+                .with_context(|| format!("Failed to read from the {} exec session", self.runtime))?
+                == 0
+            {
+                bail!("{} exec session ended unexpectedly", self.runtime);
+            }
+            match line.strip_prefix(CALL_END) {
+                Some(status) => return Ok((status.trim() == "0", output.trim().to_string())),
+                None => output.push_str(&line),
+            }
+        }
+    }
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        // Closing stdin ends the shell
+        drop(self.stdin.take());
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,148 @@
+use bitcoin::hex::DisplayHex;
+use bitcoin::secp256k1::Message;
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::taproot::TapLeafHash;
+use bitcoin::{Psbt, TxOut};
+use serde::Serialize;
+
+/// A digest a signer of the input will be asked to sign.
+#[derive(Debug, Serialize)]
+pub struct InputSighash {
+    pub input: usize,
+    /// `ecdsa` for pre-taproot inputs, `taproot-keypath` or `taproot-scriptpath`.
+    pub path: &'static str,
+    /// Leaf the digest commits to, for taproot script path spends.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leaf_hash: Option<String>,
+    pub sighash_type: String,
+    /// The 32 bytes signed, in hex.
+    pub digest: String,
+}
+
+/// An input without a digest, and why.
+#[derive(Debug, Serialize)]
+pub struct SkippedInput {
+    pub input: usize,
+    pub reason: String,
+}
+
+/// The digests to be signed for a batch transaction.
+#[derive(Debug, Serialize)]
+pub struct TxSighashes {
+    /// Position in the batch, from 1.
+    pub index: usize,
+    pub txid: String,
+    pub sighashes: Vec<InputSighash>,
+    pub skipped: Vec<SkippedInput>,
+}
+
+/// The digests signing each unsigned input of `psbt` will produce, with the
+/// sighash type the PSBT asks for (`ALL`, or `DEFAULT` for taproot, if none).
+///
+/// Taproot inputs get their key path digest, and one for each leaf the PSBT
+/// has a script for. Pre-taproot inputs need the PSBT to carry what the
+/// sighash commits to: the spent output for segwit ones, the parent
+/// transaction for legacy ones, and the redeem or witness script for P2SH
+/// and P2WSH. Inputs missing it are skipped, as are those already signed.
+pub fn preview(index: usize, psbt: &Psbt) -> TxSighashes {
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    let mut sighashes = Vec::new();
+    let mut skipped = Vec::new();
+    for (i, input) in psbt.inputs.iter().enumerate() {
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            skipped.push(SkippedInput {
+                input: i,
+                reason: "already signed".into(),
+            });
+            continue;
+        }
+        let Some(spent) = spent_output(psbt, i) else {
+            skipped.push(SkippedInput {
+                input: i,
+                reason: "the output it spends is unknown".into(),
+            });
+            continue;
+        };
+
+        if !spent.script_pubkey.is_p2tr() {
+            match psbt.sighash_ecdsa(i, &mut cache) {
+                Ok((message, sighash_type)) => sighashes.push(InputSighash {
+                    input: i,
+                    path: "ecdsa",
+                    leaf_hash: None,
+                    sighash_type: sighash_type.to_string(),
+                    digest: message[..].to_lower_hex_string(),
+                }),
+                Err(e) => skipped.push(SkippedInput {
+                    input: i,
+                    reason: e.to_string(),
+                }),
+            }
+            continue;
+        }
+
+        let all_spent: Option<Vec<TxOut>> = (0..psbt.inputs.len())
+            .map(|j| spent_output(psbt, j).cloned())
+            .collect();
+        let Some(all_spent) = all_spent else {
+            skipped.push(SkippedInput {
+                input: i,
+                reason: "the outputs some other inputs spend are unknown".into(),
+            });
+            continue;
+        };
+        let sighash_type = match input.taproot_hash_ty() {
+            Ok(sighash_type) => sighash_type,
+            Err(e) => {
+                skipped.push(SkippedInput {
+                    input: i,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let prevouts = Prevouts::All(&all_spent);
+        let leaves = input
+            .tap_scripts
+            .values()
+            .map(|(script, version)| Some(TapLeafHash::from_script(script, *version)));
+        for leaf_hash in std::iter::once(None).chain(leaves) {
+            let sighash = match leaf_hash {
+                Some(leaf_hash) => {
+                    cache.taproot_script_spend_signature_hash(i, &prevouts, leaf_hash, sighash_type)
+                }
+                None => cache.taproot_key_spend_signature_hash(i, &prevouts, sighash_type),
+            };
+            match sighash {
+                Ok(sighash) => sighashes.push(InputSighash {
+                    input: i,
+                    path: match leaf_hash {
+                        Some(_) => "taproot-scriptpath",
+                        None => "taproot-keypath",
+                    },
+                    leaf_hash: leaf_hash.map(|hash| hash.to_string()),
+                    sighash_type: sighash_type.to_string(),
+                    digest: Message::from(sighash)[..].to_lower_hex_string(),
+                }),
+                Err(e) => skipped.push(SkippedInput {
+                    input: i,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+    }
+    TxSighashes {
+        index: index + 1,
+        txid: psbt.unsigned_tx.compute_txid().to_string(),
+        sighashes,
+        skipped,
+    }
+}
+
+fn spent_output(psbt: &Psbt, index: usize) -> Option<&TxOut> {
+    let input = &psbt.inputs[index];
+    input.witness_utxo.as_ref().or_else(|| {
+        let vout = psbt.unsigned_tx.input[index].previous_output.vout;
+        input.non_witness_utxo.as_ref()?.output.get(vout as usize)
+    })
+}
@@ -0,0 +1,1114 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use bitcoin::consensus::encode::{deserialize_hex, serialize_hex};
+use bitcoin::hex::DisplayHex;
+use bitcoin::{Amount, OutPoint, ScriptBuf, Transaction, TxOut};
+use serde::{Deserialize, Serialize};
+
+use crate::analysis;
+use crate::anchor::{self, Anchors};
+use crate::external;
+use crate::kms::Kms;
+use crate::node::{ChainType, Node};
+use crate::psbt;
+use crate::recover::{self, Recovery};
+use crate::rewrite;
+use crate::spell;
+use crate::taproot::TaprootKeys;
+use crate::witness;
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct DecodeResult {
+    pub vin: Vec<VinEntry>,
+    pub vout: Vec<VoutEntry>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct VinEntry {
+    pub txid: String,
+    pub vout: u32,
+    pub txinwitness: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TxInfo {
+    vout: Vec<VoutEntry>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct VoutEntry {
+    /// Absent for blinded Elements outputs, which carry `valuecommitment` instead.
+    #[serde(default, deserialize_with = "deserialize_btc")]
+    pub value: Option<Amount>,
+    pub valuecommitment: Option<String>,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pubkey: ScriptPubKey,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ScriptPubKey {
+    pub hex: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrevOut {
+    pub txid: String,
+    pub vout: u32,
+    #[serde(flatten)]
+    pub amount: PrevOutAmount,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pubkey: String,
+}
+
+/// Value of a prevout as passed to signrawtransactionwithwallet.
+#[derive(Debug, Clone, Serialize)]
+pub enum PrevOutAmount {
+    #[serde(rename = "amount", serialize_with = "serialize_btc")]
+    Explicit(Amount),
+    /// Confidential (blinded) Elements amount.
+    #[serde(rename = "amountcommitment")]
+    Blinded(String),
+}
+
+/// An amount in BTC with all 8 decimal places, as the node reads it exactly.
+fn btc_string(amount: Amount) -> String {
+    let sat = amount.to_sat();
+    format!("{}.{:08}", sat / 100_000_000, sat % 100_000_000)
+}
+
+/// Amounts go to the node as decimal strings, which it accepts wherever it
+/// takes a number: a float on the way can pick up digits past the eighth.
+fn serialize_btc<S: serde::Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&btc_string(*amount))
+}
+
+fn deserialize_btc<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Amount>, D::Error> {
+    Option::<f64>::deserialize(deserializer)?
+        .map(|value| Amount::from_btc(value).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl std::fmt::Display for PrevOutAmount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrevOutAmount::Explicit(amount) => write!(f, "amount={}", btc_string(*amount)),
+            PrevOutAmount::Blinded(commitment) => write!(f, "amountcommitment={}", commitment),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignResult {
+    pub hex: String,
+    pub complete: bool,
+    pub errors: Option<Vec<SignError>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignError {
+    pub txid: String,
+    pub vout: u32,
+    #[serde(flatten)]
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Where the outputs spent by the inputs to sign are looked up, and what signs them.
+///
+/// [`Node`] is one, with its wallet either behind the local CLI
+/// ([`Node::local`]) or in a Docker container ([`Node::docker`]). Code
+/// embedding the signing flow can bring its own in [`Signers::wallet`].
+pub trait Signer: Sync {
+    /// Value and scriptPubKey (hex) of the output `txid:vout`, `None` if the transaction has no such output.
+    fn prevout(&self, txid: &str, vout: u32) -> Result<Option<(PrevOutAmount, String)>>;
+
+    /// Sign the inputs it can, as `signrawtransactionwithwallet` does, given the outputs they spend.
+    fn sign(&self, raw_tx: &str, prevouts: &[&PrevOut]) -> Result<SignResult>;
+}
+
+impl Signer for Node {
+    fn prevout(&self, txid: &str, vout: u32) -> Result<Option<(PrevOutAmount, String)>> {
+        get_prevout_info(self, txid, vout)
+    }
+
+    fn sign(&self, raw_tx: &str, prevouts: &[&PrevOut]) -> Result<SignResult> {
+        let prevouts_json = serde_json::to_string(prevouts)?;
+        if !fits_command_line(self, raw_tx, &prevouts_json) {
+            return sign_in_chunks(self, raw_tx, prevouts);
+        }
+        sign_with_wallet(self, raw_tx, &prevouts_json)
+    }
+}
+
+/// Longest signing call, transaction and prevouts together, made in one
+/// piece on a command line: Linux takes no argument over 128 KiB, and over
+/// SSH the whole command is one.
+const MAX_COMMAND_LINE: usize = 100_000;
+
+fn sign_with_wallet(node: &Node, raw_tx: &str, prevouts_json: &str) -> Result<SignResult> {
+    let output = node.run_wallet(&["signrawtransactionwithwallet", raw_tx, prevouts_json])?;
+    serde_json::from_str(&output).context("Failed to parse sign result")
+}
+
+/// Whether a signing call fits the command line of the wallet's CLI, which
+/// JSON-RPC has none of.
+fn fits_command_line(node: &Node, raw_tx: &str, prevouts_json: &str) -> bool {
+    node.wallet_over_rpc() || raw_tx.len() + prevouts_json.len() <= MAX_COMMAND_LINE
+}
+
+/// Sign a transaction spending too many prevouts for one command line (such
+/// as a consolidation of hundreds of outputs), with a chunk of them per call,
+/// and merge the inputs each call signed into the transaction.
+///
+/// Each call is given the unsigned transaction, so the calls don't grow with
+/// the signatures. Taproot signatures commit to every prevout, so
+/// transactions spending taproot outputs can't be signed this way.
+fn sign_in_chunks(node: &Node, raw_tx: &str, prevouts: &[&PrevOut]) -> Result<SignResult> {
+    let too_large = || {
+        format!(
+            "Transaction too large to sign with {} on a command line, reach the node with --rpc-url instead",
+            node.chain_type.cli()
+        )
+    };
+    let budget = MAX_COMMAND_LINE.saturating_sub(raw_tx.len());
+    if node.chain_type != ChainType::Bitcoin || budget < MAX_COMMAND_LINE / 4 {
+        bail!(too_large());
+    }
+    let taproot = prevouts.iter().any(|prevout| {
+        ScriptBuf::from_hex(&prevout.script_pubkey).is_ok_and(|script| script.is_p2tr())
+    });
+    if taproot {
+        bail!(
+            "{}: it spends taproot outputs, whose signatures commit to every prevout",
+            too_large()
+        );
+    }
+
+    let mut chunks: Vec<Vec<&PrevOut>> = Vec::new();
+    let mut size = 0;
+    for prevout in prevouts {
+        let len = serde_json::to_string(prevout)?.len() + 1;
+        match chunks.last_mut() {
+            Some(chunk) if size + len <= budget => chunk.push(prevout),
+            _ => {
+                chunks.push(vec![prevout]);
+                size = 2;
+            }
+        }
+        size += len;
+    }
+    eprintln!(
+        "  Signing with the wallet in {} calls, the prevouts being too many for one command line...",
+        chunks.len()
+    );
+
+    let mut tx = rewrite::decode_tx(raw_tx)?;
+    let chunked: Vec<(String, u32)> = prevouts
+        .iter()
+        .map(|prevout| (prevout.txid.clone(), prevout.vout))
+        .collect();
+    let mut errors = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let result = sign_with_wallet(node, raw_tx, &serde_json::to_string(chunk)?)?;
+        let signed = rewrite::decode_tx(&result.hex)
+            .context("Failed to parse the transaction signed by the wallet")?;
+        let last = i + 1 == chunks.len();
+        // Inputs spending no prevout given are whatever the last call made of them
+        let ours = |txid: &str, vout: u32| {
+            chunk
+                .iter()
+                .any(|prevout| prevout.txid == txid && prevout.vout == vout)
+                || (last
+                    && !chunked
+                        .iter()
+                        .any(|(chunked, n)| chunked == txid && *n == vout))
+        };
+        for (input, signed) in tx.input.iter_mut().zip(signed.input) {
+            let outpoint = input.previous_output;
+            if ours(&outpoint.txid.to_string(), outpoint.vout) {
+                input.script_sig = signed.script_sig;
+                input.witness = signed.witness;
+            }
+        }
+        errors.extend(
+            result
+                .errors
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|error| ours(&error.txid, error.vout)),
+        );
+    }
+    Ok(SignResult {
+        hex: serialize_hex(&tx),
+        complete: errors.is_empty(),
+        errors: (!errors.is_empty()).then_some(errors),
+    })
+}
+
+/// Value and scriptPubKey (hex) of an output, as [`Signer::prevout`] finds it.
+type Prevout = (PrevOutAmount, String);
+
+/// A wallet signing the transactions of a batch ahead, several per call, and
+/// answering `sign` with what it signed.
+///
+/// Each `docker exec` (or HTTP request) has a setup cost, which dominates
+/// large batches when every transaction is signed with its own. The prevouts
+/// looked up ahead are kept for signing. A transaction signed again, or with
+/// other prevouts than guessed ahead (e.g. after importing descriptors), is
+/// signed by the wallet as usual.
+pub struct Presigned<'a> {
+    /// Where prevouts not looked up ahead come from, and what signs what wasn't signed ahead.
+    wallet: &'a dyn Signer,
+    prevouts: Mutex<HashMap<(String, u32), Option<Prevout>>>,
+    /// By raw transaction and prevouts (JSON).
+    signed: Mutex<HashMap<(String, String), SignResult>>,
+}
+
+impl<'a> Presigned<'a> {
+    /// Sign `raw_txs` with the node's wallet, `batch_size` per call, looking
+    /// prevouts up with [`Signers::wallet`] if set.
+    ///
+    /// Transactions that can't be signed ahead (e.g. with an annex) are left
+    /// for signing as usual.
+    pub fn sign(
+        node: &'a Node,
+        raw_txs: &[&str],
+        signers: &Signers<'a>,
+        anchors: &Anchors,
+        batch_size: usize,
+    ) -> Result<Self> {
+        let presigned = Presigned {
+            wallet: signers.wallet.unwrap_or(node),
+            prevouts: Mutex::new(HashMap::new()),
+            signed: Mutex::new(HashMap::new()),
+        };
+        let mut calls: Vec<(String, String)> = Vec::new();
+        for raw_tx in raw_txs {
+            let Ok(inputs) = decode_transaction(node, raw_tx)
+                .and_then(|decoded| wallet_inputs(&presigned, &decoded, signers, anchors, false))
+            else {
+                continue;
+            };
+            if inputs.prevouts.is_empty() {
+                continue;
+            }
+            let all_prevouts: Vec<&PrevOut> = inputs
+                .prevouts
+                .iter()
+                .chain(&inputs.local_prevouts)
+                .chain(&inputs.anchor_prevouts)
+                .collect();
+            let call = (raw_tx.to_string(), serde_json::to_string(&all_prevouts)?);
+            // Left for signing in chunks
+            if !fits_command_line(node, &call.0, &call.1) {
+                continue;
+            }
+            if !calls.contains(&call) {
+                calls.push(call);
+            }
+        }
+
+        for chunk in calls.chunks(batch_size.max(1)) {
+            eprintln!(
+                "  Signing {} transaction(s) with the wallet in one call...",
+                chunk.len()
+            );
+            let requests: Vec<Vec<&str>> = chunk
+                .iter()
+                .map(|(raw_tx, prevouts)| vec!["signrawtransactionwithwallet", raw_tx, prevouts])
+                .collect();
+            let results = node.run_wallet_many(&requests)?;
+            let mut signed = presigned.signed.lock().expect("not poisoned");
+            for (call, result) in chunk.iter().zip(results) {
+                // Failures are left for signing as usual, which reports them
+                if let Some(result) = result
+                    .ok()
+                    .and_then(|output| serde_json::from_str(&output).ok())
+                {
+                    signed.insert(call.clone(), result);
+                }
+            }
+        }
+        Ok(presigned)
+    }
+}
+
+impl Signer for Presigned<'_> {
+    fn prevout(&self, txid: &str, vout: u32) -> Result<Option<(PrevOutAmount, String)>> {
+        let key = (txid.to_string(), vout);
+        if let Some(prevout) = self.prevouts.lock().expect("not poisoned").get(&key) {
+            return Ok(prevout.clone());
+        }
+        let prevout = self.wallet.prevout(txid, vout)?;
+        self.prevouts
+            .lock()
+            .expect("not poisoned")
+            .insert(key, prevout.clone());
+        Ok(prevout)
+    }
+
+    fn sign(&self, raw_tx: &str, prevouts: &[&PrevOut]) -> Result<SignResult> {
+        let key = (raw_tx.to_string(), serde_json::to_string(prevouts)?);
+        match self.signed.lock().expect("not poisoned").get(&key).cloned() {
+            Some(result) => Ok(result),
+            None => self.wallet.sign(raw_tx, prevouts),
+        }
+    }
+}
+
+/// The outputs of a batch's transactions, answering prevout lookups for the
+/// inputs spending them ahead of the wallet, as they may not be on chain yet.
+///
+/// Signing changes the txid of a transaction with non-witness inputs, so
+/// each transaction should be [added](Self::add) again once signed, for the
+/// transactions after it spending its new txid.
+pub struct BatchOutputs<'a> {
+    wallet: &'a dyn Signer,
+    outputs: Mutex<HashMap<OutPoint, TxOut>>,
+}
+
+impl<'a> BatchOutputs<'a> {
+    /// The outputs of `txs`, other prevouts being looked up with `wallet`.
+    pub fn new(wallet: &'a dyn Signer, txs: &[Option<Transaction>]) -> Self {
+        let outputs = BatchOutputs {
+            wallet,
+            outputs: Mutex::new(HashMap::new()),
+        };
+        for tx in txs.iter().flatten() {
+            outputs.add(tx);
+        }
+        outputs
+    }
+
+    pub fn add(&self, tx: &Transaction) {
+        let txid = tx.compute_txid();
+        let mut outputs = self.outputs.lock().expect("not poisoned");
+        for (vout, output) in tx.output.iter().enumerate() {
+            outputs.insert(OutPoint::new(txid, vout as u32), output.clone());
+        }
+    }
+}
+
+impl Signer for BatchOutputs<'_> {
+    fn prevout(&self, txid: &str, vout: u32) -> Result<Option<(PrevOutAmount, String)>> {
+        let output = OutPoint::from_str(&format!("{}:{}", txid, vout))
+            .ok()
+            .and_then(|outpoint| {
+                self.outputs
+                    .lock()
+                    .expect("not poisoned")
+                    .get(&outpoint)
+                    .cloned()
+            });
+        match output {
+            Some(output) => Ok(Some((
+                PrevOutAmount::Explicit(output.value),
+                output.script_pubkey.to_hex_string(),
+            ))),
+            None => self.wallet.prevout(txid, vout),
+        }
+    }
+
+    fn sign(&self, raw_tx: &str, prevouts: &[&PrevOut]) -> Result<SignResult> {
+        self.wallet.sign(raw_tx, prevouts)
+    }
+}
+
+/// Prevouts looked up with one signer and inputs signed by another, for
+/// transactions of a batch routed to another wallet (their `wallet` field),
+/// which still find the outputs of the batch.
+pub struct Routed<'a> {
+    pub prevouts: &'a dyn Signer,
+    pub wallet: &'a dyn Signer,
+}
+
+impl Signer for Routed<'_> {
+    fn prevout(&self, txid: &str, vout: u32) -> Result<Option<(PrevOutAmount, String)>> {
+        self.prevouts.prevout(txid, vout)
+    }
+
+    fn sign(&self, raw_tx: &str, prevouts: &[&PrevOut]) -> Result<SignResult> {
+        self.wallet.sign(raw_tx, prevouts)
+    }
+}
+
+/// Wallets of a node signing in turn (`--wallets`), as the cosigners of a
+/// multisig do: each is given the transaction as the one before left it,
+/// with its signatures, until one completes it.
+///
+/// Prevouts are looked up with the first.
+pub struct Wallets {
+    /// By name.
+    pub wallets: Vec<(String, Node)>,
+}
+
+impl Signer for Wallets {
+    fn prevout(&self, txid: &str, vout: u32) -> Result<Option<(PrevOutAmount, String)>> {
+        match self.wallets.first() {
+            Some((_, wallet)) => wallet.prevout(txid, vout),
+            None => bail!("No wallet to look prevouts up with"),
+        }
+    }
+
+    fn sign(&self, raw_tx: &str, prevouts: &[&PrevOut]) -> Result<SignResult> {
+        let mut result: Option<SignResult> = None;
+        for (name, wallet) in &self.wallets {
+            let hex = result.as_ref().map_or(raw_tx, |result| &result.hex);
+            let signed = wallet
+                .sign(hex, prevouts)
+                .with_context(|| format!("Failed to sign with wallet {}", name))?;
+            let complete = signed.complete;
+            eprintln!(
+                "  Wallet {}: {}",
+                name,
+                if complete { "complete" } else { "incomplete" }
+            );
+            result = Some(signed);
+            if complete {
+                break;
+            }
+        }
+        result.context("No wallet to sign with")
+    }
+}
+
+/// The inputs and outputs of a transaction, as `decoderawtransaction` gives them.
+///
+/// Bitcoin transactions are decoded locally, and also by the node with
+/// [`Node::check_decode`], failing if it disagrees. Elements transactions
+/// are always decoded by the node.
+pub fn decode_transaction(node: &Node, raw_tx: &str) -> Result<DecodeResult> {
+    if node.chain_type == ChainType::Elements {
+        return decode_with_node(node, raw_tx);
+    }
+    let tx = rewrite::decode_tx(raw_tx)?;
+    let decoded = DecodeResult {
+        vin: tx
+            .input
+            .iter()
+            .map(|input| VinEntry {
+                txid: input.previous_output.txid.to_string(),
+                vout: input.previous_output.vout,
+                txinwitness: (!input.witness.is_empty()).then(|| {
+                    input
+                        .witness
+                        .iter()
+                        .map(|element| element.to_lower_hex_string())
+                        .collect()
+                }),
+            })
+            .collect(),
+        vout: tx
+            .output
+            .iter()
+            .map(|output| VoutEntry {
+                value: Some(output.value),
+                valuecommitment: None,
+                script_pubkey: ScriptPubKey {
+                    hex: output.script_pubkey.to_hex_string(),
+                },
+            })
+            .collect(),
+    };
+    if node.check_decode && decode_with_node(node, raw_tx)? != decoded {
+        bail!(
+            "The node decodes transaction {} differently",
+            tx.compute_txid()
+        );
+    }
+    Ok(decoded)
+}
+
+fn decode_with_node(node: &Node, raw_tx: &str) -> Result<DecodeResult> {
+    let output = node.run(&["decoderawtransaction", raw_tx])?;
+    serde_json::from_str(&output).context("Failed to parse decoded transaction")
+}
+
+fn get_prevout_info(node: &Node, txid: &str, vout: u32) -> Result<Option<(PrevOutAmount, String)>> {
+    let vout_entry = match node.raw_transaction(txid) {
+        Ok(output) => {
+            let mut tx_info: TxInfo =
+                serde_json::from_str(&output).context("Failed to parse transaction info")?;
+            if vout as usize >= tx_info.vout.len() {
+                return Ok(None);
+            }
+            tx_info.vout.swap_remove(vout as usize)
+        }
+        // Without -txindex the node only finds wallet and mempool transactions,
+        // but it has every unspent output
+        Err(e) => match unspent_output(node, txid, vout) {
+            Ok(Some(entry)) => entry,
+            Ok(None) | Err(_) if node.scan_utxo_set => match scanned_output(node, txid, vout) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => {
+                    return Err(e.context(format!(
+                        "{}:{} isn't in the node's UTXO set either (gettxout), nor among the wallet's unspent outputs (scantxoutset)",
+                        txid, vout
+                    )))
+                }
+                Err(scan) => return Err(e.context(format!("scantxoutset failed too: {:#}", scan))),
+            },
+            Ok(None) => {
+                return Err(e.context(format!(
+                    "{}:{} isn't in the node's UTXO set either (gettxout)",
+                    txid, vout
+                )))
+            }
+            Err(_) => return Err(e),
+        },
+    };
+    let amount = match (vout_entry.value, &vout_entry.valuecommitment) {
+        (Some(value), _) => PrevOutAmount::Explicit(value),
+        (None, Some(commitment)) => PrevOutAmount::Blinded(commitment.clone()),
+        (None, None) => bail!(
+            "Output {}:{} has neither a value nor a value commitment",
+            txid,
+            vout
+        ),
+    };
+
+    Ok(Some((amount, vout_entry.script_pubkey.hex.clone())))
+}
+
+/// The output `txid:vout` in the node's UTXO set (`gettxout`, the mempool's
+/// included), `None` if it is spent or was never created.
+fn unspent_output(node: &Node, txid: &str, vout: u32) -> Result<Option<VoutEntry>> {
+    let output = node.run(&["gettxout", txid, &vout.to_string(), "true"])?;
+    // bitcoin-cli prints nothing for a null result
+    match output.trim() {
+        "" | "null" => Ok(None),
+        output => serde_json::from_str(output)
+            .map(Some)
+            .context("Failed to parse gettxout result"),
+    }
+}
+
+/// The output `txid:vout` among the wallet's unspent outputs in the UTXO set
+/// (`scantxoutset`, see [`Node::scanned_output`]), `None` if it isn't one.
+fn scanned_output(node: &Node, txid: &str, vout: u32) -> Result<Option<VoutEntry>> {
+    node.scanned_output(txid, vout)?
+        .map(|output| serde_json::from_str(&output).context("Failed to parse scanned output"))
+        .transpose()
+}
+
+/// The output at `txid:vout`, looked up with `wallet` (on chain, for a [`Node`]).
+pub fn prevout_txout(wallet: &dyn Signer, txid: &str, vout: u32) -> Result<TxOut> {
+    let Some((amount, script_pubkey)) = wallet.prevout(txid, vout)? else {
+        bail!("Prevout {}:{} not found", txid, vout);
+    };
+    let PrevOutAmount::Explicit(amount) = amount else {
+        bail!("Prevout {}:{} has a blinded amount", txid, vout);
+    };
+    Ok(TxOut {
+        value: amount,
+        script_pubkey: ScriptBuf::from_hex(&script_pubkey)?,
+    })
+}
+
+/// Outputs spent by every input of a transaction, in input order.
+pub fn spent_outputs(wallet: &dyn Signer, decoded: &DecodeResult) -> Result<Vec<TxOut>> {
+    decoded
+        .vin
+        .iter()
+        .map(|input| prevout_txout(wallet, &input.txid, input.vout))
+        .collect()
+}
+
+/// Print what is missing for each input the wallet could not complete.
+fn explain_incomplete(
+    node: &Node,
+    signed_hex: &str,
+    decoded: &DecodeResult,
+    prevouts: &[PrevOut],
+    errors: &[&SignError],
+) {
+    let Ok(tx) = deserialize_hex::<Transaction>(signed_hex) else {
+        return;
+    };
+    for error in errors {
+        let Some(i) = decoded
+            .vin
+            .iter()
+            .position(|input| input.txid == error.txid && input.vout == error.vout)
+        else {
+            continue;
+        };
+        let Some(PrevOut {
+            amount: PrevOutAmount::Explicit(amount),
+            script_pubkey,
+            ..
+        }) = prevouts
+            .iter()
+            .find(|prevout| prevout.txid == error.txid && prevout.vout == error.vout)
+        else {
+            continue;
+        };
+        let Ok(script_pubkey) = ScriptBuf::from_hex(script_pubkey) else {
+            continue;
+        };
+        let spent = TxOut {
+            value: *amount,
+            script_pubkey,
+        };
+        match analysis::explain_input(node, &tx, i, &spent) {
+            Ok(notes) => {
+                for note in notes {
+                    eprintln!("  Input {}: {}", i, note);
+                }
+            }
+            Err(e) => eprintln!("  Input {}: could not analyze: {:#}", i, e),
+        }
+    }
+}
+
+/// What signs the inputs the node wallet can't, and how to recover when it can't.
+#[derive(Default, Clone, Copy)]
+pub struct Signers<'a> {
+    /// Looks up prevouts and signs with its wallet instead of the node.
+    pub wallet: Option<&'a dyn Signer>,
+    pub taproot_keys: Option<&'a TaprootKeys>,
+    pub recovery: Option<&'a Recovery>,
+    /// AWS KMS keys (`--kms-key`).
+    pub kms: Option<&'a Kms>,
+    /// External signer program (`--signer-cmd`).
+    pub signer_cmd: Option<&'a str>,
+    /// The node's wallet has private keys disabled: the inputs it would sign
+    /// are left unsigned, for PSBTs.
+    pub watch_only: bool,
+}
+
+/// The inputs of a transaction by what signs them, and the prevouts the wallet is given.
+#[derive(Default)]
+struct WalletInputs {
+    prevouts: Vec<PrevOut>,
+    local_inputs: Vec<usize>,
+    // Signed with the provided Taproot keys, but taproot sighashes of the
+    // wallet's inputs commit to every prevout, so the wallet is told theirs too
+    local_prevouts: Vec<PrevOut>,
+    kms_inputs: Vec<usize>,
+    // Anchors need no signature, but the wallet is told their prevouts for sighashes
+    anchor_inputs: Vec<usize>,
+    anchor_prevouts: Vec<PrevOut>,
+}
+
+/// Sort the inputs of a transaction, looking their prevouts up with `wallet`, and reporting what was found if `log`.
+fn wallet_inputs(
+    wallet: &dyn Signer,
+    decoded: &DecodeResult,
+    signers: &Signers,
+    anchors: &Anchors,
+    log: bool,
+) -> Result<WalletInputs> {
+    macro_rules! note {
+        ($($arg:tt)*) => {
+            if log {
+                eprintln!($($arg)*);
+            }
+        };
+    }
+
+    let mut inputs = WalletInputs::default();
+
+    for (i, input) in decoded.vin.iter().enumerate() {
+        // Check if this input has witness data (already signed)
+        let input_witness = input.txinwitness.as_deref();
+        if witness::is_signed(input_witness) {
+            note!("  Input {}: already signed, skipping", i);
+            continue;
+        }
+
+        // The signature for an input with an annex has to commit to it, which
+        // the wallet cannot do: it would sign without the annex and drop it
+        if let Some(annex) = input_witness.and_then(witness::annex) {
+            bail!(
+                "Input {} carries an annex ({}) but is not signed yet; the wallet cannot sign it",
+                i,
+                annex
+            );
+        }
+
+        if let Some(anchor) = anchors.get(&input.txid, input.vout) {
+            note!(
+                "  Input {}: {}:{} - pay-to-anchor, no signature needed",
+                i,
+                input.txid,
+                input.vout
+            );
+            inputs.anchor_inputs.push(i);
+            inputs.anchor_prevouts.push(PrevOut {
+                txid: input.txid.clone(),
+                vout: input.vout,
+                amount: PrevOutAmount::Explicit(anchor.value),
+                script_pubkey: anchor.script_pubkey.to_hex_string(),
+            });
+            continue;
+        }
+
+        if signers
+            .taproot_keys
+            .is_some_and(|keys| keys.contains(&input.txid, input.vout))
+        {
+            note!(
+                "  Input {}: {}:{} - keypath spend with provided internal key",
+                i,
+                input.txid,
+                input.vout
+            );
+            inputs.local_inputs.push(i);
+            let prevout = wallet.prevout(&input.txid, input.vout).with_context(|| {
+                format!(
+                    "Failed to find the output input {} spends ({}:{}) in the batch or with the node",
+                    i, input.txid, input.vout
+                )
+            })?;
+            if let Some((amount, script_pubkey)) = prevout {
+                inputs.local_prevouts.push(PrevOut {
+                    txid: input.txid.clone(),
+                    vout: input.vout,
+                    amount,
+                    script_pubkey,
+                });
+            }
+            continue;
+        }
+
+        note!(
+            "  Input {}: {}:{} - fetching prevout info...",
+            i,
+            input.txid,
+            input.vout
+        );
+
+        // Get the previous output info from the remote node
+        let prevout = wallet.prevout(&input.txid, input.vout).with_context(|| {
+            format!(
+                "Failed to find the output input {} spends ({}:{}) in the batch or with the node",
+                i, input.txid, input.vout
+            )
+        })?;
+        match prevout {
+            Some((amount, script_pubkey))
+                if ScriptBuf::from_hex(&script_pubkey)
+                    .is_ok_and(|script| anchor::is_p2a(&script)) =>
+            {
+                note!("  Input {}: pay-to-anchor, no signature needed", i);
+                inputs.anchor_inputs.push(i);
+                inputs.anchor_prevouts.push(PrevOut {
+                    txid: input.txid.clone(),
+                    vout: input.vout,
+                    amount,
+                    script_pubkey,
+                });
+            }
+            Some((amount, script_pubkey)) => {
+                note!("  Input {}: {}, scriptPubKey={}", i, amount, script_pubkey);
+                if signers.kms.is_some_and(|kms| {
+                    ScriptBuf::from_hex(&script_pubkey)
+                        .is_ok_and(|script| kms.key_for(&script).is_some())
+                }) {
+                    note!("  Input {}: key held in AWS KMS", i);
+                    inputs.kms_inputs.push(i);
+                }
+                inputs.prevouts.push(PrevOut {
+                    txid: input.txid.clone(),
+                    vout: input.vout,
+                    amount,
+                    script_pubkey,
+                });
+            }
+            None => {
+                note!(
+                    "  Input {}: prevout not found, the transaction has no such output",
+                    i
+                );
+            }
+        }
+    }
+
+    Ok(inputs)
+}
+
+pub fn sign_transaction(
+    node: &Node,
+    raw_tx: &str,
+    tx_index: usize,
+    verify_spell: bool,
+    signers: &Signers,
+    anchors: &Anchors,
+) -> Result<String> {
+    let taproot_keys = signers.taproot_keys;
+    let wallet = signers.wallet.unwrap_or(node);
+    eprintln!("\nProcessing transaction {}...", tx_index + 1);
+
+    // Decode the transaction to get inputs
+    let decoded = decode_transaction(node, raw_tx)?;
+
+    let WalletInputs {
+        prevouts,
+        local_inputs,
+        local_prevouts,
+        kms_inputs,
+        anchor_inputs,
+        anchor_prevouts,
+    } = wallet_inputs(wallet, &decoded, signers, anchors, true)?;
+
+    if prevouts.is_empty() && local_inputs.is_empty() {
+        eprintln!("  No inputs to sign, returning original transaction");
+        return Ok(raw_tx.to_string());
+    }
+
+    let mut signed_hex = raw_tx.to_string();
+
+    if !prevouts.is_empty() && signers.watch_only {
+        eprintln!(
+            "  Leaving {} input(s) unsigned, the wallet being watch-only",
+            prevouts.len()
+        );
+    } else if !prevouts.is_empty() {
+        eprintln!("  Signing {} input(s) with wallet...", prevouts.len());
+
+        // Sign with wallet (either via Docker or local bitcoin-cli)
+        let all_prevouts: Vec<&PrevOut> = prevouts
+            .iter()
+            .chain(&local_prevouts)
+            .chain(&anchor_prevouts)
+            .collect();
+        let mut sign_result = wallet.sign(raw_tx, &all_prevouts)?;
+
+        // A wallet missing the input scripts is the usual case on a fresh signer node
+        let unknown = sign_result.errors.iter().flatten().any(|error| {
+            error
+                .details
+                .get("error")
+                .and_then(|message| message.as_str())
+                .is_some_and(recover::is_unknown_script)
+        });
+        if let Some(recovery) = signers.recovery.filter(|_| unknown) {
+            eprintln!("  Wallet doesn't know some input scripts, importing descriptors...");
+            if recovery.run(node)? {
+                sign_result = wallet.sign(raw_tx, &all_prevouts)?;
+            }
+        }
+
+        // Inputs signed locally below, and anchors, are expected to be incomplete at this point
+        let errors: Vec<&SignError> = sign_result
+            .errors
+            .iter()
+            .flatten()
+            .filter(|error| {
+                !local_inputs
+                    .iter()
+                    .chain(&kms_inputs)
+                    .chain(&anchor_inputs)
+                    .any(|&i| {
+                        decoded.vin[i].txid == error.txid && decoded.vin[i].vout == error.vout
+                    })
+            })
+            .collect();
+
+        if sign_result.complete || errors.is_empty() {
+            eprintln!("  Wallet inputs signed");
+        } else if !errors.is_empty() {
+            eprintln!(
+                "  Warning: Transaction not fully signed. Errors: {}",
+                serde_json::to_string_pretty(&errors)?
+            );
+            explain_incomplete(node, &sign_result.hex, &decoded, &prevouts, &errors);
+        }
+
+        signed_hex = sign_result.hex;
+    }
+
+    if let Some(keys) = taproot_keys.filter(|_| !local_inputs.is_empty()) {
+        eprintln!(
+            "  Signing {} input(s) with provided Taproot keys...",
+            local_inputs.len()
+        );
+        let spent = spent_outputs(wallet, &decoded)?;
+        signed_hex = keys.sign(&signed_hex, &local_inputs, &spent)?;
+    }
+
+    if let Some(kms) = signers.kms.filter(|_| !kms_inputs.is_empty()) {
+        let mut tx = rewrite::decode_tx(&signed_hex)?;
+        let unsigned: Vec<usize> = kms_inputs
+            .iter()
+            .copied()
+            .filter(|&i| tx.input[i].witness.is_empty() && tx.input[i].script_sig.is_empty())
+            .collect();
+        if !unsigned.is_empty() {
+            eprintln!("  Signing {} input(s) with AWS KMS...", unsigned.len());
+            let spent = spent_outputs(wallet, &decoded)?;
+            let count = kms.sign(&mut tx, &unsigned, &spent)?;
+            eprintln!("  AWS KMS signed {} input(s)", count);
+            signed_hex = rewrite::encode_tx(&tx);
+        }
+    }
+
+    if let Some(cmd) = signers.signer_cmd {
+        let mut tx = rewrite::decode_tx(&signed_hex)?;
+        let unsigned: Vec<usize> = (0..tx.input.len())
+            .filter(|&i| {
+                let input = &tx.input[i];
+                input.witness.is_empty()
+                    && input.script_sig.is_empty()
+                    && !anchor_inputs.contains(&i)
+            })
+            .collect();
+        if !unsigned.is_empty() {
+            eprintln!(
+                "  Signing {} input(s) with external signer {}...",
+                unsigned.len(),
+                cmd
+            );
+            let spent = spent_outputs(wallet, &decoded).ok();
+            let mut psbt = psbt::from_tx(&tx, spent.as_deref())?;
+            if let Ok(updated) = psbt::wallet_update(node, &psbt) {
+                psbt = updated;
+            }
+            let signed = external::sign(cmd, &psbt, &unsigned)?;
+            let count = psbt::apply_final(&mut tx, &finalize(node, &signed)?);
+            eprintln!("  External signer signed {} input(s)", count);
+            signed_hex = rewrite::encode_tx(&tx);
+        }
+    }
+
+    if verify_spell || witness::has_annex(&decoded) {
+        let signed = decode_transaction(node, &signed_hex)?;
+        witness::check_annexes_preserved(&decoded, &signed)
+            .with_context(|| format!("Annex lost in transaction {}", tx_index + 1))?;
+        if verify_spell {
+            spell::verify_commitment(&decoded, &signed).with_context(|| {
+                format!("Spell commitment broken in transaction {}", tx_index + 1)
+            })?;
+            eprintln!("  Spell commitment intact");
+        }
+    }
+
+    Ok(signed_hex)
+}
+
+/// Stops [`sign_batch`] before its next transaction once cancelled, e.g. from
+/// another thread. Clones share the same state.
+#[derive(Debug, Default, Clone)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What [`sign_batch`] reports as it goes, transactions being identified by their position in the batch.
+#[derive(Debug)]
+pub enum Progress<'a> {
+    /// Transaction `index` is next, `done` of the `total` having been signed (or failed) already.
+    Signing {
+        index: usize,
+        done: usize,
+        total: usize,
+    },
+    /// Transaction `index` is signed, with its hex, or failed to be.
+    Done {
+        index: usize,
+        result: &'a Result<String>,
+    },
+}
+
+/// Sign a batch of raw transactions with [`sign_transaction`], reporting
+/// each step to `progress`, and stopping before the next transaction once
+/// `cancel` is cancelled.
+///
+/// As with the binary, parents are signed before their children, and inputs
+/// spending outputs of the batch find them there rather than with the node.
+/// A transaction failing to sign doesn't stop the others. Returns each
+/// transaction's result in batch order, `None` for those not reached before
+/// the cancellation; only a batch that can't be ordered (its transactions
+/// spending each other's outputs in a cycle) fails as a whole.
+pub fn sign_batch(
+    node: &Node,
+    raw_txs: &[&str],
+    signers: &Signers,
+    cancel: &Cancellation,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Vec<Option<Result<String>>>> {
+    // Elements transactions can't be decoded locally, and have no anchors
+    let txs: Vec<Option<Transaction>> = match node.chain_type {
+        ChainType::Bitcoin => raw_txs
+            .iter()
+            .map(|raw_tx| rewrite::decode_tx(raw_tx).ok())
+            .collect(),
+        ChainType::Elements => Vec::new(),
+    };
+    let anchors = Anchors::new(&txs);
+    let order = match node.chain_type {
+        ChainType::Bitcoin => rewrite::topological_order(&txs)?,
+        ChainType::Elements => (0..raw_txs.len()).collect(),
+    };
+    let outputs = BatchOutputs::new(signers.wallet.unwrap_or(node), &txs);
+    let signers = Signers {
+        wallet: Some(&outputs),
+        ..*signers
+    };
+
+    let mut results: Vec<Option<Result<String>>> = raw_txs.iter().map(|_| None).collect();
+    for (done, &index) in order.iter().enumerate() {
+        if cancel.is_cancelled() {
+            eprintln!(
+                "\nCancelled, {} transaction(s) left unsigned",
+                order.len() - done
+            );
+            break;
+        }
+        progress(Progress::Signing {
+            index,
+            done,
+            total: order.len(),
+        });
+        let result = sign_transaction(node, raw_txs[index], index, false, &signers, &anchors);
+        // Signing changes the txid of transactions with non-witness inputs
+        let signed_tx = match (node.chain_type, &result) {
+            (ChainType::Bitcoin, Ok(signed_hex)) => rewrite::decode_tx(signed_hex).ok(),
+            _ => None,
+        };
+        if let Some(tx) = signed_tx {
+            outputs.add(&tx);
+        }
+        progress(Progress::Done {
+            index,
+            result: &result,
+        });
+        results[index] = Some(result);
+    }
+    Ok(results)
+}
+
+#[derive(Debug, Deserialize)]
+struct FinalizeResult {
+    psbt: Option<String>,
+    hex: Option<String>,
+}
+
+/// Finalize the inputs of a PSBT that have all their signatures (`finalizepsbt`, without extracting).
+pub fn finalize(node: &Node, psbt: &bitcoin::Psbt) -> Result<bitcoin::Psbt> {
+    let result: FinalizeResult =
+        serde_json::from_str(&node.run(&["finalizepsbt", &psbt.to_string(), "false"])?)
+            .context("Failed to parse finalizepsbt result")?;
+    match (result.psbt, result.hex) {
+        (Some(psbt), _) => Ok(bitcoin::Psbt::from_str(&psbt)?),
+        (None, Some(hex)) => psbt::from_tx(&rewrite::decode_tx(&hex)?, None),
+        (None, None) => bail!("finalizepsbt returned nothing"),
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use bitcoin::{Amount, OutPoint, ScriptBuf, Transaction, TxOut};
+use serde::{Deserialize, Serialize};
+
+use crate::anchor;
+use crate::mempool;
+use crate::node::Node;
+use crate::verify::{self, InputCheck};
+
+/// Largest standard transaction weight (`MAX_STANDARD_TX_WEIGHT`).
+const MAX_STANDARD_WEIGHT: u64 = 400_000;
+
+#[derive(Debug, Deserialize)]
+struct TxOutInfo {
+    value: f64,
+    #[serde(rename = "scriptPubKey")]
+    script_pub_key: ScriptPubKey,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScriptPubKey {
+    hex: String,
+}
+
+/// What the simulated mempool made of a batch transaction.
+#[derive(Debug, Serialize)]
+pub struct Verdict {
+    /// Position in the batch, from 1.
+    pub index: usize,
+    pub txid: String,
+    pub accepted: bool,
+    /// Position in the acceptance order, from 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+    /// Satoshis.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<u64>,
+    /// sat/vB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// Outcome of a simulation: every bitcoin transaction's verdict, in batch order,
+/// and the order (batch positions) they were accepted in.
+#[derive(Debug, Serialize)]
+pub struct Simulation {
+    pub order: Vec<usize>,
+    /// Whether the batch order works as is, every transaction coming after its parents.
+    pub in_order: bool,
+    pub transactions: Vec<Verdict>,
+}
+
+/// The output `outpoint` as the node has it unspent (mempool included), if it does.
+fn unspent(node: &Node, outpoint: &OutPoint) -> Result<Option<TxOut>> {
+    let output = node.run(&[
+        "gettxout",
+        &outpoint.txid.to_string(),
+        &outpoint.vout.to_string(),
+        "true",
+    ])?;
+    if output.trim().is_empty() {
+        return Ok(None);
+    }
+    let info: TxOutInfo = serde_json::from_str(&output).context("Failed to parse gettxout")?;
+    Ok(Some(TxOut {
+        value: Amount::from_btc(info.value)?,
+        script_pubkey: ScriptBuf::from_hex(&info.script_pub_key.hex)?,
+    }))
+}
+
+/// Run the batch through a model of the node's mempool, without sending anything.
+///
+/// The UTXO set starts as the node's unspent outputs the batch spends (mempool
+/// included), and transactions are accepted as soon as their inputs are
+/// available, batch order first, each adding its outputs. A transaction is
+/// rejected if an input is missing or already spent (by the chain or an
+/// earlier batch transaction), a signature doesn't verify locally, its outputs
+/// exceed its inputs, or it is over the standard weight. Feerates below the
+/// mempool's minimum are warnings, as a child may still carry it as a package.
+pub fn run(node: &Node, txs: &[Option<Transaction>]) -> Result<Simulation> {
+    let min_fee_rate = mempool::min_fee_rate(node)?;
+
+    let mut available: HashMap<OutPoint, TxOut> = HashMap::new();
+    let mut chain_spent: Vec<OutPoint> = Vec::new();
+    let batch_txids: Vec<_> = txs
+        .iter()
+        .map(|tx| tx.as_ref().map(Transaction::compute_txid))
+        .collect();
+    for tx in txs.iter().flatten() {
+        for input in &tx.input {
+            let outpoint = input.previous_output;
+            if batch_txids.contains(&Some(outpoint.txid)) || available.contains_key(&outpoint) {
+                continue;
+            }
+            match unspent(node, &outpoint)? {
+                Some(output) => {
+                    available.insert(outpoint, output);
+                }
+                None => chain_spent.push(outpoint),
+            }
+        }
+    }
+
+    let mut spent_by: HashMap<OutPoint, usize> = HashMap::new();
+    let mut verdicts: Vec<Option<Verdict>> = txs.iter().map(|_| None).collect();
+    let mut order = Vec::new();
+    loop {
+        let mut progress = false;
+        for (i, tx) in txs.iter().enumerate() {
+            let Some(tx) = tx.as_ref().filter(|_| verdicts[i].is_none()) else {
+                continue;
+            };
+            let outpoints: Vec<OutPoint> =
+                tx.input.iter().map(|input| input.previous_output).collect();
+            if let Some((outpoint, j)) = outpoints
+                .iter()
+                .find_map(|outpoint| spent_by.get(outpoint).map(|&j| (outpoint, j)))
+            {
+                verdicts[i] = Some(rejected(
+                    i,
+                    tx,
+                    format!(
+                        "input {} already spent by batch transaction {}",
+                        outpoint,
+                        j + 1
+                    ),
+                ));
+                progress = true;
+                continue;
+            }
+            if !outpoints
+                .iter()
+                .all(|outpoint| available.contains_key(outpoint))
+            {
+                continue;
+            }
+
+            let spent: Vec<TxOut> = outpoints
+                .iter()
+                .map(|outpoint| available[outpoint].clone())
+                .collect();
+            progress = true;
+            match check(tx, &spent, min_fee_rate) {
+                Ok((fee, fee_rate, warnings)) => {
+                    for outpoint in outpoints {
+                        spent_by.insert(outpoint, i);
+                    }
+                    let txid = tx.compute_txid();
+                    for (vout, output) in tx.output.iter().enumerate() {
+                        available.insert(OutPoint::new(txid, vout as u32), output.clone());
+                    }
+                    order.push(i + 1);
+                    verdicts[i] = Some(Verdict {
+                        index: i + 1,
+                        txid: txid.to_string(),
+                        accepted: true,
+                        position: Some(order.len()),
+                        fee: Some(fee),
+                        fee_rate: Some(fee_rate),
+                        reason: None,
+                        warnings,
+                    });
+                }
+                Err(reason) => verdicts[i] = Some(rejected(i, tx, reason)),
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+
+    // What is left misses inputs, from the chain or from rejected batch transactions
+    for (i, tx) in txs.iter().enumerate() {
+        let Some(tx) = tx.as_ref().filter(|_| verdicts[i].is_none()) else {
+            continue;
+        };
+        let missing: Vec<String> = tx
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .filter(|outpoint| !available.contains_key(outpoint))
+            .map(|outpoint| {
+                match batch_txids
+                    .iter()
+                    .position(|txid| *txid == Some(outpoint.txid))
+                {
+                    Some(j) => format!(
+                        "{} (of batch transaction {}, not accepted)",
+                        outpoint,
+                        j + 1
+                    ),
+                    None if chain_spent.contains(&outpoint) => {
+                        format!("{} (missing or spent)", outpoint)
+                    }
+                    None => outpoint.to_string(),
+                }
+            })
+            .collect();
+        verdicts[i] = Some(rejected(
+            i,
+            tx,
+            format!("missing inputs: {}", missing.join(", ")),
+        ));
+    }
+
+    let in_order = order.windows(2).all(|pair| pair[0] < pair[1]);
+    Ok(Simulation {
+        order,
+        in_order,
+        transactions: verdicts.into_iter().flatten().collect(),
+    })
+}
+
+fn rejected(i: usize, tx: &Transaction, reason: String) -> Verdict {
+    Verdict {
+        index: i + 1,
+        txid: tx.compute_txid().to_string(),
+        accepted: false,
+        position: None,
+        fee: None,
+        fee_rate: None,
+        reason: Some(reason),
+        warnings: Vec::new(),
+    }
+}
+
+/// Check a transaction whose inputs are all available, returning its fee, feerate and warnings.
+fn check(
+    tx: &Transaction,
+    spent: &[TxOut],
+    min_fee_rate: f64,
+) -> std::result::Result<(u64, f64, Vec<String>), String> {
+    let mut warnings = Vec::new();
+    for index in 0..tx.input.len() {
+        if anchor::is_p2a(&spent[index].script_pubkey) {
+            continue;
+        }
+        match verify::check_input(tx, index, spent) {
+            InputCheck::Valid => {}
+            InputCheck::Unknown => warnings.push(format!(
+                "input {} is a taproot script path spend, not verified",
+                index
+            )),
+            InputCheck::Unsigned => return Err(format!("input {} is not signed", index)),
+            InputCheck::Partial => return Err(format!("input {} is missing signatures", index)),
+            InputCheck::Invalid(reason) => return Err(format!("input {}: {}", index, reason)),
+        }
+    }
+
+    let input_value: u64 = spent.iter().map(|output| output.value.to_sat()).sum();
+    let output_value: u64 = tx.output.iter().map(|output| output.value.to_sat()).sum();
+    let Some(fee) = input_value.checked_sub(output_value) else {
+        return Err(format!(
+            "outputs ({} sat) exceed inputs ({} sat)",
+            output_value, input_value
+        ));
+    };
+    let weight = tx.weight().to_wu();
+    if weight > MAX_STANDARD_WEIGHT {
+        return Err(format!(
+            "weight {} is over the standard {}",
+            weight, MAX_STANDARD_WEIGHT
+        ));
+    }
+    let fee_rate = fee as f64 / tx.vsize() as f64;
+    if fee_rate < min_fee_rate {
+        warnings.push(format!(
+            "feerate {:.2} sat/vB is below the mempool minimum {:.2}, needs a child paying for it",
+            fee_rate, min_fee_rate
+        ));
+    }
+    Ok((fee, fee_rate, warnings))
+}
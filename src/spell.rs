@@ -0,0 +1,47 @@
+use anyhow::{bail, Result};
+
+use crate::sign::DecodeResult;
+
+/// Push of the `spell` tag opening a Charms envelope (`OP_FALSE OP_IF "spell" ...`).
+const ENVELOPE_MARKER: &str = "0063057370656c6c";
+
+/// Check that signing left the spell commitment of a transaction intact.
+///
+/// The wallet is only supposed to add witnesses for the inputs it was asked to
+/// sign: inputs, outputs, and every witness already present in the unsigned
+/// transaction (in particular the one carrying the spell envelope) must come
+/// out byte-for-byte identical, or the proof no longer matches the transaction.
+pub fn verify_commitment(unsigned: &DecodeResult, signed: &DecodeResult) -> Result<()> {
+    if unsigned.vin.len() != signed.vin.len() || unsigned.vout.len() != signed.vout.len() {
+        bail!("signed transaction has a different number of inputs or outputs");
+    }
+
+    for (i, (before, after)) in unsigned.vin.iter().zip(&signed.vin).enumerate() {
+        if before.txid != after.txid || before.vout != after.vout {
+            bail!("input {} spends a different outpoint after signing", i);
+        }
+        let Some(witness) = &before.txinwitness else {
+            continue;
+        };
+        if after.txinwitness.as_ref() != Some(witness) {
+            if witness.iter().any(|item| item.contains(ENVELOPE_MARKER)) {
+                bail!(
+                    "input {}: wallet altered the witness carrying the spell envelope",
+                    i
+                );
+            }
+            bail!(
+                "input {}: wallet altered a witness that was present before signing",
+                i
+            );
+        }
+    }
+
+    for (i, (before, after)) in unsigned.vout.iter().zip(&signed.vout).enumerate() {
+        if before.value != after.value || before.script_pubkey.hex != after.script_pubkey.hex {
+            bail!("output {} changed after signing", i);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::metrics::BackendStats;
+
+/// Metrics of one batch entry, for `--stats-out`.
+#[derive(Debug, Default, Serialize)]
+pub struct TxStats {
+    /// Position in the batch, from 1.
+    pub index: usize,
+    pub txid: Option<String>,
+    /// `complete`, `incomplete`, `signed` (Elements, whose completeness isn't checked),
+    /// `bumped` or `skipped` (non-bitcoin entries).
+    pub status: &'static str,
+    pub inputs: Option<usize>,
+    /// Inputs that had no signature before and have one now.
+    pub inputs_signed: Option<usize>,
+    pub size: Option<usize>,
+    pub vsize: Option<usize>,
+    pub weight: Option<u64>,
+    /// Satoshis.
+    pub fee: Option<u64>,
+    /// sat/vB, at the estimated signed size for incomplete transactions.
+    pub fee_rate: Option<f64>,
+    /// Time spent signing the entry.
+    pub duration_ms: u128,
+    /// The entry's `label`.
+    pub label: Option<String>,
+}
+
+const CSV_HEADER: &str =
+    "index,txid,status,inputs,inputs_signed,size,vsize,weight,fee,fee_rate,duration_ms,label";
+
+const BACKENDS_CSV_HEADER: &str =
+    "backend,calls,errors,error_rate,total_ms,mean_ms,p50_ms,p95_ms,max_ms";
+
+/// Quote a CSV field if it has to be.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Write the metrics as CSV if `path` ends in `.csv`, as a JSON array otherwise.
+pub fn write(path: &Path, stats: &[TxStats]) -> Result<()> {
+    let content = if path.extension().is_some_and(|ext| ext == "csv") {
+        let field = |value: Option<String>| value.unwrap_or_default();
+        let mut csv = format!("{}\n", CSV_HEADER);
+        for tx in stats {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                tx.index,
+                field(tx.txid.clone()),
+                tx.status,
+                field(tx.inputs.map(|n| n.to_string())),
+                field(tx.inputs_signed.map(|n| n.to_string())),
+                field(tx.size.map(|n| n.to_string())),
+                field(tx.vsize.map(|n| n.to_string())),
+                field(tx.weight.map(|n| n.to_string())),
+                field(tx.fee.map(|n| n.to_string())),
+                field(tx.fee_rate.map(|rate| format!("{:.2}", rate))),
+                tx.duration_ms,
+                field(tx.label.as_deref().map(csv_field))
+            ));
+        }
+        csv
+    } else {
+        serde_json::to_string_pretty(stats)? + "\n"
+    };
+    std::fs::write(path, content)
+        .with_context(|| format!("Failed to write statistics to {}", path.display()))
+}
+
+/// Where the backend metrics go next to the statistics at `path`:
+/// `stats.csv` gives `stats-backends.csv`.
+pub fn backends_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) => path.with_file_name(format!("{}-backends.{}", stem, ext.to_string_lossy())),
+        None => path.with_file_name(format!("{}-backends", stem)),
+    }
+}
+
+/// Write the backend metrics as CSV if `path` ends in `.csv`, as a JSON array otherwise.
+pub fn write_backends(path: &Path, backends: &[BackendStats]) -> Result<()> {
+    let content = if path.extension().is_some_and(|ext| ext == "csv") {
+        let mut csv = format!("{}\n", BACKENDS_CSV_HEADER);
+        for backend in backends {
+            csv.push_str(&format!(
+                "{},{},{},{:.4},{},{},{},{},{}\n",
+                csv_field(&backend.backend),
+                backend.calls,
+                backend.errors,
+                backend.error_rate,
+                backend.total_ms,
+                backend.mean_ms,
+                backend.p50_ms,
+                backend.p95_ms,
+                backend.max_ms
+            ));
+        }
+        csv
+    } else {
+        serde_json::to_string_pretty(backends)? + "\n"
+    };
+    std::fs::write(path, content)
+        .with_context(|| format!("Failed to write backend metrics to {}", path.display()))
+}
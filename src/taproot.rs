@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::consensus::encode::{deserialize_hex, serialize_hex};
+use bitcoin::hashes::Hash;
+use bitcoin::key::{Keypair, Secp256k1, TapTweak};
+use bitcoin::secp256k1::{All, Message, SecretKey};
+use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+use bitcoin::taproot::{self, TapNodeHash};
+use bitcoin::{OutPoint, PrivateKey, ScriptBuf, Transaction, TxOut, Witness};
+use serde::Deserialize;
+
+/// Key for a Taproot keypath spend, as given in the `--taproot-keys` file.
+#[derive(Debug, Deserialize)]
+struct KeyEntry {
+    /// Outpoint being spent, as `txid:vout`.
+    outpoint: String,
+    /// Internal private key, as WIF or 32-byte hex.
+    internal_key: String,
+    /// Merkle root of the script tree committed to by the output (none for BIP 86 outputs).
+    merkle_root: Option<String>,
+}
+
+/// Internal keys for Taproot outputs the wallet does not own, by the outpoint they unlock.
+///
+/// Outputs committing to a script tree (such as Charms commit outputs) can be
+/// spent through the keypath with the internal key tweaked by the tree's merkle
+/// root. The node wallet cannot do this for keys it doesn't hold, so these
+/// inputs are signed locally instead.
+pub struct TaprootKeys {
+    secp: Secp256k1<All>,
+    keys: HashMap<OutPoint, (Keypair, Option<TapNodeHash>)>,
+}
+
+impl TaprootKeys {
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path).context("Failed to read Taproot keys file")?;
+        let entries: Vec<KeyEntry> =
+            serde_json::from_str(&content).context("Failed to parse Taproot keys file")?;
+
+        let secp = Secp256k1::new();
+        let mut keys = HashMap::new();
+        for entry in entries {
+            let outpoint = OutPoint::from_str(&entry.outpoint)
+                .with_context(|| format!("Invalid outpoint {}", entry.outpoint))?;
+            let secret_key = match PrivateKey::from_wif(&entry.internal_key) {
+                Ok(key) => key.inner,
+                Err(_) => SecretKey::from_str(&entry.internal_key)
+                    .with_context(|| format!("Invalid internal key for {}", outpoint))?,
+            };
+            let merkle_root = entry
+                .merkle_root
+                .as_deref()
+                .map(TapNodeHash::from_str)
+                .transpose()
+                .with_context(|| format!("Invalid merkle root for {}", outpoint))?;
+            keys.insert(
+                outpoint,
+                (Keypair::from_secret_key(&secp, &secret_key), merkle_root),
+            );
+        }
+
+        Ok(TaprootKeys { secp, keys })
+    }
+
+    pub fn contains(&self, txid: &str, vout: u32) -> bool {
+        OutPoint::from_str(&format!("{}:{}", txid, vout))
+            .is_ok_and(|outpoint| self.keys.contains_key(&outpoint))
+    }
+
+    /// Sign the given inputs of a transaction through the Taproot keypath.
+    ///
+    /// `spent` holds the outputs spent by every input of the transaction, in
+    /// order, as the sighash commits to all of them.
+    pub fn sign(&self, raw_tx: &str, inputs: &[usize], spent: &[TxOut]) -> Result<String> {
+        let mut tx: Transaction = deserialize_hex(raw_tx).context("Failed to parse transaction")?;
+        let prevouts = Prevouts::All(spent);
+
+        let mut witnesses = Vec::new();
+        let mut cache = SighashCache::new(&tx);
+        for &i in inputs {
+            let (keypair, merkle_root) = &self.keys[&tx.input[i].previous_output];
+            let tweaked = keypair.tap_tweak(&self.secp, *merkle_root);
+
+            let (output_key, _) = keypair
+                .x_only_public_key()
+                .0
+                .tap_tweak(&self.secp, *merkle_root);
+            if spent[i].script_pubkey != ScriptBuf::new_p2tr_tweaked(output_key) {
+                bail!(
+                    "Input {}: internal key and merkle root do not match the output being spent",
+                    i
+                );
+            }
+
+            let sighash = cache
+                .taproot_key_spend_signature_hash(i, &prevouts, TapSighashType::Default)
+                .with_context(|| format!("Failed to compute sighash for input {}", i))?;
+            let msg = Message::from_digest(sighash.to_byte_array());
+            let signature = taproot::Signature {
+                signature: self.secp.sign_schnorr(&msg, &tweaked.to_keypair()),
+                sighash_type: TapSighashType::Default,
+            };
+            witnesses.push((i, Witness::p2tr_key_spend(&signature)));
+        }
+
+        for (i, witness) in witnesses {
+            tx.input[i].witness = witness;
+        }
+        Ok(serialize_hex(&tx))
+    }
+}
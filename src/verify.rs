@@ -0,0 +1,255 @@
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::Hash;
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::{Message, Secp256k1, XOnlyPublicKey};
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::{ecdsa, taproot, PublicKey, Script, ScriptBuf, Transaction, TxOut, Witness};
+use serde::Serialize;
+
+use crate::anchor;
+use crate::mempool::Validation;
+
+/// What the signatures of an input are worth, checked locally.
+#[derive(Debug)]
+pub enum InputCheck {
+    /// No scriptSig or witness.
+    Unsigned,
+    /// The input spends its prevout.
+    Valid,
+    /// A multisig witness still missing signatures, every signature present being valid.
+    Partial,
+    /// Cannot be checked locally (e.g. a taproot script path spend).
+    Unknown,
+    Invalid(String),
+}
+
+/// Check the signatures of input `index`, `spent` being the outputs all inputs spend.
+///
+/// Pre-taproot inputs are verified with libbitcoinconsensus. Taproot key
+/// path spends are checked against the output key; script path spends aren't.
+pub fn check_input(tx: &Transaction, index: usize, spent: &[TxOut]) -> InputCheck {
+    let input = &tx.input[index];
+    if input.script_sig.is_empty() && input.witness.is_empty() {
+        return InputCheck::Unsigned;
+    }
+    let prevout = &spent[index];
+    if prevout.script_pubkey.is_p2tr() {
+        return check_taproot(tx, index, spent);
+    }
+    match prevout
+        .script_pubkey
+        .verify(index, prevout.value, &serialize(tx))
+    {
+        Ok(()) => InputCheck::Valid,
+        Err(_) if partial_multisig(tx, index, prevout) => InputCheck::Partial,
+        Err(_) => InputCheck::Invalid("script verification failed".into()),
+    }
+}
+
+/// An input that isn't validly signed, and what is wrong with it.
+#[derive(Debug, Serialize)]
+pub struct InputProblem {
+    pub index: usize,
+    pub problem: String,
+}
+
+/// The signatures of a batch transaction, checked locally.
+#[derive(Debug, Serialize)]
+pub struct TxReport {
+    /// Position in the batch, from 1.
+    pub index: usize,
+    pub txid: String,
+    /// `complete`, `incomplete` (inputs unsigned or missing signatures) or `invalid`.
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<InputProblem>,
+    /// Taproot script path spends, which aren't verified.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unverified: Vec<usize>,
+    /// What `testmempoolaccept` made of it, with `verify --validate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mempool: Option<Validation>,
+}
+
+/// Check every input of batch transaction `index` (from 1), pay-to-anchor inputs needing no signature.
+pub fn report(index: usize, tx: &Transaction, spent: &[TxOut]) -> TxReport {
+    let mut inputs = Vec::new();
+    let mut unverified = Vec::new();
+    let mut invalid = false;
+    for i in 0..tx.input.len() {
+        if anchor::is_p2a(&spent[i].script_pubkey) {
+            continue;
+        }
+        let problem = match check_input(tx, i, spent) {
+            InputCheck::Valid => continue,
+            InputCheck::Unknown => {
+                unverified.push(i);
+                continue;
+            }
+            InputCheck::Unsigned => "not signed".to_string(),
+            InputCheck::Partial => "missing signatures".to_string(),
+            InputCheck::Invalid(reason) => {
+                invalid = true;
+                format!("invalid signature ({})", reason)
+            }
+        };
+        inputs.push(InputProblem { index: i, problem });
+    }
+    TxReport {
+        index,
+        txid: tx.compute_txid().to_string(),
+        status: match (invalid, inputs.is_empty()) {
+            (true, _) => "invalid",
+            (false, false) => "incomplete",
+            (false, true) => "complete",
+        },
+        inputs,
+        unverified,
+        mempool: None,
+    }
+}
+
+/// Remove the scriptSig and witness of the inputs whose signatures are invalid,
+/// returning those inputs and why they were.
+pub fn strip_invalid(tx: &mut Transaction, spent: &[TxOut]) -> Vec<(usize, String)> {
+    let invalid: Vec<(usize, String)> = (0..tx.input.len())
+        .filter_map(|index| match check_input(tx, index, spent) {
+            InputCheck::Invalid(reason) => Some((index, reason)),
+            _ => None,
+        })
+        .collect();
+    for (index, _) in &invalid {
+        tx.input[*index].script_sig = ScriptBuf::new();
+        tx.input[*index].witness = Witness::new();
+    }
+    invalid
+}
+
+fn check_taproot(tx: &Transaction, index: usize, spent: &[TxOut]) -> InputCheck {
+    let input = &tx.input[index];
+    let elements = input.witness.len() - usize::from(input.witness.taproot_annex().is_some());
+    if elements != 1 {
+        return InputCheck::Unknown;
+    }
+    let Ok(signature) = taproot::Signature::from_slice(&input.witness[0]) else {
+        return InputCheck::Invalid("malformed Schnorr signature".into());
+    };
+    let Ok(key) = XOnlyPublicKey::from_slice(&spent[index].script_pubkey.as_bytes()[2..]) else {
+        return InputCheck::Invalid("invalid taproot output key".into());
+    };
+    let sighash = match SighashCache::new(tx).taproot_key_spend_signature_hash(
+        index,
+        &Prevouts::All(spent),
+        signature.sighash_type,
+    ) {
+        Ok(sighash) => sighash,
+        Err(e) => return InputCheck::Invalid(e.to_string()),
+    };
+    let msg = Message::from_digest(sighash.to_byte_array());
+    match Secp256k1::verification_only().verify_schnorr(&signature.signature, &msg, &key) {
+        Ok(()) => InputCheck::Valid,
+        Err(e) => InputCheck::Invalid(e.to_string()),
+    }
+}
+
+/// A key whose signature of an input is valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigningKey {
+    Ecdsa(PublicKey),
+    /// Taproot output key of a key path spend.
+    Schnorr(XOnlyPublicKey),
+}
+
+/// Keys with a valid signature in input `index`.
+///
+/// Covers single-key inputs (P2PKH, P2WPKH, P2SH-P2WPKH, taproot key path)
+/// whose spend verifies, and the keys of a P2WSH (or P2SH-P2WSH) witness
+/// script with a valid signature, complete or not. Empty for other inputs.
+pub fn signing_keys(tx: &Transaction, index: usize, spent: &[TxOut]) -> Vec<SigningKey> {
+    let input = &tx.input[index];
+    let prevout = &spent[index];
+    match check_input(tx, index, spent) {
+        InputCheck::Valid if prevout.script_pubkey.is_p2tr() => {
+            XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..])
+                .map(|key| vec![SigningKey::Schnorr(key)])
+                .unwrap_or_default()
+        }
+        InputCheck::Valid | InputCheck::Partial => {
+            let single = if input.witness.is_empty() {
+                // P2PKH: <signature> <public key>
+                input
+                    .script_sig
+                    .instructions()
+                    .last()
+                    .and_then(|instruction| match instruction {
+                        Ok(Instruction::PushBytes(bytes)) => Some(bytes.as_bytes().to_vec()),
+                        _ => None,
+                    })
+            } else if input.witness.len() == 2 {
+                input.witness.last().map(<[u8]>::to_vec)
+            } else {
+                None
+            };
+            match single.and_then(|key| PublicKey::from_slice(&key).ok()) {
+                Some(key) => vec![SigningKey::Ecdsa(key)],
+                None => witness_script_signers(tx, index, prevout)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(SigningKey::Ecdsa)
+                    .collect(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Whether a P2WSH (or P2SH-P2WSH) input's witness is an incomplete one
+/// whose every signature is valid for a key of its witness script.
+fn partial_multisig(tx: &Transaction, index: usize, prevout: &TxOut) -> bool {
+    witness_script_signers(tx, index, prevout).is_some_and(|keys| !keys.is_empty())
+}
+
+/// Keys of a P2WSH (or P2SH-P2WSH) input's witness script that signed it,
+/// `None` if any signature of the witness is invalid for all of them.
+fn witness_script_signers(
+    tx: &Transaction,
+    index: usize,
+    prevout: &TxOut,
+) -> Option<Vec<PublicKey>> {
+    let witness = &tx.input[index].witness;
+    if !(prevout.script_pubkey.is_p2wsh() || prevout.script_pubkey.is_p2sh()) {
+        return None;
+    }
+    let script = witness.last().map(Script::from_bytes)?;
+    let keys: Vec<PublicKey> = script
+        .instructions()
+        .filter_map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => PublicKey::from_slice(bytes.as_bytes()).ok(),
+            _ => None,
+        })
+        .collect();
+    if keys.is_empty() {
+        return None;
+    }
+
+    let secp = Secp256k1::verification_only();
+    let mut cache = SighashCache::new(tx);
+    let elements = witness.len() - 1;
+    let mut signers = Vec::new();
+    for element in witness.iter().take(elements) {
+        if element.is_empty() {
+            continue;
+        }
+        let signature = ecdsa::Signature::from_slice(element).ok()?;
+        let sighash = cache
+            .p2wsh_signature_hash(index, script, prevout.value, signature.sighash_type)
+            .ok()?;
+        let msg = Message::from_digest(sighash.to_byte_array());
+        let signer = keys.iter().find(|key| {
+            secp.verify_ecdsa(&msg, &signature.signature, &key.inner)
+                .is_ok()
+        })?;
+        signers.push(*signer);
+    }
+    Some(signers)
+}
@@ -0,0 +1,258 @@
+use std::path::{Component, Path};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::hashes::{sha256, Hash};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::not_before::NotBefore;
+
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+#[derive(Debug, Deserialize)]
+struct WindowEntry {
+    /// Days the window opens on (`mon` to `sun`); every day if absent.
+    days: Option<Vec<String>>,
+    /// Local time the window opens at, as `HH:MM`.
+    from: String,
+    /// Local time the window closes at (excluded); earlier than `from` for a window spanning midnight.
+    to: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WindowPolicyFile {
+    /// IANA time zone (e.g. `Europe/Zurich`) the windows are in; UTC if absent.
+    timezone: Option<String>,
+    /// May be left out when there is a `not_before`.
+    #[serde(default)]
+    windows: Vec<WindowEntry>,
+    /// SHA-256 (hex) of the token allowing to sign outside the windows.
+    override_sha256: Option<String>,
+    /// Earliest block height and/or time batches may be signed at, whatever the windows.
+    not_before: Option<Value>,
+}
+
+struct Window {
+    /// Indexes in `DAYS`.
+    days: Vec<usize>,
+    from: u32,
+    to: u32,
+}
+
+/// When signing is allowed, as days of the week and local times, and not before.
+pub struct WindowPolicy {
+    timezone: String,
+    windows: Vec<Window>,
+    override_hash: Option<sha256::Hash>,
+    /// Not lifted by the override token, unlike the windows.
+    pub not_before: Option<NotBefore>,
+}
+
+/// Minutes since midnight of an `HH:MM` time.
+fn parse_time(time: &str) -> Result<u32> {
+    let (hours, minutes) = time
+        .split_once(':')
+        .and_then(|(hours, minutes)| {
+            Some((hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?))
+        })
+        .filter(|&(hours, minutes)| hours <= 24 && minutes < 60 && hours * 60 + minutes <= 24 * 60)
+        .with_context(|| {
+            format!(
+                "Invalid time {} in signing window policy, expected HH:MM",
+                time
+            )
+        })?;
+    Ok(hours * 60 + minutes)
+}
+
+/// Whether `timezone` is in the system's time zone database, named by its
+/// path there: absolute paths and `..` could point `TZ` anywhere.
+fn known_timezone(timezone: &str) -> bool {
+    let path = Path::new(timezone);
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        && Path::new(ZONEINFO).join(path).is_file()
+}
+
+impl WindowPolicy {
+    pub fn load(path: &str) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).context("Failed to read signing window policy file")?;
+        let file: WindowPolicyFile =
+            serde_json::from_str(&content).context("Failed to parse signing window policy file")?;
+        if file.windows.is_empty() && file.not_before.is_none() {
+            bail!("The signing window policy has no windows");
+        }
+        let mut windows = Vec::new();
+        for entry in file.windows {
+            let days = match entry.days {
+                Some(days) => days
+                    .iter()
+                    .map(|day| {
+                        DAYS.iter()
+                            .position(|name| name.eq_ignore_ascii_case(day))
+                            .with_context(|| {
+                                format!("Invalid day {} in signing window policy", day)
+                            })
+                    })
+                    .collect::<Result<_>>()?,
+                None => (0..DAYS.len()).collect(),
+            };
+            windows.push(Window {
+                days,
+                from: parse_time(&entry.from)?,
+                to: parse_time(&entry.to)?,
+            });
+        }
+        let override_hash = file
+            .override_sha256
+            .as_deref()
+            .map(|hash| hash.parse::<sha256::Hash>())
+            .transpose()
+            .context("Invalid override_sha256 in signing window policy")?;
+        // date silently falls back to UTC for time zones it doesn't know
+        if let Some(timezone) = file
+            .timezone
+            .as_ref()
+            .filter(|timezone| !known_timezone(timezone))
+        {
+            bail!("Unknown time zone {} in signing window policy", timezone);
+        }
+        Ok(WindowPolicy {
+            timezone: file.timezone.unwrap_or_else(|| "UTC".to_string()),
+            windows,
+            override_hash,
+            not_before: file
+                .not_before
+                .as_ref()
+                .map(NotBefore::parse)
+                .transpose()
+                .context("Invalid signing window policy")?,
+        })
+    }
+
+    /// Fail unless now is within a window, or the override token is valid.
+    ///
+    /// A policy with only a `not_before` has no windows to be outside of.
+    pub fn check(&self, override_token: Option<&str>) -> Result<()> {
+        if self.windows.is_empty() {
+            return Ok(());
+        }
+        let (day, time) = self.now()?;
+        let local = format!(
+            "{} {:02}:{:02} {}",
+            DAYS[day],
+            time / 60,
+            time % 60,
+            self.timezone
+        );
+        if self.windows.iter().any(|window| window.contains(day, time)) {
+            eprintln!("Within the signing windows ({})", local);
+            return Ok(());
+        }
+        match (override_token, &self.override_hash) {
+            (Some(token), Some(hash)) if sha256::Hash::hash(token.as_bytes()) == *hash => {
+                eprintln!(
+                    "Warning: outside the signing windows ({}), signing with the override token",
+                    local
+                );
+                Ok(())
+            }
+            (Some(_), _) => bail!(
+                "Outside the signing windows ({}) and the override token is invalid",
+                local
+            ),
+            (None, _) => bail!("Outside the signing windows ({})", local),
+        }
+    }
+
+    /// Day of the week (0 for Monday) and minutes since midnight, in the policy's time zone.
+    fn now(&self) -> Result<(usize, u32)> {
+        let output = Command::new("date")
+            .env("TZ", &self.timezone)
+            .arg("+%u %H %M")
+            .output()
+            .context("Failed to execute date")?;
+        let output = String::from_utf8_lossy(&output.stdout);
+        let fields: Vec<u32> = output
+            .split_whitespace()
+            .filter_map(|field| field.parse().ok())
+            .collect();
+        match fields[..] {
+            [day @ 1..=7, hours, minutes] => Ok((day as usize - 1, hours * 60 + minutes)),
+            _ => bail!(
+                "Failed to get the time in {}: {}",
+                self.timezone,
+                output.trim()
+            ),
+        }
+    }
+}
+
+impl Window {
+    fn contains(&self, day: usize, time: u32) -> bool {
+        if self.from <= self.to {
+            self.days.contains(&day) && self.from <= time && time < self.to
+        } else {
+            // Spans midnight: the evening of a listed day, or the morning after
+            let previous = (day + DAYS.len() - 1) % DAYS.len();
+            (self.days.contains(&day) && time >= self.from)
+                || (self.days.contains(&previous) && time < self.to)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(days: &[usize], from: &str, to: &str) -> Window {
+        Window {
+            days: days.to_vec(),
+            from: parse_time(from).unwrap(),
+            to: parse_time(to).unwrap(),
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let window = window(&[0, 1, 2, 3, 4], "09:00", "17:30");
+        assert!(window.contains(0, 9 * 60));
+        assert!(window.contains(4, 17 * 60 + 29));
+        assert!(!window.contains(4, 17 * 60 + 30));
+        assert!(!window.contains(0, 8 * 60 + 59));
+        assert!(!window.contains(5, 12 * 60));
+    }
+
+    #[test]
+    fn window_spanning_midnight() {
+        // Friday 22:00 to Saturday 06:00, and Sunday night into Monday morning
+        let window = window(&[4, 6], "22:00", "06:00");
+        assert!(window.contains(4, 22 * 60));
+        assert!(window.contains(5, 5 * 60 + 59));
+        assert!(!window.contains(5, 6 * 60));
+        assert!(!window.contains(5, 22 * 60));
+        assert!(window.contains(6, 23 * 60));
+        assert!(window.contains(0, 0));
+        assert!(!window.contains(4, 3 * 60));
+    }
+
+    #[test]
+    fn window_from_equal_to_is_empty() {
+        let window = window(&[0, 1, 2, 3, 4, 5, 6], "12:00", "12:00");
+        assert!((0..DAYS.len()).all(|day| (0..24 * 60).all(|time| !window.contains(day, time))));
+    }
+
+    #[test]
+    fn timezone_names_stay_in_the_database() {
+        assert!(!known_timezone("/etc/passwd"));
+        assert!(!known_timezone(&format!("{}/UTC", ZONEINFO)));
+        assert!(!known_timezone("../../../etc/passwd"));
+        assert!(!known_timezone("Europe/../UTC"));
+        assert!(!known_timezone("Europe"));
+        assert!(!known_timezone(""));
+    }
+}
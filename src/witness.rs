@@ -0,0 +1,49 @@
+use anyhow::{bail, Result};
+
+use crate::sign::DecodeResult;
+
+/// First byte of a Taproot annex (BIP 341).
+const ANNEX_TAG: &str = "50";
+
+/// The annex of a witness stack, if it carries one.
+///
+/// Per BIP 341 the last element is an annex when the stack has at least two
+/// elements and it starts with `0x50`. A lone `0x50`-prefixed element that is
+/// not signature-sized is also taken as an annex: that is how an unsigned
+/// input carrying a pre-committed annex looks before its signature is added.
+pub fn annex(witness: &[String]) -> Option<&str> {
+    let last = witness.last()?;
+    if !last.starts_with(ANNEX_TAG) {
+        return None;
+    }
+    let is_signature = witness.len() == 1 && matches!(last.len() / 2, 64 | 65);
+    (!is_signature).then_some(last.as_str())
+}
+
+/// Whether an input carries a signature, ignoring any annex.
+pub fn is_signed(witness: Option<&[String]>) -> bool {
+    match witness {
+        Some(witness) => witness.len() > annex(witness).map_or(0, |_| 1),
+        None => false,
+    }
+}
+
+/// Check that every annex present before signing is still in place afterwards.
+pub fn check_annexes_preserved(unsigned: &DecodeResult, signed: &DecodeResult) -> Result<()> {
+    for (i, (before, after)) in unsigned.vin.iter().zip(&signed.vin).enumerate() {
+        let Some(expected) = before.txinwitness.as_deref().and_then(annex) else {
+            continue;
+        };
+        if after.txinwitness.as_deref().and_then(annex) != Some(expected) {
+            bail!("Input {}: annex was dropped or altered by signing", i);
+        }
+    }
+    Ok(())
+}
+
+/// Whether any input of the transaction carries an annex.
+pub fn has_annex(tx: &DecodeResult) -> bool {
+    tx.vin
+        .iter()
+        .any(|input| input.txinwitness.as_deref().and_then(annex).is_some())
+}